@@ -1,5 +1,5 @@
 from abc import ABC, abstractmethod
-from typing import Optional
+from typing import List, Optional
 
 
 class AggregatorABC(ABC):
@@ -18,3 +18,22 @@ class AggregatorABC(ABC):
     @abstractmethod
     def get_global_weights(self) -> bytes:
         raise NotImplementedError()
+
+    def partial_aggregate(self) -> bytes:
+        """Aggregate the weights added since the last aggregation into
+        a partial result, without updating the global weights.
+
+        This is only called when the coordinator spreads the
+        participants over several aggregators. The partial results of
+        all the aggregators are then passed to `merge`. Aggregators
+        that don't implement these two methods cannot be scaled out.
+        """
+        raise NotImplementedError()
+
+    def merge(self, partials: List[bytes]) -> bytes:
+        """Merge the partial results returned by the `partial_aggregate`
+        method of all the aggregators, and return the new global
+        weights. Every aggregator merges the same partial results, in
+        the same order, so the merge must be deterministic.
+        """
+        raise NotImplementedError()
@@ -0,0 +1,117 @@
+from io import BytesIO
+import logging
+from typing import List, Optional
+
+import numpy as np
+
+from .aggregator import AggregatorABC
+
+LOG = logging.getLogger("PythonDeltaAverageAggregator")
+
+
+class Aggregator(AggregatorABC):
+    """Aggregator for participants that upload the difference between
+    their local weights and the global weights they trained from,
+    instead of their local weights.
+
+    Like the weighted average aggregator, each upload starts with the
+    number of samples the participant trained on, as a 4 bytes big
+    endian integer, followed by the delta as a numpy array. The deltas
+    are averaged, weighted by the number of samples, and applied to
+    the global weights by a server optimizer: SGD with momentum
+    (FedAvgM). With the default parameters, this is equivalent to
+    averaging the local weights.
+
+    To change the parameters of the server optimizer, subclass this
+    aggregator and override `learning_rate` and `momentum`.
+    """
+
+    learning_rate: float = 1.0
+    momentum: float = 0.0
+
+    def __init__(self) -> None:
+        logging.basicConfig(level=logging.INFO)
+        LOG.info("initializing aggregator")
+        self.global_weights: Optional[np.ndarray] = None
+        self.velocity: Optional[np.ndarray] = None
+        self.deltas: List[np.ndarray] = []
+        self.aggregation_data: List[float] = []
+
+    def add_weights(self, data: bytes) -> bool:
+        LOG.info("adding delta (len = %d)", len(data))
+
+        number_of_samples = int.from_bytes(data[:4], byteorder="big")
+        delta = np.load(BytesIO(data[4:]), allow_pickle=False)
+        if self.global_weights is not None and delta.shape != self.global_weights.shape:
+            LOG.error(
+                "invalid delta shape %s, expected %s",
+                delta.shape,
+                self.global_weights.shape,
+            )
+            return False
+
+        self.aggregation_data.append(number_of_samples)
+        self.deltas.append(delta)
+
+        return True
+
+    def aggregate(self) -> bytes:
+        LOG.info("starting aggregation (%d deltas)", len(self.deltas))
+        aggregation_weights: np.ndarray
+        if any(self.aggregation_data):
+            aggregation_weights = np.array(self.aggregation_data) / np.sum(
+                self.aggregation_data
+            )
+        else:
+            aggregation_weights = np.ones_like(self.aggregation_data) / len(
+                self.aggregation_data
+            )
+
+        delta = np.sum(
+            [
+                delta * aggregation_weight
+                for delta, aggregation_weight in zip(self.deltas, aggregation_weights)
+            ],
+            axis=0,
+        )
+        if self.velocity is None:
+            self.velocity = np.zeros_like(delta)
+        self.velocity = self.momentum * self.velocity + delta
+        update = self.learning_rate * self.velocity
+
+        # Before the first aggregation, the participants trained from
+        # scratch, so the deltas are their local weights
+        if self.global_weights is None:
+            self.global_weights = update
+        else:
+            self.global_weights = self.global_weights + update
+        # If global_weights is a scalar, make it a one dimensional
+        # array
+        if self.global_weights.shape == ():
+            self.global_weights = np.array([self.global_weights])
+        self.deltas = []
+        self.aggregation_data = []
+        LOG.info("finished aggregation")
+        return self.get_global_weights()
+
+    def reset(self, global_weights: Optional[bytes]) -> None:
+        LOG.info("resetting aggregator")
+        if global_weights is None:
+            self.global_weights = None
+            self.velocity = None
+        else:
+            reader = BytesIO(global_weights)
+            self.global_weights = np.load(reader, allow_pickle=False)
+
+        self.deltas = []
+        self.aggregation_data = []
+
+    def get_global_weights(self) -> bytes:
+        LOG.info("returning global weights")
+        if self.global_weights is None:
+            return b""
+        writer = BytesIO()
+        np.save(writer, self.global_weights, allow_pickle=False)
+        # We cannot use getvalue here because it copies the buffer.
+        # getbuffer will not as long as the data is not modified.
+        return writer.getbuffer()[:]
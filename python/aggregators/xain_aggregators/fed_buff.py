@@ -0,0 +1,37 @@
+import logging
+
+from .delta_average import Aggregator as DeltaAverageAggregator
+
+LOG = logging.getLogger("PythonFedBuffAggregator")
+
+
+class Aggregator(DeltaAverageAggregator):
+    """Aggregator for the asynchronous mode of the coordinator
+    (FedBuff).
+
+    Participants upload deltas, like for the delta average
+    aggregator, but they may have computed them from an older version
+    of the global model. The weight of each delta in the average is
+    its number of samples, discounted by its staleness:
+    `(1 + staleness) ** -staleness_exponent`, so that the deltas
+    computed from outdated models contribute less to the global
+    model.
+
+    To change the discount, subclass this aggregator and override
+    `staleness_exponent`.
+    """
+
+    staleness_exponent: float = 0.5
+
+    # pylint: disable=arguments-differ
+    def add_weights(self, data: bytes, staleness: int = 0) -> bool:
+        if not super().add_weights(data):
+            return False
+        LOG.info("delta staleness: %d", staleness)
+        # Participants that don't report their number of samples
+        # still have their delta discounted
+        number_of_samples = self.aggregation_data[-1] or 1
+        self.aggregation_data[-1] = (
+            number_of_samples * (1 + staleness) ** -self.staleness_exponent
+        )
+        return True
@@ -56,6 +56,75 @@ class Aggregator(AggregatorABC):
         LOG.info("finished aggregation")
         return self.get_global_weights()
 
+    def partial_aggregate(self) -> bytes:
+        """Return the number of samples and of models added so far, as
+        8 and 4 bytes big endian integers, followed by the sum of the
+        weights scaled by their number of samples. If no model has a
+        sample, the weights are summed without scaling.
+        """
+        LOG.info("starting partial aggregation (%d models)", len(self.weights))
+        number_of_samples = int(np.sum(self.aggregation_data, dtype=np.int64))
+        header = number_of_samples.to_bytes(8, byteorder="big") + len(
+            self.weights
+        ).to_bytes(4, byteorder="big")
+        if not self.weights:
+            return header
+        if number_of_samples:
+            weights = np.sum(
+                [
+                    model_weights * samples
+                    for model_weights, samples in zip(
+                        self.weights, self.aggregation_data
+                    )
+                ],
+                axis=0,
+            )
+        else:
+            weights = np.sum(self.weights, axis=0)
+        self.weights = []
+        self.aggregation_data = []
+        writer = BytesIO()
+        writer.write(header)
+        np.save(writer, weights, allow_pickle=False)
+        LOG.info("finished partial aggregation")
+        return writer.getbuffer()[:]
+
+    def merge(self, partials: List[bytes]) -> bytes:
+        LOG.info("merging %d partial aggregations", len(partials))
+        number_of_samples = 0
+        number_of_models = 0
+        weighted_sums: List[np.ndarray] = []
+        sums: List[np.ndarray] = []
+        for partial in partials:
+            samples = int.from_bytes(partial[:8], byteorder="big")
+            models = int.from_bytes(partial[8:12], byteorder="big")
+            if not models:
+                continue
+            weights = np.load(BytesIO(partial[12:]), allow_pickle=False)
+            number_of_samples += samples
+            number_of_models += models
+            if samples:
+                weighted_sums.append(weights)
+            else:
+                sums.append(weights)
+
+        # Like in `aggregate`, models without samples only count if
+        # no model has a sample
+        if number_of_samples:
+            self.global_weights = np.sum(weighted_sums, axis=0) / number_of_samples
+        elif number_of_models:
+            self.global_weights = np.sum(sums, axis=0) / number_of_models
+        else:
+            self.global_weights = np.sum([], axis=0)
+        # If global_weights is a scalar, make it a one dimensional
+        # array
+        if self.global_weights.shape == ():
+            self.global_weights = np.array([self.global_weights])
+        self.weights = []
+        self.aggregation_data = []
+        LOG.info("finished merging")
+        return self.get_global_weights()
+
     def reset(self, global_weights: Optional[bytes]) -> None:
         LOG.info("resetting aggregator")
         if global_weights is None:
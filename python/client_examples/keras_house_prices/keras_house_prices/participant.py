@@ -5,7 +5,7 @@ from io import BytesIO
 import logging
 import os
 import random
-from typing import List, Optional, Tuple
+from typing import Any, Dict, List, Optional, Tuple
 
 import numpy as np
 import pandas as pd
@@ -34,6 +34,7 @@ class Participant(  # pylint: disable=too-few-public-methods,too-many-instance-a
         testset_x: A dataset for test.
         testset_y: Labels for test.
         number_samples: The number of samples in the training dataset.
+        epochs: The number of epochs to train for in the current round.
         performance_metrics: metrics collected after each round of training
 
     """
@@ -45,6 +46,13 @@ class Participant(  # pylint: disable=too-few-public-methods,too-many-instance-a
         self.load_random_dataset(dataset_dir)
         self.regressor = Regressor(len(self.trainset_x.columns))
         self.performance_metrics: List[Tuple[float, float]] = []
+        self.epochs: int = 10
+
+    def configure_round(self, config: Dict[str, Any]) -> None:
+        """Use the number of epochs configured on the coordinator, if
+        any.
+        """
+        self.epochs = config.get("epochs", 10)
 
     def load_random_dataset(self, dataset_dir: str) -> None:
         """Load a random dataset from the data directory
@@ -94,10 +102,8 @@ class Participant(  # pylint: disable=too-few-public-methods,too-many-instance-a
             return (self.regressor.get_weights(), 0)
 
         weights = training_input
-        # FIXME: what should this be?
-        epochs = 10
         self.regressor.set_weights(weights)
-        self.regressor.train_n_epochs(epochs, self.trainset_x, self.trainset_y)
+        self.regressor.train_n_epochs(self.epochs, self.trainset_x, self.trainset_y)
 
         loss: float
         r_squared: float
@@ -109,6 +115,39 @@ class Participant(  # pylint: disable=too-few-public-methods,too-many-instance-a
 
         return (self.regressor.get_weights(), self.number_of_samples)
 
+    def training_metrics(
+        self, training_result: Tuple[np.ndarray, int]
+    ) -> Optional[Dict[str, float]]:
+        """Report the loss of the last round of training."""
+        (_, number_of_samples) = training_result
+        if not self.performance_metrics or number_of_samples == 0:
+            return None
+        loss, _ = self.performance_metrics[-1]
+        return {"samples": number_of_samples, "loss": float(loss)}
+
+    def evaluate(
+        self, training_input: Optional[np.ndarray]
+    ) -> Tuple[int, Dict[str, float]]:
+        """Evaluate the global model on the test dataset.
+
+        Args:
+
+            training_input: The weights of the global model.
+
+        Returns:
+
+            The number of test samples, and the loss and R² of the
+            global model.
+
+        """
+        if training_input is None:
+            return (0, {})
+        self.regressor.set_weights(training_input)
+        loss, r_squared = self.regressor.evaluate_on_test(
+            self.testset_x, self.testset_y
+        )
+        return (len(self.testset_x), {"loss": loss, "r_squared": r_squared})
+
     def deserialize_training_input(self, data: bytes) -> Optional[np.ndarray]:
         if not data:
             return None
@@ -1,20 +1,42 @@
 """Provides xain package SDK"""
 
 import sys
+from typing import Optional
 
 from .participant import ParticipantABC, ParticipantError
 from .utils import configure_logging
 
 
 def run_participant(
-    participant: ParticipantABC, coordinator_url: str, heartbeat_period: float = 1
+    participant: ParticipantABC,
+    coordinator_url: str,
+    heartbeat_period: float = 1,
+    task: Optional[str] = None,
+    upload_deltas: bool = False,
 ):
+    """Run the given participant until the training is complete.
+
+    Args:
+        participant: the participant to run
+        coordinator_url: the URL of the coordinator API
+        heartbeat_period: the number of seconds between heartbeats
+        task: the name of the training task to take part in. If it is
+            not set, the participant takes part in the default task.
+        upload_deltas: whether to upload the difference between the
+            local weights and the global weights, computed by
+            `ParticipantABC.compute_delta`, instead of the local
+            weights. The aggregator must be configured to expect
+            deltas.
+    """
     from .participant import (  # pylint: disable=import-outside-toplevel
         InternalParticipant,
     )
 
+    if task is not None:
+        coordinator_url = f"{coordinator_url.rstrip('/')}/tasks/{task}"
+
     internal_participant = InternalParticipant(
-        participant, coordinator_url, heartbeat_period
+        participant, coordinator_url, heartbeat_period, upload_deltas
     )
     internal_participant.run()
 
@@ -0,0 +1,101 @@
+"""Lossy compression of the local model updates.
+
+The functions of this module serialize a numpy array in the
+compressed update format understood by the aggregator, which expands
+it back to a dense numpy array of `float32` before aggregating
+it. They can be used in `ParticipantABC.serialize_training_result`
+to reduce the size of the uploads.
+
+The `prefix` argument is copied in front of the dense array by the
+aggregator. For instance, the weighted average aggregator expects the
+number of samples as a 4 bytes big endian integer.
+"""
+
+import struct
+
+import numpy as np
+
+MAGIC = b"XFLU"
+VERSION = 1
+
+QUANTIZED_8 = 1
+QUANTIZED_4 = 2
+TOP_K = 3
+SIGN = 4
+
+
+def _header(scheme: int, prefix: bytes, shape: tuple) -> bytes:
+    return b"".join(
+        [
+            MAGIC,
+            struct.pack("<BBI", VERSION, scheme, len(prefix)),
+            prefix,
+            struct.pack(f"<B{len(shape)}Q", len(shape), *shape),
+        ]
+    )
+
+
+def quantize(weights: np.ndarray, bits: int = 8, prefix: bytes = b"") -> bytes:
+    """Quantize each value on 8 or 4 bits. The values decoded by the
+    aggregator are within `(max - min) / (2 ** bits - 1) / 2` of the
+    original ones.
+    """
+    if bits not in (8, 4):
+        raise ValueError("only 8 and 4 bits are supported")
+    values = np.asarray(weights, dtype=np.float32).ravel()
+    levels = 2 ** bits - 1
+    minimum = float(values.min()) if values.size else 0.0
+    maximum = float(values.max()) if values.size else 0.0
+    scale = (maximum - minimum) / levels if maximum > minimum else 0.0
+    if scale == 0.0:
+        quantized = np.zeros(values.shape, dtype=np.uint8)
+    else:
+        quantized = np.clip(np.round((values - minimum) / scale), 0, levels).astype(
+            np.uint8
+        )
+    if bits == 4:
+        if quantized.size % 2:
+            quantized = np.append(quantized, np.uint8(0))
+        quantized = quantized[0::2] | (quantized[1::2] << 4)
+
+    scheme = QUANTIZED_8 if bits == 8 else QUANTIZED_4
+    return b"".join(
+        [
+            _header(scheme, prefix, np.shape(weights)),
+            struct.pack("<ff", minimum, scale),
+            quantized.tobytes(),
+        ]
+    )
+
+
+def top_k(weights: np.ndarray, k: int, prefix: bytes = b"") -> bytes:
+    """Keep only the `k` values with the largest magnitude. The
+    aggregator decodes the other values as zero.
+    """
+    values = np.asarray(weights, dtype=np.float32).ravel()
+    k = min(k, values.size)
+    indices = np.sort(np.argsort(-np.abs(values), kind="stable")[:k]).astype("<u4")
+    return b"".join(
+        [
+            _header(TOP_K, prefix, np.shape(weights)),
+            struct.pack("<I", k),
+            indices.tobytes(),
+            values[indices].astype("<f4").tobytes(),
+        ]
+    )
+
+
+def sign(weights: np.ndarray, prefix: bytes = b"") -> bytes:
+    """Keep only the sign of each value (sign-SGD). The aggregator
+    decodes the values as plus or minus their mean magnitude.
+    """
+    values = np.asarray(weights, dtype=np.float32).ravel()
+    scale = float(np.abs(values).mean()) if values.size else 0.0
+    bits = np.packbits(values >= 0, bitorder="little")
+    return b"".join(
+        [
+            _header(SIGN, prefix, np.shape(weights)),
+            struct.pack("<f", scale),
+            bits.tobytes(),
+        ]
+    )
@@ -1,12 +1,21 @@
 # pylint: disable=missing-docstring,invalid-name
+import gzip
 import json
 import logging
+from typing import Any, Dict, Optional
 import urllib
 
 import requests
 
+from .__version__ import __version__
+
 LOG = logging.getLogger("xain-sdk.http")
 
+# Version of the coordinator API spoken by the SDK
+API_VERSION = 1
+# Optional features of the coordinator API supported by the SDK
+CAPABILITIES = ["training_config", "training_metrics", "evaluation", "heartbeat_events"]
+
 
 def log_headers(headers):
     for (name, value) in headers.items():
@@ -44,8 +53,11 @@ class HttpClient:
         assert self._url.scheme
 
     def url(self, path):
+        # Keep the path of the base URL: the API of a task is served
+        # under `/tasks/<task_id>`
+        base = self._url.path.rstrip("/")
         path = path.strip("/")
-        return f"{self._url.scheme}://{self._url.netloc}/{path}"
+        return f"{self._url.scheme}://{self._url.netloc}{base}/{path}"
 
     def delete(self, path, status=204, **kwargs):
         req = self.build_req("DELETE", path, **kwargs)
@@ -67,6 +79,12 @@ class HttpClient:
         req = self.build_req("GET", path, **kwargs)
         return self.send(req, status=status)
 
+    def get_stream(self, path, status=200, **kwargs):
+        """Send a GET request, without waiting for the body of the
+        response to be received"""
+        req = self.build_req("GET", path, **kwargs)
+        return self.send(req, status=status, stream=True)
+
     @staticmethod
     def headers():
         headers = {}
@@ -76,10 +94,10 @@ class HttpClient:
         kwargs["headers"] = dict(kwargs.get("headers", {}), **self.headers())
         return requests.Request(method.upper(), self.url(path), **kwargs)
 
-    def send(self, req, status=200):
+    def send(self, req, status=200, stream=False):
         prepared = req.prepare()
         log_request(prepared)
-        resp = requests.Session().send(prepared)
+        resp = requests.Session().send(prepared, stream=stream)
         log_response(resp)
         self.check_response(resp, status=status)
         return resp
@@ -94,6 +112,13 @@ class ApiError(Exception):
     def __init__(self, response, *args, **kwargs):
         self.response = response
         self.error = response.text
+        # Machine readable error code, if the response is a JSON error
+        self.code = None
+        if response.headers.get("content-type") == "application/json":
+            try:
+                self.code = response.json().get("error")
+            except ValueError:
+                pass
         super().__init__(self.error, *args, **kwargs)
 
 
@@ -102,42 +127,107 @@ class AnonymousCoordinatorClient:
         self.url = url
         self.http = HttpClient(url)
 
-    def rendez_vous(self):
-        id = json.loads(self.http.get("rendez_vous").text)["id"]
-        return CoordinatorClient(self.url, id)
+    def rendez_vous(self, idempotency_key: str):
+        body = {
+            "version": API_VERSION,
+            "capabilities": ",".join(CAPABILITIES),
+            "metadata": {"sdk": "xain-sdk", "sdk_version": __version__},
+            # Make sure that the coordinator doesn't register a new
+            # client if the request is retried or replayed
+            "idempotency_key": idempotency_key,
+        }
+        try:
+            resp = json.loads(
+                self.http.post(f"v{API_VERSION}/rendez_vous", json=body).text
+            )
+        except ApiError as err:
+            # The other 400 errors are invalid requests, which are bugs
+            # of the SDK rather than incompatibilities
+            if err.response.status_code == 400 and err.code == "rejected":
+                raise IncompatibleVersion(err.response.json().get("message"))
+            raise
+        if not resp["ok"]:
+            raise RendezVousRejected(resp.get("retry_after"))
+        return CoordinatorClient(self.url, resp["id"], resp.get("capabilities", []))
 
 
 class CoordinatorClient:
-    def __init__(self, url, id):
+    def __init__(self, url, id, capabilities=None):
         self.url = url
         self.http = HttpClient(url)
         self.id = id
+        # Capabilities supported by both the SDK and the coordinator
+        self.capabilities = capabilities or []
 
     def heartbeat(self):
-        return json.loads(self.http.get(f"heartbeat/{self.id}").text)
-
-    def start_training(self):
-        resp = json.loads(self.http.get(f"start_training/{self.id}").text)
+        return json.loads(self.http.get(f"v{API_VERSION}/heartbeat/{self.id}").text)
+
+    def heartbeat_events(self):
+        """Open the push channel, and yield the heartbeat responses
+        sent by the coordinator, until it closes the channel. The
+        channel counts as a heartbeat for as long as it is open.
+        """
+        resp = self.http.get_stream(f"v{API_VERSION}/heartbeat/{self.id}/events")
+        with resp:
+            # The responses are sent as server-sent events. The other
+            # lines are keep-alive comments and event separators.
+            for line in resp.iter_lines(decode_unicode=True):
+                if line.startswith("data:"):
+                    yield json.loads(line[len("data:") :])
+
+    def start_training(self, idempotency_key: str):
+        try:
+            resp = json.loads(
+                self.http.post(
+                    f"v{API_VERSION}/start_training/{self.id}",
+                    json={"idempotency_key": idempotency_key},
+                ).text
+            )
+        except ApiError as err:
+            # The coordinator could not reach the aggregator: the
+            # participant is still selected and can try again later
+            if err.response.status_code == 503:
+                raise StartTrainingRejected()
+            raise
         if not resp["ok"]:
             raise StartTrainingRejected()
         url = resp["url"]
         token = resp["token"]
-        return AggregatorClient(url, self.id, token)
+        return AggregatorClient(url, self.id, token, resp.get("config"))
 
 
 class AggregatorClient:
-    def __init__(self, url, id, token):
+    def __init__(self, url, id, token, config=None):
         self.url = url
         self.http = HttpClient(url)
         self.id = id
         self.token = token
+        # Configuration of the local training sent by the coordinator
+        self.config = config or {}
+        # Entity tag of the last global model downloaded, used to
+        # tell the aggregator which model the local weights were
+        # computed from.
+        self.etag = None
 
     def download(self) -> bytes:
         resp = self.http.get(f"{self.id}/{self.token}")
+        self.etag = resp.headers.get("etag")
         return resp.content
 
-    def upload(self, data: bytes):
-        self.http.post(f"{self.id}/{self.token}", data=data)
+    def upload(self, data: bytes, metrics: Optional[Dict[str, Any]] = None):
+        headers = {"Content-Encoding": "gzip"}
+        data = gzip.compress(data)
+        if self.etag is not None:
+            headers["If-Match"] = self.etag
+        if metrics is not None:
+            headers["X-Training-Metrics"] = json.dumps(metrics)
+        self.http.post(f"{self.id}/{self.token}", data=data, headers=headers)
+
+    def upload_metrics(self, samples: int, metrics: Dict[str, float]):
+        self.http.post(
+            f"{self.id}/{self.token}/metrics",
+            json={"samples": samples, "metrics": metrics},
+        )
 
 
 class Clients:
@@ -149,3 +239,15 @@ class Clients:
 
 class StartTrainingRejected(Exception):
     pass
+
+
+class IncompatibleVersion(Exception):
+    """The coordinator does not speak the version of the API of the SDK"""
+
+
+class RendezVousRejected(Exception):
+    def __init__(self, retry_after=None):
+        # Number of seconds after which a new rendez-vous request can
+        # be sent. If it is None, the participant should give up.
+        self.retry_after = retry_after
+        super().__init__(retry_after)
@@ -4,14 +4,19 @@ import enum
 import logging
 import sys
 import threading
-from typing import Any, Optional, Tuple, TypeVar
+import time
+from typing import Any, Callable, Dict, Optional, Tuple, TypeVar
+import uuid
 
-from requests.exceptions import ConnectionError
+from requests.exceptions import ChunkedEncodingError, ConnectionError
 
 from .http import (
     AggregatorClient,
     AnonymousCoordinatorClient,
+    ApiError,
     CoordinatorClient,
+    IncompatibleVersion,
+    RendezVousRejected,
     StartTrainingRejected,
 )
 
@@ -19,6 +24,13 @@ LOG = logging.getLogger("http")
 
 TrainingResult = TypeVar("TrainingResult")
 TrainingInput = TypeVar("TrainingInput")
+T = TypeVar("T")
+
+# Number of times a request that could not reach the coordinator is
+# sent before giving up
+MAX_ATTEMPTS = 3
+# Number of seconds to wait before retrying such a request
+RETRY_DELAY = 5
 
 
 class ParticipantABC(ABC):
@@ -34,6 +46,52 @@ class ParticipantABC(ABC):
     def deserialize_training_input(self, data: bytes) -> TrainingInput:
         raise NotImplementedError()
 
+    def configure_round(self, config: Dict[str, Any]) -> None:
+        """Called before `train_round` with the training configuration
+        sent by the coordinator for the round: `round`, and optionally
+        `epochs`, `batch_size`, `learning_rate` and `hyperparameters`.
+        Does nothing by default.
+        """
+
+    def training_metrics(
+        self, training_result: TrainingResult
+    ) -> Optional[Dict[str, float]]:
+        """Called after `train_round` to report statistics of the local
+        training to the coordinator: `samples` (required), and
+        optionally `loss`, `accuracy` and `duration` (in seconds). If
+        the duration is not reported, the duration of `train_round` is
+        used. Return `None`, the default, to report nothing.
+        """
+        return None
+
+    def compute_delta(
+        self, training_input: TrainingInput, training_result: TrainingResult
+    ) -> TrainingResult:
+        """Called after `train_round` when the participant uploads
+        deltas instead of its local weights. Return the difference
+        between the local weights of `training_result` and the global
+        weights of `training_input` they were trained from, in a form
+        `serialize_training_result` accepts. Before the first
+        aggregation there are no global weights, and the delta is the
+        local weights.
+
+        Participants must implement this method if they are run with
+        `upload_deltas`.
+        """
+        raise NotImplementedError()
+
+    def evaluate(self, training_input: TrainingInput) -> Tuple[int, Dict[str, float]]:
+        """Evaluate the global model on the local data, during the
+        evaluation rounds of the coordinator. Return the number of
+        samples the model was evaluated on, and the value of each
+        metric. The aggregator computes the sample-weighted average
+        of the metrics of all the participants.
+
+        Participants must implement this method if the coordinator
+        runs evaluation rounds.
+        """
+        raise NotImplementedError()
+
 
 class State(enum.Enum):
     WAITING = 1
@@ -103,10 +161,12 @@ class InternalParticipant:
         participant: ParticipantABC,
         coordinator_url: str,
         heartbeat_period: float,
+        upload_deltas: bool = False,
     ):
         self.state_record = StateRecord()
         self.participant = participant
         self.heartbeat_period = heartbeat_period
+        self.upload_deltas = upload_deltas
 
         self.anonymous_client = AnonymousCoordinatorClient(coordinator_url)
         self.coordinator_client: Optional[CoordinatorClient] = None
@@ -146,12 +206,20 @@ class InternalParticipant:
     def train(self) -> None:
         try:
             LOG.info("requesting training information to the coordinator")
-            assert self.coordinator_client is not None
-            self.aggregator_client = self.coordinator_client.start_training()
+            coordinator_client = self.coordinator_client
+            assert coordinator_client is not None
+            # The same key is sent with all the attempts, so that the
+            # coordinator hands out a single token
+            idempotency_key = str(uuid.uuid4())
+            self.aggregator_client = self.send_with_retries(
+                lambda: coordinator_client.start_training(idempotency_key),
+                "start training request",
+            )
         except StartTrainingRejected:
             LOG.warning("start training request rejected")
             with self.state_record:
                 self.state_record.set_state(State.WAITING)
+            return
 
         LOG.info("downloading global weights from the aggregator")
         assert self.aggregator_client is not None
@@ -164,17 +232,34 @@ class InternalParticipant:
         except Exception as exc:
             raise DeserializationError() from exc
 
-        LOG.info("training")
+        if self.aggregator_client.config.get("evaluation", False):
+            self.evaluate(training_input)
+            return
+
+        LOG.info("training with configuration: %s", self.aggregator_client.config)
         try:
+            self.participant.configure_round(self.aggregator_client.config)
+            start = time.monotonic()
             result: Any = self.participant.train_round(training_input)
+            duration = time.monotonic() - start
+            metrics = self.participant.training_metrics(result)
+            if self.upload_deltas:
+                result = self.participant.compute_delta(training_input, result)
         except InterruptedError:
             raise
         except Exception as exc:
             raise TrainingError() from exc
         LOG.info("training finished")
+        if metrics is not None:
+            metrics = {"duration": duration, **metrics}
 
-        LOG.info("sending the local weights to the aggregator")
         assert self.aggregator_client is not None
+        # The aggregator cannot apply a delta without knowing which
+        # global model it was computed from
+        if self.upload_deltas and self.aggregator_client.etag is None:
+            raise ParticipantError("the version of the global model is unknown")
+
+        LOG.info("sending the local weights to the aggregator")
         try:
             data = self.participant.serialize_training_result(result)
         except InterruptedError:
@@ -182,20 +267,78 @@ class InternalParticipant:
         except Exception as exc:
             raise SerializationError() from exc
 
-        self.aggregator_client.upload(data)
+        self.aggregator_client.upload(data, metrics)
 
         LOG.info("going back to WAITING state")
         with self.state_record:
             self.state_record.set_state(State.WAITING)
 
-    def rendez_vous(self):
+    def evaluate(self, training_input: Any) -> None:
+        LOG.info("evaluating the global model")
+        assert self.aggregator_client is not None
         try:
-            self.coordinator_client = self.anonymous_client.rendez_vous()
-        except ConnectionError as err:
-            LOG.error("rendez vous failed: %s", err)
-            raise ParticipantError("Rendez-vous request failed")
+            self.participant.configure_round(self.aggregator_client.config)
+            samples, metrics = self.participant.evaluate(training_input)
+        except InterruptedError:
+            raise
+        except Exception as exc:
+            raise EvaluationError() from exc
+        LOG.info("evaluation finished: %s", metrics)
+
+        LOG.info("sending the metrics to the aggregator")
+        self.aggregator_client.upload_metrics(samples, metrics)
+
+        LOG.info("going back to WAITING state")
+        with self.state_record:
+            self.state_record.set_state(State.WAITING)
+
+    def rendez_vous(self):
+        # The same key is sent with all the attempts, so that the
+        # coordinator registers the participant only once
+        idempotency_key = str(uuid.uuid4())
+        while True:
+            try:
+                self.coordinator_client = self.send_with_retries(
+                    lambda: self.anonymous_client.rendez_vous(idempotency_key),
+                    "rendez vous request",
+                )
+                break
+            except ConnectionError as err:
+                LOG.error("rendez vous failed: %s", err)
+                raise ParticipantError("Rendez-vous request failed")
+            except IncompatibleVersion as err:
+                LOG.error("rendez vous rejected: %s", err)
+                raise ParticipantError(f"Incompatible coordinator: {err}")
+            except RendezVousRejected as err:
+                if err.retry_after is None:
+                    LOG.error("rendez vous rejected")
+                    raise ParticipantError("Rendez-vous request rejected")
+                LOG.warning(
+                    "coordinator is full, retrying in %d seconds", err.retry_after
+                )
+                if self.exit_event.wait(timeout=err.retry_after):
+                    raise InterruptedError()
         self.start_heartbeat()
 
+    def send_with_retries(self, request: Callable[[], T], name: str) -> T:
+        """Send a request, and send it again if it could not reach the
+        coordinator. The request must carry the same idempotency key
+        every time, so that the coordinator only processes it once.
+        """
+        attempt = 1
+        while True:
+            try:
+                return request()
+            except ConnectionError as err:
+                if attempt == MAX_ATTEMPTS:
+                    raise
+                LOG.warning(
+                    "%s failed, retrying in %d seconds: %s", name, RETRY_DELAY, err
+                )
+                if self.exit_event.wait(timeout=RETRY_DELAY):
+                    raise InterruptedError()
+                attempt += 1
+
     def start_heartbeat(self):
         self.heartbeat_thread = HeartBeatWorker(
             deepcopy(self.coordinator_client),
@@ -223,6 +366,9 @@ class HeartBeatWorker(threading.Thread):
     def run(self):
         LOG.debug("heartbeat thread starting")
         try:
+            if "heartbeat_events" in self.coordinator_client.capabilities:
+                if self.listen():
+                    return
             while True:
                 self.heartbeat()
                 if self.exit_event.wait(timeout=self.heartbeat_period):
@@ -234,9 +380,28 @@ class HeartBeatWorker(threading.Thread):
                 state_record.set_state(State.DONE)
             return
 
+    def listen(self) -> bool:
+        """Follow the heartbeat responses pushed by the coordinator
+        instead of polling them. Return whether the coordinator told
+        the participant to stop, otherwise the participant must go
+        back to polling.
+        """
+        LOG.debug("opening the heartbeat push channel")
+        state = None
+        try:
+            for resp in self.coordinator_client.heartbeat_events():
+                self.handle_response(resp)
+                state = resp["state"]
+                if self.exit_event.is_set():
+                    return True
+        except (ConnectionError, ChunkedEncodingError, ApiError) as err:
+            LOG.warning("heartbeat push channel failed: %s", err)
+        return state in ("finish", "reject")
+
     def heartbeat(self):
-        resp = self.coordinator_client.heartbeat()
+        self.handle_response(self.coordinator_client.heartbeat())
 
+    def handle_response(self, resp):
         with self.state_record as state_record:
             current_state, current_round = state_record.lookup()
             state = resp["state"]
@@ -273,5 +438,9 @@ class DeserializationError(ParticipantError):
     pass
 
 
+class EvaluationError(ParticipantError):
+    pass
+
+
 class SerializationError(ParticipantError):
     pass
@@ -1,13 +1,43 @@
 use crate::{
-    aggregator::service::{Aggregator, DownloadError, ServiceError, ServiceHandle, UploadError},
-    common::client::{ClientId, Credentials, Token},
+    aggregator::{
+        encoding::{EncodedModels, Encoding, UnsupportedEncoding},
+        evaluation::LocalMetrics,
+        service::{
+            Aggregator, DownloadError, InvalidModelVersion, Model, ModelVersion, ServiceError,
+            ServiceHandle, UploadError,
+        },
+        settings::{ApiSettings, UpdateKind},
+        spool::{Spool, SpoolError},
+        update,
+    },
+    common::{
+        auth,
+        client::{ClientId, Credentials, Token},
+        rate_limit::{self, RateLimiter},
+        shutdown::Shutdown,
+        task::{self, TaskId, Tasks},
+    },
+    coordinator::models::TrainingMetrics,
 };
-use bytes::Bytes;
-use std::{convert::Infallible, error::Error};
+use bytes::{Buf, Bytes};
+use futures::stream::{self, Stream};
+use std::{cmp, collections::HashMap, convert::Infallible, error::Error, path::PathBuf, sync::Arc};
 use tokio::net::TcpListener;
 use tracing_futures::Instrument;
 use warp::{
-    http::{header::CONTENT_TYPE, method::Method, Response, StatusCode},
+    http::{
+        header::{
+            ACCEPT_ENCODING, ACCEPT_RANGES, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH,
+            CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, IF_RANGE, RANGE, VARY,
+        },
+        method::Method,
+        Response, StatusCode,
+    },
+    hyper::{
+        server::{accept, Server},
+        service::make_service_fn,
+        Body,
+    },
     reject::{Reject, Rejection},
     reply::Reply,
     Filter,
@@ -22,6 +52,23 @@ struct ErrorResponse {
 // Make it possible to turn a ServiceError into a Rejection
 impl<E> Reject for ServiceError<E> where E: Error + Sized + Send + Sync + 'static {}
 
+impl Reject for SpoolError {}
+
+impl Reject for UnsupportedEncoding {}
+
+/// Rejection returned when the training statistics attached to an
+/// upload cannot be parsed
+#[derive(Debug)]
+struct InvalidTrainingMetrics;
+
+impl Reject for InvalidTrainingMetrics {}
+
+/// Rejection returned when the global weights cannot be compressed
+#[derive(Debug)]
+struct EncodingFailed;
+
+impl Reject for EncodingFailed {}
+
 type JsonErrorReply = warp::reply::WithStatus<warp::reply::Json>;
 
 /// Create a JSON response from a status code and an error message
@@ -65,92 +112,601 @@ async fn handle_rejection(e: Rejection) -> Result<impl Reply, Infallible> {
     Ok(error(code, message))
 }
 
+/// Header containing the round that produced the global weights
+const MODEL_ROUND: &str = "x-model-round";
+
+/// Header containing the hash of the global weights
+const MODEL_HASH: &str = "x-model-hash";
+
+/// Return the entity tag of the given model version, sent with the
+/// given encoding. Each encoding has its own tag, because the ranges
+/// requested by the clients apply to the encoded weights.
+fn etag(version: &ModelVersion, encoding: Encoding) -> String {
+    match encoding.header_value() {
+        Some(coding) => format!("\"{}-{}\"", version, coding),
+        None => format!("\"{}\"", version),
+    }
+}
+
+/// Parse the value of an `If-Match`, `If-None-Match` or `If-Range`
+/// header into a model version and the encoding it was sent with. A
+/// wildcard matches any version and is returned as `None`.
+fn parse_etag(value: &str) -> Result<Option<(ModelVersion, Encoding)>, InvalidModelVersion> {
+    let value = value.trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    let value = value.trim_start_matches("W/").trim_matches('"');
+    let (value, encoding) = [Encoding::Gzip, Encoding::Zstd]
+        .iter()
+        .find_map(|&encoding| {
+            let coding = encoding.header_value()?;
+            let value = value.strip_suffix(coding)?.strip_suffix('-')?;
+            Some((value, encoding))
+        })
+        .unwrap_or((value, Encoding::Identity));
+    value.parse().map(|version| Some((version, encoding)))
+}
+
+/// Size of the chunks in which the global weights are streamed
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Part of the global weights requested by a client
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// The whole weights
+    Full,
+    /// The bytes from `start` (included) to `end` (excluded)
+    Partial { start: u64, end: u64 },
+    /// A range that is outside of the weights
+    Unsatisfiable,
+}
+
+/// Parse the value of a `Range` header for a resource of `len`
+/// bytes.
+///
+/// Only single byte ranges are supported. Other ranges are ignored
+/// and the whole resource is sent, as allowed by RFC 7233.
+fn parse_range(value: &str, len: u64) -> ByteRange {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec,
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.find('-') {
+        Some(i) => (spec[..i].trim(), spec[i + 1..].trim()),
+        None => return ByteRange::Full,
+    };
+    if first.is_empty() {
+        // Suffix range: the last N bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Partial {
+                start: len.saturating_sub(suffix),
+                end: len,
+            },
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let start = match first.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Full,
+    };
+    let end = if last.is_empty() {
+        len
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= start => cmp::min(last + 1, len),
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial { start, end }
+    }
+}
+
+/// Split the given weights into a stream of chunks. The chunks are
+/// views into the same buffer, so the weights are not copied.
+fn chunked(weights: Bytes) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let len = weights.len();
+    stream::iter((0..len).step_by(DOWNLOAD_CHUNK_SIZE).map(move |start| {
+        let end = cmp::min(start + DOWNLOAD_CHUNK_SIZE, len);
+        Ok(weights.slice(start..end))
+    }))
+}
+
+/// Extract the encoding of the request body from the
+/// `Content-Encoding` header, and reject the requests with an
+/// unsupported encoding.
+fn content_encoding() -> impl Filter<Extract = (Encoding,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(CONTENT_ENCODING.as_str()).and_then(
+        |value: Option<String>| async move {
+            value
+                .map(|value| value.parse())
+                .unwrap_or(Ok(Encoding::Identity))
+                .map_err(warp::reject::custom)
+        },
+    )
+}
+
+/// Header in which the participants can report the statistics of
+/// their local training, as JSON, when uploading their weights
+const TRAINING_METRICS: &str = "x-training-metrics";
+
+/// Extract the training statistics from the `X-Training-Metrics`
+/// header, and reject the requests for which they cannot be parsed.
+fn training_metrics() -> impl Filter<Extract = (Option<TrainingMetrics>,), Error = Rejection> + Clone
+{
+    warp::header::optional::<String>(TRAINING_METRICS).and_then(
+        |value: Option<String>| async move {
+            value
+                .map(|value| serde_json::from_str(&value))
+                .transpose()
+                .map_err(|_| warp::reject::custom(InvalidTrainingMetrics))
+        },
+    )
+}
+
+/// Extract the encoding to use for the response from the
+/// `Accept-Encoding` header
+fn accept_encoding() -> impl Filter<Extract = (Encoding,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(ACCEPT_ENCODING.as_str())
+        .map(|value: Option<String>| Encoding::negotiate(value.as_deref()))
+}
+
 /// Handler for global weights download requests
+#[allow(clippy::too_many_arguments)]
 async fn handle_download_request<A: Aggregator + 'static>(
     id: ClientId,
     token: Token,
+    if_none_match: Option<String>,
+    range: Option<String>,
+    if_range: Option<String>,
+    encoding: Encoding,
+    encoded_models: Arc<EncodedModels>,
     handle: ServiceHandle<A>,
 ) -> Result<impl Reply, Rejection> {
     debug!("handling download request");
-    handle
+    let Model { version, weights } = handle
         .download(Credentials(id, token))
         .await
-        .map(|weights| {
-            Response::builder()
-                .header("Content-Type", "application/octet-stream")
-                .body(weights)
-        })
-        .map_err(warp::reject::custom)
+        .map_err(warp::reject::custom)?;
+
+    // The weights are the same whatever the encoding they were sent
+    // with
+    let not_modified = match if_none_match.as_deref().map(parse_etag) {
+        Some(Ok(Some((cached, _)))) => cached == version,
+        Some(Ok(None)) => true,
+        Some(Err(_)) | None => false,
+    };
+    let response = Response::builder()
+        .header(ETAG, etag(&version, encoding))
+        .header(MODEL_ROUND, version.round)
+        .header(MODEL_HASH, version.hash.as_str())
+        .header(ACCEPT_RANGES, "bytes")
+        .header(VARY, ACCEPT_ENCODING.as_str());
+    if not_modified {
+        debug!("client already has version {}", version);
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty()));
+    }
+
+    // A client resuming a download of another version of the model,
+    // or of the model sent with another encoding, must get the whole
+    // new model.
+    let range_applies = match if_range.as_deref().map(parse_etag) {
+        Some(Ok(Some((resumed, resumed_encoding)))) => {
+            resumed == version && resumed_encoding == encoding
+        }
+        Some(_) => false,
+        None => true,
+    };
+    // Ranges apply to the encoded weights
+    let weights = encoded_models
+        .get(&version, weights, encoding)
+        .await
+        .map_err(|e| {
+            error!("failed to encode the global weights: {}", e);
+            warp::reject::custom(EncodingFailed)
+        })?;
+    let response = match encoding.header_value() {
+        Some(value) => response.header(CONTENT_ENCODING, value),
+        None => response,
+    };
+
+    let len = weights.len() as u64;
+    let range = range
+        .filter(|_| range_applies)
+        .map(|range| parse_range(&range, len))
+        .unwrap_or(ByteRange::Full);
+    let response = response.header(CONTENT_TYPE, "application/octet-stream");
+    Ok(match range {
+        ByteRange::Full => response
+            .header(CONTENT_LENGTH, len)
+            .body(Body::wrap_stream(chunked(weights))),
+        ByteRange::Partial { start, end } => {
+            debug!("sending bytes {}-{} of {}", start, end, len);
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end - 1, len),
+                )
+                .header(CONTENT_LENGTH, end - start)
+                .body(Body::wrap_stream(chunked(
+                    weights.slice(start as usize..end as usize),
+                )))
+        }
+        ByteRange::Unsatisfiable => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty()),
+    })
 }
 
 /// Handler for local weights upload requests
-async fn handle_upload_request<A: Aggregator + 'static>(
+#[allow(clippy::too_many_arguments)]
+async fn handle_upload_request<A: Aggregator + 'static, S, B>(
     id: ClientId,
     token: Token,
-    weights: Bytes,
+    if_match: Option<String>,
+    metrics: Option<TrainingMetrics>,
+    content_length: Option<u64>,
+    encoding: Encoding,
+    body: S,
+    spool: Spool,
+    updates: UpdateKind,
     handle: ServiceHandle<A>,
-) -> Result<impl Reply, Rejection> {
+) -> Result<impl Reply, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
     debug!("handling upload request");
+    // A version that cannot be parsed cannot match the current one
+    let version = if_match
+        .as_deref()
+        .map(parse_etag)
+        .transpose()
+        .map_err(|_| warp::reject::custom(ServiceError::Request(UploadError::StaleModel)))?
+        .flatten()
+        .map(|(version, _)| version);
+    // Deltas are meaningless without the weights they apply to
+    if updates == UpdateKind::Delta && version.is_none() {
+        warn!("rejecting delta upload without a model version");
+        return Err(warp::reject::custom(ServiceError::Request(
+            UploadError::MissingModelVersion,
+        )));
+    }
+    // Don't receive bodies from clients that are not allowed to
+    // upload anything
+    let credentials = Credentials(id, token);
+    let global_model = handle
+        .check_credentials(credentials)
+        .await
+        .map_err(warp::reject::custom)?;
+    // Compressed updates must match the global model, which is
+    // unknown until the first aggregation
+    let model_len = update::npy_len(&global_model.weights);
+    let weights = spool
+        .receive(body, content_length, encoding, model_len)
+        .await
+        .map_err(|e| {
+            warn!("failed to receive the local weights: {}", e);
+            warp::reject::custom(e)
+        })?;
     handle
-        .upload(Credentials(id, token), weights)
+        .upload(credentials, version, metrics, weights)
         .await
         .map(|()| StatusCode::OK)
         .map_err(warp::reject::custom)
 }
 
+/// Maximum size of the metrics uploaded during an evaluation round
+const MAX_METRICS_SIZE: u64 = 64 * 1024;
+
+/// Handler for the upload of the metrics computed during an
+/// evaluation round
+async fn handle_upload_metrics_request<A: Aggregator + 'static>(
+    id: ClientId,
+    token: Token,
+    metrics: LocalMetrics,
+    handle: ServiceHandle<A>,
+) -> Result<impl Reply, Rejection> {
+    debug!("handling upload metrics request");
+    handle
+        .upload_metrics(Credentials(id, token), metrics)
+        .await
+        .map(|()| StatusCode::OK)
+        .map_err(warp::reject::custom)
+}
+
+/// Handler for the requests for the results of the evaluation
+/// rounds. The request must carry the admin token as a bearer token
+/// in the `Authorization` header.
+async fn handle_evaluations_request<A: Aggregator + 'static>(
+    authorization: Option<String>,
+    admin_token: Option<String>,
+    handle: ServiceHandle<A>,
+) -> Result<impl Reply, Rejection> {
+    debug!("handling evaluations request");
+    if !auth::is_authorized(authorization.as_deref(), admin_token.as_deref()) {
+        warn!("rejecting unauthorized evaluations request");
+        return Ok(unauthorized("Missing or invalid admin token"));
+    }
+    Ok(match handle.evaluations().await {
+        Ok(evaluations) => {
+            warp::reply::with_status(warp::reply::json(&evaluations), StatusCode::OK)
+        }
+        Err(_) => service_unavailable(),
+    })
+}
+
 async fn handle_upload_rejection(e: Rejection) -> Result<impl Reply, Rejection> {
+    if let Some(e) = e.find::<SpoolError>() {
+        return Ok(match e {
+            SpoolError::TooLarge(_) => error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Local model weights are too large",
+            ),
+            SpoolError::Body(_) => error(
+                StatusCode::BAD_REQUEST,
+                "Failed to receive the local model weights",
+            ),
+            SpoolError::Io(_) => error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to store the local model weights",
+            ),
+            SpoolError::Decode(_) => error(
+                StatusCode::BAD_REQUEST,
+                "Failed to decode the local model weights",
+            ),
+            SpoolError::InvalidUpdate(_) => error(
+                StatusCode::BAD_REQUEST,
+                "Invalid compressed local model update",
+            ),
+        });
+    }
+    if e.find::<UnsupportedEncoding>().is_some() {
+        return Ok(error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Unsupported content encoding",
+        ));
+    }
+    if e.find::<InvalidTrainingMetrics>().is_some() {
+        return Ok(error(StatusCode::BAD_REQUEST, "Invalid training metrics"));
+    }
+    if e.find::<warp::body::BodyDeserializeError>().is_some() {
+        return Ok(error(StatusCode::BAD_REQUEST, "Invalid metrics"));
+    }
+    if e.find::<warp::reject::PayloadTooLarge>().is_some() {
+        return Ok(error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Metrics are too large",
+        ));
+    }
     e.find::<ServiceError<UploadError>>()
-        .map(|e| {
-            Ok(match e {
-                ServiceError::Handle(_) => service_unavailable(),
-                ServiceError::Request(UploadError::Unauthorized) => {
-                    unauthorized("Not authorized to upload local model weights")
-                }
-            })
+        .map(|e| match e {
+            ServiceError::Handle(_) => service_unavailable(),
+            ServiceError::Request(UploadError::Unauthorized) => {
+                unauthorized("Not authorized to upload local model weights")
+            }
+            ServiceError::Request(UploadError::StaleModel) => error(
+                StatusCode::PRECONDITION_FAILED,
+                "Local model weights computed from an outdated global model",
+            ),
+            ServiceError::Request(UploadError::MissingModelVersion) => error(
+                StatusCode::PRECONDITION_REQUIRED,
+                "The If-Match header is required to upload deltas",
+            ),
+            ServiceError::Request(UploadError::EvaluationRound) => error(
+                StatusCode::CONFLICT,
+                "Local model weights cannot be uploaded during an evaluation round",
+            ),
+            ServiceError::Request(UploadError::NotEvaluationRound) => error(
+                StatusCode::CONFLICT,
+                "Metrics can only be uploaded during an evaluation round",
+            ),
         })
         .ok_or_else(|| e)
 }
 
 async fn handle_download_rejection(e: Rejection) -> Result<impl Reply, Rejection> {
+    if e.find::<EncodingFailed>().is_some() {
+        return Ok(error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to encode the global model weights",
+        ));
+    }
     e.find::<ServiceError<DownloadError>>()
-        .map(|e| {
-            Ok(match e {
-                ServiceError::Handle(_) => service_unavailable(),
-                ServiceError::Request(DownloadError::Unauthorized) => {
-                    unauthorized("Not authorized to retrieve the global model weights")
-                }
-            })
+        .map(|e| match e {
+            ServiceError::Handle(_) => service_unavailable(),
+            ServiceError::Request(DownloadError::Unauthorized) => {
+                unauthorized("Not authorized to retrieve the global model weights")
+            }
         })
         .ok_or_else(|| e)
 }
 
-pub async fn serve<A: Aggregator + 'static>(bind_address: &str, handle: ServiceHandle<A>) {
-    let handle = warp::any().map(move || handle.clone());
+/// Serve the API of the given tasks. The requests for a task are
+/// prefixed with `/tasks/<task_id>`, and the requests without this
+/// prefix are for the default task. The server stops once `shutdown`
+/// is triggered.
+pub async fn serve<A: Aggregator + 'static>(
+    settings: ApiSettings,
+    tasks: Tasks<ServiceHandle<A>>,
+    shutdown: Shutdown,
+) {
+    let ApiSettings {
+        bind_address,
+        admin_token,
+        rate_limit,
+        max_upload_size,
+        upload_spool_dir,
+        updates,
+    } = settings;
+    let spool = Spool::new(upload_spool_dir.map(PathBuf::from), max_upload_size);
+    let spool = warp::any().map(move || spool.clone());
+    // Each task has its own model, and so its own cache
+    let encoded_models: Arc<HashMap<TaskId, Arc<EncodedModels>>> = Arc::new(
+        tasks
+            .ids()
+            .map(|task| (task.clone(), Arc::new(EncodedModels::new())))
+            .collect(),
+    );
+    let encoded_models = warp::any().map(move || encoded_models.clone());
+    let task = task::route(tasks);
     let parent_span = tracing::Span::current();
 
     let download_global_weights = warp::get()
+        .and(task.clone())
         .and(warp::path::param::<ClientId>())
         .and(warp::path::param::<Token>())
-        .and(handle.clone())
-        .and_then(move |id, token, handle| {
-            let span =
-                trace_span!(parent: parent_span.clone(), "api_download_request", client_id = %id);
-            handle_download_request(id, token, handle).instrument(span)
-        })
+        .and(rate_limit::limit(RateLimiter::new(rate_limit.download)))
+        .and(warp::header::optional::<String>(IF_NONE_MATCH.as_str()))
+        .and(warp::header::optional::<String>(RANGE.as_str()))
+        .and(warp::header::optional::<String>(IF_RANGE.as_str()))
+        .and(accept_encoding())
+        .and(encoded_models)
+        .and_then(
+            move |task: TaskId,
+                  handle,
+                  id,
+                  token,
+                  if_none_match,
+                  range,
+                  if_range,
+                  encoding,
+                  encoded_models: Arc<HashMap<TaskId, Arc<EncodedModels>>>| {
+                let span = trace_span!(
+                    parent: parent_span.clone(),
+                    "api_download_request",
+                    task = %task,
+                    client_id = %id
+                );
+                // The requests are only routed to the tasks the caches
+                // were created for
+                let encoded_models = encoded_models[&task].clone();
+                handle_download_request(
+                    id,
+                    token,
+                    if_none_match,
+                    range,
+                    if_range,
+                    encoding,
+                    encoded_models,
+                    handle,
+                )
+                .instrument(span)
+            },
+        )
         .recover(handle_download_rejection)
-        .with(warp::cors().allow_any_origin().allow_method(Method::GET));
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_method(Method::GET)
+                .allow_headers(vec![IF_NONE_MATCH, RANGE, IF_RANGE])
+                .expose_headers(vec![
+                    ETAG.as_str(),
+                    MODEL_ROUND,
+                    MODEL_HASH,
+                    ACCEPT_RANGES.as_str(),
+                    CONTENT_RANGE.as_str(),
+                    CONTENT_ENCODING.as_str(),
+                ]),
+        );
+
+    // The weights and the metrics are uploaded at the end of the
+    // training, so they count against the same limit
+    let upload_limiter = RateLimiter::new(rate_limit.upload);
+
+    let parent_span = tracing::Span::current();
+    let upload_metrics = warp::post()
+        .and(task.clone())
+        .and(warp::path!(ClientId / Token / "metrics"))
+        .and(rate_limit::limit(upload_limiter.clone()))
+        .and(warp::body::content_length_limit(MAX_METRICS_SIZE))
+        .and(warp::body::json())
+        .and_then(move |task: TaskId, handle, id, token, metrics| {
+            let span = trace_span!(
+                parent: parent_span.clone(),
+                "api_upload_metrics_request",
+                task = %task,
+                client_id = %id
+            );
+            handle_upload_metrics_request::<A>(id, token, metrics, handle).instrument(span)
+        })
+        .recover(handle_upload_rejection)
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_method(Method::POST)
+                .allow_headers(vec![CONTENT_TYPE]),
+        );
+
+    let evaluations = warp::get()
+        .and(task.clone())
+        .and(warp::path!("evaluations"))
+        .and(warp::header::optional::<String>(AUTHORIZATION.as_str()))
+        .and(warp::any().map(move || admin_token.clone()))
+        .and_then(|_task: TaskId, handle, authorization, admin_token| {
+            handle_evaluations_request::<A>(authorization, admin_token, handle)
+        })
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_method(Method::GET)
+                .allow_headers(vec![AUTHORIZATION]),
+        );
 
     let parent_span = tracing::Span::current();
     let upload_local_weights = warp::post()
+        .and(task)
         .and(warp::path::param::<ClientId>())
         .and(warp::path::param::<Token>())
-        .and(warp::body::bytes())
-        .and(handle.clone())
-        .and_then(move |id, token, weights, handle: ServiceHandle<A>| {
-            let span =
-                trace_span!(parent: parent_span.clone(), "api_upload_request", client_id = %id);
-            handle_upload_request(id, token, weights, handle).instrument(span)
-        })
+        .and(rate_limit::limit(upload_limiter))
+        .and(warp::header::optional::<String>(IF_MATCH.as_str()))
+        .and(training_metrics())
+        .and(warp::header::optional::<u64>(CONTENT_LENGTH.as_str()))
+        .and(content_encoding())
+        .and(warp::body::stream())
+        .and(spool)
+        .and(warp::any().map(move || updates))
+        .and_then(
+            move |task: TaskId,
+                  handle,
+                  id,
+                  token,
+                  if_match,
+                  metrics,
+                  content_length,
+                  encoding,
+                  body,
+                  spool,
+                  updates| {
+                let span = trace_span!(
+                    parent: parent_span.clone(),
+                    "api_upload_request",
+                    task = %task,
+                    client_id = %id
+                );
+                handle_upload_request::<A, _, _>(
+                    id,
+                    token,
+                    if_match,
+                    metrics,
+                    content_length,
+                    encoding,
+                    body,
+                    spool,
+                    updates,
+                    handle,
+                )
+                .instrument(span)
+            },
+        )
         .recover(handle_upload_rejection)
         .with(
             warp::cors()
@@ -159,19 +715,79 @@ pub async fn serve<A: Aggregator + 'static>(bind_address: &str, handle: ServiceH
                 // Allow the "content-type" header which is requested
                 // in the CORS preflight request. Without this header,
                 // we will get an CORS error in the swagger ui.
-                .allow_header(CONTENT_TYPE),
+                .allow_headers(vec![
+                    CONTENT_TYPE.as_str(),
+                    CONTENT_ENCODING.as_str(),
+                    IF_MATCH.as_str(),
+                    TRAINING_METRICS,
+                ]),
         );
 
-    let mut listener = TcpListener::bind(bind_address).await.unwrap();
+    let mut listener = TcpListener::bind(bind_address.as_str()).await.unwrap();
 
     info!("starting HTTP server on {}", bind_address);
     let log = warp::log("http");
-    warp::serve(
-        download_global_weights
-            .or(upload_local_weights)
-            .recover(handle_rejection)
-            .with(log),
-    )
-    .run_incoming(listener.incoming())
-    .await
+    // The metrics are uploaded to a sub-path of the weights upload
+    // path, so they must be matched first
+    let routes = evaluations
+        .or(download_global_weights)
+        .or(upload_metrics)
+        .or(upload_local_weights)
+        .recover(rate_limit::handle_rejection)
+        .recover(handle_rejection)
+        .with(log);
+    // Once the shutdown is triggered, the server stops accepting
+    // connections and waits for the pending requests to complete
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let server = Server::builder(accept::from_stream(listener.incoming()))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.wait());
+    if let Err(e) = server.await {
+        error!("HTTP server error: {}", e);
+    }
+    info!("HTTP server stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        let partial = |start, end| ByteRange::Partial { start, end };
+        assert_eq!(parse_range("bytes=0-9", 100), partial(0, 10));
+        assert_eq!(parse_range("bytes=90-", 100), partial(90, 100));
+        assert_eq!(parse_range("bytes=90-200", 100), partial(90, 100));
+        assert_eq!(parse_range("bytes=-10", 100), partial(90, 100));
+        assert_eq!(parse_range("bytes=-200", 100), partial(0, 100));
+        assert_eq!(parse_range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), ByteRange::Unsatisfiable);
+        // Unsupported or invalid ranges are ignored
+        assert_eq!(parse_range("bytes=0-9,20-29", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=9-0", 100), ByteRange::Full);
+        assert_eq!(parse_range("items=0-9", 100), ByteRange::Full);
+        assert_eq!(parse_range("bytes=a-b", 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_etag() {
+        let version = ModelVersion::new(3, b"1111");
+        for &encoding in &[Encoding::Identity, Encoding::Gzip, Encoding::Zstd] {
+            let etag = etag(&version, encoding);
+            assert_eq!(
+                parse_etag(&etag).unwrap(),
+                Some((version.clone(), encoding))
+            );
+        }
+        assert_ne!(
+            etag(&version, Encoding::Identity),
+            etag(&version, Encoding::Gzip)
+        );
+        assert_eq!(parse_etag("*").unwrap(), None);
+        assert!(parse_etag("\"gzip\"").is_err());
+    }
 }
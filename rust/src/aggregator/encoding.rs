@@ -0,0 +1,318 @@
+use crate::aggregator::service::ModelVersion;
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, prelude::*, BufReader},
+    path::PathBuf,
+    str::FromStr,
+};
+use thiserror::Error;
+use tokio::{sync::Mutex, task};
+
+/// Content codings supported for the weights sent to and by the
+/// aggregator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Error, Debug)]
+#[error("unsupported content encoding `{0}`")]
+pub struct UnsupportedEncoding(String);
+
+impl FromStr for Encoding {
+    type Err = UnsupportedEncoding;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "identity" => Ok(Encoding::Identity),
+            "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+            "zstd" => Ok(Encoding::Zstd),
+            other => Err(UnsupportedEncoding(other.to_string())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("the decoded body is larger than {0} bytes")]
+    TooLarge(u64),
+
+    #[error("failed to decode the body: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl Encoding {
+    /// Value of the `Content-Encoding` header for this encoding, if
+    /// any
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            Encoding::Identity => None,
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Zstd => Some("zstd"),
+        }
+    }
+
+    /// Pick the encoding to use for a response, from the value of the
+    /// `Accept-Encoding` header sent by the client. Among the
+    /// encodings the client accepts with the highest preference, zstd
+    /// is preferred over gzip, which is preferred over no encoding at
+    /// all.
+    pub fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let accept_encoding = match accept_encoding {
+            Some(value) => value,
+            None => return Encoding::Identity,
+        };
+
+        let mut wildcard = None;
+        let mut qualities = HashMap::new();
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim();
+            let quality = params
+                .filter_map(|param| {
+                    let param = param.trim();
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|quality| quality.parse::<f32>().ok())
+                })
+                .next()
+                .unwrap_or(1.0);
+            if coding == "*" {
+                wildcard = Some(quality);
+            } else if let Ok(encoding) = coding.parse::<Encoding>() {
+                qualities.insert(encoding, quality);
+            }
+        }
+
+        let quality = |encoding| {
+            qualities.get(&encoding).copied().or(wildcard).unwrap_or(
+                // Identity is always acceptable unless explicitly
+                // refused
+                if encoding == Encoding::Identity {
+                    0.001
+                } else {
+                    0.0
+                },
+            )
+        };
+        let mut best = Encoding::Identity;
+        let mut best_quality = 0.0;
+        for &encoding in &[Encoding::Zstd, Encoding::Gzip, Encoding::Identity] {
+            let quality = quality(encoding);
+            if quality > best_quality {
+                best = encoding;
+                best_quality = quality;
+            }
+        }
+        best
+    }
+
+    /// Compress the given data. This is CPU intensive and should not
+    /// run on the executor threads.
+    pub fn encode(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Identity => Ok(data.to_vec()),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// Decompress the given data, failing if the decompressed data is
+    /// larger than `limit` bytes. This is CPU intensive and should
+    /// not run on the executor threads.
+    pub fn decode(self, data: &[u8], limit: Option<u64>) -> Result<Vec<u8>, DecodeError> {
+        if self == Encoding::Identity {
+            return Ok(data.to_vec());
+        }
+        self.decode_from(data, limit)
+    }
+
+    /// Decompress the data read from the given reader, failing if the
+    /// decompressed data is larger than `limit` bytes. The reader is
+    /// consumed as the data is decompressed.
+    pub fn decode_from<'a, R: Read + 'a>(
+        self,
+        reader: R,
+        limit: Option<u64>,
+    ) -> Result<Vec<u8>, DecodeError> {
+        let decoder: Box<dyn Read + 'a> = match self {
+            Encoding::Identity => Box::new(reader),
+            Encoding::Gzip => Box::new(GzDecoder::new(reader)),
+            Encoding::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        };
+        let mut decoded = Vec::new();
+        match limit {
+            // Read one more byte than allowed to detect bodies that
+            // are too large, without decompressing them entirely.
+            Some(limit) => {
+                decoder.take(limit + 1).read_to_end(&mut decoded)?;
+                if decoded.len() as u64 > limit {
+                    return Err(DecodeError::TooLarge(limit));
+                }
+            }
+            None => {
+                let mut decoder = decoder;
+                decoder.read_to_end(&mut decoded)?;
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+/// Decode a request body on the blocking thread pool
+pub async fn decode(
+    encoding: Encoding,
+    data: Bytes,
+    limit: Option<u64>,
+) -> Result<Bytes, DecodeError> {
+    if encoding == Encoding::Identity {
+        return Ok(data);
+    }
+    task::spawn_blocking(move || encoding.decode(&data, limit))
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        .map(Bytes::from)
+}
+
+/// Decode a request body spooled to the given file on the blocking
+/// thread pool. The file is decoded as it is read, so the encoded
+/// body is never entirely held in memory.
+pub async fn decode_file(
+    encoding: Encoding,
+    path: PathBuf,
+    limit: Option<u64>,
+) -> Result<Bytes, DecodeError> {
+    task::spawn_blocking(move || {
+        let file = BufReader::new(File::open(path)?);
+        encoding.decode_from(file, limit)
+    })
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+    .map(Bytes::from)
+}
+
+/// The encoded global weights, for the current version of the
+/// model. Compressing large models is expensive, so each version is
+/// compressed at most once per encoding, no matter how many
+/// participants download it.
+#[derive(Default)]
+pub struct EncodedModels(Mutex<Option<(ModelVersion, HashMap<Encoding, Bytes>)>>);
+
+impl EncodedModels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the given weights with the given encoding, compressing
+    /// them if they have not been yet.
+    pub async fn get(
+        &self,
+        version: &ModelVersion,
+        weights: Bytes,
+        encoding: Encoding,
+    ) -> io::Result<Bytes> {
+        if encoding == Encoding::Identity {
+            return Ok(weights);
+        }
+
+        // The lock is held while compressing, so that clients asking
+        // for the same encoding wait for the first one to be done
+        // instead of compressing the weights again.
+        let mut cache = self.0.lock().await;
+        match *cache {
+            Some((ref cached_version, _)) if cached_version == version => {}
+            _ => *cache = Some((version.clone(), HashMap::new())),
+        }
+        // UNWRAP_SAFE: we just made sure the cache is set
+        let encoded = &mut cache.as_mut().unwrap().1;
+        if let Some(bytes) = encoded.get(&encoding) {
+            return Ok(bytes.clone());
+        }
+
+        debug!(
+            "encoding version {} of the model with {:?}",
+            version, encoding
+        );
+        let bytes = task::spawn_blocking(move || encoding.encode(&weights))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .map(Bytes::from)?;
+        encoded.insert(encoding, bytes.clone());
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate(None), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("gzip")), Encoding::Gzip);
+        assert_eq!(Encoding::negotiate(Some("gzip, zstd")), Encoding::Zstd);
+        assert_eq!(
+            Encoding::negotiate(Some("gzip;q=1.0, zstd;q=0.5")),
+            Encoding::Gzip
+        );
+        assert_eq!(Encoding::negotiate(Some("br, deflate")), Encoding::Identity);
+        assert_eq!(Encoding::negotiate(Some("*")), Encoding::Zstd);
+        assert_eq!(Encoding::negotiate(Some("*, zstd;q=0")), Encoding::Gzip);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = vec![42; 10_000];
+        for &encoding in &[Encoding::Identity, Encoding::Gzip, Encoding::Zstd] {
+            let encoded = encoding.encode(&data).unwrap();
+            let decoded = encoding.decode(&encoded, Some(10_000)).unwrap();
+            assert_eq!(decoded, data);
+        }
+    }
+
+    /// Test that decoding stops as soon as the limit is exceeded
+    #[test]
+    fn test_decode_limit() {
+        let data = vec![42; 10_000];
+        for &encoding in &[Encoding::Gzip, Encoding::Zstd] {
+            let encoded = encoding.encode(&data).unwrap();
+            let res = encoding.decode(&encoded, Some(9_999));
+            assert!(matches!(res, Err(DecodeError::TooLarge(9_999))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encoded_models_cache() {
+        let cache = EncodedModels::new();
+        let weights = Bytes::from(vec![42; 10_000]);
+        let version = ModelVersion::new(1, &weights);
+
+        let encoded = cache
+            .get(&version, weights.clone(), Encoding::Gzip)
+            .await
+            .unwrap();
+        let cached = cache
+            .get(&version, weights.clone(), Encoding::Gzip)
+            .await
+            .unwrap();
+        // The cached bytes are shared with the first response
+        assert_eq!(encoded.as_ptr(), cached.as_ptr());
+
+        let new_weights = Bytes::from(vec![24; 10_000]);
+        let new_version = ModelVersion::new(2, &new_weights);
+        let encoded = cache
+            .get(&new_version, new_weights.clone(), Encoding::Gzip)
+            .await
+            .unwrap();
+        assert_eq!(Encoding::Gzip.decode(&encoded, None).unwrap(), new_weights);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+/// Metrics computed by a participant, during an evaluation round, by
+/// evaluating the global model on its local data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalMetrics {
+    /// Number of samples the metrics were computed on
+    pub samples: u64,
+    /// Value of each metric, by name
+    pub metrics: HashMap<String, f64>,
+}
+
+/// Sample-weighted sums of the metrics uploaded during an evaluation
+/// round. When the uploads are spread over several aggregators, the
+/// partial sums of all the aggregators are merged before computing
+/// the averages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSum {
+    /// Number of participants that uploaded metrics
+    participants: u32,
+    /// Total number of samples
+    samples: u64,
+    /// Sum of each metric, weighted by the number of samples
+    sums: HashMap<String, f64>,
+    /// Number of samples each metric was computed on, since the
+    /// participants do not necessarily report the same metrics
+    weights: HashMap<String, u64>,
+}
+
+impl MetricsSum {
+    /// Add the metrics uploaded by a participant
+    pub fn add(&mut self, local: &LocalMetrics) {
+        self.participants += 1;
+        self.samples += local.samples;
+        for (name, value) in local.metrics.iter() {
+            *self.sums.entry(name.clone()).or_default() += value * local.samples as f64;
+            *self.weights.entry(name.clone()).or_default() += local.samples;
+        }
+    }
+
+    /// Add the sums computed by another aggregator
+    pub fn merge(&mut self, other: MetricsSum) {
+        self.participants += other.participants;
+        self.samples += other.samples;
+        for (name, sum) in other.sums {
+            *self.sums.entry(name).or_default() += sum;
+        }
+        for (name, weight) in other.weights {
+            *self.weights.entry(name).or_default() += weight;
+        }
+    }
+
+    /// Compute the sample-weighted average of each metric. Metrics
+    /// that were only computed on empty datasets are left out.
+    pub fn average(&self, round: u32) -> Evaluation {
+        let metrics = self
+            .sums
+            .iter()
+            .filter_map(|(name, sum)| match self.weights.get(name) {
+                Some(&weight) if weight > 0 => Some((name.clone(), sum / weight as f64)),
+                _ => None,
+            })
+            .collect();
+        Evaluation {
+            round,
+            participants: self.participants,
+            samples: self.samples,
+            metrics,
+        }
+    }
+}
+
+/// Result of an evaluation round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Evaluation {
+    /// Number of training rounds that produced the evaluated model
+    pub round: u32,
+    /// Number of participants that uploaded metrics
+    pub participants: u32,
+    /// Total number of samples the metrics were computed on
+    pub samples: u64,
+    /// Sample-weighted average of each metric, by name
+    pub metrics: HashMap<String, f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(samples: u64, metrics: &[(&str, f64)]) -> LocalMetrics {
+        LocalMetrics {
+            samples,
+            metrics: metrics
+                .iter()
+                .map(|(name, value)| (name.to_string(), *value))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_average() {
+        let mut sum = MetricsSum::default();
+        sum.add(&local(10, &[("loss", 1.0), ("accuracy", 0.5)]));
+        sum.add(&local(30, &[("loss", 2.0)]));
+        sum.add(&local(0, &[("recall", 1.0)]));
+
+        let evaluation = sum.average(3);
+        assert_eq!(evaluation.round, 3);
+        assert_eq!(evaluation.participants, 3);
+        assert_eq!(evaluation.samples, 40);
+        assert_eq!(evaluation.metrics.len(), 2);
+        assert!((evaluation.metrics["loss"] - 1.75).abs() < 1e-9);
+        assert!((evaluation.metrics["accuracy"] - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut partial_1 = MetricsSum::default();
+        partial_1.add(&local(10, &[("loss", 1.0)]));
+        let mut partial_2 = MetricsSum::default();
+        partial_2.add(&local(30, &[("loss", 2.0)]));
+
+        let mut expected = MetricsSum::default();
+        expected.add(&local(10, &[("loss", 1.0)]));
+        expected.add(&local(30, &[("loss", 2.0)]));
+
+        partial_1.merge(partial_2);
+        assert_eq!(partial_1, expected);
+    }
+}
@@ -1,5 +1,9 @@
 pub mod api;
+pub mod encoding;
+pub mod evaluation;
 pub mod py_aggregator;
 pub mod rpc;
 pub mod service;
 pub mod settings;
+pub mod spool;
+pub mod update;
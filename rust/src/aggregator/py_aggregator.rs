@@ -12,8 +12,8 @@ use tokio::{
 
 use crate::aggregator::{service::Aggregator, settings::PythonAggregatorSettings};
 use pyo3::{
-    types::{PyBytes, PyModule},
-    GILGuard, PyObject, PyResult, Python, ToPyObject,
+    types::{PyBytes, PyList, PyModule, PyTuple},
+    GILGuard, IntoPy, Py, PyObject, PyResult, Python, ToPyObject,
 };
 
 pub struct PyAggregator {
@@ -52,29 +52,69 @@ impl PyAggregator {
 
     pub fn aggregate(&mut self) -> Result<Bytes, PyAggregatorError<AggregationFailed>> {
         info!("running aggregation");
-        let py = self.get_py();
-        let result = self
-            .aggregator
-            .call_method0(py, "aggregate")
-            .map_err(|e| {
-                // Currently, there is no easy way to convert `PyErr` into
-                // a Rust error type so we just print the error on
-                // stderr. See: https://github.com/PyO3/pyo3/issues/592
-                // and https://github.com/PyO3/pyo3/issues/682
-                e.print(py);
-                PyAggregatorError::<AggregationFailed>::from(PythonError::Call("aggregate"))
-            })?
-            .extract::<Vec<u8>>(py)
-            .map(Bytes::from)
-            .map_err(|e| {
-                // Currently, there is no easy way to convert `PyErr` into
-                // a Rust error type so we just print the error on
-                // stderr. See: https://github.com/PyO3/pyo3/issues/592
-                // and https://github.com/PyO3/pyo3/issues/682
-                e.print(py);
-                PythonError::Cast("bytes", "Vec<u8>")
-            })?;
+        let result = self.call_aggregation("aggregate", |_| ())?;
         info!("finished aggregation");
+        Ok(result)
+    }
+
+    /// Aggregate the weights added so far into a partial result, to
+    /// be merged with the partial results of the other aggregators
+    pub fn partial_aggregate(&mut self) -> Result<Bytes, PyAggregatorError<AggregationFailed>> {
+        info!("running partial aggregation");
+        let result = self.call_aggregation("partial_aggregate", |_| ())?;
+        info!("finished partial aggregation");
+        Ok(result)
+    }
+
+    /// Merge the partial results of all the aggregators into the new
+    /// global weights
+    pub fn merge(
+        &mut self,
+        partials: &[Bytes],
+    ) -> Result<Bytes, PyAggregatorError<AggregationFailed>> {
+        info!("merging {} partial aggregations", partials.len());
+        let result = self.call_aggregation("merge", |py| {
+            let partials = partials.iter().map(|partial| PyBytes::new(py, partial));
+            (PyList::new(py, partials).to_object(py),)
+        })?;
+        info!("finished merging");
+        Ok(result)
+    }
+
+    /// Call an aggregation method of the aggregator, with the
+    /// arguments built by `args`, and return the weights it returns
+    fn call_aggregation<F, T>(
+        &mut self,
+        method: &'static str,
+        args: F,
+    ) -> Result<Bytes, PyAggregatorError<AggregationFailed>>
+    where
+        F: FnOnce(Python) -> T,
+        T: IntoPy<Py<PyTuple>>,
+    {
+        let result = {
+            let py = self.get_py();
+            self.aggregator
+                .call_method1(py, method, args(py))
+                .map_err(|e| {
+                    // Currently, there is no easy way to convert `PyErr` into
+                    // a Rust error type so we just print the error on
+                    // stderr. See: https://github.com/PyO3/pyo3/issues/592
+                    // and https://github.com/PyO3/pyo3/issues/682
+                    e.print(py);
+                    PyAggregatorError::<AggregationFailed>::from(PythonError::Call(method))
+                })?
+                .extract::<Vec<u8>>(py)
+                .map(Bytes::from)
+                .map_err(|e| {
+                    // Currently, there is no easy way to convert `PyErr` into
+                    // a Rust error type so we just print the error on
+                    // stderr. See: https://github.com/PyO3/pyo3/issues/592
+                    // and https://github.com/PyO3/pyo3/issues/682
+                    e.print(py);
+                    PythonError::Cast("bytes", "Vec<u8>")
+                })?
+        };
         self.re_acquire_gil();
         Ok(result)
     }
@@ -85,6 +125,20 @@ impl PyAggregator {
         self.gil = Some(Python::acquire_gil());
     }
 
+    /// Release the GIL, so that the aggregators running in other
+    /// threads can run. It must be acquired again with
+    /// [`PyAggregator::acquire_gil`] before calling the aggregator.
+    fn release_gil(&mut self) {
+        self.gil = None;
+    }
+
+    /// Acquire the GIL released by [`PyAggregator::release_gil`]
+    fn acquire_gil(&mut self) {
+        if self.gil.is_none() {
+            self.gil = Some(Python::acquire_gil());
+        }
+    }
+
     pub fn get_global_weights(&self) -> PyResult<Bytes> {
         let py = self.get_py();
         Ok(self
@@ -94,16 +148,24 @@ impl PyAggregator {
             .map(Bytes::from)?)
     }
 
+    /// Add the given local weights. If `staleness` is set, it is
+    /// passed to the aggregator as second argument.
     pub fn add_weights(
         &self,
         local_weights: &[u8],
+        staleness: Option<u32>,
     ) -> Result<(), PyAggregatorError<InvalidWeights>> {
         info!("adding weights");
         let py = self.get_py();
         let py_bytes = PyBytes::new(py, local_weights);
-        let args = (py_bytes,);
-        self.aggregator
-            .call_method1(py, "add_weights", args)
+        let result = match staleness {
+            Some(staleness) => {
+                self.aggregator
+                    .call_method1(py, "add_weights", (py_bytes, staleness))
+            }
+            None => self.aggregator.call_method1(py, "add_weights", (py_bytes,)),
+        };
+        result
             .map_err(|e| {
                 // Currently, there is no easy way to convert `PyErr` into
                 // a Rust error type so we just print the error on
@@ -129,16 +191,23 @@ impl PyAggregator {
 
     pub fn get_py(&self) -> Python<'_> {
         // UNWRAP_SAFE: As long as PyAggregator exists, self.gil
-        // cannot be None: the only place where we temporarily set it
-        // to None is in PyAggregator.re_acquire_gil(), but we set it
-        // back to Some right away.
+        // cannot be None when the aggregator is called: we
+        // temporarily set it to None in PyAggregator.re_acquire_gil()
+        // and while py_aggregator() waits for requests, but we set it
+        // back to Some before calling the aggregator.
         self.gil.as_ref().unwrap().python()
     }
 
     pub fn reset(&mut self, global_weights: &[u8]) -> Result<(), PythonError> {
         info!("resetting weights");
         let py = self.get_py();
-        let py_bytes = PyBytes::new(py, global_weights);
+        // No aggregation happened yet, so there are no global
+        // weights to restore
+        let py_bytes = if global_weights.is_empty() {
+            py.None()
+        } else {
+            PyBytes::new(py, global_weights).to_object(py)
+        };
         let args = (py_bytes,);
         self.aggregator
             .call_method1(py, "reset", args)
@@ -159,20 +228,36 @@ pub type Request<T, U> = (T, oneshot::Sender<U>);
 pub type RequestRx<T, U> = UnboundedReceiver<Request<T, U>>;
 pub type RequestTx<T, U> = UnboundedSender<Request<T, U>>;
 
+/// The aggregations the Python aggregator can run
+#[derive(Debug)]
+pub enum Aggregation {
+    /// Aggregate the weights and update the global weights
+    Full,
+    /// Aggregate the weights into a partial result
+    Partial,
+    /// Merge the partial results of all the aggregators and update
+    /// the global weights
+    Merge(Vec<Bytes>),
+}
+
 pub fn spawn_py_aggregator(
     settings: PythonAggregatorSettings,
 ) -> (PyAggregatorHandle, Receiver<()>) {
-    let (aggregate_tx, aggregate_rx) =
-        unbounded_channel::<Request<(), Result<Bytes, PyAggregatorError<AggregationFailed>>>>();
+    let (aggregate_tx, aggregate_rx) = unbounded_channel::<
+        Request<Aggregation, Result<Bytes, PyAggregatorError<AggregationFailed>>>,
+    >();
+
+    let (add_weights_tx, add_weights_rx) = unbounded_channel::<
+        Request<(Bytes, Option<u32>), Result<(), PyAggregatorError<InvalidWeights>>>,
+    >();
 
-    let (add_weights_tx, add_weights_rx) =
-        unbounded_channel::<Request<Bytes, Result<(), PyAggregatorError<InvalidWeights>>>>();
+    let (reset_tx, reset_rx) = unbounded_channel::<Request<Bytes, Result<(), PythonError>>>();
 
     let (mut shutdown_tx, shutdown_rx) = channel::<()>(1);
 
     thread::spawn(move || {
         block_on(async move {
-            let _ = py_aggregator(settings, aggregate_rx, add_weights_rx)
+            let _ = py_aggregator(settings, aggregate_rx, add_weights_rx, reset_rx)
                 .await
                 .map_err(|e| {
                     error!(error=%e, "py_aggregator terminated with an error");
@@ -186,26 +271,46 @@ pub fn spawn_py_aggregator(
     let handle = PyAggregatorHandle {
         aggregate_requests: aggregate_tx,
         add_weights_requests: add_weights_tx,
+        reset_requests: reset_tx,
     };
     (handle, shutdown_rx)
 }
 
 pub struct PyAggregatorHandle {
-    pub aggregate_requests: RequestTx<(), Result<Bytes, PyAggregatorError<AggregationFailed>>>,
-    pub add_weights_requests: RequestTx<Bytes, Result<(), PyAggregatorError<InvalidWeights>>>,
+    pub aggregate_requests:
+        RequestTx<Aggregation, Result<Bytes, PyAggregatorError<AggregationFailed>>>,
+    pub add_weights_requests:
+        RequestTx<(Bytes, Option<u32>), Result<(), PyAggregatorError<InvalidWeights>>>,
+    pub reset_requests: RequestTx<Bytes, Result<(), PythonError>>,
+}
+
+impl PyAggregatorHandle {
+    fn run_aggregation(&self, aggregation: Aggregation) -> <Self as Aggregator>::AggregateFut {
+        let (tx, rx) = oneshot::channel::<Result<Bytes, PyAggregatorError<AggregationFailed>>>();
+        let aggregate_requests = self.aggregate_requests.clone();
+        Box::pin(async move {
+            aggregate_requests
+                .send((aggregation, tx))
+                .map_err(|_| ChannelError::Request)?;
+            rx.await
+                .map_err(|_| ChannelError::Response)?
+                .map_err(From::from)
+        })
+    }
 }
 
 impl Aggregator for PyAggregatorHandle {
     type Error = PyAggregatorHandleError;
     type AggregateFut = Pin<Box<dyn Future<Output = Result<Bytes, Self::Error>> + Send>>;
     type AddWeightsFut = Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send>>;
+    type ResetFut = Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send>>;
 
-    fn add_weights(&mut self, weights: Bytes) -> Self::AddWeightsFut {
+    fn add_weights(&mut self, weights: Bytes, staleness: Option<u32>) -> Self::AddWeightsFut {
         let (tx, rx) = oneshot::channel::<Result<(), PyAggregatorError<InvalidWeights>>>();
         let add_weights_requests = self.add_weights_requests.clone();
         Box::pin(async move {
             add_weights_requests
-                .send((weights, tx))
+                .send(((weights, staleness), tx))
                 .map_err(|_| ChannelError::Request)?;
             rx.await
                 .map_err(|_| ChannelError::Response)?
@@ -214,11 +319,23 @@ impl Aggregator for PyAggregatorHandle {
     }
 
     fn aggregate(&mut self) -> Self::AggregateFut {
-        let (tx, rx) = oneshot::channel::<Result<Bytes, PyAggregatorError<AggregationFailed>>>();
-        let aggregate_requests = self.aggregate_requests.clone();
+        self.run_aggregation(Aggregation::Full)
+    }
+
+    fn partial_aggregate(&mut self) -> Self::AggregateFut {
+        self.run_aggregation(Aggregation::Partial)
+    }
+
+    fn merge(&mut self, partials: Vec<Bytes>) -> Self::AggregateFut {
+        self.run_aggregation(Aggregation::Merge(partials))
+    }
+
+    fn reset(&mut self, global_weights: Bytes) -> Self::ResetFut {
+        let (tx, rx) = oneshot::channel::<Result<(), PythonError>>();
+        let reset_requests = self.reset_requests.clone();
         Box::pin(async move {
-            aggregate_requests
-                .send(((), tx))
+            reset_requests
+                .send((global_weights, tx))
                 .map_err(|_| ChannelError::Request)?;
             rx.await
                 .map_err(|_| ChannelError::Response)?
@@ -229,28 +346,52 @@ impl Aggregator for PyAggregatorHandle {
 
 async fn py_aggregator(
     settings: PythonAggregatorSettings,
-    mut aggregate_requests: RequestRx<(), Result<Bytes, PyAggregatorError<AggregationFailed>>>,
-    mut add_weights_requests: RequestRx<Bytes, Result<(), PyAggregatorError<InvalidWeights>>>,
+    mut aggregate_requests: RequestRx<
+        Aggregation,
+        Result<Bytes, PyAggregatorError<AggregationFailed>>,
+    >,
+    mut add_weights_requests: RequestRx<
+        (Bytes, Option<u32>),
+        Result<(), PyAggregatorError<InvalidWeights>>,
+    >,
+    mut reset_requests: RequestRx<Bytes, Result<(), PythonError>>,
 ) -> Result<(), PythonError> {
     let mut aggregator = PyAggregator::load(settings)?;
 
     loop {
+        // Don't hold the GIL while waiting for requests: other
+        // aggregators may run in other threads, one for each task.
+        aggregator.release_gil();
         select! {
-            Some(((), resp_tx)) = aggregate_requests.recv() => {
-                let weights = aggregator.aggregate();
+            Some((aggregation, resp_tx)) = aggregate_requests.recv() => {
+                aggregator.acquire_gil();
+                let weights = match aggregation {
+                    Aggregation::Full => aggregator.aggregate(),
+                    Aggregation::Partial => aggregator.partial_aggregate(),
+                    Aggregation::Merge(partials) => aggregator.merge(&partials),
+                };
                 if resp_tx.send(weights).is_err() {
                     warn!("cannot send aggregate response: receiver is closed");
                     break;
                 }
 
             }
-            Some((weights, resp_tx)) = add_weights_requests.recv() => {
-                let res = aggregator.add_weights(&weights[..]);
+            Some(((weights, staleness), resp_tx)) = add_weights_requests.recv() => {
+                aggregator.acquire_gil();
+                let res = aggregator.add_weights(&weights[..], staleness);
                 if resp_tx.send(res).is_err() {
                     warn!("cannot send add_weights response: receiver is closed");
                     break;
                 }
             }
+            Some((global_weights, resp_tx)) = reset_requests.recv() => {
+                aggregator.acquire_gil();
+                let res = aggregator.reset(&global_weights[..]);
+                if resp_tx.send(res).is_err() {
+                    warn!("cannot send reset response: receiver is closed");
+                    break;
+                }
+            }
             else => {
                 warn!("PyAggregator shutting down: at least one receiver is closed");
                 break;
@@ -267,6 +408,9 @@ async fn py_aggregator(
     add_weights_requests.close();
     while add_weights_requests.try_recv().is_ok() {}
 
+    reset_requests.close();
+    while reset_requests.try_recv().is_ok() {}
+
     Ok(())
 }
 
@@ -445,7 +589,7 @@ res = list(np.load(reader, allow_pickle=False))
     fn test_py_aggregator_add_weights() {
         let aggregator = spawn_weighted_average_aggregator();
         let data = generate_serialized_weights(1);
-        let _ = aggregator.add_weights(&data[..]).unwrap();
+        let _ = aggregator.add_weights(&data[..], None).unwrap();
     }
 
     /// Load a new `PythonAggregator` and call the `add_weights`
@@ -457,7 +601,7 @@ res = list(np.load(reader, allow_pickle=False))
 
         let weights = [1, 2, 3, 4];
 
-        let res = aggregator.add_weights(&weights[..]);
+        let res = aggregator.add_weights(&weights[..], None);
         assert!(res.is_err());
         assert_eq!(
             "error while executing Python code: call to `add_weights` resulted in an exception"
@@ -475,10 +619,10 @@ res = list(np.load(reader, allow_pickle=False))
         let mut aggregator = spawn_weighted_average_aggregator();
 
         let weights_0 = generate_serialized_weights(0);
-        let _ = aggregator.add_weights(&weights_0[..]);
+        let _ = aggregator.add_weights(&weights_0[..], None);
 
         let weights_4 = generate_serialized_weights(4);
-        let _ = aggregator.add_weights(&weights_4[..]);
+        let _ = aggregator.add_weights(&weights_4[..], None);
 
         let raw = aggregator.aggregate().unwrap();
         let aggregated_weights = deserialize_weights(&raw[..]);
@@ -505,7 +649,7 @@ res = list(np.load(reader, allow_pickle=False))
     fn test_py_aggregator_aggregate_with_error_on_add_weights() {
         let mut aggregator = spawn_weighted_average_aggregator();
 
-        let res = aggregator.add_weights(&[1, 2, 3, 4][..]);
+        let res = aggregator.add_weights(&[1, 2, 3, 4][..], None);
         assert!(res.is_err());
 
         let raw = aggregator.aggregate().unwrap();
@@ -524,7 +668,7 @@ res = list(np.load(reader, allow_pickle=False))
         let mut aggregator = spawn_weighted_average_aggregator();
 
         let data = generate_serialized_weights(1);
-        let _ = aggregator.add_weights(&data[..]).unwrap();
+        let _ = aggregator.add_weights(&data[..], None).unwrap();
         let _ = aggregator.aggregate().unwrap();
 
         let raw = aggregator.get_global_weights().unwrap();
@@ -551,7 +695,7 @@ res = list(np.load(reader, allow_pickle=False))
         let mut aggregator = spawn_weighted_average_aggregator();
 
         let _ = aggregator
-            .add_weights(&generate_serialized_weights(1)[..])
+            .add_weights(&generate_serialized_weights(1)[..], None)
             .unwrap();
         let _ = aggregator.aggregate();
 
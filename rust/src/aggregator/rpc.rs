@@ -1,9 +1,18 @@
 #![cfg_attr(test, allow(unused_imports))]
 use crate::{
-    aggregator::service::{Aggregator, ServiceError, ServiceHandle},
-    common::client::Credentials,
+    aggregator::service::{Aggregator, RoundConfig, ServiceError, ServiceHandle},
+    common::{
+        client::Credentials,
+        rpc::{ConnectionLimit, RequestId, Retryable, RpcCodec},
+        shutdown::{drain, Shutdown, UntilShutdown, DRAIN_TIMEOUT},
+        task::{TaskId, Tasks},
+    },
+};
+use bytes::Bytes;
+use futures::{
+    future::{self, TryFutureExt},
+    pin_mut,
 };
-use futures::future::{self, TryFutureExt};
 use std::{
     error::Error,
     fmt::{Debug, Display},
@@ -17,11 +26,13 @@ use tarpc::{
     client::Config,
     context::Context,
     rpc::server::{BaseChannel, Channel},
-    serde_transport::{tcp::listen, Transport},
+    serde_transport::{tcp, Transport},
 };
 use thiserror::Error;
-use tokio::{net::ToSocketAddrs, stream::StreamExt};
-use tokio_serde::formats::Json;
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    stream::StreamExt,
+};
 use tracing_futures::Instrument;
 
 /// Error returned by the RPC server.
@@ -38,6 +49,11 @@ where
     /// but the response to that request is an error.
     #[error("RPC call `{0}` resulted in an error: {1}")]
     Request(String, E),
+
+    /// Returned when the aggregator doesn't run the task the request
+    /// is for.
+    #[error("RPC call `{0}` is for unknown task `{1}`")]
+    UnknownTask(String, TaskId),
 }
 
 impl<E> ServerError<E>
@@ -50,10 +66,20 @@ where
             ServerError::Request(method, inner) => {
                 ServerError::Request(method, format!("{}", inner))
             }
+            ServerError::UnknownTask(method, task) => ServerError::UnknownTask(method, task),
         }
     }
 }
 
+impl<E> Retryable for ServerError<E>
+where
+    E: Display + Debug,
+{
+    fn is_retryable(&self) -> bool {
+        false
+    }
+}
+
 impl<E> From<(String, ServiceError<E>)> for ServerError<E>
 where
     E: Error,
@@ -79,6 +105,21 @@ where
 
     #[error("request failed: {0}")]
     Request(E),
+
+    #[error("the aggregator does not run task `{0}`")]
+    UnknownTask(TaskId),
+}
+
+impl<E> Retryable for ClientError<E>
+where
+    E: Display + Debug,
+{
+    fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Rpc(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
 }
 
 impl<E> From<ServerError<E>> for ClientError<E>
@@ -89,13 +130,17 @@ where
         match e {
             ServerError::Internal(_) => Self::Internal,
             ServerError::Request(_, e) => Self::Request(e),
+            ServerError::UnknownTask(_, task) => Self::UnknownTask(task),
         }
     }
 }
 
 mod inner {
     use super::ServerError;
-    use crate::common::client::Credentials;
+    use crate::{
+        aggregator::service::RoundConfig,
+        common::{client::Credentials, rpc::RequestId, task::TaskId},
+    };
     use std::fmt::Debug;
 
     // Ideally we'd like our trait to be generic over the aggregator,
@@ -116,16 +161,60 @@ mod inner {
 
     #[tarpc::service]
     /// Definition of the methods exposed by the aggregator RPC service.
+    ///
+    /// The retries of a request carry the same `request_id` as the
+    /// original request, so that the aggregator doesn't run the same
+    /// aggregation twice.
     pub trait Rpc {
         /// Notify the aggregator that the given client has been selected
-        /// and should use the given token to download the global weights
-        /// and upload their local weights.
-        async fn select(credentials: Credentials) -> Result<(), ServerError<String>>;
+        /// for the given task and should use the given token to
+        /// download the global weights and upload their local weights.
+        async fn select(
+            task: TaskId,
+            request_id: RequestId,
+            credentials: Credentials,
+        ) -> Result<(), ServerError<String>>;
 
-        /// Notify the aggregator that it should clear its pool of client
-        /// IDs and tokens. This should be called before starting a new
-        /// round.
-        async fn aggregate() -> Result<(), ServerError<String>>;
+        /// Notify the aggregator that it should clear the pool of client
+        /// IDs and tokens of the given task. This should be called
+        /// before starting a new round.
+        async fn aggregate(task: TaskId, request_id: RequestId) -> Result<(), ServerError<String>>;
+
+        /// Notify the aggregator that it should clear the pool of client
+        /// IDs and tokens of the given task, and return the partial
+        /// aggregation of the weights uploaded to it. This is used
+        /// instead of `aggregate` when the clients are spread over
+        /// several aggregators.
+        async fn partial_aggregate(task: TaskId) -> Result<Vec<u8>, ServerError<String>>;
+
+        /// Merge the partial aggregations of all the aggregators into
+        /// the new global weights of the given task.
+        async fn merge(
+            task: TaskId,
+            request_id: RequestId,
+            partials: Vec<Vec<u8>>,
+        ) -> Result<(), ServerError<String>>;
+
+        /// Notify the aggregator that the given round of the given
+        /// task has been aborted: it should clear its pool of client
+        /// IDs and tokens, discard the weights uploaded so far, and
+        /// roll back the global weights if an aggregation of this
+        /// round already updated them.
+        async fn reset(task: TaskId, round: u32) -> Result<(), ServerError<String>>;
+
+        /// Notify the aggregator that the given round of the given
+        /// task starts. Uploads of the previous rounds can no longer
+        /// be rolled back after this call.
+        async fn start_round(
+            task: TaskId,
+            round: u32,
+            config: RoundConfig,
+        ) -> Result<(), ServerError<String>>;
+
+        /// Notify the aggregator that the training of the given task
+        /// is over. The aggregator stops serving the task once the
+        /// running aggregation, if any, completes.
+        async fn finish_training(task: TaskId) -> Result<(), ServerError<String>>;
     }
 }
 
@@ -134,32 +223,43 @@ pub use inner::Rpc;
 #[cfg(test)]
 pub use crate::tests::lib::rpc::aggregator::Client;
 
+/// A client for the aggregator RPC service. Each client sends its
+/// requests for a single task, see [`Client::for_task`].
 #[cfg(not(test))]
 #[derive(Clone)]
-pub struct Client(inner::RpcClient);
+pub struct Client(inner::RpcClient, TaskId);
 
 #[cfg(not(test))]
 impl Client {
     pub async fn connect<A: ToSocketAddrs + Unpin + Clone + Send + Sync + 'static>(
         addr: A,
+        codec: RpcCodec,
     ) -> io::Result<Self> {
         let reconnect_opts = ReconnectOptions::new()
             .with_exit_if_first_connect_fails(false)
             .with_retries_generator(|| iter::repeat(Duration::from_secs(1)));
         let tcp_stream = StubbornTcpStream::connect_with_options(addr, reconnect_opts).await?;
-        let transport = Transport::from((tcp_stream, Json::default()));
+        let transport = Transport::from((tcp_stream, codec.codec()));
         Ok(Self(
             inner::RpcClient::new(Config::default(), transport).spawn()?,
+            TaskId::default(),
         ))
     }
 
+    /// Return a client that shares the same connection, but sends
+    /// its requests for the given task.
+    pub fn for_task(&self, task: TaskId) -> Self {
+        Self(self.0.clone(), task)
+    }
+
     pub fn select(
         &mut self,
         ctx: Context,
+        request_id: RequestId,
         credentials: Credentials,
     ) -> impl Future<Output = Result<(), ClientError<String>>> + '_ {
         self.0
-            .select(ctx, credentials)
+            .select(ctx, self.1.clone(), request_id, credentials)
             .map_err(ClientError::from)
             .and_then(|res| future::ready(res.map_err(ClientError::from)))
     }
@@ -167,9 +267,65 @@ impl Client {
     pub fn aggregate(
         &mut self,
         ctx: Context,
+        request_id: RequestId,
     ) -> impl Future<Output = Result<(), ClientError<String>>> + '_ {
         self.0
-            .aggregate(ctx)
+            .aggregate(ctx, self.1.clone(), request_id)
+            .map_err(ClientError::from)
+            .and_then(|res| future::ready(res.map_err(ClientError::from)))
+    }
+
+    pub fn partial_aggregate(
+        &mut self,
+        ctx: Context,
+    ) -> impl Future<Output = Result<Vec<u8>, ClientError<String>>> + '_ {
+        self.0
+            .partial_aggregate(ctx, self.1.clone())
+            .map_err(ClientError::from)
+            .and_then(|res| future::ready(res.map_err(ClientError::from)))
+    }
+
+    pub fn merge(
+        &mut self,
+        ctx: Context,
+        request_id: RequestId,
+        partials: Vec<Vec<u8>>,
+    ) -> impl Future<Output = Result<(), ClientError<String>>> + '_ {
+        self.0
+            .merge(ctx, self.1.clone(), request_id, partials)
+            .map_err(ClientError::from)
+            .and_then(|res| future::ready(res.map_err(ClientError::from)))
+    }
+
+    pub fn reset(
+        &mut self,
+        ctx: Context,
+        round: u32,
+    ) -> impl Future<Output = Result<(), ClientError<String>>> + '_ {
+        self.0
+            .reset(ctx, self.1.clone(), round)
+            .map_err(ClientError::from)
+            .and_then(|res| future::ready(res.map_err(ClientError::from)))
+    }
+
+    pub fn start_round(
+        &mut self,
+        ctx: Context,
+        round: u32,
+        config: RoundConfig,
+    ) -> impl Future<Output = Result<(), ClientError<String>>> + '_ {
+        self.0
+            .start_round(ctx, self.1.clone(), round, config)
+            .map_err(ClientError::from)
+            .and_then(|res| future::ready(res.map_err(ClientError::from)))
+    }
+
+    pub fn finish_training(
+        &mut self,
+        ctx: Context,
+    ) -> impl Future<Output = Result<(), ClientError<String>>> + '_ {
+        self.0
+            .finish_training(ctx, self.1.clone())
             .map_err(ClientError::from)
             .and_then(|res| future::ready(res.map_err(ClientError::from)))
     }
@@ -177,7 +333,7 @@ impl Client {
 
 /// A server that serves a single client. A new `Server` is created
 /// for each new client.
-pub struct Server<A>(ServiceHandle<A>)
+pub struct Server<A>(Tasks<ServiceHandle<A>>)
 where
     A: Aggregator;
 
@@ -190,19 +346,52 @@ where
     }
 }
 
+impl<A> Server<A>
+where
+    A: Aggregator + 'static,
+{
+    /// Return the handle of the service running the given task
+    fn handle(&self, method: &str, task: TaskId) -> Result<ServiceHandle<A>, ServerError<String>> {
+        self.0.get(&task).cloned().ok_or_else(|| {
+            warn!("rejecting `{}` request for unknown task {}", method, task);
+            ServerError::UnknownTask(method.to_string(), task)
+        })
+    }
+}
+
 impl<A> Rpc for Server<A>
 where
     A: Aggregator + 'static,
 {
     type SelectFut = Pin<Box<dyn Future<Output = Result<(), ServerError<String>>> + Send>>;
     type AggregateFut = Pin<Box<dyn Future<Output = Result<(), ServerError<String>>> + Send>>;
+    type PartialAggregateFut =
+        Pin<Box<dyn Future<Output = Result<Vec<u8>, ServerError<String>>> + Send>>;
+    type MergeFut = Pin<Box<dyn Future<Output = Result<(), ServerError<String>>> + Send>>;
+    type ResetFut = Pin<Box<dyn Future<Output = Result<(), ServerError<String>>> + Send>>;
+    type StartRoundFut = Pin<Box<dyn Future<Output = Result<(), ServerError<String>>> + Send>>;
+    type FinishTrainingFut = Pin<Box<dyn Future<Output = Result<(), ServerError<String>>> + Send>>;
 
-    fn select(self, _: tarpc::context::Context, credentials: Credentials) -> Self::SelectFut {
+    fn select(
+        self,
+        _: tarpc::context::Context,
+        task: TaskId,
+        request_id: RequestId,
+        credentials: Credentials,
+    ) -> Self::SelectFut {
         debug!("handling select request");
-        let span = trace_span!("rpc_select_handler", client_id = %credentials.id());
+        // Selecting a client twice is harmless, so there is no need
+        // to recognize the retries
+        let span = trace_span!(
+            "rpc_select_handler",
+            task = %task,
+            request_id = %request_id,
+            client_id = %credentials.id()
+        );
+        let handle = self.handle("select", task);
         Box::pin(
             async move {
-                self.0.select(credentials).await.map_err(|e| {
+                handle?.select(credentials).await.map_err(|e| {
                     ServerError::<A::Error>::from((String::from("select"), e)).stringify()
                 })
             }
@@ -210,42 +399,311 @@ where
         )
     }
 
-    fn aggregate(self, _: tarpc::context::Context) -> Self::AggregateFut {
+    fn aggregate(
+        self,
+        _: tarpc::context::Context,
+        task: TaskId,
+        request_id: RequestId,
+    ) -> Self::AggregateFut {
         debug!("handling aggregate request");
-        let span = trace_span!("rpc_aggregate_handler");
+        let span = trace_span!("rpc_aggregate_handler", task = %task, request_id = %request_id);
+        let handle = self.handle("aggregate", task);
         Box::pin(
             async move {
-                self.0.aggregate().await.map_err(|e| {
+                handle?.aggregate(request_id).await.map_err(|e| {
                     ServerError::<A::Error>::from((String::from("aggregate"), e)).stringify()
                 })
             }
             .instrument(span),
         )
     }
+
+    fn partial_aggregate(
+        self,
+        _: tarpc::context::Context,
+        task: TaskId,
+    ) -> Self::PartialAggregateFut {
+        debug!("handling partial aggregate request");
+        let span = trace_span!("rpc_partial_aggregate_handler", task = %task);
+        let handle = self.handle("partial_aggregate", task);
+        Box::pin(
+            async move {
+                handle?
+                    .partial_aggregate()
+                    .await
+                    .map(|partial| partial.to_vec())
+                    .map_err(|e| {
+                        ServerError::<A::Error>::from((String::from("partial_aggregate"), e))
+                            .stringify()
+                    })
+            }
+            .instrument(span),
+        )
+    }
+
+    fn merge(
+        self,
+        _: tarpc::context::Context,
+        task: TaskId,
+        request_id: RequestId,
+        partials: Vec<Vec<u8>>,
+    ) -> Self::MergeFut {
+        debug!("handling merge request");
+        let span = trace_span!("rpc_merge_handler", task = %task, request_id = %request_id);
+        let handle = self.handle("merge", task);
+        let partials = partials.into_iter().map(Bytes::from).collect();
+        Box::pin(
+            async move {
+                handle?.merge(request_id, partials).await.map_err(|e| {
+                    ServerError::<A::Error>::from((String::from("merge"), e)).stringify()
+                })
+            }
+            .instrument(span),
+        )
+    }
+
+    fn reset(self, _: tarpc::context::Context, task: TaskId, round: u32) -> Self::ResetFut {
+        debug!("handling reset request");
+        let span = trace_span!("rpc_reset_handler", task = %task, round = round);
+        let handle = self.handle("reset", task);
+        Box::pin(
+            async move {
+                handle?.reset(round).await.map_err(|e| {
+                    ServerError::<A::Error>::from((String::from("reset"), e)).stringify()
+                })
+            }
+            .instrument(span),
+        )
+    }
+
+    fn start_round(
+        self,
+        _: tarpc::context::Context,
+        task: TaskId,
+        round: u32,
+        config: RoundConfig,
+    ) -> Self::StartRoundFut {
+        debug!("handling start round request");
+        let span = trace_span!("rpc_start_round_handler", task = %task, round = round);
+        let handle = self.handle("start_round", task);
+        Box::pin(
+            async move {
+                handle?.start_round(round, config).await.map_err(|e| {
+                    ServerError::<A::Error>::from((String::from("start_round"), e)).stringify()
+                })
+            }
+            .instrument(span),
+        )
+    }
+
+    fn finish_training(self, _: tarpc::context::Context, task: TaskId) -> Self::FinishTrainingFut {
+        debug!("handling finish training request");
+        let span = trace_span!("rpc_finish_training_handler", task = %task);
+        let handle = self.handle("finish_training", task);
+        Box::pin(
+            async move {
+                handle?.finish_training().await.map_err(|e| {
+                    ServerError::<A::Error>::from((String::from("finish_training"), e)).stringify()
+                })
+            }
+            .instrument(span),
+        )
+    }
 }
 
-/// Run an RPC server that processes only one connection at a time.
-pub async fn serve<A, T>(addr: T, service_handle: ServiceHandle<A>) -> ::std::io::Result<()>
+/// Run an RPC server. Connections are processed concurrently, up to
+/// `max_connections` if it is set. The messages are serialized with
+/// the given `codec`. Once `shutdown` is triggered, the server stops
+/// accepting connections and returns when the open ones are closed.
+pub async fn serve<A, T>(
+    addr: T,
+    tasks: Tasks<ServiceHandle<A>>,
+    max_connections: Option<usize>,
+    codec: RpcCodec,
+    shutdown: Shutdown,
+) -> ::std::io::Result<()>
 where
     A: Aggregator + 'static,
     T: ToSocketAddrs + Send + Sync + 'static,
 {
-    let mut listener = listen(addr, Json::default).await?;
+    let listener = TcpListener::bind(addr).await?;
+    serve_listener(listener, tasks, max_connections, codec, shutdown).await
+}
 
-    while let Some(accept_result) = listener.next().await {
+/// Run an RPC server on a listener that is already bound, see
+/// [`serve`]
+pub async fn serve_listener<A>(
+    mut listener: TcpListener,
+    tasks: Tasks<ServiceHandle<A>>,
+    max_connections: Option<usize>,
+    codec: RpcCodec,
+    shutdown: Shutdown,
+) -> ::std::io::Result<()>
+where
+    A: Aggregator + 'static,
+{
+    let mut incoming = listener
+        .incoming()
+        .map(|stream| stream.map(|stream| tcp::new(stream, codec.codec())));
+    let limit = ConnectionLimit::new(max_connections);
+    let connections_shutdown = shutdown.clone();
+    let shutdown = shutdown.wait();
+    pin_mut!(shutdown);
+
+    loop {
+        let accept_result = tokio::select! {
+            accept_result = incoming.next() => accept_result,
+            _ = &mut shutdown => {
+                info!("shutting down the RPC server");
+                drain(&limit, DRAIN_TIMEOUT).await;
+                return Ok(());
+            }
+        };
         match accept_result {
-            Ok(transport) => {
+            Some(Ok(transport)) => {
+                let peer = transport
+                    .peer_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                let guard = match limit.try_acquire() {
+                    Some(guard) => guard,
+                    None => {
+                        warn!(
+                            "refusing RPC connection from {}: too many connections",
+                            peer
+                        );
+                        continue;
+                    }
+                };
+                // The connection is closed on shutdown once the
+                // requests in flight are answered
+                let transport = UntilShutdown::new(transport, connections_shutdown.clone());
                 let channel = BaseChannel::with_defaults(transport);
-                let server = Server(service_handle.clone());
+                let server = Server(tasks.clone());
                 let handler = channel.respond_with(server.serve());
-                handler
-                    .execute()
-                    // FIXME: add peer to span
-                    .instrument(trace_span!("rpc_handler"))
-                    .await;
+                tokio::spawn(
+                    async move {
+                        handler.execute().await;
+                        debug!("RPC connection closed");
+                        drop(guard);
+                    }
+                    .instrument(trace_span!("rpc_handler", peer = %peer)),
+                );
             }
-            Err(e) => error!("failed to accept RPC connection: {:?}", e),
+            Some(Err(e)) => error!("failed to accept RPC connection: {:?}", e),
+            None => return Ok(()),
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        aggregator::service::Service,
+        common::{
+            settings::RpcCallSettings,
+            shutdown::{self, ShutdownTrigger},
+        },
+        tests::lib::{
+            aggregator::ByteAggregator,
+            rpc::coordinator::{Client as CoordinatorClient, MockClient},
+        },
+    };
+    use std::{collections::HashMap, net::SocketAddr};
+    use tokio::{net::TcpStream, task::JoinHandle, time::timeout};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Start an aggregator service and an RPC server for the default
+    /// task, and return the address of the server. The server shuts
+    /// down when the returned trigger is dropped.
+    fn start_server(
+        max_connections: Option<usize>,
+    ) -> (SocketAddr, ShutdownTrigger, JoinHandle<io::Result<()>>) {
+        let (handle, requests) = ServiceHandle::new();
+        let rpc_client: CoordinatorClient = MockClient::default().into();
+        tokio::spawn(Service::new(
+            ByteAggregator::new(),
+            rpc_client,
+            RpcCallSettings::default(),
+            requests,
+            None,
+        ));
+
+        // The listener is bound before the server starts, so that
+        // the clients can connect right away
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        let mut handles = HashMap::new();
+        handles.insert(TaskId::default(), handle);
+        let (trigger, shutdown) = shutdown::channel();
+        let server = tokio::spawn(serve_listener(
+            listener,
+            Tasks::new(handles),
+            max_connections,
+            RpcCodec::default(),
+            shutdown,
+        ));
+        (addr, trigger, server)
+    }
+
+    fn connect_client(addr: SocketAddr) -> io::Result<inner::RpcClient> {
+        let stream = TcpStream::from_std(std::net::TcpStream::connect(addr)?)?;
+        let transport = tcp::new(stream, RpcCodec::default().codec());
+        inner::RpcClient::new(Config::default(), transport).spawn()
+    }
+
+    async fn reset(
+        client: &mut inner::RpcClient,
+    ) -> Result<io::Result<Result<(), ServerError<String>>>, ()> {
+        let request = client.reset(tarpc::context::current(), TaskId::default(), 0);
+        timeout(TIMEOUT, request).await.map_err(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_connections() {
+        let (addr, _trigger, _server) = start_server(None);
+
+        // The first connection stays open while the second one is
+        // being processed
+        let mut first = connect_client(addr).unwrap();
+        let mut second = connect_client(addr).unwrap();
+        reset(&mut first).await.unwrap().unwrap().unwrap();
+        reset(&mut second).await.unwrap().unwrap().unwrap();
+        reset(&mut first).await.unwrap().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let (addr, _trigger, _server) = start_server(Some(1));
+
+        let mut first = connect_client(addr).unwrap();
+        reset(&mut first).await.unwrap().unwrap().unwrap();
+
+        // The second connection is refused while the first one is
+        // open
+        let mut second = connect_client(addr).unwrap();
+        assert!(!matches!(reset(&mut second).await, Ok(Ok(Ok(())))));
+        reset(&mut first).await.unwrap().unwrap().unwrap();
+    }
+
+    /// Test that the long-lived connection of the coordinator is
+    /// closed on shutdown once it is idle, instead of holding the
+    /// shutdown back until the drain timeout expires
+    #[tokio::test]
+    async fn test_shutdown_with_idle_connection() {
+        let (addr, trigger, server) = start_server(None);
+
+        let mut client = connect_client(addr).unwrap();
+        reset(&mut client).await.unwrap().unwrap().unwrap();
+
+        trigger.trigger();
+        timeout(DRAIN_TIMEOUT / 2, server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
 }
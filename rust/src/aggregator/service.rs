@@ -1,18 +1,28 @@
 use crate::{
-    common::client::{ClientId, Credentials, Token},
-    coordinator,
+    aggregator::{
+        evaluation::{Evaluation, LocalMetrics, MetricsSum},
+        settings::AsyncAggregationSettings,
+    },
+    common::{
+        client::{ClientId, Credentials, Token},
+        rpc::{self, RequestId},
+        settings::RpcCallSettings,
+    },
+    coordinator::{self, models::TrainingMetrics},
 };
 use bytes::Bytes;
-use derive_more::From;
+use derive_more::{Display, From};
 use futures::{ready, stream::Stream};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     error::Error,
     future::Future,
+    mem,
     pin::Pin,
+    str::FromStr,
     task::{Context, Poll},
 };
-use tarpc::context::current as rpc_context;
 use thiserror::Error;
 use tokio::{
     stream::StreamExt,
@@ -25,9 +35,6 @@ use tracing_futures::Instrument;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
-use nix::sys::signal;
-use nix::unistd::Pid;
-use std::thread;
 use std::time::Duration;
 
 /// A future that orchestrates the entire aggregator service.
@@ -57,6 +64,23 @@ where
     // complexity that is not worth it.
     global_weights: Bytes,
 
+    /// Version of the global weights
+    version: ModelVersion,
+
+    /// In asynchronous mode, the versions that preceded the current
+    /// one, most recent first. Updates computed from these versions
+    /// are still accepted.
+    previous_versions: VecDeque<ModelVersion>,
+
+    /// The global model that preceded the last aggregation. It is
+    /// restored if the coordinator aborts the round that produced
+    /// the current model.
+    previous_model: Option<Model>,
+
+    /// Settings for the asynchronous mode. If they are not set,
+    /// updates must be computed from the current version.
+    async_settings: Option<AsyncAggregationSettings>,
+
     /// The aggregator itself, which handles the weights or performs
     /// the aggregations.
     aggregator: A,
@@ -64,25 +88,65 @@ where
     /// A client for the coordinator RPC service.
     rpc_client: coordinator::rpc::Client,
 
+    /// Deadlines and retries of the calls to the coordinator
+    rpc_settings: RpcCallSettings,
+
     requests: ServiceRequests<A>,
 
     aggregation_future: Option<AggregationFuture<A>>,
-    model_number: usize,
+
+    /// ID of the request of the last aggregation that succeeded, to
+    /// recognize the retries of this request
+    last_aggregation: Option<RequestId>,
+
+    /// Round announced by the coordinator. The checkpoints of the
+    /// global weights are labelled with it.
+    round: u32,
+
+    /// During an evaluation round, the sums of the metrics uploaded
+    /// by the participants
+    evaluation: Option<MetricsSum>,
+
+    /// Results of the evaluation rounds
+    evaluations: Vec<Evaluation>,
+
+    /// Whether the coordinator notified the end of the training. The
+    /// service terminates once the running aggregation, if any,
+    /// completes.
+    training_finished: bool,
 }
 
 /// This trait defines the methods that an aggregator should
 /// implement.
 pub trait Aggregator {
     type Error: Error + Send + 'static + Sync;
-    type AggregateFut: Future<Output = Result<Bytes, Self::Error>> + Unpin;
+    type AggregateFut: Future<Output = Result<Bytes, Self::Error>> + Unpin + Send + 'static;
     type AddWeightsFut: Future<Output = Result<(), Self::Error>> + Unpin + Send + 'static;
+    type ResetFut: Future<Output = Result<(), Self::Error>> + Unpin + Send + 'static;
 
     /// Check the validity of the given weights and if they are valid,
-    /// add them to the set of weights to aggregate.
-    fn add_weights(&mut self, weights: Bytes) -> Self::AddWeightsFut;
+    /// add them to the set of weights to aggregate. In asynchronous
+    /// mode, `staleness` is the number of aggregations that happened
+    /// since the weights were computed. Otherwise it is `None`.
+    fn add_weights(&mut self, weights: Bytes, staleness: Option<u32>) -> Self::AddWeightsFut;
 
     /// Run the aggregator and return the result.
     fn aggregate(&mut self) -> Self::AggregateFut;
+
+    /// Aggregate the weights added since the last aggregation into a
+    /// partial result, without updating the global weights. This is
+    /// used when the uploads are spread over several aggregators: the
+    /// partial results of all the aggregators are then combined with
+    /// [`Aggregator::merge`].
+    fn partial_aggregate(&mut self) -> Self::AggregateFut;
+
+    /// Combine the partial results of all the aggregators, and
+    /// return the new global weights.
+    fn merge(&mut self, partials: Vec<Bytes>) -> Self::AggregateFut;
+
+    /// Discard the weights added since the last aggregation and
+    /// restore the given global weights.
+    fn reset(&mut self, global_weights: Bytes) -> Self::ResetFut;
 }
 
 impl<A> Service<A>
@@ -92,16 +156,27 @@ where
     pub fn new(
         aggregator: A,
         rpc_client: coordinator::rpc::Client,
+        rpc_settings: RpcCallSettings,
         requests: ServiceRequests<A>,
+        async_settings: Option<AsyncAggregationSettings>,
     ) -> Self {
         Self {
             aggregator,
             requests,
             rpc_client,
+            rpc_settings,
             allowed_ids: HashMap::new(),
             global_weights: Bytes::new(),
+            version: ModelVersion::new(0, &[]),
+            previous_versions: VecDeque::new(),
+            previous_model: None,
+            async_settings,
             aggregation_future: None,
-            model_number: 0,
+            last_aggregation: None,
+            round: 0,
+            evaluation: None,
+            evaluations: Vec::new(),
+            training_finished: false,
         }
     }
 
@@ -131,7 +206,10 @@ where
             .map(|expected_token| credentials.token() == expected_token)
             .unwrap_or(false)
         {
-            let _ = response_tx.send(Ok(self.global_weights.clone()));
+            let _ = response_tx.send(Ok(Model {
+                version: self.version.clone(),
+                weights: self.global_weights.clone(),
+            }));
         } else {
             warn!("rejecting download request");
             let _ = response_tx.send(Err(DownloadError::Unauthorized));
@@ -140,7 +218,13 @@ where
 
     fn handle_upload_request(&mut self, request: UploadRequest) {
         debug!("handling upload request");
-        let UploadRequest { credentials, data } = request;
+        let UploadRequest {
+            credentials,
+            version,
+            metrics,
+            data,
+            response_tx,
+        } = request;
         let accept_upload = self
             .allowed_ids
             .get(credentials.id())
@@ -149,48 +233,393 @@ where
 
         if !accept_upload {
             warn!("rejecting upload request");
+            let _ = response_tx.send(Err(UploadError::Unauthorized));
+            return;
+        }
+        if self.evaluation.is_some() {
+            warn!("rejecting upload request: the current round is an evaluation round");
+            let _ = response_tx.send(Err(UploadError::EvaluationRound));
             return;
         }
 
-        let mut rpc_client = self.rpc_client.clone();
-        let fut = self.aggregator.add_weights(data);
+        // Weights computed from an older version of the model would
+        // pollute the current round.
+        let staleness = match version {
+            Some(version) => match self.staleness(&version) {
+                Some(staleness) => staleness,
+                None => {
+                    warn!(
+                        "rejecting upload request: weights computed from version {} but current version is {}",
+                        version, self.version
+                    );
+                    let _ = response_tx.send(Err(UploadError::StaleModel));
+                    return;
+                }
+            },
+            // In asynchronous mode, the staleness of the weights must
+            // be known to merge them.
+            None if self.async_settings.is_some() => {
+                warn!("rejecting upload request: model version is missing");
+                let _ = response_tx.send(Err(UploadError::MissingModelVersion));
+                return;
+            }
+            None => 0,
+        };
+        let _ = response_tx.send(Ok(()));
+
+        // In asynchronous mode, the credentials are not cleared when
+        // an aggregation starts, so they are discarded once used.
+        let staleness = if self.async_settings.is_some() {
+            self.allowed_ids.remove(credentials.id());
+            Some(staleness)
+        } else {
+            None
+        };
+
+        let rpc_client = self.rpc_client.clone();
+        let rpc_settings = self.rpc_settings;
+        let fut = self.aggregator.add_weights(data, staleness);
         tokio::spawn(
             async move {
                 let result = fut.await;
-                debug!("sending end training request to the coordinator");
-                rpc_client
-                    .end_training(rpc_context(), *credentials.id(), result.is_ok())
-                    .await
-                    .map_err(|e| {
-                        warn!(
-                            "failed to send end training request to the coordinator: {}",
-                            e
-                        );
-                    })
+                // The statistics of the weights that could not be
+                // aggregated are not relevant
+                let metrics = metrics.filter(|_| result.is_ok());
+                end_training(
+                    rpc_client,
+                    rpc_settings,
+                    *credentials.id(),
+                    result.is_ok(),
+                    metrics,
+                )
+                .await
+            }
+            .instrument(trace_span!("end_training_rpc_request")),
+        );
+    }
+
+    /// Handle the upload of the metrics computed by a participant
+    /// during an evaluation round. The metrics of a participant are
+    /// only counted once.
+    fn handle_upload_metrics_request(&mut self, request: UploadMetricsRequest) {
+        debug!("handling upload metrics request");
+        let UploadMetricsRequest {
+            credentials,
+            metrics,
+            response_tx,
+        } = request;
+        let accept_upload = self
+            .allowed_ids
+            .get(credentials.id())
+            .map(|expected_token| credentials.token() == expected_token)
+            .unwrap_or(false);
+        if !accept_upload {
+            warn!("rejecting upload metrics request");
+            let _ = response_tx.send(Err(UploadError::Unauthorized));
+            return;
+        }
+        let sum = match self.evaluation.as_mut() {
+            Some(sum) => sum,
+            None => {
+                warn!("rejecting upload metrics request: the current round is not an evaluation round");
+                let _ = response_tx.send(Err(UploadError::NotEvaluationRound));
+                return;
             }
+        };
+        sum.add(&metrics);
+        self.allowed_ids.remove(credentials.id());
+        let _ = response_tx.send(Ok(()));
+
+        tokio::spawn(
+            end_training(
+                self.rpc_client.clone(),
+                self.rpc_settings,
+                *credentials.id(),
+                true,
+                None,
+            )
             .instrument(trace_span!("end_training_rpc_request")),
         );
     }
 
+    /// Number of aggregations that happened since the given version,
+    /// or `None` if the version is unknown or too old.
+    fn staleness(&self, version: &ModelVersion) -> Option<u32> {
+        if *version == self.version {
+            return Some(0);
+        }
+        self.previous_versions
+            .iter()
+            .position(|previous| previous == version)
+            .map(|position| position as u32 + 1)
+    }
+
     fn handle_request(&mut self, request: Request<A>) {
         match request {
             Request::Download(req) => self.handle_download_request(req),
             Request::Upload(req) => self.handle_upload_request(req),
+            Request::UploadMetrics(req) => self.handle_upload_metrics_request(req),
             Request::Select(req) => self.handle_select_request(req),
             Request::Aggregate(req) => self.handle_aggregate_request(req),
+            Request::PartialAggregate(req) => self.handle_partial_aggregate_request(req),
+            Request::Merge(req) => self.handle_merge_request(req),
+            Request::Reset(req) => self.handle_reset_request(req),
+            Request::StartRound(req) => self.handle_start_round_request(req),
+            Request::FinishTraining(req) => self.handle_finish_training_request(req),
+            Request::Evaluations(req) => self.handle_evaluations_request(req),
         }
     }
 
     fn handle_aggregate_request(&mut self, request: AggregateRequest<A>) {
         info!("handling aggregate request");
-        let AggregateRequest { response_tx } = request;
-        self.allowed_ids = HashMap::new();
+        let AggregateRequest {
+            request_id,
+            response_tx,
+        } = request;
+        let response_tx = match self.handle_aggregation_retry(request_id, response_tx) {
+            Some(response_tx) => response_tx,
+            None => return,
+        };
+        // In asynchronous mode, participants keep training while the
+        // aggregation runs.
+        if self.async_settings.is_none() {
+            self.allowed_ids = HashMap::new();
+        }
+
+        if let Some(sum) = self.evaluation.take() {
+            self.end_evaluation(sum, request_id);
+            if response_tx.send(Ok(())).is_err() {
+                warn!("failed to send reponse: channel closed");
+            }
+            return;
+        }
 
         self.aggregation_future = Some(AggregationFuture {
+            request_id,
             future: self.aggregator.aggregate(),
             response_tx,
         });
     }
+
+    /// Check whether an aggregation request is a retry of the
+    /// aggregation that is running or that last succeeded. A retry
+    /// takes over the response of the running aggregation, or is
+    /// answered right away if the aggregation already succeeded, so
+    /// that the weights are not aggregated twice. The response
+    /// channel is given back if the request is not a retry.
+    fn handle_aggregation_retry(
+        &mut self,
+        request_id: RequestId,
+        response_tx: oneshot::Sender<Result<(), A::Error>>,
+    ) -> Option<oneshot::Sender<Result<(), A::Error>>> {
+        if let Some(future) = self.aggregation_future.as_mut() {
+            if future.request_id == request_id {
+                info!(
+                    "request {} is a retry of the running aggregation",
+                    request_id
+                );
+                // The caller gave up on the original request, so its
+                // response can be dropped
+                future.response_tx = response_tx;
+                return None;
+            }
+        }
+        if self.last_aggregation == Some(request_id) {
+            info!("request {} is a retry of the last aggregation", request_id);
+            if response_tx.send(Ok(())).is_err() {
+                warn!("failed to send reponse: channel closed");
+            }
+            return None;
+        }
+        Some(response_tx)
+    }
+
+    fn handle_partial_aggregate_request(&mut self, request: PartialAggregateRequest<A>) {
+        info!("handling partial aggregate request");
+        let PartialAggregateRequest { response_tx } = request;
+        if self.async_settings.is_none() {
+            self.allowed_ids = HashMap::new();
+        }
+
+        // During an evaluation round, the partial result is made of
+        // the sums of the metrics received so far
+        if let Some(sum) = self.evaluation.as_mut() {
+            let sum = mem::take(sum);
+            // UNWRAP_SAFE: the sums can always be serialized
+            let partial = serde_json::to_vec(&sum).unwrap();
+            if response_tx.send(Ok(Bytes::from(partial))).is_err() {
+                warn!("failed to send reponse: channel closed");
+            }
+            return;
+        }
+
+        let fut = self.aggregator.partial_aggregate();
+        tokio::spawn(async move {
+            let result = fut.await;
+            if let Err(ref e) = result {
+                error!(error = %e, "partial aggregation failed");
+            }
+            if response_tx.send(result).is_err() {
+                warn!("failed to send reponse: channel closed");
+            }
+        });
+    }
+
+    /// Handle a merge request. Like an aggregation, a successful
+    /// merge updates the global weights.
+    fn handle_merge_request(&mut self, request: MergeRequest<A>) {
+        info!("handling merge request");
+        let MergeRequest {
+            request_id,
+            partials,
+            response_tx,
+        } = request;
+        let response_tx = match self.handle_aggregation_retry(request_id, response_tx) {
+            Some(response_tx) => response_tx,
+            None => return,
+        };
+
+        if self.evaluation.is_some() {
+            let mut sum = MetricsSum::default();
+            for partial in partials {
+                match serde_json::from_slice(&partial) {
+                    Ok(partial) => sum.merge(partial),
+                    Err(e) => error!(error = %e, "ignoring invalid partial evaluation"),
+                }
+            }
+            self.end_evaluation(sum, request_id);
+            if response_tx.send(Ok(())).is_err() {
+                warn!("failed to send reponse: channel closed");
+            }
+            return;
+        }
+
+        self.aggregation_future = Some(AggregationFuture {
+            request_id,
+            future: self.aggregator.merge(partials),
+            response_tx,
+        });
+    }
+
+    /// Compute the results of the evaluation round from the sums of
+    /// the metrics, and store them. If the coordinator aborts the
+    /// evaluation round, it starts over with new sums.
+    fn end_evaluation(&mut self, sum: MetricsSum, request_id: RequestId) {
+        let evaluation = sum.average(self.round);
+        info!(
+            "evaluation of round {} finished: {} participants, {} samples, metrics: {:?}",
+            evaluation.round, evaluation.participants, evaluation.samples, evaluation.metrics
+        );
+        // UNWRAP_SAFE: the evaluation can always be serialized
+        let json = serde_json::to_vec_pretty(&evaluation).unwrap();
+        write_output(&format!("evaluation_{}.json", self.round), &json);
+        self.evaluations.push(evaluation);
+        self.evaluation = Some(MetricsSum::default());
+        self.last_aggregation = Some(request_id);
+    }
+
+    /// Handle a reset request. If the global weights were produced
+    /// by an aggregation of the round being aborted, they are rolled
+    /// back to the model that preceded it. An aggregation that is
+    /// still running is cancelled.
+    fn handle_reset_request(&mut self, request: ResetRequest<A>) {
+        info!("handling reset request");
+        let ResetRequest { round, response_tx } = request;
+        if let Some(AggregationFuture { request_id, .. }) = self.aggregation_future.take() {
+            // Dropping the response channel answers the aggregation
+            // request with an error, and the weights it would have
+            // produced are discarded along with the future
+            warn!(
+                "cancelling aggregation {}: the round is aborted",
+                request_id
+            );
+        }
+        self.allowed_ids = HashMap::new();
+        if self.evaluation.is_some() {
+            self.evaluation = Some(MetricsSum::default());
+        }
+
+        if self.version.round > round {
+            match self.previous_model.take() {
+                Some(Model { version, weights }) if version.round == round => {
+                    info!(
+                        "rolling back the global weights from version {} to {}",
+                        self.version, version
+                    );
+                    self.version = version;
+                    self.global_weights = weights;
+                    if self.async_settings.is_some() {
+                        self.previous_versions.pop_front();
+                    }
+                    // A retry of the aggregation must not be answered
+                    // with the weights that were discarded
+                    self.last_aggregation = None;
+                }
+                _ => {
+                    error!(
+                        "cannot roll back the global weights from version {} to round {}",
+                        self.version, round
+                    );
+                }
+            }
+        }
+
+        let fut = self.aggregator.reset(self.global_weights.clone());
+        tokio::spawn(async move {
+            let result = fut.await;
+            if response_tx.send(result).is_err() {
+                warn!("failed to send reponse: channel closed");
+            }
+        });
+    }
+
+    /// Handle a start round request. The previous round ended
+    /// successfully, so the state kept to roll back or retry its
+    /// aggregation is dropped.
+    fn handle_start_round_request(&mut self, request: StartRoundRequest) {
+        let StartRoundRequest {
+            round,
+            config,
+            response_tx,
+        } = request;
+        if config.evaluation {
+            info!("starting the evaluation of round {}", round);
+        } else {
+            info!("starting round {} of {}", round + 1, config.rounds);
+        }
+        if round != self.round {
+            self.previous_model = None;
+            self.last_aggregation = None;
+        }
+        self.round = round;
+        self.evaluation = if config.evaluation {
+            Some(MetricsSum::default())
+        } else {
+            None
+        };
+        if response_tx.send(()).is_err() {
+            warn!("failed to send reponse: channel closed");
+        }
+    }
+
+    fn handle_finish_training_request(&mut self, request: FinishTrainingRequest) {
+        info!("handling finish training request");
+        let FinishTrainingRequest { response_tx } = request;
+        self.training_finished = true;
+        self.allowed_ids = HashMap::new();
+        if response_tx.send(()).is_err() {
+            warn!("failed to send reponse: channel closed");
+        }
+    }
+
+    fn handle_evaluations_request(&mut self, request: EvaluationsRequest) {
+        debug!("handling evaluations request");
+        let EvaluationsRequest { response_tx } = request;
+        if response_tx.send(self.evaluations.clone()).is_err() {
+            warn!("failed to send reponse: channel closed");
+        }
+    }
+
     fn handle_select_request(&mut self, request: SelectRequest<A>) {
         info!("handling select request");
         let SelectRequest {
@@ -218,21 +647,30 @@ where
         trace!("polling aggregation future");
 
         let AggregationFuture {
+            request_id,
             mut future,
             response_tx,
         } = future;
 
         let result = match Pin::new(&mut future).poll(cx) {
             Poll::Ready(Ok(weights)) => {
-                info!("aggregation succeeded, settings global weights");
-                self.global_weights = weights;
-                if let Ok(path) = env::var("NEVERMINED_OUTPUTS_PATH") {
-                    let file_name = format!("{}/model_{}.npy", path, self.model_number);
-                    let mut file = File::create(&file_name).unwrap();
-                    info!("Writing model {}", file_name);
-                    file.write_all(&self.global_weights).unwrap();
-                    self.model_number += 1;
+                let version = ModelVersion::new(self.version.round + 1, &weights);
+                let previous_version = mem::replace(&mut self.version, version);
+                if let Some(AsyncAggregationSettings { max_staleness }) = self.async_settings {
+                    self.previous_versions.push_front(previous_version.clone());
+                    self.previous_versions.truncate(max_staleness as usize);
                 }
+                info!(
+                    "aggregation succeeded, settings global weights (version {})",
+                    self.version
+                );
+                let previous_weights = mem::replace(&mut self.global_weights, weights);
+                self.previous_model = Some(Model {
+                    version: previous_version,
+                    weights: previous_weights,
+                });
+                self.last_aggregation = Some(request_id);
+                self.write_checkpoint();
 
                 Ok(())
             }
@@ -243,6 +681,7 @@ where
             Poll::Pending => {
                 debug!("aggregation future still running");
                 self.aggregation_future = Some(AggregationFuture {
+                    request_id,
                     future,
                     response_tx,
                 });
@@ -252,10 +691,64 @@ where
         if response_tx.send(result).is_err() {
             error!("failed to send aggregation response to RPC task: receiver dropped");
         }
-        if self.model_number == 10 {
-            thread::sleep(Duration::from_millis(10 * 1000));
-            signal::kill(Pid::this(), signal::Signal::SIGINT).unwrap();
+    }
+
+    /// Write a checkpoint of the global weights. If the round is
+    /// aborted and trained again, its checkpoint is overwritten.
+    fn write_checkpoint(&self) {
+        write_output(&format!("model_{}.npy", self.round), &self.global_weights);
+    }
+}
+
+/// Write a file to the directory given by the
+/// `NEVERMINED_OUTPUTS_PATH` environment variable, if it is set. The
+/// file is synced to disk, so that it is complete when the aggregator
+/// shuts down.
+fn write_output(file_name: &str, data: &[u8]) {
+    let path = match env::var("NEVERMINED_OUTPUTS_PATH") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let file_name = format!("{}/{}", path, file_name);
+    info!("Writing {}", file_name);
+    let result = File::create(&file_name).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+    if let Err(e) = result {
+        error!(error = %e, "failed to write {}", file_name);
+    }
+}
+
+/// Tell the coordinator that a participant finished training, or
+/// evaluating the global model, along with the training statistics it
+/// reported
+async fn end_training(
+    rpc_client: coordinator::rpc::Client,
+    rpc_settings: RpcCallSettings,
+    id: ClientId,
+    success: bool,
+    metrics: Option<TrainingMetrics>,
+) {
+    debug!("sending end training request to the coordinator");
+    let timeout = Duration::from_secs(rpc_settings.timeout);
+    // The coordinator ignores the end training requests of the
+    // clients that are not training, so the request can be retried.
+    let result = rpc::retry(&rpc_settings, "end_training", || {
+        let mut rpc_client = rpc_client.clone();
+        let metrics = metrics.clone();
+        async move {
+            rpc_client
+                .end_training(rpc::context(timeout), id, success, metrics)
+                .await
         }
+    })
+    .await;
+    if let Err(e) = result {
+        warn!(
+            "failed to send end training request to the coordinator: {}",
+            e
+        );
     }
 }
 
@@ -263,6 +756,8 @@ struct AggregationFuture<A>
 where
     A: Aggregator,
 {
+    /// ID of the request that started the aggregation
+    request_id: RequestId,
     future: A::AggregateFut,
     response_tx: oneshot::Sender<Result<(), A::Error>>,
 }
@@ -283,6 +778,11 @@ where
 
         pin.poll_aggregation(cx);
 
+        if pin.training_finished && pin.aggregation_future.is_none() {
+            info!("training finished, stopping the service");
+            return Poll::Ready(());
+        }
+
         Poll::Pending
     }
 }
@@ -309,15 +809,29 @@ where
 {
     fn new(
         upload: UnboundedReceiver<UploadRequest>,
+        upload_metrics: UnboundedReceiver<UploadMetricsRequest>,
         download: UnboundedReceiver<DownloadRequest>,
         aggregate: UnboundedReceiver<AggregateRequest<A>>,
+        partial_aggregate: UnboundedReceiver<PartialAggregateRequest<A>>,
+        merge: UnboundedReceiver<MergeRequest<A>>,
         select: UnboundedReceiver<SelectRequest<A>>,
+        reset: UnboundedReceiver<ResetRequest<A>>,
+        start_round: UnboundedReceiver<StartRoundRequest>,
+        finish_training: UnboundedReceiver<FinishTrainingRequest>,
+        evaluations: UnboundedReceiver<EvaluationsRequest>,
     ) -> Self {
         let stream = download
             .map(Request::from)
             .merge(upload.map(Request::from))
+            .merge(upload_metrics.map(Request::from))
             .merge(aggregate.map(Request::from))
-            .merge(select.map(Request::from));
+            .merge(partial_aggregate.map(Request::from))
+            .merge(merge.map(Request::from))
+            .merge(select.map(Request::from))
+            .merge(reset.map(Request::from))
+            .merge(start_round.map(Request::from))
+            .merge(finish_training.map(Request::from))
+            .merge(evaluations.map(Request::from));
         Self(Box::pin(stream))
     }
 }
@@ -325,13 +839,26 @@ where
 #[derive(From)]
 pub struct UploadRequest {
     credentials: Credentials,
+    /// Version of the model the weights were computed from, if the
+    /// client provided it
+    version: Option<ModelVersion>,
+    /// Statistics of the local training, if the client reported them
+    metrics: Option<TrainingMetrics>,
     data: Bytes,
+    response_tx: oneshot::Sender<Result<(), UploadError>>,
+}
+
+#[derive(From)]
+pub struct UploadMetricsRequest {
+    credentials: Credentials,
+    metrics: LocalMetrics,
+    response_tx: oneshot::Sender<Result<(), UploadError>>,
 }
 
 #[derive(From)]
 pub struct DownloadRequest {
     credentials: Credentials,
-    response_tx: oneshot::Sender<Result<Bytes, DownloadError>>,
+    response_tx: oneshot::Sender<Result<Model, DownloadError>>,
 }
 
 #[derive(From)]
@@ -339,9 +866,54 @@ pub struct AggregateRequest<A>
 where
     A: Aggregator,
 {
+    request_id: RequestId,
+    response_tx: oneshot::Sender<Result<(), A::Error>>,
+}
+
+#[derive(From)]
+pub struct PartialAggregateRequest<A>
+where
+    A: Aggregator,
+{
+    response_tx: oneshot::Sender<Result<Bytes, A::Error>>,
+}
+
+#[derive(From)]
+pub struct MergeRequest<A>
+where
+    A: Aggregator,
+{
+    request_id: RequestId,
+    partials: Vec<Bytes>,
+    response_tx: oneshot::Sender<Result<(), A::Error>>,
+}
+
+#[derive(From)]
+pub struct ResetRequest<A>
+where
+    A: Aggregator,
+{
+    round: u32,
     response_tx: oneshot::Sender<Result<(), A::Error>>,
 }
 
+#[derive(From)]
+pub struct StartRoundRequest {
+    round: u32,
+    config: RoundConfig,
+    response_tx: oneshot::Sender<()>,
+}
+
+#[derive(From)]
+pub struct FinishTrainingRequest {
+    response_tx: oneshot::Sender<()>,
+}
+
+#[derive(From)]
+pub struct EvaluationsRequest {
+    response_tx: oneshot::Sender<Vec<Evaluation>>,
+}
+
 #[derive(From)]
 pub struct SelectRequest<A>
 where
@@ -357,9 +929,16 @@ where
     A: Aggregator,
 {
     Upload(UploadRequest),
+    UploadMetrics(UploadMetricsRequest),
     Download(DownloadRequest),
     Aggregate(AggregateRequest<A>),
+    PartialAggregate(PartialAggregateRequest<A>),
+    Merge(MergeRequest<A>),
     Select(SelectRequest<A>),
+    Reset(ResetRequest<A>),
+    StartRound(StartRoundRequest),
+    FinishTraining(FinishTrainingRequest),
+    Evaluations(EvaluationsRequest),
 }
 
 pub struct ServiceHandle<A>
@@ -367,9 +946,16 @@ where
     A: Aggregator,
 {
     upload: UnboundedSender<UploadRequest>,
+    upload_metrics: UnboundedSender<UploadMetricsRequest>,
     download: UnboundedSender<DownloadRequest>,
     aggregate: UnboundedSender<AggregateRequest<A>>,
+    partial_aggregate: UnboundedSender<PartialAggregateRequest<A>>,
+    merge: UnboundedSender<MergeRequest<A>>,
     select: UnboundedSender<SelectRequest<A>>,
+    reset: UnboundedSender<ResetRequest<A>>,
+    start_round: UnboundedSender<StartRoundRequest>,
+    finish_training: UnboundedSender<FinishTrainingRequest>,
+    evaluations: UnboundedSender<EvaluationsRequest>,
 }
 
 // We implement Clone manually because it can only be derived if A:
@@ -381,9 +967,16 @@ where
     fn clone(&self) -> Self {
         Self {
             upload: self.upload.clone(),
+            upload_metrics: self.upload_metrics.clone(),
             download: self.download.clone(),
             aggregate: self.aggregate.clone(),
+            partial_aggregate: self.partial_aggregate.clone(),
+            merge: self.merge.clone(),
             select: self.select.clone(),
+            reset: self.reset.clone(),
+            start_round: self.start_round.clone(),
+            finish_training: self.finish_training.clone(),
+            evaluations: self.evaluations.clone(),
         }
     }
 }
@@ -394,25 +987,51 @@ where
 {
     pub fn new() -> (Self, ServiceRequests<A>) {
         let (upload_tx, upload_rx) = unbounded_channel::<UploadRequest>();
+        let (upload_metrics_tx, upload_metrics_rx) = unbounded_channel::<UploadMetricsRequest>();
         let (download_tx, download_rx) = unbounded_channel::<DownloadRequest>();
         let (aggregate_tx, aggregate_rx) = unbounded_channel::<AggregateRequest<A>>();
+        let (partial_aggregate_tx, partial_aggregate_rx) =
+            unbounded_channel::<PartialAggregateRequest<A>>();
+        let (merge_tx, merge_rx) = unbounded_channel::<MergeRequest<A>>();
         let (select_tx, select_rx) = unbounded_channel::<SelectRequest<A>>();
+        let (reset_tx, reset_rx) = unbounded_channel::<ResetRequest<A>>();
+        let (start_round_tx, start_round_rx) = unbounded_channel::<StartRoundRequest>();
+        let (finish_training_tx, finish_training_rx) = unbounded_channel::<FinishTrainingRequest>();
+        let (evaluations_tx, evaluations_rx) = unbounded_channel::<EvaluationsRequest>();
 
         let handle = Self {
             upload: upload_tx,
+            upload_metrics: upload_metrics_tx,
             download: download_tx,
             aggregate: aggregate_tx,
+            partial_aggregate: partial_aggregate_tx,
+            merge: merge_tx,
             select: select_tx,
+            reset: reset_tx,
+            start_round: start_round_tx,
+            finish_training: finish_training_tx,
+            evaluations: evaluations_tx,
         };
-        let service_requests =
-            ServiceRequests::new(upload_rx, download_rx, aggregate_rx, select_rx);
+        let service_requests = ServiceRequests::new(
+            upload_rx,
+            upload_metrics_rx,
+            download_rx,
+            aggregate_rx,
+            partial_aggregate_rx,
+            merge_rx,
+            select_rx,
+            reset_rx,
+            start_round_rx,
+            finish_training_rx,
+            evaluations_rx,
+        );
         (handle, service_requests)
     }
     pub async fn download(
         &self,
         credentials: Credentials,
-    ) -> Result<Bytes, ServiceError<DownloadError>> {
-        let (tx, rx) = oneshot::channel::<Result<Bytes, DownloadError>>();
+    ) -> Result<Model, ServiceError<DownloadError>> {
+        let (tx, rx) = oneshot::channel::<Result<Model, DownloadError>>();
         let request = DownloadRequest::from((credentials, tx));
         Self::send_request(request, &self.download)?;
         Self::recv_response(rx)
@@ -420,19 +1039,91 @@ where
             .map_err(ServiceError::Request)
     }
 
+    /// Check that the given credentials are those of a client allowed
+    /// to upload weights or metrics, without uploading anything, and
+    /// return the current global model. The credentials are checked
+    /// again by the upload itself.
+    pub async fn check_credentials(
+        &self,
+        credentials: Credentials,
+    ) -> Result<Model, ServiceError<UploadError>> {
+        // The clients allowed to upload are those allowed to
+        // download, and downloading doesn't copy the weights.
+        match self.download(credentials).await {
+            Ok(model) => Ok(model),
+            Err(ServiceError::Handle(e)) => Err(ServiceError::Handle(e)),
+            Err(ServiceError::Request(DownloadError::Unauthorized)) => {
+                Err(ServiceError::Request(UploadError::Unauthorized))
+            }
+        }
+    }
+
+    /// Upload local weights. If `version` is set, the weights are
+    /// rejected unless they were computed from the current version of
+    /// the global model. The training statistics, if any, are
+    /// forwarded to the coordinator once the weights are added.
     pub async fn upload(
         &self,
         credentials: Credentials,
+        version: Option<ModelVersion>,
+        metrics: Option<TrainingMetrics>,
         data: Bytes,
     ) -> Result<(), ServiceError<UploadError>> {
-        let request = UploadRequest::from((credentials, data));
+        let (tx, rx) = oneshot::channel::<Result<(), UploadError>>();
+        let request = UploadRequest::from((credentials, version, metrics, data, tx));
         Self::send_request(request, &self.upload)?;
-        Ok(())
+        Self::recv_response(rx)
+            .await?
+            .map_err(ServiceError::Request)
+    }
+
+    /// Upload the metrics computed by a participant during an
+    /// evaluation round
+    pub async fn upload_metrics(
+        &self,
+        credentials: Credentials,
+        metrics: LocalMetrics,
+    ) -> Result<(), ServiceError<UploadError>> {
+        let (tx, rx) = oneshot::channel::<Result<(), UploadError>>();
+        let request = UploadMetricsRequest::from((credentials, metrics, tx));
+        Self::send_request(request, &self.upload_metrics)?;
+        Self::recv_response(rx)
+            .await?
+            .map_err(ServiceError::Request)
     }
 
-    pub async fn aggregate(&self) -> Result<(), ServiceError<A::Error>> {
+    /// Aggregate the weights uploaded so far into the new global
+    /// weights. The retries of a request must have the same
+    /// `request_id`.
+    pub async fn aggregate(&self, request_id: RequestId) -> Result<(), ServiceError<A::Error>> {
         let (tx, rx) = oneshot::channel::<Result<(), A::Error>>();
-        Self::send_request(AggregateRequest::from(tx), &self.aggregate)?;
+        Self::send_request(AggregateRequest::from((request_id, tx)), &self.aggregate)?;
+        Self::recv_response(rx)
+            .await?
+            .map_err(ServiceError::Request)
+    }
+
+    /// Aggregate the weights uploaded to this aggregator into a
+    /// partial result, to be merged with the partial results of the
+    /// other aggregators
+    pub async fn partial_aggregate(&self) -> Result<Bytes, ServiceError<A::Error>> {
+        let (tx, rx) = oneshot::channel::<Result<Bytes, A::Error>>();
+        Self::send_request(PartialAggregateRequest::from(tx), &self.partial_aggregate)?;
+        Self::recv_response(rx)
+            .await?
+            .map_err(ServiceError::Request)
+    }
+
+    /// Merge the partial results of all the aggregators into the new
+    /// global weights. The retries of a request must have the same
+    /// `request_id`.
+    pub async fn merge(
+        &self,
+        request_id: RequestId,
+        partials: Vec<Bytes>,
+    ) -> Result<(), ServiceError<A::Error>> {
+        let (tx, rx) = oneshot::channel::<Result<(), A::Error>>();
+        Self::send_request(MergeRequest::from((request_id, partials, tx)), &self.merge)?;
         Self::recv_response(rx)
             .await?
             .map_err(ServiceError::Request)
@@ -446,6 +1137,46 @@ where
             .map_err(ServiceError::Request)
     }
 
+    /// Abort the given round: the weights uploaded so far are
+    /// discarded, and the global weights are rolled back if they
+    /// were produced during this round.
+    pub async fn reset(&self, round: u32) -> Result<(), ServiceError<A::Error>> {
+        let (tx, rx) = oneshot::channel::<Result<(), A::Error>>();
+        Self::send_request(ResetRequest::from((round, tx)), &self.reset)?;
+        Self::recv_response(rx)
+            .await?
+            .map_err(ServiceError::Request)
+    }
+
+    /// Notify the service that the given round starts
+    pub async fn start_round(
+        &self,
+        round: u32,
+        config: RoundConfig,
+    ) -> Result<(), ServiceError<A::Error>> {
+        let (tx, rx) = oneshot::channel::<()>();
+        Self::send_request(
+            StartRoundRequest::from((round, config, tx)),
+            &self.start_round,
+        )?;
+        Ok(Self::recv_response(rx).await?)
+    }
+
+    /// Notify the service that the training is over. The service
+    /// terminates once the running aggregation, if any, completes.
+    pub async fn finish_training(&self) -> Result<(), ServiceError<A::Error>> {
+        let (tx, rx) = oneshot::channel::<()>();
+        Self::send_request(FinishTrainingRequest::from(tx), &self.finish_training)?;
+        Ok(Self::recv_response(rx).await?)
+    }
+
+    /// Retrieve the results of the evaluation rounds
+    pub async fn evaluations(&self) -> Result<Vec<Evaluation>, ServiceError<A::Error>> {
+        let (tx, rx) = oneshot::channel::<Vec<Evaluation>>();
+        Self::send_request(EvaluationsRequest::from(tx), &self.evaluations)?;
+        Ok(Self::recv_response(rx).await?)
+    }
+
     fn send_request<P>(payload: P, tx: &UnboundedSender<P>) -> Result<(), ChannelError> {
         trace!("send request to the service");
         if tx.send(payload).is_err() {
@@ -465,6 +1196,68 @@ where
     }
 }
 
+/// Identify a version of the global model. The round is the number
+/// of aggregations that produced the model, and the hash is the
+/// hex-encoded SHA-256 hash of the weights.
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+#[display(fmt = "{}-{}", round, hash)]
+pub struct ModelVersion {
+    pub round: u32,
+    pub hash: String,
+}
+
+impl ModelVersion {
+    pub fn new(round: u32, weights: &[u8]) -> Self {
+        let hash = Sha256::digest(weights)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Self { round, hash }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid model version")]
+pub struct InvalidModelVersion;
+
+impl FromStr for ModelVersion {
+    type Err = InvalidModelVersion;
+
+    /// Parse a version formatted as `<round>-<hash>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '-');
+        let round = parts
+            .next()
+            .and_then(|round| round.parse().ok())
+            .ok_or(InvalidModelVersion)?;
+        let hash = parts.next().ok_or(InvalidModelVersion)?;
+        Ok(Self {
+            round,
+            hash: hash.to_string(),
+        })
+    }
+}
+
+/// Information about a round, sent by the coordinator to the
+/// aggregators when the round starts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoundConfig {
+    /// Total number of rounds. It can increase during the training if
+    /// an operator extends it.
+    pub rounds: u32,
+    /// Whether the participants evaluate the global model instead of
+    /// training it. They upload metrics instead of weights, and the
+    /// global model doesn't change.
+    pub evaluation: bool,
+}
+
+/// The global model, along with its version
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub version: ModelVersion,
+    pub weights: Bytes,
+}
+
 #[derive(Error, Debug)]
 pub enum DownloadError {
     #[error("the user does not have the proper permissions")]
@@ -475,6 +1268,18 @@ pub enum DownloadError {
 pub enum UploadError {
     #[error("the user does not have the proper permissions")]
     Unauthorized,
+
+    #[error("the weights were computed from an outdated version of the model")]
+    StaleModel,
+
+    #[error("the version of the model the weights were computed from is missing")]
+    MissingModelVersion,
+
+    #[error("weights cannot be uploaded during an evaluation round")]
+    EvaluationRound,
+
+    #[error("metrics can only be uploaded during an evaluation round")]
+    NotEvaluationRound,
 }
 
 #[derive(Error, Debug)]
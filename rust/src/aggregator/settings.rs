@@ -1,12 +1,41 @@
-use crate::common::settings::LoggingSettings;
+use crate::common::{
+    rpc::RpcCodec,
+    settings::{LoggingSettings, RateLimitSettings, RpcCallSettings},
+    task::TaskId,
+};
 use config::{Config, ConfigError};
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub logging: LoggingSettings,
     pub api: ApiSettings,
     pub rpc: RpcSettings,
+    /// Aggregator of the default task
     pub aggregation: AggregationSettings,
+    /// Settings for the asynchronous mode of the coordinator. If it
+    /// is not set, the updates must have been computed from the
+    /// current global model.
+    pub async_aggregation: Option<AsyncAggregationSettings>,
+    /// Settings of the other tasks, by name. The coordinator must
+    /// run the same tasks.
+    #[serde(default)]
+    pub tasks: HashMap<TaskId, TaskSettings>,
+}
+
+/// Settings of a task other than the default one
+#[derive(Debug, Deserialize)]
+pub struct TaskSettings {
+    pub aggregation: AggregationSettings,
+    pub async_aggregation: Option<AsyncAggregationSettings>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct AsyncAggregationSettings {
+    /// Maximum number of aggregations that may happen between the
+    /// moment a participant downloads the global model and the
+    /// moment it uploads its update. Older updates are rejected.
+    pub max_staleness: u32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -24,18 +53,90 @@ pub struct PythonAggregatorSettings {
 #[derive(Debug, Deserialize)]
 pub struct ApiSettings {
     pub bind_address: String,
+    /// Token that operators must send in the `Authorization` header
+    /// to retrieve the results of the evaluation rounds. If it is not
+    /// set, the results are not served.
+    pub admin_token: Option<String>,
+    #[serde(default)]
+    pub rate_limit: ApiRateLimitSettings,
+    /// Maximum size of the local weights uploaded by the
+    /// participants, in bytes. If it is not set, the size is not
+    /// limited.
+    pub max_upload_size: Option<u64>,
+    /// Directory in which the uploads are spooled while they are
+    /// received. If it is not set, they are buffered in memory.
+    pub upload_spool_dir: Option<String>,
+    /// What the participants upload
+    #[serde(default)]
+    pub updates: UpdateKind,
+}
+
+/// What the participants upload at the end of their training
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateKind {
+    /// Their local weights
+    Weights,
+    /// The difference between their local weights and the global
+    /// weights they trained from. The uploads must then tell which
+    /// version of the global model they were computed from, and the
+    /// aggregator must apply the deltas to the global weights, like
+    /// `xain_aggregators.delta_average` does.
+    Delta,
+}
+
+impl Default for UpdateKind {
+    fn default() -> Self {
+        UpdateKind::Weights
+    }
+}
+
+/// Rate limits for the endpoints used by the participants
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct ApiRateLimitSettings {
+    #[serde(default)]
+    pub upload: RateLimitSettings,
+    #[serde(default)]
+    pub download: RateLimitSettings,
+}
+
+impl ApiRateLimitSettings {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.upload.validate("upload")?;
+        self.download.validate("download")
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RpcSettings {
     pub bind_address: String,
     pub coordinator_address: String,
+    /// Maximum number of RPC connections processed concurrently. New
+    /// connections are refused when the limit is reached. By default
+    /// the number of connections is not limited.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Serialization format of the RPC messages, which must be the
+    /// same on the coordinator and the aggregator. JSON by default.
+    #[serde(default)]
+    pub codec: RpcCodec,
+    /// Deadlines and retries of the calls
+    #[serde(default)]
+    pub calls: RpcCallSettings,
 }
 
 impl Settings {
     pub fn new(path: &str) -> Result<Self, ConfigError> {
         let mut s = Config::new();
         s.merge(config::File::with_name(path))?;
-        s.try_into()
+        let settings: Self = s.try_into()?;
+        if settings.tasks.keys().any(TaskId::is_default) {
+            return Err(ConfigError::Message(format!(
+                "the `{}` task is configured by the `aggregation` settings",
+                TaskId::default()
+            )));
+        }
+        settings.api.rate_limit.validate()?;
+        Ok(settings)
     }
 }
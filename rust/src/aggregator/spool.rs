@@ -0,0 +1,282 @@
+use crate::aggregator::{
+    encoding::{self, DecodeError, Encoding},
+    update::{self, UpdateError},
+};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::stream::{Stream, StreamExt};
+use std::{
+    cmp, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use tokio::{fs, io::AsyncWriteExt};
+use uuid::Uuid;
+
+/// Maximum amount of memory reserved upfront for a body that is
+/// buffered in memory. Bodies larger than this grow the buffer as
+/// they are received, so that a client cannot make us allocate a lot
+/// of memory just by announcing a large body.
+const MAX_INITIAL_CAPACITY: u64 = 16 * 1024 * 1024;
+
+/// Receive request bodies, up to a size limit.
+///
+/// Uploads of large models can take a while, and buffering them all
+/// in memory at the same time can exhaust the memory of the
+/// aggregator when many participants upload concurrently. If the
+/// spool has a directory, the bodies are written to temporary files
+/// while they are received and decoded from there, so that only the
+/// decoded weights of the uploads that have been fully received are
+/// held in memory.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    /// Directory in which the bodies are written. If it is `None`,
+    /// the bodies are buffered in memory.
+    directory: Option<PathBuf>,
+    /// Maximum size of a body, in bytes
+    limit: Option<u64>,
+}
+
+#[derive(Error, Debug)]
+pub enum SpoolError {
+    #[error("the body is larger than {0} bytes")]
+    TooLarge(u64),
+
+    #[error("failed to receive the body: {0}")]
+    Body(#[from] warp::Error),
+
+    #[error("failed to spool the body: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("failed to decode the body: {0}")]
+    Decode(io::Error),
+
+    #[error("invalid compressed update: {0}")]
+    InvalidUpdate(UpdateError),
+}
+
+impl From<DecodeError> for SpoolError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::TooLarge(limit) => SpoolError::TooLarge(limit),
+            DecodeError::Io(e) => SpoolError::Decode(e),
+        }
+    }
+}
+
+impl From<UpdateError> for SpoolError {
+    fn from(e: UpdateError) -> Self {
+        match e {
+            UpdateError::TooLarge(limit) => SpoolError::TooLarge(limit),
+            e => SpoolError::InvalidUpdate(e),
+        }
+    }
+}
+
+impl Spool {
+    pub fn new(directory: Option<PathBuf>, limit: Option<u64>) -> Self {
+        Self { directory, limit }
+    }
+
+    /// Receive a body and decode it, failing if it is larger than the
+    /// limit, before or after decoding. Compressed updates are
+    /// expanded to dense arrays, see [`update`], and must have
+    /// `model_len` values if it is set. `size_hint` is the length
+    /// announced by the client, if any.
+    pub async fn receive<S, B>(
+        &self,
+        body: S,
+        size_hint: Option<u64>,
+        encoding: Encoding,
+        model_len: Option<u64>,
+    ) -> Result<Bytes, SpoolError>
+    where
+        S: Stream<Item = Result<B, warp::Error>> + Unpin,
+        B: Buf,
+    {
+        // Don't even start receiving a body that is announced to be
+        // too large.
+        if let Some(size) = size_hint {
+            check_limit(size, self.limit)?;
+        }
+        let body = match self.directory {
+            None => {
+                let body = receive_in_memory(body, size_hint, self.limit).await?;
+                encoding::decode(encoding, body, self.limit).await?
+            }
+            Some(ref directory) => receive_in_file(body, directory, encoding, self.limit).await?,
+        };
+        Ok(update::expand(body, self.limit, model_len).await?)
+    }
+}
+
+fn check_limit(received: u64, limit: Option<u64>) -> Result<(), SpoolError> {
+    match limit {
+        Some(limit) if received > limit => Err(SpoolError::TooLarge(limit)),
+        _ => Ok(()),
+    }
+}
+
+async fn receive_in_memory<S, B>(
+    mut body: S,
+    size_hint: Option<u64>,
+    limit: Option<u64>,
+) -> Result<Bytes, SpoolError>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
+    let capacity = cmp::min(size_hint.unwrap_or(0), MAX_INITIAL_CAPACITY);
+    let mut buffer = BytesMut::with_capacity(capacity as usize);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk?;
+        check_limit((buffer.len() + chunk.remaining()) as u64, limit)?;
+        while chunk.has_remaining() {
+            let bytes = chunk.bytes();
+            let len = bytes.len();
+            buffer.extend_from_slice(bytes);
+            chunk.advance(len);
+        }
+    }
+    Ok(buffer.freeze())
+}
+
+/// Write a body to a temporary file, and decode it from there
+async fn receive_in_file<S, B>(
+    body: S,
+    directory: &Path,
+    encoding: Encoding,
+    limit: Option<u64>,
+) -> Result<Bytes, SpoolError>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
+    let path = directory.join(format!("upload-{}", Uuid::new_v4()));
+    let result = match spool_to_file(body, &path, limit).await {
+        Ok(()) => encoding::decode_file(encoding, path.clone(), limit)
+            .await
+            .map_err(SpoolError::from),
+        Err(e) => Err(e),
+    };
+    // Whatever happened, get rid of the file
+    if let Err(e) = fs::remove_file(&path).await {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("failed to remove spool file {}: {}", path.display(), e);
+        }
+    }
+    result
+}
+
+async fn spool_to_file<S, B>(mut body: S, path: &Path, limit: Option<u64>) -> Result<(), SpoolError>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
+    let mut file = fs::File::create(path).await?;
+    let mut received: u64 = 0;
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk?;
+        received += chunk.remaining() as u64;
+        check_limit(received, limit)?;
+        while chunk.has_remaining() {
+            let written = file.write(chunk.bytes()).await?;
+            chunk.advance(written);
+        }
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::env;
+
+    fn body(chunks: &[&[u8]]) -> impl Stream<Item = Result<Bytes, warp::Error>> + Unpin {
+        stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_receive_in_memory() {
+        let spool = Spool::new(None, Some(8));
+        let bytes = spool
+            .receive(body(&[b"1111", b"2222"]), None, Encoding::Identity, None)
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], b"11112222");
+
+        let spool = Spool::new(None, Some(7));
+        let res = spool
+            .receive(body(&[b"1111", b"2222"]), None, Encoding::Identity, None)
+            .await;
+        assert!(matches!(res, Err(SpoolError::TooLarge(7))));
+    }
+
+    #[tokio::test]
+    async fn test_receive_in_file() {
+        let spool = Spool::new(Some(env::temp_dir()), None);
+        let bytes = spool
+            .receive(body(&[b"1111", b"2222"]), None, Encoding::Identity, None)
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], b"11112222");
+
+        let spool = Spool::new(Some(env::temp_dir()), Some(7));
+        let res = spool
+            .receive(body(&[b"1111", b"2222"]), None, Encoding::Identity, None)
+            .await;
+        assert!(matches!(res, Err(SpoolError::TooLarge(7))));
+    }
+
+    /// Test that a body announced to be too large is rejected
+    /// upfront.
+    #[tokio::test]
+    async fn test_receive_announced_too_large() {
+        let spool = Spool::new(None, Some(8));
+        let res = spool
+            .receive(body(&[]), Some(1024), Encoding::Identity, None)
+            .await;
+        assert!(matches!(res, Err(SpoolError::TooLarge(8))));
+    }
+
+    /// Test that compressed bodies are decoded, and that the limit
+    /// also applies to the decoded body.
+    #[tokio::test]
+    async fn test_receive_encoded() {
+        let data = vec![42; 1024];
+        let encoded = Encoding::Gzip.encode(&data).unwrap();
+        assert!(encoded.len() < 1024);
+
+        let spool = Spool::new(None, Some(1024));
+        let bytes = spool
+            .receive(body(&[&encoded]), None, Encoding::Gzip, None)
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], &data[..]);
+
+        let spool = Spool::new(None, Some(1023));
+        let res = spool
+            .receive(body(&[&encoded]), None, Encoding::Gzip, None)
+            .await;
+        assert!(matches!(res, Err(SpoolError::TooLarge(1023))));
+
+        let spool = Spool::new(Some(env::temp_dir()), Some(1024));
+        let bytes = spool
+            .receive(body(&[&encoded]), None, Encoding::Gzip, None)
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], &data[..]);
+
+        let spool = Spool::new(Some(env::temp_dir()), Some(1023));
+        let res = spool
+            .receive(body(&[&encoded]), None, Encoding::Gzip, None)
+            .await;
+        assert!(matches!(res, Err(SpoolError::TooLarge(1023))));
+    }
+}
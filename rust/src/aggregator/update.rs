@@ -0,0 +1,752 @@
+//! Compressed local model updates.
+//!
+//! Participants on constrained networks can upload their local
+//! weights in a lossy compressed format instead of a dense numpy
+//! array. The aggregator expands them back to a dense numpy array
+//! before passing them to the aggregator, so that aggregators don't
+//! have to know about the compression.
+//!
+//! A compressed update is laid out as follows, with all the integers
+//! and floats in little endian:
+//!
+//! | field      | size           | description                                     |
+//! |------------|----------------|-------------------------------------------------|
+//! | magic      | 4              | `XFLU`                                          |
+//! | version    | 1              | version of the format, currently 1              |
+//! | scheme     | 1              | compression scheme, see [`Scheme`]              |
+//! | prefix_len | 4              | length of the prefix                            |
+//! | prefix     | prefix_len     | opaque bytes copied in front of the dense array |
+//! | ndim       | 1              | number of dimensions of the array               |
+//! | shape      | 8 * ndim       | length of each dimension                        |
+//! | payload    | see [`Scheme`] | compressed values                               |
+//!
+//! The prefix is useful for aggregators that expect some metadata
+//! before the numpy array, like the number of samples the weighted
+//! average aggregator reads from the first four bytes of the upload.
+//!
+//! The dense arrays produced by the aggregator always contain 32 bits
+//! floats.
+
+use bytes::{Buf, BufMut, Bytes};
+use std::{cmp::Ordering, convert::TryFrom, io, iter};
+use thiserror::Error;
+use tokio::task;
+
+/// Magic bytes at the beginning of every compressed update. Dense
+/// numpy arrays start with `\x93NUMPY` so they cannot be mistaken for
+/// a compressed update.
+pub const MAGIC: &[u8; 4] = b"XFLU";
+
+/// Current version of the compressed update format
+const VERSION: u8 = 1;
+
+/// Maximum size of the dense array a compressed update decodes to,
+/// when no limit is configured. Compressed updates can be tiny
+/// compared to the array they decode to, so they are always bounded.
+const DEFAULT_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Compression schemes for the local model updates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    /// Each value is quantized on 8 bits. The payload is the minimum
+    /// value and the scale as `f32`, followed by one byte per value.
+    /// Values are decoded as `min + q * scale`.
+    Quantized8,
+    /// Each value is quantized on 4 bits. The payload is the same as
+    /// for [`Scheme::Quantized8`], but each byte holds two values,
+    /// the first one in the low nibble.
+    Quantized4,
+    /// Only the `k` values with the largest magnitude are sent. The
+    /// payload is `k` as `u32`, followed by the `k` indices as `u32`
+    /// into the flattened array, and the `k` values as `f32`. All the
+    /// other values are decoded as zero.
+    TopK,
+    /// Only the sign of each value is sent (sign-SGD). The payload is
+    /// the scale as `f32`, followed by one bit per value, set for
+    /// positive values, the first value in the least significant
+    /// bit. Values are decoded as `scale` or `-scale`.
+    Sign,
+}
+
+impl Scheme {
+    fn to_u8(self) -> u8 {
+        match self {
+            Scheme::Quantized8 => 1,
+            Scheme::Quantized4 => 2,
+            Scheme::TopK => 3,
+            Scheme::Sign => 4,
+        }
+    }
+}
+
+impl TryFrom<u8> for Scheme {
+    type Error = UpdateError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Scheme::Quantized8),
+            2 => Ok(Scheme::Quantized4),
+            3 => Ok(Scheme::TopK),
+            4 => Ok(Scheme::Sign),
+            other => Err(UpdateError::UnknownScheme(other)),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UpdateError {
+    #[error("not a compressed update")]
+    NotCompressed,
+
+    #[error("unsupported compressed update version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("unknown compression scheme {0}")]
+    UnknownScheme(u8),
+
+    #[error("the compressed update is truncated")]
+    Truncated,
+
+    #[error("the compressed update has {0} trailing bytes")]
+    TrailingBytes(usize),
+
+    #[error("index {0} is out of the bounds of the array")]
+    IndexOutOfBounds(u32),
+
+    #[error("the decoded update is larger than {0} bytes")]
+    TooLarge(u64),
+
+    #[error("the update has {0} values but the global model has {1}")]
+    LengthMismatch(u64, u64),
+
+    #[error("failed to decode the update: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// A compressed local model update
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressedUpdate {
+    /// Opaque bytes copied in front of the dense array
+    pub prefix: Vec<u8>,
+    /// Shape of the dense array
+    pub shape: Vec<u64>,
+    payload: Payload,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Payload {
+    Quantized {
+        bits: u8,
+        min: f32,
+        scale: f32,
+        data: Vec<u8>,
+    },
+    TopK {
+        indices: Vec<u32>,
+        values: Vec<f32>,
+    },
+    Sign {
+        scale: f32,
+        data: Vec<u8>,
+    },
+}
+
+/// Number of values in an array of the given shape, or `None` if it
+/// overflows.
+fn len(shape: &[u64]) -> Option<u64> {
+    shape
+        .iter()
+        .try_fold(1_u64, |len, &dim| len.checked_mul(dim))
+}
+
+impl CompressedUpdate {
+    /// Quantize the given values on 8 or 4 bits. The decoded values
+    /// are within `scale / 2` of the original ones, where `scale` is
+    /// `(max - min) / (2^bits - 1)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bits` is neither 8 nor 4, or if the number of
+    /// values doesn't match the shape.
+    pub fn quantize(prefix: Vec<u8>, shape: Vec<u64>, values: &[f32], bits: u8) -> Self {
+        assert!(bits == 8 || bits == 4, "only 8 and 4 bits are supported");
+        assert_eq!(len(&shape), Some(values.len() as u64));
+
+        let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let levels = ((1_u16 << bits) - 1) as f32;
+        let (min, scale) = if values.is_empty() || max <= min {
+            (if values.is_empty() { 0.0 } else { min }, 0.0)
+        } else {
+            (min, (max - min) / levels)
+        };
+        let quantize = |value: f32| {
+            if scale == 0.0 {
+                0
+            } else {
+                ((value - min) / scale).round().max(0.0).min(levels) as u8
+            }
+        };
+
+        let data = if bits == 8 {
+            values.iter().copied().map(quantize).collect()
+        } else {
+            values
+                .chunks(2)
+                .map(|pair| {
+                    let low = quantize(pair[0]);
+                    let high = pair.get(1).copied().map(quantize).unwrap_or(0);
+                    low | (high << 4)
+                })
+                .collect()
+        };
+        Self {
+            prefix,
+            shape,
+            payload: Payload::Quantized {
+                bits,
+                min,
+                scale,
+                data,
+            },
+        }
+    }
+
+    /// Keep only the `k` values with the largest magnitude. The other
+    /// values are decoded as zero.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values doesn't match the shape, or if
+    /// there are more than `u32::MAX` values.
+    pub fn top_k(prefix: Vec<u8>, shape: Vec<u64>, values: &[f32], k: usize) -> Self {
+        assert_eq!(len(&shape), Some(values.len() as u64));
+        assert!(values.len() <= u32::MAX as usize);
+
+        let mut indices: Vec<u32> = (0..values.len() as u32).collect();
+        indices.sort_by(|&a, &b| {
+            let (a, b) = (values[a as usize].abs(), values[b as usize].abs());
+            b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+        });
+        indices.truncate(k);
+        indices.sort_unstable();
+        let values = indices.iter().map(|&i| values[i as usize]).collect();
+        Self {
+            prefix,
+            shape,
+            payload: Payload::TopK { indices, values },
+        }
+    }
+
+    /// Keep only the sign of the values (sign-SGD). The values are
+    /// decoded as plus or minus their mean magnitude.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values doesn't match the shape.
+    pub fn sign(prefix: Vec<u8>, shape: Vec<u64>, values: &[f32]) -> Self {
+        assert_eq!(len(&shape), Some(values.len() as u64));
+
+        let scale = if values.is_empty() {
+            0.0
+        } else {
+            values.iter().map(|v| v.abs()).sum::<f32>() / values.len() as f32
+        };
+        let data = values
+            .chunks(8)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &v)| v >= 0.0)
+                    .fold(0_u8, |byte, (i, _)| byte | (1 << i))
+            })
+            .collect();
+        Self {
+            prefix,
+            shape,
+            payload: Payload::Sign { scale, data },
+        }
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        match self.payload {
+            Payload::Quantized { bits: 8, .. } => Scheme::Quantized8,
+            Payload::Quantized { .. } => Scheme::Quantized4,
+            Payload::TopK { .. } => Scheme::TopK,
+            Payload::Sign { .. } => Scheme::Sign,
+        }
+    }
+
+    /// Number of values in the dense array
+    fn len(&self) -> usize {
+        // UNWRAP_SAFE: the shape is checked when the update is
+        // created or parsed
+        len(&self.shape).unwrap() as usize
+    }
+
+    /// Serialize the update
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_slice(MAGIC);
+        buf.put_u8(VERSION);
+        buf.put_u8(self.scheme().to_u8());
+        buf.put_u32_le(self.prefix.len() as u32);
+        buf.put_slice(&self.prefix);
+        buf.put_u8(self.shape.len() as u8);
+        for &dim in self.shape.iter() {
+            buf.put_u64_le(dim);
+        }
+        match self.payload {
+            Payload::Quantized {
+                min,
+                scale,
+                ref data,
+                ..
+            } => {
+                buf.put_f32_le(min);
+                buf.put_f32_le(scale);
+                buf.put_slice(data);
+            }
+            Payload::TopK {
+                ref indices,
+                ref values,
+            } => {
+                buf.put_u32_le(indices.len() as u32);
+                for &index in indices.iter() {
+                    buf.put_u32_le(index);
+                }
+                for &value in values.iter() {
+                    buf.put_f32_le(value);
+                }
+            }
+            Payload::Sign { scale, ref data } => {
+                buf.put_f32_le(scale);
+                buf.put_slice(data);
+            }
+        }
+        buf
+    }
+
+    /// Parse a compressed update. The size of the dense array it
+    /// decodes to is checked against `limit`, or a default limit if
+    /// it is not set, before anything is allocated for it.
+    pub fn from_bytes(mut buf: &[u8], limit: Option<u64>) -> Result<Self, UpdateError> {
+        let magic = take(&mut buf, MAGIC.len())?;
+        if magic != MAGIC {
+            return Err(UpdateError::NotCompressed);
+        }
+        let version = get_u8(&mut buf)?;
+        if version != VERSION {
+            return Err(UpdateError::UnsupportedVersion(version));
+        }
+        let scheme = Scheme::try_from(get_u8(&mut buf)?)?;
+        let prefix_len = get_u32(&mut buf)? as usize;
+        let prefix = take(&mut buf, prefix_len)?.to_vec();
+        let ndim = get_u8(&mut buf)? as usize;
+        // Make sure the shape is there before allocating it
+        take(&mut &buf[..], ndim * 8)?;
+        let shape: Vec<u64> = (0..ndim).map(|_| buf.get_u64_le()).collect();
+
+        let limit = limit.unwrap_or(DEFAULT_LIMIT);
+        let len = len(&shape)
+            .filter(|&len| {
+                let size = len
+                    .checked_mul(4)
+                    .and_then(|size| size.checked_add(prefix_len as u64))
+                    .and_then(|size| size.checked_add(npy_header(&shape).len() as u64));
+                matches!(size, Some(size) if size <= limit && size <= usize::MAX as u64)
+            })
+            .ok_or(UpdateError::TooLarge(limit))? as usize;
+
+        let payload = match scheme {
+            Scheme::Quantized8 | Scheme::Quantized4 => {
+                let bits = if scheme == Scheme::Quantized8 { 8 } else { 4 };
+                let min = get_f32(&mut buf)?;
+                let scale = get_f32(&mut buf)?;
+                let data_len = if bits == 8 { len } else { (len + 1) / 2 };
+                let data = take(&mut buf, data_len)?.to_vec();
+                Payload::Quantized {
+                    bits,
+                    min,
+                    scale,
+                    data,
+                }
+            }
+            Scheme::TopK => {
+                let k = get_u32(&mut buf)? as usize;
+                take(&mut &buf[..], k * 8)?;
+                let indices: Vec<u32> = (0..k).map(|_| buf.get_u32_le()).collect();
+                if let Some(&index) = indices.iter().find(|&&i| i as usize >= len) {
+                    return Err(UpdateError::IndexOutOfBounds(index));
+                }
+                let values = (0..k).map(|_| buf.get_f32_le()).collect();
+                Payload::TopK { indices, values }
+            }
+            Scheme::Sign => {
+                let scale = get_f32(&mut buf)?;
+                let data = take(&mut buf, (len + 7) / 8)?.to_vec();
+                Payload::Sign { scale, data }
+            }
+        };
+        if !buf.is_empty() {
+            return Err(UpdateError::TrailingBytes(buf.len()));
+        }
+
+        Ok(Self {
+            prefix,
+            shape,
+            payload,
+        })
+    }
+
+    /// Decompress the update into a flat array of values
+    pub fn to_dense(&self) -> Vec<f32> {
+        let len = self.len();
+        match self.payload {
+            Payload::Quantized {
+                bits: 8,
+                min,
+                scale,
+                ref data,
+            } => data.iter().map(|&q| min + q as f32 * scale).collect(),
+            Payload::Quantized {
+                min,
+                scale,
+                ref data,
+                ..
+            } => data
+                .iter()
+                .flat_map(|&byte| iter::once(byte & 0x0f).chain(iter::once(byte >> 4)))
+                .take(len)
+                .map(|q| min + q as f32 * scale)
+                .collect(),
+            Payload::TopK {
+                ref indices,
+                ref values,
+            } => {
+                let mut dense = vec![0.0; len];
+                for (&index, &value) in indices.iter().zip(values.iter()) {
+                    dense[index as usize] = value;
+                }
+                dense
+            }
+            Payload::Sign { scale, ref data } => (0..len)
+                .map(|i| {
+                    if data[i / 8] & (1 << (i % 8)) != 0 {
+                        scale
+                    } else {
+                        -scale
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Decompress the update into the prefix followed by a dense
+    /// numpy array of `f32`, as the aggregators expect.
+    pub fn to_npy(&self) -> Vec<u8> {
+        let header = npy_header(&self.shape);
+        let mut buf = Vec::with_capacity(self.prefix.len() + header.len() + self.len() * 4);
+        buf.put_slice(&self.prefix);
+        buf.put_slice(&header);
+        for value in self.to_dense() {
+            buf.put_f32_le(value);
+        }
+        buf
+    }
+}
+
+/// Header of a version 1.0 numpy array of little endian `f32` with
+/// the given shape.
+fn npy_header(shape: &[u64]) -> Vec<u8> {
+    let shape = match shape {
+        [] => "()".to_string(),
+        [dim] => format!("({},)", dim),
+        dims => format!(
+            "({})",
+            dims.iter()
+                .map(|dim| dim.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut dict = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // The magic string, the version and the header length take 10
+    // bytes, and the whole header must be aligned on 64 bytes,
+    // including the final newline.
+    let padding = 63 - (10 + dict.len()) % 64;
+    dict.extend(iter::repeat(' ').take(padding));
+    dict.push('\n');
+
+    let mut header = Vec::with_capacity(10 + dict.len());
+    header.put_slice(b"\x93NUMPY\x01\x00");
+    header.put_u16_le(dict.len() as u16);
+    header.put_slice(dict.as_bytes());
+    header
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], UpdateError> {
+    if buf.len() < n {
+        return Err(UpdateError::Truncated);
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+fn get_u8(buf: &mut &[u8]) -> Result<u8, UpdateError> {
+    Ok(take(buf, 1)?[0])
+}
+
+fn get_u32(buf: &mut &[u8]) -> Result<u32, UpdateError> {
+    Ok(take(buf, 4)?.get_u32_le())
+}
+
+fn get_f32(buf: &mut &[u8]) -> Result<f32, UpdateError> {
+    Ok(take(buf, 4)?.get_f32_le())
+}
+
+/// Number of values of the numpy array at the beginning of `data`,
+/// read from its header, or `None` if `data` doesn't start with a
+/// numpy array.
+pub fn npy_len(data: &[u8]) -> Option<u64> {
+    let mut buf = data.strip_prefix(&b"\x93NUMPY"[..])?;
+    let major = get_u8(&mut buf).ok()?;
+    let _minor = get_u8(&mut buf).ok()?;
+    let header_len = match major {
+        1 => take(&mut buf, 2).ok()?.get_u16_le() as usize,
+        2 | 3 => get_u32(&mut buf).ok()? as usize,
+        _ => return None,
+    };
+    let header = std::str::from_utf8(take(&mut buf, header_len).ok()?).ok()?;
+    let shape = header.split("'shape':").nth(1)?.trim_start();
+    let shape = shape.strip_prefix('(')?.split(')').next()?;
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    len(&shape)
+}
+
+/// Whether the given local weights are a compressed update
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Expand the given local weights into a dense numpy array if they
+/// are a compressed update, on the blocking thread pool. Other
+/// weights are returned unchanged. If `model_len` is set, compressed
+/// updates must have as many values as the global model.
+pub async fn expand(
+    data: Bytes,
+    limit: Option<u64>,
+    model_len: Option<u64>,
+) -> Result<Bytes, UpdateError> {
+    if !is_compressed(&data) {
+        return Ok(data);
+    }
+    task::spawn_blocking(move || {
+        let update = CompressedUpdate::from_bytes(&data, limit)?;
+        match model_len {
+            Some(model_len) if update.len() as u64 != model_len => {
+                Err(UpdateError::LengthMismatch(update.len() as u64, model_len))
+            }
+            _ => Ok(Bytes::from(update.to_npy())),
+        }
+    })
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_values(seed: u64, len: usize) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..len).map(|_| rng.gen_range(-1.0, 1.0)).collect()
+    }
+
+    fn round_trip(update: &CompressedUpdate) -> Vec<f32> {
+        let parsed = CompressedUpdate::from_bytes(&update.to_bytes(), None).unwrap();
+        assert_eq!(&parsed, update);
+        parsed.to_dense()
+    }
+
+    fn max_error(a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_quantize_error_bound() {
+        // Odd length, to check the padding of 4 bits values
+        let values = random_values(0, 1001);
+        for &bits in &[8, 4] {
+            let update = CompressedUpdate::quantize(vec![], vec![7, 143], &values, bits);
+            let scale = match update.payload {
+                Payload::Quantized { scale, .. } => scale,
+                _ => unreachable!(),
+            };
+            // The values span about [-1, 1]
+            assert!(scale <= 2.0 / ((1 << bits) - 1) as f32);
+            let decoded = round_trip(&update);
+            assert!(max_error(&values, &decoded) <= scale / 2.0 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_quantize_constant() {
+        let values = vec![0.5; 10];
+        for &bits in &[8, 4] {
+            let update = CompressedUpdate::quantize(vec![], vec![10], &values, bits);
+            assert_eq!(round_trip(&update), values);
+        }
+    }
+
+    #[test]
+    fn test_top_k() {
+        let values = random_values(1, 1000);
+        let update = CompressedUpdate::top_k(vec![], vec![1000], &values, 100);
+        let decoded = round_trip(&update);
+
+        let kept: Vec<f32> = decoded.iter().copied().filter(|&v| v != 0.0).collect();
+        assert_eq!(kept.len(), 100);
+        let smallest_kept = kept.iter().map(|v| v.abs()).fold(f32::INFINITY, f32::min);
+        for (value, decoded) in values.iter().zip(decoded.iter()) {
+            if *decoded == 0.0 {
+                // Dropped values are never larger than the kept ones
+                assert!(value.abs() <= smallest_kept);
+            } else {
+                // Kept values are exact
+                assert_eq!(value, decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        let values = random_values(2, 1001);
+        let update = CompressedUpdate::sign(vec![], vec![1001], &values);
+        let decoded = round_trip(&update);
+
+        let mean = values.iter().map(|v| v.abs()).sum::<f32>() / values.len() as f32;
+        for (value, decoded) in values.iter().zip(decoded.iter()) {
+            assert_eq!(value.is_sign_negative(), decoded.is_sign_negative());
+            assert!((decoded.abs() - mean).abs() < 1e-6);
+        }
+    }
+
+    /// Test that averaging decoded updates stays close to averaging
+    /// the dense updates.
+    #[test]
+    fn test_aggregation_error_bound() {
+        let updates: Vec<Vec<f32>> = (0..10).map(|seed| random_values(seed, 500)).collect();
+        let average = |updates: &[Vec<f32>]| -> Vec<f32> {
+            (0..500)
+                .map(|i| updates.iter().map(|u| u[i]).sum::<f32>() / updates.len() as f32)
+                .collect()
+        };
+        let dense = average(&updates);
+
+        let mut max_scale: f32 = 0.0;
+        let decoded: Vec<Vec<f32>> = updates
+            .iter()
+            .map(|values| {
+                let update = CompressedUpdate::quantize(vec![], vec![500], values, 8);
+                if let Payload::Quantized { scale, .. } = update.payload {
+                    max_scale = max_scale.max(scale);
+                }
+                round_trip(&update)
+            })
+            .collect();
+        assert!(max_error(&dense, &average(&decoded)) <= max_scale / 2.0 + 1e-6);
+    }
+
+    #[test]
+    fn test_to_npy() {
+        let update = CompressedUpdate::quantize(vec![0, 0, 0, 42], vec![2, 2], &[1.0; 4], 8);
+        let npy = update.to_npy();
+        assert_eq!(&npy[..4], &[0, 0, 0, 42]);
+        let npy = &npy[4..];
+        assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+        assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 2), }"));
+        assert!(header.ends_with('\n'));
+        let mut data = &npy[10 + header_len..];
+        assert_eq!(data.len(), 16);
+        while data.has_remaining() {
+            assert_eq!(data.get_f32_le(), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_from_bytes_invalid() {
+        let update = CompressedUpdate::top_k(vec![], vec![1000], &random_values(3, 1000), 10);
+        let bytes = update.to_bytes();
+
+        let res = CompressedUpdate::from_bytes(&bytes[..bytes.len() - 1], None);
+        assert!(matches!(res, Err(UpdateError::Truncated)));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let res = CompressedUpdate::from_bytes(&trailing, None);
+        assert!(matches!(res, Err(UpdateError::TrailingBytes(1))));
+
+        // The dense array takes 4000 bytes plus the header
+        let res = CompressedUpdate::from_bytes(&bytes, Some(4000));
+        assert!(matches!(res, Err(UpdateError::TooLarge(4000))));
+
+        let mut scheme = bytes;
+        scheme[5] = 42;
+        let res = CompressedUpdate::from_bytes(&scheme, None);
+        assert!(matches!(res, Err(UpdateError::UnknownScheme(42))));
+    }
+
+    /// Test that a tiny update announcing a huge array is rejected
+    /// even when no limit is configured.
+    #[test]
+    fn test_from_bytes_huge_shape() {
+        let update = CompressedUpdate::top_k(vec![], vec![10], &random_values(4, 10), 1);
+        let mut bytes = update.to_bytes();
+        // The shape starts after the magic, version, scheme, prefix
+        // length and number of dimensions
+        bytes[11..19].copy_from_slice(&u64::MAX.to_le_bytes());
+        let res = CompressedUpdate::from_bytes(&bytes, None);
+        assert!(matches!(res, Err(UpdateError::TooLarge(DEFAULT_LIMIT))));
+        let res = CompressedUpdate::from_bytes(&bytes, Some(u64::MAX));
+        assert!(matches!(res, Err(UpdateError::TooLarge(u64::MAX))));
+    }
+
+    #[test]
+    fn test_npy_len() {
+        let update = CompressedUpdate::quantize(vec![], vec![3, 7], &[1.0; 21], 8);
+        assert_eq!(npy_len(&update.to_npy()), Some(21));
+        let update = CompressedUpdate::quantize(vec![], vec![5], &[1.0; 5], 8);
+        assert_eq!(npy_len(&update.to_npy()), Some(5));
+        assert_eq!(npy_len(b""), None);
+        assert_eq!(npy_len(b"1111"), None);
+    }
+
+    #[tokio::test]
+    async fn test_expand_length_mismatch() {
+        let update = CompressedUpdate::sign(vec![], vec![10], &random_values(5, 10));
+        let bytes = Bytes::from(update.to_bytes());
+        assert!(expand(bytes.clone(), None, Some(10)).await.is_ok());
+        assert!(expand(bytes.clone(), None, None).await.is_ok());
+        let res = expand(bytes, None, Some(11)).await;
+        assert!(matches!(res, Err(UpdateError::LengthMismatch(10, 11))));
+    }
+}
@@ -1,5 +1,6 @@
 use clap::{App, Arg};
-use std::process;
+use futures::future::{join_all, select_all};
+use std::{collections::HashMap, iter, process};
 use tokio::signal::ctrl_c;
 use tracing_futures::Instrument;
 use xain_fl::{
@@ -8,9 +9,12 @@ use xain_fl::{
         py_aggregator::spawn_py_aggregator,
         rpc,
         service::{Service, ServiceHandle},
-        settings::{AggregationSettings, ApiSettings, RpcSettings, Settings},
+        settings::{AggregationSettings, ApiSettings, RpcSettings, Settings, TaskSettings},
+    },
+    common::{
+        logging, shutdown,
+        task::{TaskId, Tasks},
     },
-    common::logging,
     coordinator,
 };
 #[macro_use]
@@ -40,57 +44,103 @@ async fn main() {
         rpc,
         api,
         aggregation,
+        async_aggregation,
+        tasks,
         logging,
     } = settings;
 
     logging::configure(logging);
 
+    let default_task = TaskSettings {
+        aggregation,
+        async_aggregation,
+    };
+    let tasks = iter::once((TaskId::default(), default_task))
+        .chain(tasks)
+        .collect();
     let span = trace_span!("root");
-    _main(rpc, api, aggregation).instrument(span).await;
+    let success = _main(rpc, api, tasks).instrument(span).await;
+    process::exit(if success { 0 } else { 1 });
 }
 
-async fn _main(rpc: RpcSettings, api: ApiSettings, aggregation: AggregationSettings) {
-    let (service_handle, service_requests) = ServiceHandle::new();
-    let rpc_server = rpc::serve(rpc.bind_address.clone(), service_handle.clone())
-        .instrument(trace_span!("rpc_server"));
-    let rpc_server_task_handle = tokio::spawn(rpc_server);
+async fn _main(rpc: RpcSettings, api: ApiSettings, tasks: HashMap<TaskId, TaskSettings>) -> bool {
+    let mut service_handles = HashMap::new();
+    let mut service_requests = Vec::new();
+    for (task, settings) in tasks {
+        let (service_handle, requests) = ServiceHandle::new();
+        service_handles.insert(task.clone(), service_handle);
+        service_requests.push((task, settings, requests));
+    }
+    let service_handles = Tasks::new(service_handles);
+    let (shutdown_trigger, shutdown) = shutdown::channel();
+
+    let rpc_server = rpc::serve(
+        rpc.bind_address.clone(),
+        service_handles.clone(),
+        rpc.max_connections,
+        rpc.codec,
+        shutdown.clone(),
+    )
+    .instrument(trace_span!("rpc_server"));
+    let mut rpc_server_task_handle = tokio::spawn(rpc_server);
 
     let rpc_client_span = trace_span!("rpc_client");
-    let rpc_client = coordinator::rpc::client_connect(rpc.coordinator_address.clone())
+    let rpc_client = coordinator::rpc::Client::connect(rpc.coordinator_address.clone(), rpc.codec)
         .instrument(rpc_client_span.clone())
         .await
         .unwrap();
 
-    let (aggregator, mut shutdown_rx) = match aggregation {
-        AggregationSettings::Python(python_aggregator_settings) => {
-            spawn_py_aggregator(python_aggregator_settings)
-        }
-    };
-
-    // Spawn the task that waits for the aggregator running in a
-    // background thread to finish.
-    let aggregator_task_handle = tokio::spawn(async move { shutdown_rx.recv().await });
-
     // Spawn the task that provides the public HTTP API.
-    let api_task_handle = tokio::spawn(
-        async move { api::serve(&api.bind_address, service_handle.clone()).await }
+    let api_shutdown = shutdown.clone();
+    let mut api_task_handle = tokio::spawn(
+        async move { api::serve(api, service_handles, api_shutdown).await }
             .instrument(trace_span!("api_server")),
     );
 
-    let service = Service::new(aggregator, rpc_client, service_requests);
+    // Each task has its own aggregator and service
+    let mut services = Vec::new();
+    let mut aggregators = Vec::new();
+    for (task, settings, requests) in service_requests {
+        let (aggregator, mut shutdown_rx) = match settings.aggregation {
+            AggregationSettings::Python(python_aggregator_settings) => {
+                spawn_py_aggregator(python_aggregator_settings)
+            }
+        };
+
+        // Spawn the task that waits for the aggregator running in a
+        // background thread to finish.
+        aggregators.push(tokio::spawn(async move { shutdown_rx.recv().await }));
+
+        let service = Service::new(
+            aggregator,
+            rpc_client.for_task(task.clone()),
+            rpc.calls,
+            requests,
+            settings.async_aggregation,
+        );
+        services.push(tokio::spawn(
+            service.instrument(trace_span!("service", task = %task)),
+        ));
+    }
 
-    tokio::select! {
-        _ = service.instrument(trace_span!("service")) => {
-            info!("shutting down: Service terminated");
+    // The services terminate when the coordinator notifies the end
+    // of the training
+    let success = tokio::select! {
+        _ = join_all(services) => {
+            info!("shutting down: training ended");
+            true
         }
-        _ = aggregator_task_handle => {
-            info!("shutting down: Aggregator terminated");
+        _ = select_all(aggregators.iter_mut()) => {
+            error!("shutting down: Aggregator terminated");
+            false
         }
-        _ = api_task_handle => {
-            info!("shutting down: API task terminated");
+        _ = &mut api_task_handle => {
+            error!("shutting down: API task terminated");
+            false
         }
-        _ = rpc_server_task_handle => {
-            info!("shutting down: RPC server task terminated");
+        _ = &mut rpc_server_task_handle => {
+            error!("shutting down: RPC server task terminated");
+            false
         }
         result = ctrl_c() => {
             match result {
@@ -98,6 +148,18 @@ async fn _main(rpc: RpcSettings, api: ApiSettings, aggregation: AggregationSetti
                 Err(e) => error!("shutting down: error while waiting for SIGINT: {}", e),
 
             }
+            false
         }
+    };
+
+    // Stop accepting new requests, and wait for the pending ones
+    shutdown_trigger.trigger();
+    let _ = api_task_handle.await;
+    let _ = rpc_server_task_handle.await;
+
+    // The Python aggregators stop once their service is dropped
+    if success {
+        join_all(aggregators).await;
     }
+    success
 }
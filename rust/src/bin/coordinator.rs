@@ -2,8 +2,9 @@
 extern crate tracing;
 
 use clap::{App, Arg};
+use futures::future::join_all;
 use rand::seq::IteratorRandom;
-use std::process;
+use std::{collections::HashMap, iter, process, time::Duration};
 use tokio::signal::ctrl_c;
 use tracing_futures::Instrument;
 
@@ -15,12 +16,22 @@ use xain_fl::{
 
 use xain_fl::{
     aggregator,
-    common::{client::ClientId, logging},
+    common::{
+        client::ClientId,
+        logging,
+        rpc::{context, retry},
+        settings::RpcCallSettings,
+        shutdown,
+        task::{TaskId, Tasks},
+    },
     coordinator::{
         api,
-        core::{Selector, Service, ServiceHandle},
+        core::{AggregatorClient, BanList, Selector, Service, ServiceHandle, TrainingOutcome},
         rpc,
-        settings::{ApiSettings, FederatedLearningSettings, RpcSettings, Settings},
+        settings::{
+            AggregatorSettings, ApiSettings, EvictionSettings, FederatedLearningSettings,
+            RpcSettings, Settings,
+        },
     },
 };
 
@@ -49,7 +60,10 @@ async fn main() {
         rpc,
         api,
         federated_learning,
+        tasks,
+        eviction,
         aggregator_url,
+        additional_aggregators,
         #[cfg(feature = "influx_metrics")]
         // FIXME: when compiling without the `influx_metrics` feature,
         // rustc emits a warning about this variable being
@@ -64,38 +78,69 @@ async fn main() {
     } = settings;
     logging::configure(logging);
 
+    let tasks = iter::once((TaskId::default(), federated_learning))
+        .chain(tasks)
+        .collect();
     let span = trace_span!("root");
-    _main(
+    let success = _main(
         rpc,
         api,
-        federated_learning,
+        tasks,
+        eviction,
         aggregator_url,
+        additional_aggregators,
         #[cfg(feature = "influx_metrics")]
         _metric_store,
     )
     .instrument(span)
     .await;
+    process::exit(if success { 0 } else { 1 });
 }
 
 async fn _main(
     rpc: RpcSettings,
     api: ApiSettings,
-    federated_learning: FederatedLearningSettings,
+    tasks: HashMap<TaskId, FederatedLearningSettings>,
+    eviction: EvictionSettings,
     aggregator_url: String,
+    additional_aggregators: Vec<AggregatorSettings>,
     #[cfg(feature = "influx_metrics")] metric_store: Option<MetricStoreSettings>,
-) {
-    let (service_handle, service_requests) = ServiceHandle::new();
+) -> bool {
+    let mut service_handles = HashMap::new();
+    let mut service_requests = Vec::new();
+    for (task, federated_learning) in tasks {
+        let (service_handle, requests) = ServiceHandle::new();
+        service_handles.insert(task.clone(), service_handle);
+        service_requests.push((task, federated_learning, requests));
+    }
+    let service_handles = Tasks::new(service_handles);
+    let (shutdown_trigger, shutdown) = shutdown::channel();
 
     // Start the RPC server
-    let rpc_server = rpc::serve(rpc.bind_address.clone(), service_handle.clone())
-        .instrument(trace_span!("rpc_server"));
-    let rpc_server_task_handle = tokio::spawn(rpc_server);
+    let rpc_server = rpc::serve(
+        rpc.bind_address.clone(),
+        service_handles.clone(),
+        rpc.max_connections,
+        rpc.codec,
+        shutdown.clone(),
+    )
+    .instrument(trace_span!("rpc_server"));
+    let mut rpc_server_task_handle = tokio::spawn(rpc_server);
 
-    // Start the RPC client
-    let rpc_client = aggregator::rpc::Client::connect(rpc.aggregator_address.clone())
-        .instrument(trace_span!("rpc_client"))
-        .await
-        .unwrap();
+    // Start the RPC clients
+    let aggregators = iter::once(AggregatorSettings {
+        url: aggregator_url,
+        rpc_address: rpc.aggregator_address,
+    })
+    .chain(additional_aggregators);
+    let mut aggregator_clients = Vec::new();
+    for AggregatorSettings { url, rpc_address } in aggregators {
+        let rpc_client = aggregator::rpc::Client::connect(rpc_address.clone(), rpc.codec)
+            .instrument(trace_span!("rpc_client", aggregator = %rpc_address))
+            .await
+            .unwrap();
+        aggregator_clients.push(AggregatorClient { url, rpc_client });
+    }
 
     #[cfg(feature = "influx_metrics")]
     let metric_sender = if let Some(metric_store) = metric_store {
@@ -105,39 +150,84 @@ async fn _main(
             &metric_store.database_name[..],
         );
 
-        let _ = tokio::spawn(async move { run_metricstore(influx_client).await });
-        Some(metric_sender)
+        let metric_store_task_handle =
+            tokio::spawn(async move { run_metricstore(influx_client).await });
+        Some((metric_sender, metric_store_task_handle))
     } else {
         None
     };
 
     // Start the api server
-    let api_server_task_handle = tokio::spawn(
-        async move { api::serve(api.bind_address.as_str(), service_handle.clone()).await }
+    let api_shutdown = shutdown.clone();
+    let mut api_server_task_handle = tokio::spawn(
+        async move { api::serve(api, service_handles, api_shutdown).await }
             .instrument(trace_span!("api_server")),
     );
 
-    // Create the service
-    let service = Service::new(
-        RandomSelector,
-        federated_learning,
-        aggregator_url,
-        rpc_client,
-        service_requests,
-        #[cfg(feature = "influx_metrics")]
-        metric_sender,
-    );
+    // Create and run one service per task. The tasks share the ban
+    // list, so a client banned in one task is banned from all of
+    // them.
+    let ban_list = match eviction.ban_list {
+        Some(ref path) => BanList::load(path.into()).unwrap_or_else(|err| {
+            eprintln!("Problem loading the ban list: {}", err);
+            process::exit(1);
+        }),
+        None => BanList::default(),
+    };
+    let mut services = Vec::new();
+    for (task, federated_learning, requests) in service_requests {
+        let aggregators: Vec<AggregatorClient> = aggregator_clients
+            .iter()
+            .map(|aggregator| AggregatorClient {
+                url: task.url(&aggregator.url),
+                rpc_client: aggregator.rpc_client.for_task(task.clone()),
+            })
+            .collect();
+        let rpc_clients: Vec<_> = aggregators
+            .iter()
+            .map(|aggregator| aggregator.rpc_client.clone())
+            .collect();
+        let service = Service::new(
+            RandomSelector,
+            federated_learning,
+            eviction.clone(),
+            ban_list.clone(),
+            aggregators,
+            rpc.calls,
+            requests,
+            #[cfg(feature = "influx_metrics")]
+            metric_sender.as_ref().map(|(sender, _)| sender.clone()),
+        );
+        let calls = rpc.calls;
+        let span = trace_span!("service", task = %task);
+        services.push(tokio::spawn(
+            async move {
+                let outcome = service.await;
+                info!("training ended: {:?}", outcome);
+                if outcome != TrainingOutcome::Interrupted {
+                    finish_training(rpc_clients, calls).await;
+                }
+                outcome
+            }
+            .instrument(span),
+        ));
+    }
 
-    // Run the service, and wait for one of the tasks to terminate
-    tokio::select! {
-        _ = service.instrument(trace_span!("service")) => {
-            info!("shutting down: CoordinatorService terminated");
+    // Wait for the training of all the tasks to end
+    let success = tokio::select! {
+        outcomes = join_all(services) => {
+            info!("shutting down: training ended");
+            outcomes
+                .into_iter()
+                .all(|outcome| matches!(outcome, Ok(TrainingOutcome::Complete)))
         }
-        _ = api_server_task_handle => {
-            info!("shutting down: API task terminated");
+        _ = &mut api_server_task_handle => {
+            error!("shutting down: API task terminated");
+            false
         }
-        _ = rpc_server_task_handle => {
-            info!("shutting down: RPC server task terminated");
+        _ = &mut rpc_server_task_handle => {
+            error!("shutting down: RPC server task terminated");
+            false
         }
         result = ctrl_c() => {
             match result {
@@ -145,8 +235,50 @@ async fn _main(
                 Err(e) => error!("shutting down: error while waiting for SIGINT: {}", e),
 
             }
+            false
+        }
+    };
+
+    // Stop accepting new requests, and wait for the pending ones
+    shutdown_trigger.trigger();
+    let _ = api_server_task_handle.await;
+    let _ = rpc_server_task_handle.await;
+
+    // Once all the senders are dropped, the metric store writes the
+    // remaining measurements and terminates. Senders held by services
+    // that were interrupted may still be alive, so we don't wait
+    // forever.
+    #[cfg(feature = "influx_metrics")]
+    {
+        if let Some((metric_sender, metric_store_task_handle)) = metric_sender {
+            drop(metric_sender);
+            if tokio::time::timeout(Duration::from_secs(5), metric_store_task_handle)
+                .await
+                .is_err()
+            {
+                warn!("some metrics could not be written");
+            }
         }
     }
+
+    success
+}
+
+/// Notify the aggregators of a task that its training ended
+async fn finish_training(rpc_clients: Vec<aggregator::rpc::Client>, calls: RpcCallSettings) {
+    let timeout = Duration::from_secs(calls.timeout);
+    let notifications = rpc_clients.into_iter().map(|rpc_client| async move {
+        // Finishing the training twice is harmless
+        let result = retry(&calls, "finish_training", || {
+            let mut rpc_client = rpc_client.clone();
+            async move { rpc_client.finish_training(context(timeout)).await }
+        })
+        .await;
+        if let Err(e) = result {
+            error!(error = %e, "failed to notify the aggregator of the end of the training");
+        }
+    });
+    join_all(notifications).await;
 }
 
 pub struct RandomSelector;
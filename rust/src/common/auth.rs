@@ -0,0 +1,37 @@
+/// Whether the `Authorization` header of a request carries the given
+/// token as a bearer token. If no token is configured, no request is
+/// authorized.
+pub fn is_authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    match (authorization, token) {
+        (Some(header), Some(token)) => match header.strip_prefix("Bearer ") {
+            Some(candidate) => constant_time_eq(candidate.as_bytes(), token.as_bytes()),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Compare two byte strings in a time that only depends on their
+/// lengths, so that the comparison of a secret does not leak how many
+/// of its first bytes were guessed right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(Some("Bearer secret"), Some("secret")));
+        assert!(!is_authorized(Some("Bearer secreT"), Some("secret")));
+        assert!(!is_authorized(Some("Bearer secret2"), Some("secret")));
+        assert!(!is_authorized(Some("secret"), Some("secret")));
+        assert!(!is_authorized(None, Some("secret")));
+        assert!(!is_authorized(Some("Bearer secret"), None));
+    }
+}
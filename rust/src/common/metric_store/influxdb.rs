@@ -5,6 +5,7 @@ use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 pub enum Measurement {
     Round(RoundMeasurement),
     Counters(CountersMeasurement),
+    Training(TrainingMeasurement),
 }
 
 impl InfluxDbWriteable for Measurement {
@@ -12,6 +13,7 @@ impl InfluxDbWriteable for Measurement {
         match self {
             Self::Round(round) => round.into_query(name),
             Self::Counters(counters) => counters.into_query(name),
+            Self::Training(training) => training.into_query(name),
         }
     }
 }
@@ -72,6 +74,67 @@ impl From<CountersMeasurement> for Measurement {
     }
 }
 
+/// Training statistics reported by the participants during a round
+pub struct TrainingMeasurement {
+    time: DateTime<Utc>,
+    round: u32,
+    participants: u32,
+    samples: u64,
+    loss: Option<f64>,
+    accuracy: Option<f64>,
+    duration: Option<f64>,
+}
+
+impl TrainingMeasurement {
+    pub fn new(
+        round: u32,
+        participants: u32,
+        samples: u64,
+        loss: Option<f64>,
+        accuracy: Option<f64>,
+        duration: Option<f64>,
+    ) -> TrainingMeasurement {
+        TrainingMeasurement {
+            time: Timestamp::Now.into(),
+            round,
+            participants,
+            samples,
+            loss,
+            accuracy,
+            duration,
+        }
+    }
+}
+
+// The statistics that no participant reported are left out, so the
+// trait cannot be derived
+impl InfluxDbWriteable for TrainingMeasurement {
+    fn into_query<I: Into<String>>(self, name: I) -> WriteQuery {
+        let timestamp: Timestamp = self.time.into();
+        let mut query = timestamp
+            .into_query(name)
+            .add_field("round", self.round)
+            .add_field("participants", self.participants)
+            .add_field("samples", self.samples);
+        if let Some(loss) = self.loss {
+            query = query.add_field("loss", loss);
+        }
+        if let Some(accuracy) = self.accuracy {
+            query = query.add_field("accuracy", accuracy);
+        }
+        if let Some(duration) = self.duration {
+            query = query.add_field("duration", duration);
+        }
+        query
+    }
+}
+
+impl From<TrainingMeasurement> for Measurement {
+    fn from(value: TrainingMeasurement) -> Self {
+        Self::Training(value)
+    }
+}
+
 pub async fn run_metricstore(mut influxdb_connector: InfluxDBConnector) {
     loop {
         match influxdb_connector.receiver.recv().await {
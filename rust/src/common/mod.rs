@@ -1,5 +1,10 @@
+pub mod auth;
 pub mod client;
 pub mod logging;
 #[cfg(feature = "influx_metrics")]
 pub mod metric_store;
+pub mod rate_limit;
+pub mod rpc;
 pub mod settings;
+pub mod shutdown;
+pub mod task;
@@ -0,0 +1,291 @@
+use crate::common::settings::{RateLimitSettings, TokenBucketSettings};
+use std::{
+    cmp,
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use warp::{
+    http::{header::RETRY_AFTER, StatusCode},
+    reject::{Reject, Rejection},
+    reply::Reply,
+    Filter,
+};
+
+/// Minimum number of addresses to track before we start dropping
+/// the buckets that are full again.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A token bucket. Each request consumes a token, and tokens are
+/// refilled at a constant rate, up to the bucket capacity.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(settings: &TokenBucketSettings, now: Instant) -> Self {
+        let capacity = f64::from(settings.burst);
+        Self {
+            capacity,
+            rate: settings.rate,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = f64::min(
+            self.capacity,
+            self.tokens + elapsed.as_secs_f64() * self.rate,
+        );
+        self.last_refill = now;
+    }
+
+    /// Return whether the bucket has at least one token
+    fn has_token(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        } else {
+            Err(Duration::from_secs(u64::from(u32::MAX)))
+        }
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// Return whether the bucket is full, in which case it can be
+    /// dropped and re-created later without any difference.
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    global: Option<TokenBucket>,
+    per_ip: HashMap<IpAddr, TokenBucket>,
+    next_prune: usize,
+}
+
+/// A rate limiter with an optional global limit and an optional
+/// limit per client address. It can be cloned and all the clones
+/// share the same state.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    settings: Arc<RateLimitSettings>,
+    state: Arc<Mutex<State>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        let global = settings
+            .global
+            .as_ref()
+            .map(|bucket| TokenBucket::new(bucket, Instant::now()));
+        Self {
+            settings: Arc::new(settings),
+            state: Arc::new(Mutex::new(State {
+                global,
+                per_ip: HashMap::new(),
+                next_prune: PRUNE_THRESHOLD,
+            })),
+        }
+    }
+
+    /// Consume a token for a request coming from the given
+    /// address. If the request must be rejected, return how long the
+    /// client should wait before retrying.
+    pub fn check(&self, addr: Option<IpAddr>) -> Result<(), Duration> {
+        self.check_at(addr, Instant::now())
+    }
+
+    fn check_at(&self, addr: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        // UNWRAP_SAFE: the lock is never held across a panic
+        let mut state = self.state.lock().unwrap();
+        let State {
+            ref mut global,
+            ref mut per_ip,
+            ref mut next_prune,
+        } = *state;
+
+        let per_ip_bucket = match (self.settings.per_ip.as_ref(), addr) {
+            (Some(settings), Some(addr)) => {
+                if per_ip.len() >= *next_prune {
+                    per_ip.retain(|_, bucket| !bucket.is_full(now));
+                    *next_prune = cmp::max(PRUNE_THRESHOLD, 2 * per_ip.len());
+                }
+                Some(
+                    per_ip
+                        .entry(addr)
+                        .or_insert_with(|| TokenBucket::new(settings, now)),
+                )
+            }
+            _ => None,
+        };
+
+        // Check all the buckets before consuming any token, so that
+        // a request rejected by one limit doesn't count against the
+        // other one.
+        let mut buckets: Vec<&mut TokenBucket> = global.iter_mut().chain(per_ip_bucket).collect();
+        let retry_after = buckets
+            .iter_mut()
+            .filter_map(|bucket| bucket.has_token(now).err())
+            .max();
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+        for bucket in buckets {
+            bucket.take();
+        }
+        Ok(())
+    }
+}
+
+/// Rejection returned when a request exceeds the rate limit
+#[derive(Debug)]
+pub struct RateLimited(Duration);
+
+impl Reject for RateLimited {}
+
+impl RateLimited {
+    /// Number of seconds after which the request can be retried
+    pub fn retry_after(&self) -> u64 {
+        retry_after_secs(self.0)
+    }
+}
+
+/// Convert a delay after which a client can retry a request to the
+/// number of seconds sent in `Retry-After` headers and fields. The
+/// delay is rounded up, otherwise clients would retry too early.
+pub fn retry_after_secs(delay: Duration) -> u64 {
+    delay.as_secs() + u64::from(delay.subsec_nanos() > 0)
+}
+
+/// Return a filter that rejects the requests exceeding the limits of
+/// the given rate limiter with a [`RateLimited`] rejection.
+pub fn limit(
+    limiter: RateLimiter,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static {
+    warp::addr::remote()
+        .and_then(move |addr: Option<SocketAddr>| {
+            let result = limiter.check(addr.map(|addr| addr.ip()));
+            async move {
+                result.map_err(|retry_after| {
+                    debug!("rejecting request: rate limit exceeded");
+                    warp::reject::custom(RateLimited(retry_after))
+                })
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Serialize)]
+struct RateLimitedResponse {
+    code: u16,
+    message: &'static str,
+}
+
+/// Turn a [`RateLimited`] rejection into a `429 Too Many Requests`
+/// response with a `Retry-After` header. Other rejections are passed
+/// through.
+pub async fn handle_rejection(e: Rejection) -> Result<impl Reply, Rejection> {
+    if let Some(rate_limited) = e.find::<RateLimited>() {
+        let json = warp::reply::json(&RateLimitedResponse {
+            code: StatusCode::TOO_MANY_REQUESTS.into(),
+            message: "Too many requests",
+        });
+        Ok(warp::reply::with_header(
+            warp::reply::with_status(json, StatusCode::TOO_MANY_REQUESTS),
+            RETRY_AFTER,
+            rate_limited.retry_after().to_string(),
+        ))
+    } else {
+        Err(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(global: Option<(u32, f64)>, per_ip: Option<(u32, f64)>) -> RateLimitSettings {
+        let bucket = |(burst, rate)| TokenBucketSettings { burst, rate };
+        RateLimitSettings {
+            global: global.map(bucket),
+            per_ip: per_ip.map(bucket),
+        }
+    }
+
+    /// Test that a limiter without any limit accepts all the requests
+    #[test]
+    fn test_no_limit() {
+        let limiter = RateLimiter::new(settings(None, None));
+        for _ in 0..100 {
+            assert!(limiter.check(None).is_ok());
+        }
+    }
+
+    /// Test that the global limit rejects the requests exceeding the
+    /// burst, and accepts them again once tokens are refilled.
+    #[test]
+    fn test_global_limit() {
+        let limiter = RateLimiter::new(settings(Some((2, 1.0)), None));
+        let now = Instant::now();
+        assert!(limiter.check_at(None, now).is_ok());
+        assert!(limiter.check_at(None, now).is_ok());
+        let retry_after = limiter.check_at(None, now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check_at(None, later).is_ok());
+        assert!(limiter.check_at(None, later).is_err());
+    }
+
+    /// Test that the per-address limit only affects the address that
+    /// exceeds it.
+    #[test]
+    fn test_per_ip_limit() {
+        let limiter = RateLimiter::new(settings(None, Some((1, 0.5))));
+        let addr_1: IpAddr = "192.0.2.1".parse().unwrap();
+        let addr_2: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(Some(addr_1), now).is_ok());
+        let retry_after = limiter.check_at(Some(addr_1), now).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(2));
+        assert!(limiter.check_at(Some(addr_2), now).is_ok());
+    }
+
+    /// Test that a request rejected by the per-address limit doesn't
+    /// consume a token from the global limit.
+    #[test]
+    fn test_rejected_request_does_not_consume_global_token() {
+        let limiter = RateLimiter::new(settings(Some((2, 0.0)), Some((1, 0.0))));
+        let addr_1: IpAddr = "192.0.2.1".parse().unwrap();
+        let addr_2: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(Some(addr_1), now).is_ok());
+        assert!(limiter.check_at(Some(addr_1), now).is_err());
+        assert!(limiter.check_at(Some(addr_2), now).is_ok());
+        assert!(limiter.check_at(Some(addr_2), now).is_err());
+    }
+
+    #[test]
+    fn test_retry_after_secs() {
+        assert_eq!(retry_after_secs(Duration::from_secs(2)), 2);
+        assert_eq!(retry_after_secs(Duration::from_millis(2001)), 3);
+        assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+    }
+}
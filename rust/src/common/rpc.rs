@@ -0,0 +1,336 @@
+use crate::common::settings::RpcCallSettings;
+use bytes::{Bytes, BytesMut};
+use derive_more::Display;
+use futures::pin_mut;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tarpc::context::{self, Context};
+use tokio_serde::{
+    formats::{Bincode, Json, MessagePack},
+    Deserializer, Serializer,
+};
+use uuid::Uuid;
+
+#[derive(Eq, PartialEq, Hash, Debug, Copy, Clone, Display, Serialize, Deserialize, Default)]
+/// Identifier of an RPC request. The retries of a request carry the
+/// same identifier, so that the server can recognize them and avoid
+/// processing the request twice.
+pub struct RequestId(Uuid);
+
+impl RequestId {
+    /// Return a new random request identifier
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+/// Return the context of a call that must complete within `timeout`
+pub fn context(timeout: Duration) -> Context {
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + timeout;
+    ctx
+}
+
+/// Errors returned by the RPC calls
+pub trait Retryable {
+    /// Return whether the call that failed with this error may
+    /// succeed if it is sent again. This is the case for the errors
+    /// of the RPC layer, but not for the errors returned by the
+    /// server.
+    fn is_retryable(&self) -> bool;
+}
+
+/// Only the errors that come from the connection or from a call that
+/// timed out are retried. The other I/O errors, like a request that
+/// could not be serialized, would fail the same way again.
+impl Retryable for io::Error {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::NotConnected
+        )
+    }
+}
+
+/// Send a call until it succeeds, fails with an error that is not
+/// [`Retryable`], or the maximum number of retries is reached. The
+/// delay between the attempts grows exponentially. `call` is invoked
+/// for each attempt, and must only send calls that can safely be
+/// repeated.
+pub async fn retry<F, Fut, T, E>(
+    settings: &RpcCallSettings,
+    method: &str,
+    mut call: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Retryable + fmt::Display,
+{
+    let mut delay = Duration::from_millis(settings.retry_delay);
+    let mut retries = 0;
+    loop {
+        match call().await {
+            Err(e) if e.is_retryable() && retries < settings.max_retries => {
+                warn!(error = %e, "`{}` call failed, retrying in {:?}", method, delay);
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+/// Serialization format of the messages exchanged by the coordinator
+/// and the aggregator. Both must use the same format.
+pub enum RpcCodec {
+    /// JSON, the default. It is the easiest to inspect when
+    /// debugging.
+    Json,
+    /// [bincode](https://docs.rs/bincode), a compact binary format
+    Bincode,
+    /// [MessagePack](https://msgpack.org), a compact binary format
+    MessagePack,
+}
+
+impl Default for RpcCodec {
+    fn default() -> Self {
+        RpcCodec::Json
+    }
+}
+
+impl RpcCodec {
+    /// Return a codec for an RPC transport that receives `Item`s and
+    /// sends `SinkItem`s in this format
+    pub fn codec<Item, SinkItem>(self) -> Codec<Item, SinkItem> {
+        Codec {
+            format: self,
+            ghost: PhantomData,
+        }
+    }
+}
+
+/// A codec for the RPC transports, that serializes the messages in
+/// the format selected at runtime
+pub struct Codec<Item, SinkItem> {
+    format: RpcCodec,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> Deserializer<Item> for Codec<Item, SinkItem>
+where
+    for<'a> Item: Deserialize<'a>,
+{
+    type Error = io::Error;
+
+    fn deserialize(self: Pin<&mut Self>, src: &BytesMut) -> Result<Item, Self::Error> {
+        match self.format {
+            RpcCodec::Json => {
+                let codec = Json::<Item, SinkItem>::default();
+                pin_mut!(codec);
+                Ok(codec.deserialize(src)?)
+            }
+            RpcCodec::Bincode => {
+                let codec = Bincode::<Item, SinkItem>::default();
+                pin_mut!(codec);
+                codec.deserialize(src)
+            }
+            RpcCodec::MessagePack => {
+                let codec = MessagePack::<Item, SinkItem>::default();
+                pin_mut!(codec);
+                codec.deserialize(src)
+            }
+        }
+    }
+}
+
+impl<Item, SinkItem> Serializer<SinkItem> for Codec<Item, SinkItem>
+where
+    SinkItem: Serialize,
+{
+    type Error = io::Error;
+
+    fn serialize(self: Pin<&mut Self>, item: &SinkItem) -> Result<Bytes, Self::Error> {
+        match self.format {
+            RpcCodec::Json => {
+                let codec = Json::<Item, SinkItem>::default();
+                pin_mut!(codec);
+                Ok(codec.serialize(item)?)
+            }
+            RpcCodec::Bincode => {
+                let codec = Bincode::<Item, SinkItem>::default();
+                pin_mut!(codec);
+                codec.serialize(item)
+            }
+            RpcCodec::MessagePack => {
+                let codec = MessagePack::<Item, SinkItem>::default();
+                pin_mut!(codec);
+                codec.serialize(item)
+            }
+        }
+    }
+}
+
+/// Limit on the number of connections an RPC server processes
+/// concurrently
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl ConnectionLimit {
+    /// Create a new limit. If `max` is `None`, the number of
+    /// connections is not limited.
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Number of connections currently open
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Try to reserve a slot for a new connection. The slot is
+    /// released when the returned guard is dropped, and `None` is
+    /// returned if the limit is reached.
+    pub fn try_acquire(&self) -> Option<ConnectionGuard> {
+        let max = self.max.unwrap_or(usize::MAX);
+        let mut open = self.open.load(Ordering::SeqCst);
+        loop {
+            if open >= max {
+                return None;
+            }
+            match self
+                .open
+                .compare_exchange(open, open + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Some(ConnectionGuard(self.open.clone())),
+                Err(current) => open = current,
+            }
+        }
+    }
+}
+
+/// A slot reserved for a connection by [`ConnectionLimit::try_acquire`]
+#[derive(Debug)]
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Message {
+        Weights(Vec<u8>),
+        EndTraining { id: String, success: bool },
+    }
+
+    #[test]
+    fn test_codecs() {
+        let messages = vec![
+            Message::Weights(vec![0, 1, 2, 255]),
+            Message::EndTraining {
+                id: "client".to_string(),
+                success: true,
+            },
+        ];
+        for format in &[RpcCodec::Json, RpcCodec::Bincode, RpcCodec::MessagePack] {
+            let mut codec = format.codec::<Message, Message>();
+            for message in &messages {
+                let bytes = Pin::new(&mut codec).serialize(message).unwrap();
+                let decoded = Pin::new(&mut codec)
+                    .deserialize(&BytesMut::from(&bytes[..]))
+                    .unwrap();
+                assert_eq!(&decoded, message);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let settings = RpcCallSettings {
+            max_retries: 2,
+            retry_delay: 1,
+            ..Default::default()
+        };
+        let attempts = AtomicUsize::new(0);
+        let call = || {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt < 2 {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "deadline expired"))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        };
+        assert_eq!(retry(&settings, "test", call).await.unwrap(), 2);
+
+        // The call is not retried more than `max_retries` times
+        attempts.store(0, Ordering::SeqCst);
+        let settings = RpcCallSettings {
+            max_retries: 1,
+            ..settings
+        };
+        assert!(retry(&settings, "test", call).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // An error that doesn't come from the connection is not
+        // retried
+        attempts.store(0, Ordering::SeqCst);
+        let call = || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                Err::<(), _>(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid message",
+                ))
+            }
+        };
+        assert!(retry(&settings, "test", call).await.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+
+        let unlimited = ConnectionLimit::new(None);
+        let guards: Vec<_> = (0..100).map(|_| unlimited.try_acquire().unwrap()).collect();
+        assert_eq!(guards.len(), 100);
+    }
+}
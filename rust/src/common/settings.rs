@@ -1,3 +1,4 @@
+use config::ConfigError;
 use serde::de::{self, Deserializer, Visitor};
 use std::fmt;
 use tracing_subscriber::filter::EnvFilter;
@@ -47,3 +48,107 @@ pub struct TelemetrySettings {
     pub service_name: String,
     pub jaeger_endpoint: String,
 }
+
+/// Settings of a token bucket: a client can send up to `burst`
+/// requests at once, and then `rate` requests per second.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TokenBucketSettings {
+    pub burst: u32,
+    pub rate: f64,
+}
+
+/// Rate limits for an API endpoint. There are no limits by default.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RateLimitSettings {
+    /// Limit for all the requests, whatever their origin
+    pub global: Option<TokenBucketSettings>,
+    /// Limit for the requests coming from a single address
+    pub per_ip: Option<TokenBucketSettings>,
+}
+
+impl RateLimitSettings {
+    /// Check that the limits of the `endpoint` rate limits can be
+    /// enforced
+    pub fn validate(&self, endpoint: &str) -> Result<(), ConfigError> {
+        let buckets = self
+            .global
+            .iter()
+            .map(|bucket| ("global", bucket))
+            .chain(self.per_ip.iter().map(|bucket| ("per_ip", bucket)));
+        for (name, bucket) in buckets {
+            // An empty bucket would reject all the requests
+            if bucket.burst < 1 {
+                return Err(ConfigError::Message(format!(
+                    "`api.rate_limit.{}.{}.burst` must be at least 1",
+                    endpoint, name
+                )));
+            }
+            if !(bucket.rate > 0.0 && bucket.rate.is_finite()) {
+                return Err(ConfigError::Message(format!(
+                    "`api.rate_limit.{}.{}.rate` must be a positive number, got {}",
+                    endpoint, name, bucket.rate
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deadlines and retries of the RPC calls between the coordinator and
+/// the aggregator
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct RpcCallSettings {
+    /// Deadline of the calls, in seconds
+    pub timeout: u64,
+    /// Deadline of the calls that run an aggregation, in seconds.
+    /// Aggregating a large model takes much longer than the other
+    /// calls.
+    pub aggregation_timeout: u64,
+    /// Number of times a call that failed in the RPC layer, for
+    /// instance because its deadline expired, is retried. Only the
+    /// calls that can safely be repeated are retried.
+    pub max_retries: u32,
+    /// Delay before the first retry, in milliseconds. The delay
+    /// doubles after each retry.
+    pub retry_delay: u64,
+}
+
+impl Default for RpcCallSettings {
+    fn default() -> Self {
+        Self {
+            timeout: 10,
+            aggregation_timeout: 600,
+            max_retries: 3,
+            retry_delay: 500,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_rate_limit() {
+        let settings = |rate| RateLimitSettings {
+            global: None,
+            per_ip: Some(TokenBucketSettings { burst: 1, rate }),
+        };
+        assert!(settings(0.5).validate("heartbeat").is_ok());
+        assert!(RateLimitSettings::default().validate("heartbeat").is_ok());
+        for rate in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(settings(*rate).validate("heartbeat").is_err());
+        }
+    }
+
+    #[test]
+    fn test_validate_rate_limit_burst() {
+        let settings = |burst| RateLimitSettings {
+            global: Some(TokenBucketSettings { burst, rate: 1.0 }),
+            per_ip: None,
+        };
+        assert!(settings(1).validate("heartbeat").is_ok());
+        assert!(settings(0).validate("heartbeat").is_err());
+    }
+}
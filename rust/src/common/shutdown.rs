@@ -0,0 +1,186 @@
+use futures::{Sink, Stream};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{sync::watch, time::delay_for};
+
+use crate::common::rpc::ConnectionLimit;
+
+/// Maximum amount of time the RPC servers wait for the requests in
+/// flight to be answered when shutting down
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Create a shutdown signal. The servers that hold a [`Shutdown`]
+/// stop accepting new requests once the [`ShutdownTrigger`] is
+/// triggered or dropped, and finish processing the requests they
+/// already accepted.
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+    (ShutdownTrigger(tx), Shutdown(rx))
+}
+
+/// The sending half of a shutdown signal
+#[derive(Debug)]
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+impl ShutdownTrigger {
+    /// Notify all the [`Shutdown`] receivers
+    pub fn trigger(&self) {
+        info!("triggering shutdown");
+        // The receivers may all be gone already, which is fine
+        let _ = self.0.broadcast(true);
+    }
+}
+
+/// The receiving half of a shutdown signal
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Return whether the shutdown was triggered
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until the shutdown is triggered
+    pub async fn wait(mut self) {
+        while let Some(triggered) = self.0.recv().await {
+            if triggered {
+                return;
+            }
+        }
+        // The trigger was dropped without being triggered: nothing
+        // can shut the server down anymore, so we do it now
+    }
+}
+
+/// A transport that stops receiving once the shutdown is triggered.
+/// An RPC channel over such a transport is closed as soon as it
+/// answered the requests it already received, so that the idle
+/// connections don't hold the shutdown back.
+pub struct UntilShutdown<T> {
+    transport: T,
+    /// `None` once the shutdown is triggered
+    shutdown: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<T> UntilShutdown<T> {
+    pub fn new(transport: T, shutdown: Shutdown) -> Self {
+        Self {
+            transport,
+            shutdown: Some(Box::pin(shutdown.wait())),
+        }
+    }
+}
+
+impl<T> Stream for UntilShutdown<T>
+where
+    T: Stream + Unpin,
+{
+    type Item = T::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match self.shutdown {
+            Some(ref mut shutdown) => {
+                if shutdown.as_mut().poll(cx).is_ready() {
+                    self.shutdown = None;
+                    return Poll::Ready(None);
+                }
+            }
+            None => return Poll::Ready(None),
+        }
+        Pin::new(&mut self.transport).poll_next(cx)
+    }
+}
+
+impl<T, Item> Sink<Item> for UntilShutdown<T>
+where
+    T: Sink<Item> + Unpin,
+{
+    type Error = T::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.transport).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
+        Pin::new(&mut self.transport).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.transport).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.transport).poll_close(cx)
+    }
+}
+
+/// Wait until all the connections tracked by `limit` are closed, or
+/// until `timeout` expires. Return whether all the connections were
+/// closed.
+pub async fn drain(limit: &ConnectionLimit, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let open = limit.open();
+        if open == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            warn!("{} connections are still open, closing them", open);
+            return false;
+        }
+        debug!("waiting for {} connections to be closed", open);
+        delay_for(Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (trigger, shutdown) = channel();
+        assert!(!shutdown.is_triggered());
+        let waiting = tokio::spawn(shutdown.clone().wait());
+        trigger.trigger();
+        waiting.await.unwrap();
+        assert!(shutdown.is_triggered());
+
+        // Dropping the trigger also shuts down
+        let (trigger, shutdown) = channel();
+        drop(trigger);
+        shutdown.wait().await;
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let limit = ConnectionLimit::new(None);
+        assert!(drain(&limit, Duration::from_millis(0)).await);
+
+        let guard = limit.try_acquire().unwrap();
+        assert!(!drain(&limit, Duration::from_millis(100)).await);
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(50)).await;
+            drop(guard);
+        });
+        assert!(drain(&limit, Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test]
+    async fn test_until_shutdown() {
+        use futures::{stream, StreamExt};
+
+        let (trigger, shutdown) = channel();
+        let mut transport = UntilShutdown::new(stream::iter(0..).boxed(), shutdown);
+        assert_eq!(transport.next().await, Some(0));
+        assert_eq!(transport.next().await, Some(1));
+        trigger.trigger();
+        assert_eq!(transport.next().await, None);
+        assert_eq!(transport.next().await, None);
+    }
+}
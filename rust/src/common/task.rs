@@ -0,0 +1,182 @@
+use std::{collections::HashMap, convert::TryFrom, fmt, str::FromStr, sync::Arc};
+use thiserror::Error;
+use warp::{reject::Rejection, Filter};
+
+/// Maximum length of a task identifier
+const MAX_TASK_ID_LEN: usize = 64;
+
+/// Name of the task that serves the requests that don't specify a
+/// task
+const DEFAULT_TASK_ID: &str = "default";
+
+#[derive(Eq, PartialEq, Hash, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+/// The name of a training task. A coordinator and an aggregator can
+/// run several tasks at the same time, each training its own model.
+///
+/// Task names are made of lowercase ASCII letters, digits, `-` and
+/// `_`, so that they can be used in URLs and configuration keys.
+pub struct TaskId(String);
+
+#[derive(Error, Debug)]
+#[error("invalid task name `{0}`: expected at most 64 lowercase letters, digits, `-` or `_`")]
+pub struct InvalidTaskId(String);
+
+impl TaskId {
+    /// Return whether this is the task that serves the requests that
+    /// don't specify a task
+    pub fn is_default(&self) -> bool {
+        self.0 == DEFAULT_TASK_ID
+    }
+
+    /// Return the URL under which a service whose API is served at
+    /// `base_url` serves the API of this task
+    pub fn url(&self, base_url: &str) -> String {
+        if self.is_default() {
+            base_url.to_string()
+        } else {
+            format!("{}/tasks/{}", base_url.trim_end_matches('/'), self)
+        }
+    }
+}
+
+impl Default for TaskId {
+    fn default() -> Self {
+        Self(DEFAULT_TASK_ID.to_string())
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for TaskId {
+    type Error = InvalidTaskId;
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_TASK_ID_LEN
+            && name
+                .bytes()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-' || c == b'_');
+        if valid {
+            Ok(Self(name))
+        } else {
+            Err(InvalidTaskId(name))
+        }
+    }
+}
+
+impl FromStr for TaskId {
+    type Err = InvalidTaskId;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::try_from(name.to_string())
+    }
+}
+
+/// The handles of the services running each task
+#[derive(Debug)]
+pub struct Tasks<H>(Arc<HashMap<TaskId, H>>);
+
+impl<H> Clone for Tasks<H> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<H> Tasks<H> {
+    pub fn new(handles: HashMap<TaskId, H>) -> Self {
+        Self(Arc::new(handles))
+    }
+
+    /// Return the handle of the given task, if it exists
+    pub fn get(&self, task: &TaskId) -> Option<&H> {
+        self.0.get(task)
+    }
+
+    /// Return the identifiers of the tasks
+    pub fn ids(&self) -> impl Iterator<Item = &TaskId> {
+        self.0.keys()
+    }
+}
+
+/// Filter that extracts the task a request is for, and the handle of
+/// the service running it. Paths starting with `/tasks/<task_id>`
+/// are for the given task, other paths are for the default task.
+/// Requests for an unknown task are rejected as not found.
+pub fn route<H>(tasks: Tasks<H>) -> impl Filter<Extract = (TaskId, H), Error = Rejection> + Clone
+where
+    H: Clone + Send + Sync + 'static,
+{
+    warp::path("tasks")
+        .and(warp::path::param::<TaskId>())
+        .or(warp::any().map(TaskId::default))
+        .unify()
+        .and_then(move |task: TaskId| {
+            let handle = tasks.get(&task).cloned();
+            async move {
+                match handle {
+                    Some(handle) => Ok((task, handle)),
+                    None => {
+                        debug!("rejecting request for unknown task {}", task);
+                        Err(warp::reject::not_found())
+                    }
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_task_id() {
+        assert!("mnist".parse::<TaskId>().is_ok());
+        assert!("keras-mnist_2".parse::<TaskId>().is_ok());
+        assert!("".parse::<TaskId>().is_err());
+        assert!("MNIST".parse::<TaskId>().is_err());
+        assert!("mnist/2".parse::<TaskId>().is_err());
+        assert!("a".repeat(65).parse::<TaskId>().is_err());
+        assert!(TaskId::default().is_default());
+    }
+
+    #[test]
+    fn test_task_url() {
+        let task: TaskId = "mnist".parse().unwrap();
+        assert_eq!(
+            task.url("http://localhost:8082/"),
+            "http://localhost:8082/tasks/mnist"
+        );
+        assert_eq!(
+            TaskId::default().url("http://localhost:8082"),
+            "http://localhost:8082"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route() {
+        let mut handles = HashMap::new();
+        handles.insert(TaskId::default(), 0);
+        handles.insert("mnist".parse().unwrap(), 1);
+        let filter = route(Tasks::new(handles))
+            .and(warp::path!("heartbeat"))
+            .map(|_, handle: u32| handle);
+
+        let request = || warp::test::request().method("GET");
+        let handle = request().path("/heartbeat").filter(&filter).await;
+        assert_eq!(handle.unwrap(), 0);
+        let handle = request()
+            .path("/tasks/mnist/heartbeat")
+            .filter(&filter)
+            .await;
+        assert_eq!(handle.unwrap(), 1);
+        let handle = request()
+            .path("/tasks/cifar/heartbeat")
+            .filter(&filter)
+            .await;
+        assert!(handle.is_err());
+    }
+}
@@ -1,69 +1,643 @@
 use crate::{
-    common::client::ClientId,
-    coordinator::{core::ServiceHandle, models::json::*},
+    common::{
+        auth,
+        client::ClientId,
+        rate_limit::{self, RateLimited, RateLimiter},
+        shutdown::Shutdown,
+        task::{self, TaskId, Tasks},
+    },
+    coordinator::{
+        core::{RequestError, ServiceHandle},
+        idempotency::{IdempotencyCache, IDEMPOTENCY_CACHE_CAPACITY, IDEMPOTENCY_KEY_TTL},
+        models::{
+            json::*, AdminCommand, ClientVersion, RendezVousResponse, StartTrainingResponse,
+            API_VERSION,
+        },
+        settings::ApiSettings,
+    },
 };
-use tokio::net::TcpListener;
+use serde::de::DeserializeOwned;
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr};
+use tokio::{net::TcpListener, stream::StreamExt};
 use tracing_futures::Instrument;
-use warp::{http::method::Method, Filter};
+use warp::{
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER},
+        method::Method,
+        StatusCode,
+    },
+    hyper::{
+        server::{accept, Server},
+        service::make_service_fn,
+    },
+    reject::{Reject, Rejection},
+    reply::{Reply, Response},
+    Filter,
+};
+
+/// Machine readable code of the errors returned by the API
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    NotFound,
+    MethodNotAllowed,
+    InvalidRequest,
+    InvalidClientId,
+    Rejected,
+    RateLimited,
+    ServiceUnavailable,
+    Internal,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    code: u16,
+    error: ErrorCode,
+    message: String,
+}
+
+// Make it possible to turn a RequestError into a Rejection: the
+// service of the task is not running anymore
+impl Reject for RequestError {}
+
+/// Rejection returned when the client ID of a request cannot be
+/// parsed
+#[derive(Debug)]
+struct InvalidClientId;
+
+impl Reject for InvalidClientId {}
+
+/// Rejection returned when the coordinator refuses a request, with
+/// the status code and the reason to return to the client
+#[derive(Debug)]
+struct Rejected(StatusCode, String);
+
+impl Reject for Rejected {}
+
+type JsonErrorReply = warp::reply::WithStatus<warp::reply::Json>;
+
+/// Create a JSON response from a status code, an error code and an
+/// error message
+fn error(status: StatusCode, error: ErrorCode, message: impl Into<String>) -> JsonErrorReply {
+    let msg = ErrorResponse {
+        code: status.into(),
+        error,
+        message: message.into(),
+    };
+    let json = warp::reply::json(&msg);
+    warp::reply::with_status(json, status)
+}
+
+/// Create a JSON response from a `Rejection`
+async fn handle_rejection(e: Rejection) -> Result<Response, Infallible> {
+    let reply = if e.is_not_found() {
+        error(StatusCode::NOT_FOUND, ErrorCode::NotFound, "Not found")
+    } else if let Some(rate_limited) = e.find::<RateLimited>() {
+        let reply = error(
+            StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::RateLimited,
+            "Too many requests",
+        );
+        return Ok(warp::reply::with_header(
+            reply,
+            RETRY_AFTER,
+            rate_limited.retry_after().to_string(),
+        )
+        .into_response());
+    } else if e.find::<InvalidClientId>().is_some() {
+        error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidClientId,
+            "Invalid client ID",
+        )
+    } else if let Some(Rejected(status, message)) = e.find::<Rejected>() {
+        error(*status, ErrorCode::Rejected, message.as_str())
+    } else if e.find::<RequestError>().is_some() {
+        error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::ServiceUnavailable,
+            "Service temporarily unavailable",
+        )
+    } else if e.find::<warp::reject::InvalidQuery>().is_some() {
+        error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Invalid query string",
+        )
+    } else if e.find::<warp::body::BodyDeserializeError>().is_some() {
+        error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Invalid request body",
+        )
+    } else if e.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::InvalidRequest,
+            "Expected a JSON request body",
+        )
+    } else if e.find::<warp::reject::LengthRequired>().is_some() {
+        error(
+            StatusCode::LENGTH_REQUIRED,
+            ErrorCode::InvalidRequest,
+            "Missing Content-Length header",
+        )
+    } else if e.find::<warp::reject::PayloadTooLarge>().is_some() {
+        error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::InvalidRequest,
+            "Request body too large",
+        )
+    } else if e.find::<warp::reject::MethodNotAllowed>().is_some() {
+        error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::MethodNotAllowed,
+            "Method not allowed",
+        )
+    } else {
+        error!("unhandled rejection: {:?}", e);
+        error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Internal,
+            "Unhandled rejection",
+        )
+    };
+    Ok(reply.into_response())
+}
+
+/// Return a filter extracting a client ID from the next segment of the
+/// path. Unlike a plain path parameter, it rejects the malformed IDs
+/// with an [`InvalidClientId`] rejection instead of not matching.
+fn client_id() -> impl Filter<Extract = (ClientId,), Error = Rejection> + Clone {
+    warp::path::param::<String>().and_then(|id: String| async move {
+        id.parse::<ClientId>().map_err(|_| {
+            debug!("rejecting request: invalid client ID {}", id);
+            warp::reject::custom(InvalidClientId)
+        })
+    })
+}
+
+/// Maximum size of the JSON request bodies, in bytes
+const MAX_BODY_SIZE: u64 = 16 * 1024;
+
+/// Return a filter extracting the JSON body of a `POST` request
+fn json_body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    warp::post()
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::json())
+}
+
+/// Return a filter matching the legacy `GET` requests to the
+/// endpoints that change the state of the coordinator, if they are
+/// enabled
+fn legacy_get(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::get()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Rejection of the admin requests that don't carry the admin token
+fn unauthorized() -> Rejection {
+    warp::reject::custom(Rejected(
+        StatusCode::UNAUTHORIZED,
+        "Missing or invalid admin token".to_string(),
+    ))
+}
+
+/// Handler for the admin requests. The request must carry the admin
+/// token as a bearer token in the `Authorization` header.
+async fn handle_admin_request(
+    command: AdminCommand,
+    authorization: Option<String>,
+    admin_token: Option<String>,
+    handle: ServiceHandle,
+) -> Result<impl Reply, Rejection> {
+    debug!("handling admin request: {:?}", command);
+    if !auth::is_authorized(authorization.as_deref(), admin_token.as_deref()) {
+        warn!("rejecting unauthorized admin request");
+        return Err(unauthorized());
+    }
+    let response = handle.admin(command).await.map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&AdminResponseJson::from(response)))
+}
 
-pub async fn serve(bind_address: &str, handle: ServiceHandle) {
-    let handle = warp::any().map(move || handle.clone());
+/// Handler for the requests for the training statistics of the
+/// completed rounds. Like the other admin requests, the request must
+/// carry the admin token.
+async fn handle_training_metrics_request(
+    authorization: Option<String>,
+    admin_token: Option<String>,
+    handle: ServiceHandle,
+) -> Result<impl Reply, Rejection> {
+    debug!("handling training metrics request");
+    if !auth::is_authorized(authorization.as_deref(), admin_token.as_deref()) {
+        warn!("rejecting unauthorized training metrics request");
+        return Err(unauthorized());
+    }
+    let metrics = handle
+        .training_metrics()
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&metrics))
+}
+
+/// Handler for the rendez-vous requests. The clients that speak
+/// another version of the API are rejected before they take part in
+/// the training.
+async fn handle_rendez_vous_request(
+    task: TaskId,
+    request: RendezVousRequestJson,
+    addr: Option<SocketAddr>,
+    handle: ServiceHandle,
+    cache: IdempotencyCache<RendezVousResponse>,
+) -> Result<impl Reply, Rejection> {
+    let RendezVousRequestJson {
+        client,
+        metadata,
+        idempotency_key,
+    } = request;
+    if !client.is_compatible() {
+        let message = format!(
+            "Unsupported API version {}, the coordinator supports version {}",
+            client.version.unwrap_or_default(),
+            API_VERSION
+        );
+        warn!("rejecting rendez-vous request: {}", message);
+        return Err(warp::reject::custom(Rejected(
+            StatusCode::BAD_REQUEST,
+            message,
+        )));
+    }
+    if !metadata.is_empty() {
+        debug!("client metadata: {:?}", metadata);
+    }
+    let rendez_vous = || async {
+        handle
+            .rendez_vous(addr.map(|addr| addr.ip()), client.negotiate())
+            .await
+            .map_err(warp::reject::custom)
+    };
+    // The key is bound to the address of the client, so that a client
+    // can't get the ID of another one by sending the same key
+    let response = match idempotency_key {
+        Some(key) => {
+            let addr = addr.map(|addr| addr.ip().to_string()).unwrap_or_default();
+            cache
+                .get_or_process(format!("{}/{}/{}", task, addr, key), rendez_vous)
+                .await?
+        }
+        None => rendez_vous().await?,
+    };
+    Ok(warp::reply::json(&RendezVousResponseJson::new(
+        response, &client,
+    )))
+}
+
+/// Handler for the start training requests
+async fn handle_start_training_request(
+    task: TaskId,
+    id: ClientId,
+    request: StartTrainingRequestJson,
+    handle: ServiceHandle,
+    cache: IdempotencyCache<StartTrainingResponse>,
+) -> Result<impl Reply, Rejection> {
+    let start_training = || async {
+        handle
+            .start_training(id)
+            .await
+            .map_err(warp::reject::custom)
+    };
+    let response = match request.idempotency_key {
+        Some(key) => {
+            cache
+                .get_or_process(format!("{}/{}/{}", task, id, key), start_training)
+                .await?
+        }
+        None => start_training().await?,
+    };
+    let status = match response {
+        StartTrainingResponse::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    let json = warp::reply::json(&StartTrainingResponseJson::from(response));
+    Ok(warp::reply::with_status(json, status))
+}
+
+/// Serve the API of the given tasks. The requests for a task are
+/// prefixed with `/tasks/<task_id>`, and the requests without this
+/// prefix are for the default task. The endpoints are served under the
+/// `/v1` version prefix, and without it for the older clients. The
+/// rendez-vous and start training requests are `POST` requests, and
+/// their legacy `GET` variants are only served if
+/// `legacy_get_routes` is set. The server stops once `shutdown` is
+/// triggered.
+pub async fn serve(settings: ApiSettings, tasks: Tasks<ServiceHandle>, shutdown: Shutdown) {
+    let ApiSettings {
+        bind_address,
+        admin_token,
+        rate_limit,
+        legacy_get_routes,
+    } = settings;
+    // The prefix must be updated along with `API_VERSION`
+    let version = warp::path("v1").or(warp::any()).unify();
+    let task = task::route(tasks).and(version);
     let parent_span = tracing::Span::current();
 
-    let heartbeat = warp::path!("heartbeat" / ClientId)
+    // The heartbeats and the push channels share the same limits
+    let heartbeat_limiter = RateLimiter::new(rate_limit.heartbeat);
+    let heartbeat = task
+        .clone()
+        .and(warp::path("heartbeat"))
+        .and(client_id())
+        .and(warp::path::end())
         .and(warp::get())
-        .and(handle.clone())
-        .and_then(move |id, handle: ServiceHandle| {
-            let span =
-                trace_span!(parent: parent_span.clone(), "api_heartbeat_request", client_id = %id);
+        .and(rate_limit::limit(heartbeat_limiter.clone()))
+        .and_then(move |task: TaskId, handle: ServiceHandle, id| {
+            let span = trace_span!(
+                parent: parent_span.clone(),
+                "api_heartbeat_request",
+                task = %task,
+                client_id = %id
+            );
             async move {
-                match handle.heartbeat(id).await {
-                    Ok(response) => Ok(warp::reply::json(&HeartBeatResponseJson::from(response))),
-                    Err(_) => Err(warp::reject::not_found()),
-                }
+                let response = handle.heartbeat(id).await.map_err(warp::reject::custom)?;
+                Ok::<_, Rejection>(warp::reply::json(&HeartBeatResponseJson::from(response)))
             }
             .instrument(span)
         })
         .with(warp::cors().allow_any_origin().allow_method(Method::GET));
 
+    // Push channel for the clients that prefer to be notified of the
+    // changes of their heartbeat response over polling. The channel
+    // counts as a heartbeat for as long as it is open.
     let parent_span = tracing::Span::current();
-    let rendez_vous = warp::path!("rendez_vous")
+    let heartbeat_events = task
+        .clone()
+        .and(warp::path("heartbeat"))
+        .and(client_id())
+        .and(warp::path!("events"))
         .and(warp::get())
-        .and(handle.clone())
-        .and_then(move |handle: ServiceHandle| {
-            let span = trace_span!(parent: parent_span.clone(), "api_rendez_vous_request");
+        .and(rate_limit::limit(heartbeat_limiter))
+        .and_then(move |task: TaskId, handle: ServiceHandle, id| {
+            let span = trace_span!(
+                parent: parent_span.clone(),
+                "api_heartbeat_events_request",
+                task = %task,
+                client_id = %id
+            );
             async move {
-                match handle.rendez_vous().await {
-                    Ok(response) => Ok(warp::reply::json(&RendezVousResponseJson::from(response))),
-                    Err(_) => Err(warp::reject::not_found()),
-                }
+                let stream = handle.subscribe(id).await.map_err(warp::reject::custom)?;
+                let events = stream.map(|response| {
+                    Ok::<_, Infallible>(warp::sse::json(HeartBeatResponseJson::from(response)))
+                });
+                Ok::<_, Rejection>(warp::sse::reply(warp::sse::keep_alive().stream(events)))
             }
             .instrument(span)
         })
         .with(warp::cors().allow_any_origin().allow_method(Method::GET));
 
+    // The rendez-vous and start training requests change the state of
+    // the coordinator, so they should not be sent with `GET`
+    let methods = if legacy_get_routes {
+        vec![Method::POST, Method::GET]
+    } else {
+        vec![Method::POST]
+    };
+
+    // The legacy `GET` requests carry the version of the client in
+    // the query string, and don't have an idempotency key
+    let rendez_vous_request = json_body::<RendezVousRequestJson>()
+        .or(legacy_get(legacy_get_routes)
+            .and(warp::query::<ClientVersion>())
+            .map(|client| RendezVousRequestJson {
+                client,
+                metadata: HashMap::new(),
+                idempotency_key: None,
+            }))
+        .unify();
+    let cache = IdempotencyCache::new(
+        IDEMPOTENCY_KEY_TTL,
+        IDEMPOTENCY_CACHE_CAPACITY,
+        |response| matches!(response, RendezVousResponse::Accept(_)),
+    );
     let parent_span = tracing::Span::current();
-    let start_training = warp::path!("start_training" / ClientId)
+    let rendez_vous = task
+        .clone()
+        .and(warp::path!("rendez_vous"))
+        // The rate limit is checked first, so that the body of the
+        // requests that exceed it is not read
+        .and(rate_limit::limit(RateLimiter::new(rate_limit.rendez_vous)))
+        .and(rendez_vous_request)
+        .and(warp::addr::remote())
+        .and_then(
+            move |task: TaskId, handle: ServiceHandle, request, addr: Option<SocketAddr>| {
+                let span = trace_span!(
+                    parent: parent_span.clone(),
+                    "api_rendez_vous_request",
+                    task = %task
+                );
+                handle_rendez_vous_request(task, request, addr, handle, cache.clone())
+                    .instrument(span)
+            },
+        )
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_methods(methods.clone())
+                .allow_headers(vec![CONTENT_TYPE]),
+        );
+
+    let start_training_request = json_body::<StartTrainingRequestJson>()
+        .or(legacy_get(legacy_get_routes).map(StartTrainingRequestJson::default))
+        .unify();
+    let cache = IdempotencyCache::new(
+        IDEMPOTENCY_KEY_TTL,
+        IDEMPOTENCY_CACHE_CAPACITY,
+        |response| matches!(response, StartTrainingResponse::Accept(..)),
+    );
+    let parent_span = tracing::Span::current();
+    let start_training = task
+        .clone()
+        .and(warp::path("start_training"))
+        .and(client_id())
+        .and(warp::path::end())
+        .and(rate_limit::limit(RateLimiter::new(
+            rate_limit.start_training,
+        )))
+        .and(start_training_request)
+        .and_then(move |task: TaskId, handle: ServiceHandle, id, request| {
+            let span = trace_span!(
+                parent: parent_span.clone(),
+                "api_start_training_request",
+                task = %task,
+                client_id = %id
+            );
+            handle_start_training_request(task, id, request, handle, cache.clone()).instrument(span)
+        })
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_methods(methods)
+                .allow_headers(vec![CONTENT_TYPE]),
+        );
+
+    let admin_command = warp::path!("admin" / "pause")
+        .map(|| AdminCommand::Pause)
+        .or(warp::path!("admin" / "resume").map(|| AdminCommand::Resume))
+        .unify()
+        .or(warp::path!("admin" / "abort_round").map(|| AdminCommand::AbortRound))
+        .unify()
+        .or(warp::path!("admin" / "finish").map(|| AdminCommand::Finish))
+        .unify()
+        .or(warp::path!("admin" / "extend_training" / u32).map(AdminCommand::ExtendTraining))
+        .unify()
+        .or(warp::path!("admin" / "evict" / ..)
+            .and(client_id())
+            .and(warp::path::end())
+            .map(AdminCommand::Evict))
+        .unify()
+        .or(warp::path!("admin" / "ban" / ..)
+            .and(client_id())
+            .and(warp::path::end())
+            .map(AdminCommand::Ban))
+        .unify();
+
+    let admin_token = warp::any().map(move || admin_token.clone());
+    let parent_span = tracing::Span::current();
+    let training_metrics = task
+        .clone()
+        .and(warp::path!("admin" / "training_metrics"))
         .and(warp::get())
-        .and(handle.clone())
-        .and_then(move |id, handle: ServiceHandle| {
-            let span =
-                trace_span!(parent: parent_span.clone(), "api_start_training_request", client_id = %id);
-            async move {
-                match handle.start_training(id).await {
-                    Ok(response) => Ok(warp::reply::json(&StartTrainingResponseJson::from(
-                        response,
-                    ))),
-                    Err(_) => Err(warp::reject::not_found()),
-                }
-            }.instrument(span)
-        }).with(warp::cors().allow_any_origin().allow_method(Method::GET));
+        .and(warp::header::optional::<String>(AUTHORIZATION.as_str()))
+        .and(admin_token.clone())
+        .and_then(move |task: TaskId, handle, authorization, admin_token| {
+            let span = trace_span!(
+                parent: parent_span.clone(),
+                "api_training_metrics_request",
+                task = %task
+            );
+            handle_training_metrics_request(authorization, admin_token, handle).instrument(span)
+        })
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_method(Method::GET)
+                .allow_headers(vec![AUTHORIZATION]),
+        );
+
+    let parent_span = tracing::Span::current();
+    let admin = task
+        .and(admin_command)
+        .and(warp::post())
+        .and(warp::header::optional::<String>(AUTHORIZATION.as_str()))
+        .and(admin_token)
+        .and_then(
+            move |task: TaskId, handle, command, authorization, admin_token| {
+                let span =
+                    trace_span!(parent: parent_span.clone(), "api_admin_request", task = %task);
+                handle_admin_request(command, authorization, admin_token, handle).instrument(span)
+            },
+        )
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_method(Method::POST)
+                .allow_headers(vec![AUTHORIZATION, CONTENT_TYPE]),
+        );
 
-    let mut listener = TcpListener::bind(bind_address).await.unwrap();
+    let mut listener = TcpListener::bind(bind_address.as_str()).await.unwrap();
 
     info!("starting HTTP server on {}", bind_address);
     let log = warp::log("http");
-    warp::serve(heartbeat.or(rendez_vous).or(start_training).with(log))
-        .run_incoming(listener.incoming())
-        .await
+    let routes = heartbeat
+        .or(heartbeat_events)
+        .or(rendez_vous)
+        .or(start_training)
+        .or(training_metrics)
+        .or(admin)
+        .recover(handle_rejection)
+        .with(log);
+    // Once the shutdown is triggered, the server stops accepting
+    // connections and waits for the pending requests to complete
+    let service = warp::service(routes);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service) }
+    });
+    let server = Server::builder(accept::from_stream(listener.incoming()))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.wait());
+    if let Err(e) = server.await {
+        error!("HTTP server error: {}", e);
+    }
+    info!("HTTP server stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handle_rejection() {
+        let filter = warp::path("heartbeat")
+            .and(client_id())
+            .and(warp::path::end())
+            .map(|_| warp::reply())
+            .recover(handle_rejection);
+
+        let resp = warp::test::request()
+            .path("/heartbeat/not_a_client_id")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["code"], 400);
+        assert_eq!(body["error"], "invalid_client_id");
+
+        let resp = warp::test::request().path("/unknown").reply(&filter).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["error"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn test_legacy_get() {
+        let filter = |enabled| {
+            json_body::<StartTrainingRequestJson>()
+                .or(legacy_get(enabled).map(StartTrainingRequestJson::default))
+                .unify()
+                .map(|_| warp::reply())
+                .recover(handle_rejection)
+        };
+
+        let resp = warp::test::request()
+            .method("POST")
+            .json(&serde_json::json!({ "idempotency_key": "key" }))
+            .reply(&filter(false))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json")
+            .body("not json")
+            .reply(&filter(false))
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = warp::test::request().reply(&filter(true)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = warp::test::request().reply(&filter(false)).await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
 }
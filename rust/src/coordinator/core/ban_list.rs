@@ -0,0 +1,109 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{self, prelude::*, BufReader},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// A list of banned IP addresses. Clients connecting from these
+/// addresses are rejected on rendez-vous.
+///
+/// If the list is backed by a file, the file contains one address
+/// per line, and newly banned addresses are appended to it so that
+/// the list survives restarts.
+///
+/// The list can be cloned and all the clones share the same state,
+/// so that an address banned from one training task is banned from
+/// all of them.
+#[derive(Debug, Default, Clone)]
+pub struct BanList(Arc<Mutex<Inner>>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    banned: HashSet<IpAddr>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    /// Load the ban list from the given file. If the file doesn't
+    /// exist yet, the list is empty and the file is created the first
+    /// time an address is banned.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut banned = HashSet::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let addr = line.parse::<IpAddr>().map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid address `{}` in ban list: {}", line, e),
+                        )
+                    })?;
+                    banned.insert(addr);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        info!("loaded {} banned addresses", banned.len());
+        Ok(Self(Arc::new(Mutex::new(Inner {
+            banned,
+            path: Some(path),
+        }))))
+    }
+
+    /// Return whether the given address is banned
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        // UNWRAP_SAFE: the lock is never held across a panic
+        self.0.lock().unwrap().banned.contains(addr)
+    }
+
+    /// Ban the given address, and persist it if the list is backed
+    /// by a file.
+    pub fn ban(&self, addr: IpAddr) -> io::Result<()> {
+        // UNWRAP_SAFE: the lock is never held across a panic
+        let mut inner = self.0.lock().unwrap();
+        if !inner.banned.insert(addr) {
+            return Ok(());
+        }
+        info!("banning {}", addr);
+        if let Some(ref path) = inner.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    /// Test that banned addresses are persisted and loaded again.
+    #[test]
+    fn test_ban_list_persistence() {
+        let path = env::temp_dir().join(format!("xain-fl-ban-list-{}", Uuid::new_v4()));
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let ban_list = BanList::load(path.clone()).unwrap();
+        let shared = ban_list.clone();
+        assert!(!ban_list.contains(&addr));
+        ban_list.ban(addr).unwrap();
+        assert!(ban_list.contains(&addr));
+        assert!(shared.contains(&addr));
+
+        let ban_list = BanList::load(path.clone()).unwrap();
+        assert!(ban_list.contains(&addr));
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,8 +1,16 @@
+mod ban_list;
 mod client;
 mod heartbeat;
 mod protocol;
+mod schedule;
 mod service;
+mod subscription;
+mod training_metrics;
 
 #[cfg(test)]
 pub(crate) use self::service::ServiceRequests;
-pub use self::service::{RequestError, Selector, Service, ServiceHandle};
+pub use self::{
+    ban_list::BanList,
+    service::{AggregatorClient, RequestError, Selector, Service, ServiceHandle, TrainingOutcome},
+    subscription::HeartBeatStream,
+};
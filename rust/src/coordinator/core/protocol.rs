@@ -1,9 +1,13 @@
 use derive_more::Display;
-use std::{collections::VecDeque, error::Error};
+use std::{collections::VecDeque, error::Error, time::Duration};
 
 use crate::{
     common::client::ClientId,
-    coordinator::{models::HeartBeatResponse, settings::FederatedLearningSettings},
+    coordinator::{
+        core::schedule::{self, Schedule},
+        models::{HeartBeatResponse, TrainingConfig},
+        settings::{AdmissionPolicy, EvaluationSettings, FederatedLearningSettings},
+    },
 };
 
 #[derive(Eq, Debug, PartialEq, Default, Copy, Clone, Display)]
@@ -34,6 +38,11 @@ impl Counters {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Number of clients that are expected to send heartbeats
+    pub fn active(&self) -> u32 {
+        self.waiting + self.selected + self.done + self.ignored
+    }
 }
 
 /// The state machine.
@@ -43,6 +52,10 @@ pub struct Protocol {
     /// Whether all the round of training are done
     pub is_training_complete: bool,
 
+    /// Whether the training was aborted because the aggregation
+    /// failed too many times in a row
+    pub is_training_aborted: bool,
+
     /// Coordinator configuration
     settings: FederatedLearningSettings,
 
@@ -53,37 +66,55 @@ pub struct Protocol {
     events: VecDeque<Event>,
 
     waiting_for_aggregation: bool,
+
+    /// Whether an operator paused the selection of new participants
+    paused: bool,
+
+    /// The rules that depend on the mode of the training
+    schedule: Box<dyn Schedule>,
+
+    /// Number of aggregations that failed since the last successful
+    /// one
+    aggregation_failures: u32,
+
+    /// Whether the current round evaluates the model produced by the
+    /// previous round, instead of training it
+    evaluating: bool,
 }
 
 impl Protocol {
     fn number_of_clients_to_select(&self) -> Option<u32> {
-        if self.is_training_complete || self.waiting_for_aggregation {
+        if self.is_training_complete || self.paused {
             return None;
         }
+        self.schedule.clients_to_select(
+            &self.counters,
+            &self.settings,
+            self.waiting_for_aggregation,
+        )
+    }
 
-        let Counters {
-            waiting,
-            selected,
-            done,
-            done_and_inactive,
-            ..
-        } = self.counters;
-
-        let total_participants = selected + done + done_and_inactive;
-        if total_participants >= self.settings.minimum_participants() {
-            return None;
+    /// Start an aggregation if the updates received so far must be
+    /// aggregated and no aggregation is running
+    fn maybe_start_aggregation(&mut self) {
+        if self.waiting_for_aggregation
+            || !self
+                .schedule
+                .is_aggregation_due(&self.counters, self.number_of_clients_to_select())
+        {
+            return;
         }
-
-        // We need to select more clients. But do we have enough
-        // clients to perform the selection?
-        let total_clients = total_participants + waiting;
-        if total_clients < self.settings.min_clients {
-            return None;
+        self.emit_event(Event::RunAggregation);
+        self.waiting_for_aggregation = true;
+        if self.schedule.start_aggregation() {
+            info!(
+                counters = %self.counters,
+                "round complete, resetting the clients"
+            );
+            self.reset_all_clients();
+        } else {
+            info!(counters = %self.counters, "starting aggregation");
         }
-
-        let total_to_select =
-            f64::ceil(self.settings.participants_ratio * total_clients as f64) as i64 as u32;
-        Some(total_to_select - total_participants)
     }
 
     fn maybe_start_selection(&mut self) {
@@ -94,8 +125,29 @@ impl Protocol {
         }
     }
 
-    fn is_end_of_round(&self) -> bool {
-        self.counters.selected == 0 && self.number_of_clients_to_select().is_none()
+    /// Put all the active clients back in the [`ClientState::Waiting`]
+    /// state and forget about the inactive ones.
+    fn reset_all_clients(&mut self) {
+        self.emit_event(Event::ResetAll);
+        self.counters.waiting += self.counters.selected;
+        self.counters.waiting += self.counters.done;
+        self.counters.waiting += self.counters.ignored;
+        self.counters.selected = 0;
+        self.counters.done_and_inactive = 0;
+        self.counters.done = 0;
+        self.counters.ignored = 0;
+        self.schedule.reset();
+    }
+
+    /// Whether participants have been selected for the current round
+    fn is_round_in_progress(&self) -> bool {
+        let Counters {
+            selected,
+            done,
+            done_and_inactive,
+            ..
+        } = self.counters;
+        selected + done + done_and_inactive + self.schedule.buffered() > 0
     }
 
     /// Emit an event
@@ -110,12 +162,41 @@ impl Protocol {
         self.counters
     }
 
+    /// The round being trained, or the last round once the training
+    /// is complete
+    pub fn current_round(&self) -> u32 {
+        self.current_round
+    }
+
+    /// Total number of rounds. Operators can change it while the
+    /// training runs.
+    pub fn rounds(&self) -> u32 {
+        self.settings.rounds
+    }
+
+    /// Whether the current round is an evaluation round
+    pub fn is_evaluating(&self) -> bool {
+        self.evaluating
+    }
+
+    /// Configuration of the local training for the current round
+    pub fn training_config(&self) -> TrainingConfig {
+        let mut config = self.settings.training.round_config(self.current_round);
+        config.evaluation = self.evaluating;
+        config
+    }
+
     pub fn new(settings: FederatedLearningSettings) -> Self {
         Self {
+            schedule: schedule::for_mode(settings.mode),
             settings,
             counters: Counters::new(),
             is_training_complete: false,
+            is_training_aborted: false,
             waiting_for_aggregation: false,
+            paused: false,
+            aggregation_failures: 0,
+            evaluating: false,
             current_round: 0,
             events: VecDeque::new(),
         }
@@ -155,6 +236,19 @@ impl Protocol {
             return RendezVousResponse::Reject;
         }
         let response = match client_state {
+            ClientState::Unknown if self.is_full() => {
+                // Don't take more clients than we can handle
+                match self.settings.admission_policy {
+                    AdmissionPolicy::Reject => {
+                        info!("rejecting {}: maximum number of clients reached", id);
+                        RendezVousResponse::Reject
+                    }
+                    AdmissionPolicy::Queue { retry_after } => {
+                        info!("deferring {}: maximum number of clients reached", id);
+                        RendezVousResponse::Retry(Duration::from_secs(retry_after))
+                    }
+                }
+            }
             ClientState::Unknown => {
                 // Accept new clients and make them selectable
                 self.counters.waiting += 1;
@@ -202,6 +296,15 @@ impl Protocol {
         response
     }
 
+    /// Return whether the maximum number of active clients is
+    /// reached
+    fn is_full(&self) -> bool {
+        self.settings
+            .max_clients
+            .map(|max_clients| self.counters.active() >= max_clients)
+            .unwrap_or(false)
+    }
+
     /// Handle a heartbeat timeout for the given client.
     pub fn heartbeat_timeout(&mut self, id: ClientId, client_state: ClientState) {
         info!("heartbeat timeout: {}({})", id, client_state);
@@ -226,6 +329,39 @@ impl Protocol {
         self.maybe_start_selection();
     }
 
+    /// Forcibly remove the given client, whatever its state. This is
+    /// used to get rid of misbehaving clients. Note that if the
+    /// client already finished training, the weights it uploaded for
+    /// the current round are still aggregated.
+    pub fn evict(&mut self, id: ClientId, client_state: ClientState) -> Result<(), InvalidState> {
+        info!("evicting client: {}({})", id, client_state);
+        match client_state {
+            ClientState::Unknown => {
+                warn!("cannot evict unknown client {}", id);
+                return Err(InvalidState);
+            }
+            ClientState::Waiting => self.counters.waiting -= 1,
+            ClientState::Selected => self.counters.selected -= 1,
+            // The client stays a participant of the round, like the
+            // participants that stop sending heartbeats after training
+            ClientState::Done => {
+                self.counters.done -= 1;
+                self.counters.done_and_inactive += 1;
+            }
+            ClientState::DoneAndInactive => self.counters.done_and_inactive -= 1,
+            ClientState::Ignored => self.counters.ignored -= 1,
+        }
+        if client_state == ClientState::Done {
+            self.emit_event(Event::SetState(id, ClientState::DoneAndInactive));
+        } else {
+            self.emit_event(Event::Remove(id));
+        }
+        // The round may be complete without the evicted client
+        self.maybe_start_aggregation();
+        self.maybe_start_selection();
+        Ok(())
+    }
+
     /// Handle a heartbeat for the given client.
     ///
     /// # Returns
@@ -233,8 +369,19 @@ impl Protocol {
     /// This method returns the response to send back to the client.
     pub fn heartbeat(&mut self, id: ClientId, client_state: ClientState) -> HeartBeatResponse {
         info!("heartbeat: {}({})", id, client_state);
-        if self.is_training_complete {
+        let response = self.heartbeat_response(client_state);
+        // Rejected clients are not known anymore, so there is no
+        // heartbeat to reset
+        if response != HeartBeatResponse::Reject {
             self.emit_event(Event::ResetHeartBeat(id));
+        }
+        response
+    }
+
+    /// Return the response to the heartbeats of a client in the given
+    /// state, without counting it as a heartbeat
+    pub fn heartbeat_response(&self, client_state: ClientState) -> HeartBeatResponse {
+        if self.is_training_complete {
             return HeartBeatResponse::Finish;
         }
         match client_state {
@@ -251,15 +398,11 @@ impl Protocol {
 
             // Client that are waiting or done should stand by
             ClientState::Ignored | ClientState::Waiting | ClientState::Done => {
-                self.emit_event(Event::ResetHeartBeat(id));
                 HeartBeatResponse::StandBy
             }
 
             // If the client has been selected, notify them.
-            ClientState::Selected => {
-                self.emit_event(Event::ResetHeartBeat(id));
-                HeartBeatResponse::Round(self.current_round)
-            }
+            ClientState::Selected => HeartBeatResponse::Round(self.current_round),
         }
     }
 
@@ -291,35 +434,32 @@ impl Protocol {
             "end training request: {}({}) (success={})",
             id, client_state, success
         );
-        if self.is_training_complete || self.waiting_for_aggregation {
+        if self.is_training_complete {
+            warn!("got unexpected end training request");
+            return;
+        }
+        if self.waiting_for_aggregation && !self.schedule.accepts_updates_while_aggregating() {
             warn!("got unexpected end training request");
             return;
         }
 
         if client_state == ClientState::Selected {
             self.counters.selected -= 1;
+            let state = self.schedule.end_training(success);
+            match state {
+                ClientState::Waiting => self.counters.waiting += 1,
+                ClientState::Done => self.counters.done += 1,
+                ClientState::Ignored => self.counters.ignored += 1,
+                _ => panic!(
+                    "participants cannot go to the {} state after training",
+                    state
+                ),
+            }
+            self.emit_event(Event::SetState(id, state));
             if success {
-                self.emit_event(Event::SetState(id, ClientState::Done));
-                self.counters.done += 1;
-
-                if self.is_end_of_round() {
-                    self.emit_event(Event::RunAggregation);
-                    self.waiting_for_aggregation = true;
-                    info!(
-                        counters = %self.counters,
-                        "round complete, resetting the clients"
-                    );
-                    self.emit_event(Event::ResetAll);
-                    self.counters.waiting += self.counters.done;
-                    self.counters.waiting += self.counters.ignored;
-                    self.counters.done_and_inactive = 0;
-                    self.counters.done = 0;
-                    self.counters.ignored = 0;
-                }
+                self.maybe_start_aggregation();
             } else {
-                self.emit_event(Event::SetState(id, ClientState::Ignored));
-                self.counters.ignored += 1;
-                info!(counters = %self.counters, "training failed, ignoring participant");
+                info!(counters = %self.counters, "training failed, participant is now {}", state);
             }
             self.maybe_start_selection();
         }
@@ -331,31 +471,147 @@ impl Protocol {
             return;
         }
         self.waiting_for_aggregation = false;
-        if success {
+        if !success {
+            self.aggregation_failed();
+            return;
+        }
+        self.aggregation_failures = 0;
+        if self.evaluating {
+            info!("evaluation finished");
+            self.evaluating = false;
+            self.emit_event(Event::EndEvaluation(self.current_round));
+        } else {
             self.emit_event(Event::EndRound(self.current_round));
             self.current_round += 1;
+            self.evaluating = self.is_evaluation_due();
         }
-        if self.current_round == self.settings.rounds {
+        if self.current_round >= self.settings.rounds && !self.evaluating {
             info!("training complete");
             self.is_training_complete = true;
         } else {
             info!("aggregation finished, proceeding to start a new round");
+            self.maybe_start_aggregation();
             self.maybe_start_selection();
         }
     }
 
+    /// Whether the model produced by the round that just ended must
+    /// be evaluated
+    fn is_evaluation_due(&self) -> bool {
+        match self.settings.evaluation {
+            Some(EvaluationSettings { every }) => every > 0 && self.current_round % every == 0,
+            None => false,
+        }
+    }
+
+    /// Handle a failed aggregation. The weights uploaded for the
+    /// current round are discarded and the round starts over with
+    /// newly selected participants, unless the aggregation failed too
+    /// many times in a row, in which case the training is aborted.
+    fn aggregation_failed(&mut self) {
+        self.aggregation_failures += 1;
+        if self.aggregation_failures > self.settings.max_aggregation_failures {
+            error!(
+                "aggregation failed {} times in a row, aborting the training",
+                self.aggregation_failures
+            );
+            self.is_training_complete = true;
+            self.is_training_aborted = true;
+            return;
+        }
+        warn!(
+            "aggregation failed ({} times in a row), starting round {} over",
+            self.aggregation_failures, self.current_round
+        );
+        self.emit_event(Event::AbortRound(self.current_round));
+        self.reset_all_clients();
+        self.maybe_start_selection();
+    }
+
+    /// Handle a request from an operator to stop selecting new
+    /// participants. Rounds that are already running are not
+    /// affected.
+    pub fn pause(&mut self) -> Result<(), InvalidState> {
+        info!("pause request");
+        if self.is_training_complete || self.paused {
+            warn!("rejecting pause request");
+            return Err(InvalidState);
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    /// Handle a request from an operator to resume the selection of
+    /// participants after a pause.
+    pub fn resume(&mut self) -> Result<(), InvalidState> {
+        info!("resume request");
+        if self.is_training_complete || !self.paused {
+            warn!("rejecting resume request");
+            return Err(InvalidState);
+        }
+        self.paused = false;
+        self.maybe_start_selection();
+        Ok(())
+    }
+
+    /// Handle a request from an operator to abort the current
+    /// round. The weights uploaded so far are discarded, all the
+    /// participants are put back in the [`ClientState::Waiting`]
+    /// state and the round starts over.
+    pub fn abort_round(&mut self) -> Result<(), InvalidState> {
+        info!("abort round request");
+        if self.is_training_complete || self.waiting_for_aggregation {
+            warn!("rejecting abort round request");
+            return Err(InvalidState);
+        }
+        self.emit_event(Event::AbortRound(self.current_round));
+        self.reset_all_clients();
+        self.maybe_start_selection();
+        Ok(())
+    }
+
+    /// Handle a request from an operator to end the training before
+    /// all the rounds are completed. If an aggregation is ongoing,
+    /// the training ends once it finishes. Otherwise, the current
+    /// round is aborted and the training ends immediately.
+    pub fn finish_training(&mut self) -> Result<(), InvalidState> {
+        info!("finish training request");
+        if self.is_training_complete {
+            warn!("rejecting finish training request");
+            return Err(InvalidState);
+        }
+        if self.waiting_for_aggregation {
+            // An evaluation doesn't start a new round
+            self.settings.rounds = self.current_round + u32::from(!self.evaluating);
+            return Ok(());
+        }
+        if self.is_round_in_progress() {
+            self.emit_event(Event::AbortRound(self.current_round));
+        }
+        self.settings.rounds = self.current_round;
+        info!("training complete");
+        self.is_training_complete = true;
+        Ok(())
+    }
+
+    /// Handle a request from an operator to train for `rounds`
+    /// additional rounds.
+    pub fn extend_training(&mut self, rounds: u32) -> Result<(), InvalidState> {
+        info!("extend training request ({} additional rounds)", rounds);
+        if self.is_training_complete || rounds == 0 {
+            warn!("rejecting extend training request");
+            return Err(InvalidState);
+        }
+        self.settings.rounds += rounds;
+        Ok(())
+    }
+
     /// Retrieve the next event
     pub fn next_event(&mut self) -> Option<Event> {
         self.events.pop_front()
     }
 }
 
-impl FederatedLearningSettings {
-    fn minimum_participants(&self) -> u32 {
-        (self.participants_ratio * self.min_clients as f64) as i64 as u32
-    }
-}
-
 /// Response to a "start training" request.
 #[derive(Debug, PartialEq, Eq)]
 pub enum StartTrainingResponse {
@@ -371,6 +627,10 @@ pub enum RendezVousResponse {
 
     /// The coordinator rejects the client
     Reject,
+
+    /// The coordinator cannot accept the client now, but the client
+    /// can send a new rendez-vous request after the given delay
+    Retry(Duration),
 }
 
 /// Represent the state of a client, as seen by the state machine
@@ -425,8 +685,20 @@ pub enum Event {
 
     /// Indicates the end of a round
     EndRound(u32),
+
+    /// Indicates the end of the evaluation of the model produced by
+    /// the given number of rounds
+    EndEvaluation(u32),
+
+    /// Indicates that the given round has been aborted and that the
+    /// weights uploaded for it must be discarded. If a failed
+    /// aggregation updated the global weights, they must be rolled
+    /// back to the weights produced by the previous round.
+    AbortRound(u32),
 }
 
+/// Error returned when the protocol cannot handle a request in its
+/// current state
 #[derive(Debug, Display)]
 pub struct InvalidState;
 impl Error for InvalidState {}
@@ -435,7 +707,12 @@ impl Error for InvalidState {}
 mod tests {
 
     use super::*;
-    use crate::{common::client::ClientId, coordinator::settings::FederatedLearningSettings};
+    use crate::{
+        common::client::ClientId,
+        coordinator::settings::{
+            EvaluationSettings, FederatedLearningSettings, Mode, TrainingSettings,
+        },
+    };
 
     fn get_default_fl_settings() -> FederatedLearningSettings {
         FederatedLearningSettings {
@@ -443,6 +720,12 @@ mod tests {
             participants_ratio: 1.0,
             min_clients: 1,
             heartbeat_timeout: 15,
+            max_clients: None,
+            admission_policy: AdmissionPolicy::Reject,
+            mode: Mode::Sync,
+            max_aggregation_failures: 3,
+            training: TrainingSettings::default(),
+            evaluation: None,
         }
     }
 
@@ -480,6 +763,65 @@ mod tests {
         assert!(protocol.next_event().is_none());
     }
 
+    /// Test that new clients are rejected once the maximum number of
+    /// clients is reached, while known clients are still accepted.
+    #[test]
+    fn test_rendez_vous_max_clients_reject() {
+        let mut protocol = Protocol::new(FederatedLearningSettings {
+            min_clients: 10,
+            max_clients: Some(1),
+            ..get_default_fl_settings()
+        });
+        let client_1 = ClientId::new();
+        let client_2 = ClientId::new();
+
+        let resp = protocol.rendez_vous(client_1, ClientState::Unknown);
+        assert_eq!(RendezVousResponse::Accept, resp);
+        assert_eq!(protocol.next_event().unwrap(), Event::Accept(client_1));
+
+        let resp = protocol.rendez_vous(client_2, ClientState::Unknown);
+        assert_eq!(RendezVousResponse::Reject, resp);
+        assert!(protocol.next_event().is_none());
+
+        let resp = protocol.rendez_vous(client_1, ClientState::Waiting);
+        assert_eq!(RendezVousResponse::Accept, resp);
+
+        let expected = Counters {
+            waiting: 1,
+            ..Default::default()
+        };
+        assert_eq!(protocol.counters(), expected);
+    }
+
+    /// Test that new clients are asked to retry later once the
+    /// maximum number of clients is reached, and are accepted again
+    /// once a client leaves.
+    #[test]
+    fn test_rendez_vous_max_clients_queue() {
+        let mut protocol = Protocol::new(FederatedLearningSettings {
+            min_clients: 10,
+            max_clients: Some(1),
+            admission_policy: AdmissionPolicy::Queue { retry_after: 30 },
+            ..get_default_fl_settings()
+        });
+        let client_1 = ClientId::new();
+        let client_2 = ClientId::new();
+
+        protocol.rendez_vous(client_1, ClientState::Unknown);
+        assert_eq!(protocol.next_event().unwrap(), Event::Accept(client_1));
+
+        let resp = protocol.rendez_vous(client_2, ClientState::Unknown);
+        assert_eq!(RendezVousResponse::Retry(Duration::from_secs(30)), resp);
+        assert!(protocol.next_event().is_none());
+
+        protocol.heartbeat_timeout(client_1, ClientState::Waiting);
+        assert_eq!(protocol.next_event().unwrap(), Event::Remove(client_1));
+
+        let resp = protocol.rendez_vous(client_2, ClientState::Unknown);
+        assert_eq!(RendezVousResponse::Accept, resp);
+        assert_eq!(protocol.next_event().unwrap(), Event::Accept(client_2));
+    }
+
     /// Test the outcome of a rendez-vous request from a client that
     /// already sent a rendez-vous request but has not yet been selected
     #[test]
@@ -1090,6 +1432,12 @@ mod tests {
             participants_ratio: 1.0,
             min_clients: 15,
             heartbeat_timeout: 15,
+            max_clients: None,
+            admission_policy: AdmissionPolicy::Reject,
+            mode: Mode::Sync,
+            max_aggregation_failures: 3,
+            training: TrainingSettings::default(),
+            evaluation: None,
         };
         let mut protocol = Protocol::new(fl_settings);
         let client_id = ClientId::new();
@@ -1168,7 +1516,8 @@ mod tests {
         assert!(protocol.next_event().is_none());
     }
 
-    /// Test the outcome of an aggregation failure.
+    /// Test the outcome of an aggregation failure: the round starts
+    /// over with newly selected participants.
     #[test]
     fn test_end_aggregation_waiting_for_aggregation_no_success_not_last_round() {
         let mut protocol = Protocol::new(get_default_fl_settings());
@@ -1181,6 +1530,10 @@ mod tests {
 
         assert_eq!(protocol.waiting_for_aggregation, false);
         assert_eq!(protocol.is_training_complete, false);
+        assert_eq!(protocol.current_round, 0);
+        assert_eq!(protocol.next_event().unwrap(), Event::AbortRound(0));
+        assert_eq!(protocol.next_event().unwrap(), Event::ResetAll);
+        assert_eq!(protocol.next_event().unwrap(), Event::RunSelection(1));
         assert!(protocol.next_event().is_none());
     }
 
@@ -1201,7 +1554,489 @@ mod tests {
         assert_eq!(protocol.waiting_for_aggregation, false);
         assert_eq!(protocol.is_training_complete, false);
         assert_eq!(protocol.current_round, 1);
+        assert_eq!(protocol.next_event().unwrap(), Event::AbortRound(1));
+        assert_eq!(protocol.next_event().unwrap(), Event::ResetAll);
+        assert_eq!(protocol.next_event().unwrap(), Event::RunSelection(1));
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that the training is aborted when the aggregation fails
+    /// too many times in a row, and that a successful aggregation
+    /// resets the count of failures.
+    #[test]
+    fn test_end_aggregation_too_many_failures() {
+        let mut protocol = Protocol::new(FederatedLearningSettings {
+            rounds: 3,
+            max_aggregation_failures: 1,
+            training: TrainingSettings::default(),
+            evaluation: None,
+            ..get_default_fl_settings()
+        });
+        let aggregate = |protocol: &mut Protocol, success| {
+            protocol.waiting_for_aggregation = true;
+            protocol.end_aggregation(success);
+            while protocol.next_event().is_some() {}
+        };
+
+        aggregate(&mut protocol, false);
+        aggregate(&mut protocol, true);
+        aggregate(&mut protocol, false);
+        assert_eq!(protocol.is_training_complete, false);
+        assert_eq!(protocol.current_round, 1);
+
+        aggregate(&mut protocol, false);
+        assert_eq!(protocol.is_training_complete, true);
+        assert_eq!(protocol.is_training_aborted, true);
+        assert_eq!(protocol.current_round, 1);
+    }
+
+    /// Test that pausing the protocol prevents new selections and
+    /// that resuming it triggers the selection again.
+    #[test]
+    fn test_pause_and_resume() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        protocol.pause().unwrap();
+
+        let client_id = ClientId::new();
+        protocol.rendez_vous(client_id, ClientState::Unknown);
+        assert_eq!(protocol.next_event().unwrap(), Event::Accept(client_id));
+        assert!(protocol.next_event().is_none());
+
+        protocol.resume().unwrap();
+        assert_eq!(protocol.next_event().unwrap(), Event::RunSelection(1));
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that pausing an already paused protocol, or resuming a
+    /// protocol that is not paused, is rejected.
+    #[test]
+    fn test_pause_and_resume_invalid_state() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        assert!(protocol.resume().is_err());
+        protocol.pause().unwrap();
+        assert!(protocol.pause().is_err());
+
+        protocol.is_training_complete = true;
+        assert!(protocol.resume().is_err());
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that aborting a round resets all the participants and
+    /// starts a new selection for the same round.
+    #[test]
+    fn test_abort_round() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        protocol.counters = Counters {
+            waiting: 1,
+            selected: 2,
+            done: 3,
+            done_and_inactive: 1,
+            ignored: 2,
+        };
+
+        protocol.abort_round().unwrap();
+
+        let counters = protocol.counters();
+        let expected = Counters {
+            waiting: 1 + 2 + 3 + 2,
+            ..Default::default()
+        };
+        assert_eq!(counters, expected);
+        assert_eq!(protocol.current_round, 0);
+        assert_eq!(protocol.next_event().unwrap(), Event::AbortRound(0));
+        assert_eq!(protocol.next_event().unwrap(), Event::ResetAll);
+        assert_eq!(protocol.next_event().unwrap(), Event::RunSelection(8));
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that a round cannot be aborted while the aggregation is
+    /// running.
+    #[test]
+    fn test_abort_round_waiting_for_aggregation() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        protocol.waiting_for_aggregation = true;
+
+        assert!(protocol.abort_round().is_err());
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that finishing the training while a round is running
+    /// aborts that round and completes the training.
+    #[test]
+    fn test_finish_training_round_in_progress() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        protocol.counters = Counters {
+            selected: 1,
+            ..Default::default()
+        };
+
+        protocol.finish_training().unwrap();
+
+        assert_eq!(protocol.is_training_complete, true);
+        assert_eq!(protocol.next_event().unwrap(), Event::AbortRound(0));
+        assert!(protocol.next_event().is_none());
+        assert!(protocol.finish_training().is_err());
+    }
+
+    /// Test that the model is evaluated after every training round,
+    /// including the last one, when evaluation rounds are enabled.
+    #[test]
+    fn test_evaluation_rounds() {
+        let mut protocol = Protocol::new(FederatedLearningSettings {
+            evaluation: Some(EvaluationSettings { every: 1 }),
+            ..get_default_fl_settings()
+        });
+        // Run a successful aggregation and return the first event
+        fn end_aggregation(protocol: &mut Protocol) -> Event {
+            protocol.waiting_for_aggregation = true;
+            protocol.end_aggregation(true);
+            let event = protocol.next_event().unwrap();
+            while protocol.next_event().is_some() {}
+            event
+        }
+
+        assert_eq!(end_aggregation(&mut protocol), Event::EndRound(0));
+        assert_eq!(protocol.current_round, 1);
+        assert!(protocol.is_evaluating());
+        assert!(protocol.training_config().evaluation);
+
+        assert_eq!(end_aggregation(&mut protocol), Event::EndEvaluation(1));
+        assert_eq!(protocol.current_round, 1);
+        assert!(!protocol.is_evaluating());
+        assert!(!protocol.training_config().evaluation);
+
+        assert_eq!(end_aggregation(&mut protocol), Event::EndRound(1));
+        assert_eq!(protocol.current_round, 2);
+        assert!(protocol.is_evaluating());
+        assert_eq!(protocol.is_training_complete, false);
+
+        assert_eq!(end_aggregation(&mut protocol), Event::EndEvaluation(2));
+        assert_eq!(protocol.current_round, 2);
+        assert_eq!(protocol.is_training_complete, true);
+    }
+
+    /// Test that the evaluation round starts over when its
+    /// aggregation fails.
+    #[test]
+    fn test_evaluation_failure() {
+        let mut protocol = Protocol::new(FederatedLearningSettings {
+            evaluation: Some(EvaluationSettings { every: 2 }),
+            ..get_default_fl_settings()
+        });
+        protocol.waiting_for_aggregation = true;
+        protocol.end_aggregation(true);
+        assert_eq!(protocol.next_event().unwrap(), Event::EndRound(0));
+        // The first round is not evaluated
+        assert!(!protocol.is_evaluating());
+
+        protocol.current_round = 1;
+        protocol.waiting_for_aggregation = true;
+        protocol.end_aggregation(true);
+        assert!(protocol.is_evaluating());
+        while protocol.next_event().is_some() {}
+
+        protocol.waiting_for_aggregation = true;
+        protocol.end_aggregation(false);
+        assert_eq!(protocol.next_event().unwrap(), Event::AbortRound(2));
+        assert!(protocol.is_evaluating());
+        assert_eq!(protocol.is_training_complete, false);
+    }
+
+    /// Test that finishing the training while the aggregation is
+    /// running makes the current round the last one.
+    #[test]
+    fn test_finish_training_waiting_for_aggregation() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        protocol.waiting_for_aggregation = true;
+
+        protocol.finish_training().unwrap();
+        assert_eq!(protocol.is_training_complete, false);
         assert!(protocol.next_event().is_none());
+
+        protocol.end_aggregation(true);
+        assert_eq!(protocol.is_training_complete, true);
+        assert_eq!(protocol.next_event().unwrap(), Event::EndRound(0));
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that extending the training adds rounds, such that the
+    /// last round configured initially doesn't complete the training.
+    #[test]
+    fn test_extend_training() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        // rounds start at 0. The settings specify two rounds, so the
+        // last round correspond to current_round = 1
+        protocol.current_round = 1;
+        protocol.waiting_for_aggregation = true;
+
+        assert!(protocol.extend_training(0).is_err());
+        protocol.extend_training(1).unwrap();
+        protocol.end_aggregation(true);
+
+        assert_eq!(protocol.current_round, 2);
+        assert_eq!(protocol.is_training_complete, false);
+        assert_eq!(protocol.next_event().unwrap(), Event::EndRound(1));
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that the training cannot be extended once it is complete.
+    #[test]
+    fn test_extend_training_training_complete() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        protocol.is_training_complete = true;
+
+        assert!(protocol.extend_training(1).is_err());
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test the outcome of evicting a client in each possible state.
+    #[test]
+    fn test_evict() {
+        let client_id = ClientId::new();
+        let cases = vec![
+            (
+                ClientState::Waiting,
+                Counters {
+                    waiting: 1,
+                    ..Default::default()
+                },
+            ),
+            (
+                ClientState::Selected,
+                Counters {
+                    selected: 1,
+                    ..Default::default()
+                },
+            ),
+            (
+                ClientState::DoneAndInactive,
+                Counters {
+                    done_and_inactive: 1,
+                    ..Default::default()
+                },
+            ),
+            (
+                ClientState::Ignored,
+                Counters {
+                    ignored: 1,
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (state, counters) in cases.into_iter() {
+            let mut protocol = Protocol::new(get_default_fl_settings());
+            protocol.counters = counters;
+
+            protocol.evict(client_id, state).unwrap();
+
+            assert_eq!(protocol.counters(), Counters::new());
+            assert_eq!(protocol.next_event().unwrap(), Event::Remove(client_id));
+            assert!(protocol.next_event().is_none());
+        }
+    }
+
+    /// Test that evicting a selected participant triggers the
+    /// selection of a new one.
+    #[test]
+    fn test_evict_selected_participant_run_selection() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        let client_id = ClientId::new();
+        protocol.counters = Counters {
+            waiting: 1,
+            selected: 1,
+            ..Default::default()
+        };
+
+        protocol.evict(client_id, ClientState::Selected).unwrap();
+
+        let counters = protocol.counters();
+        let expected = Counters {
+            waiting: 1,
+            ..Default::default()
+        };
+        assert_eq!(counters, expected);
+        assert_eq!(protocol.next_event().unwrap(), Event::Remove(client_id));
+        assert_eq!(protocol.next_event().unwrap(), Event::RunSelection(1));
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that a participant evicted after training is still counted
+    /// as done, and that the round is complete once the other
+    /// participants are done or evicted.
+    #[test]
+    fn test_evict_done_participant() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        let client_1 = ClientId::new();
+        let client_2 = ClientId::new();
+        protocol.counters = Counters {
+            selected: 1,
+            done: 1,
+            ..Default::default()
+        };
+
+        protocol.evict(client_1, ClientState::Done).unwrap();
+        let expected = Counters {
+            selected: 1,
+            done_and_inactive: 1,
+            ..Default::default()
+        };
+        assert_eq!(protocol.counters(), expected);
+        assert_eq!(
+            protocol.next_event().unwrap(),
+            Event::SetState(client_1, ClientState::DoneAndInactive)
+        );
+        assert!(protocol.next_event().is_none());
+
+        protocol.evict(client_2, ClientState::Selected).unwrap();
+        assert_eq!(protocol.counters(), Counters::new());
+        assert_eq!(protocol.next_event().unwrap(), Event::Remove(client_2));
+        assert_eq!(protocol.next_event().unwrap(), Event::RunAggregation);
+        assert_eq!(protocol.next_event().unwrap(), Event::ResetAll);
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that evicting the last participant that is done starts
+    /// the aggregation of the weights it uploaded.
+    #[test]
+    fn test_evict_last_done_participant() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        let client_id = ClientId::new();
+        protocol.counters = Counters {
+            done: 1,
+            ..Default::default()
+        };
+
+        protocol.evict(client_id, ClientState::Done).unwrap();
+        assert_eq!(protocol.counters(), Counters::new());
+        assert_eq!(
+            protocol.next_event().unwrap(),
+            Event::SetState(client_id, ClientState::DoneAndInactive)
+        );
+        assert_eq!(protocol.next_event().unwrap(), Event::RunAggregation);
+        assert_eq!(protocol.next_event().unwrap(), Event::ResetAll);
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that evicting a client the protocol doesn't know about
+    /// is rejected.
+    #[test]
+    fn test_evict_unknown_client() {
+        let mut protocol = Protocol::new(get_default_fl_settings());
+        let client_id = ClientId::new();
+
+        assert!(protocol.evict(client_id, ClientState::Unknown).is_err());
+        assert_eq!(protocol.counters(), Counters::new());
+        assert!(protocol.next_event().is_none());
+    }
+
+    fn get_async_fl_settings() -> FederatedLearningSettings {
+        FederatedLearningSettings {
+            mode: Mode::Async {
+                concurrency: 2,
+                buffer_size: 2,
+            },
+            ..get_default_fl_settings()
+        }
+    }
+
+    /// Test that in asynchronous mode, at most `concurrency` clients
+    /// are selected at the same time.
+    #[test]
+    fn test_async_selection() {
+        let mut protocol = Protocol::new(get_async_fl_settings());
+        let clients: Vec<ClientId> = (0..3).map(|_| create_participant(&mut protocol)).collect();
+        while protocol.next_event().is_some() {}
+
+        protocol.select(clients.iter().map(|id| (*id, ClientState::Waiting)));
+        let expected = Counters {
+            waiting: 1,
+            selected: 2,
+            ..Default::default()
+        };
+        assert_eq!(protocol.counters(), expected);
+        assert_eq!(
+            protocol.next_event().unwrap(),
+            Event::SetState(clients[0], ClientState::Selected)
+        );
+        assert_eq!(
+            protocol.next_event().unwrap(),
+            Event::SetState(clients[1], ClientState::Selected)
+        );
+        assert!(protocol.next_event().is_none());
+    }
+
+    /// Test that in asynchronous mode, participants go back to the
+    /// waiting state after training, and that an aggregation starts
+    /// every `buffer_size` updates, while clients keep being
+    /// selected.
+    #[test]
+    fn test_async_end_training() {
+        let mut protocol = Protocol::new(get_async_fl_settings());
+        let clients: Vec<ClientId> = (0..3).map(|_| create_participant(&mut protocol)).collect();
+        protocol.select(clients.iter().map(|id| (*id, ClientState::Waiting)));
+        while protocol.next_event().is_some() {}
+
+        protocol.end_training(clients[0], true, ClientState::Selected);
+        assert_eq!(
+            protocol.next_event().unwrap(),
+            Event::SetState(clients[0], ClientState::Waiting)
+        );
+        // A client is waiting and there is room for a new participant
+        assert_eq!(protocol.next_event().unwrap(), Event::RunSelection(1));
+        assert!(protocol.next_event().is_none());
+
+        protocol.select(vec![(clients[2], ClientState::Waiting)].into_iter());
+        while protocol.next_event().is_some() {}
+
+        protocol.end_training(clients[1], true, ClientState::Selected);
+        assert_eq!(
+            protocol.next_event().unwrap(),
+            Event::SetState(clients[1], ClientState::Waiting)
+        );
+        assert_eq!(protocol.next_event().unwrap(), Event::RunAggregation);
+        // Selection goes on while the aggregation runs
+        assert_eq!(protocol.next_event().unwrap(), Event::RunSelection(1));
+        assert!(protocol.next_event().is_none());
+
+        // Updates received during the aggregation are buffered
+        protocol.end_training(clients[2], true, ClientState::Selected);
+        protocol.select(vec![(clients[0], ClientState::Waiting)].into_iter());
+        protocol.end_training(clients[0], true, ClientState::Selected);
+        while protocol.next_event().is_some() {}
+        assert_eq!(protocol.schedule.buffered(), 2);
+
+        // ... and merged as soon as the aggregation finishes
+        protocol.end_aggregation(true);
+        assert_eq!(protocol.next_event().unwrap(), Event::EndRound(0));
+        assert_eq!(protocol.next_event().unwrap(), Event::RunAggregation);
+        assert_eq!(protocol.current_round, 1);
+        assert_eq!(protocol.schedule.buffered(), 0);
+    }
+
+    /// Test that in asynchronous mode, the training is complete once
+    /// the given number of aggregations is reached.
+    #[test]
+    fn test_async_training_complete() {
+        let mut protocol = Protocol::new(FederatedLearningSettings {
+            rounds: 1,
+            ..get_async_fl_settings()
+        });
+        let clients: Vec<ClientId> = (0..2).map(|_| create_participant(&mut protocol)).collect();
+        protocol.select(clients.iter().map(|id| (*id, ClientState::Waiting)));
+        end_training(
+            &mut protocol,
+            clients
+                .iter()
+                .map(|id| (*id, ClientState::Selected))
+                .collect(),
+        );
+        protocol.end_aggregation(true);
+        assert!(protocol.is_training_complete);
+        assert_eq!(
+            protocol.start_training(ClientState::Selected),
+            StartTrainingResponse::Reject
+        );
     }
 
     fn create_participant(protocol: &mut Protocol) -> ClientId {
@@ -1243,6 +2078,12 @@ mod tests {
             participants_ratio: 1.0,
             min_clients: n_of_clients,
             heartbeat_timeout: 15,
+            max_clients: None,
+            admission_policy: AdmissionPolicy::Reject,
+            mode: Mode::Sync,
+            max_aggregation_failures: 3,
+            training: TrainingSettings::default(),
+            evaluation: None,
         };
 
         let mut protocol = Protocol::new(settings);
@@ -0,0 +1,197 @@
+use crate::coordinator::{
+    core::protocol::{ClientState, Counters},
+    settings::{FederatedLearningSettings, Mode},
+};
+
+/// The rules of the protocol that depend on how the participants are
+/// scheduled, see [`Mode`]. The protocol picks them once when it is
+/// created, and is otherwise the same in all the modes.
+pub trait Schedule: Send {
+    /// Number of participants to select in addition to the current
+    /// ones, if any. `aggregating` is whether an aggregation is
+    /// running.
+    fn clients_to_select(
+        &self,
+        counters: &Counters,
+        settings: &FederatedLearningSettings,
+        aggregating: bool,
+    ) -> Option<u32>;
+
+    /// Whether participants can finish training while an
+    /// aggregation is running
+    fn accepts_updates_while_aggregating(&self) -> bool;
+
+    /// Count the end of the training of a participant, and return
+    /// the state the participant goes to
+    fn end_training(&mut self, success: bool) -> ClientState;
+
+    /// Whether the updates received so far must be aggregated, given
+    /// the number of participants that remain to be selected. It is
+    /// only called when no aggregation is running.
+    fn is_aggregation_due(&self, counters: &Counters, clients_to_select: Option<u32>) -> bool;
+
+    /// Hand the updates received so far over to a new aggregation,
+    /// and return whether the aggregation ends the round, in which
+    /// case all the clients are reset.
+    fn start_aggregation(&mut self) -> bool;
+
+    /// Number of updates received since the last aggregation started,
+    /// that the counters don't account for
+    fn buffered(&self) -> u32;
+
+    /// Discard the updates received since the last aggregation
+    /// started
+    fn reset(&mut self);
+}
+
+/// Return the rules for the given mode
+pub fn for_mode(mode: Mode) -> Box<dyn Schedule> {
+    match mode {
+        Mode::Sync => Box::new(Rounds),
+        Mode::Async {
+            concurrency,
+            buffer_size,
+        } => Box::new(Buffered {
+            concurrency,
+            buffer_size,
+            buffered: 0,
+        }),
+    }
+}
+
+/// Synchronous rounds: participants are selected for a round, and
+/// their weights are aggregated once all of them finished training.
+struct Rounds;
+
+impl Schedule for Rounds {
+    fn clients_to_select(
+        &self,
+        counters: &Counters,
+        settings: &FederatedLearningSettings,
+        aggregating: bool,
+    ) -> Option<u32> {
+        if aggregating {
+            return None;
+        }
+
+        let Counters {
+            waiting,
+            selected,
+            done,
+            done_and_inactive,
+            ..
+        } = *counters;
+
+        let total_participants = selected + done + done_and_inactive;
+        if total_participants >= settings.minimum_participants() {
+            return None;
+        }
+
+        // We need to select more clients. But do we have enough
+        // clients to perform the selection?
+        let total_clients = total_participants + waiting;
+        if total_clients < settings.min_clients {
+            return None;
+        }
+
+        let total_to_select =
+            f64::ceil(settings.participants_ratio * total_clients as f64) as i64 as u32;
+        Some(total_to_select - total_participants)
+    }
+
+    fn accepts_updates_while_aggregating(&self) -> bool {
+        false
+    }
+
+    fn end_training(&mut self, success: bool) -> ClientState {
+        if success {
+            ClientState::Done
+        } else {
+            ClientState::Ignored
+        }
+    }
+
+    /// The round is complete once all the participants finished
+    /// training and no more participants are needed
+    fn is_aggregation_due(&self, counters: &Counters, clients_to_select: Option<u32>) -> bool {
+        counters.done + counters.done_and_inactive > 0
+            && counters.selected == 0
+            && clients_to_select.is_none()
+    }
+
+    fn start_aggregation(&mut self) -> bool {
+        true
+    }
+
+    fn buffered(&self) -> u32 {
+        0
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Asynchronous training (FedBuff): participants go back to the
+/// [`ClientState::Waiting`] state once they finished training, and
+/// their updates are aggregated every `buffer_size` of them. Updates
+/// received while an aggregation runs are merged by the next one.
+struct Buffered {
+    concurrency: u32,
+    buffer_size: u32,
+    /// Number of updates received since the last aggregation started
+    buffered: u32,
+}
+
+impl Schedule for Buffered {
+    /// Clients keep being selected while the aggregation is running,
+    /// as long as less than `concurrency` clients are training.
+    fn clients_to_select(
+        &self,
+        counters: &Counters,
+        settings: &FederatedLearningSettings,
+        _aggregating: bool,
+    ) -> Option<u32> {
+        let Counters {
+            waiting, selected, ..
+        } = *counters;
+        if waiting == 0 || selected >= self.concurrency || waiting + selected < settings.min_clients
+        {
+            return None;
+        }
+        Some(self.concurrency - selected)
+    }
+
+    fn accepts_updates_while_aggregating(&self) -> bool {
+        true
+    }
+
+    fn end_training(&mut self, success: bool) -> ClientState {
+        if success {
+            self.buffered += 1;
+        }
+        ClientState::Waiting
+    }
+
+    fn is_aggregation_due(&self, _counters: &Counters, _clients_to_select: Option<u32>) -> bool {
+        self.buffered >= self.buffer_size
+    }
+
+    fn start_aggregation(&mut self) -> bool {
+        info!("{} updates received", self.buffered);
+        self.buffered = 0;
+        false
+    }
+
+    fn buffered(&self) -> u32 {
+        self.buffered
+    }
+
+    fn reset(&mut self) {
+        self.buffered = 0;
+    }
+}
+
+impl FederatedLearningSettings {
+    fn minimum_participants(&self) -> u32 {
+        (self.participants_ratio * self.min_clients as f64) as i64 as u32
+    }
+}
@@ -1,35 +1,67 @@
 #[cfg(feature = "influx_metrics")]
-use crate::common::metric_store::influxdb::{CountersMeasurement, Measurement, RoundMeasurement};
+use crate::common::metric_store::influxdb::{
+    CountersMeasurement, Measurement, RoundMeasurement, TrainingMeasurement,
+};
 use crate::{
-    aggregator,
-    common::client::{ClientId, Credentials, Token},
+    aggregator::{self, service::RoundConfig},
+    common::{
+        client::{ClientId, Credentials, Token},
+        rpc::{self, RequestId},
+        settings::RpcCallSettings,
+    },
     coordinator::{
         core::{
+            ban_list::BanList,
             client::{Clients, HeartBeatResetError},
             protocol,
+            subscription::{self, HeartBeatStream, Subscription},
+            training_metrics::TrainingMetricsSum,
+        },
+        models::{
+            AdminCommand, AdminResponse, HeartBeatResponse, RendezVousResponse,
+            RoundTrainingMetrics, StartTrainingResponse, TrainingMetrics,
         },
-        models::{HeartBeatResponse, RendezVousResponse, StartTrainingResponse},
-        settings::FederatedLearningSettings,
+        settings::{EvictionSettings, FederatedLearningSettings},
     },
 };
 use derive_more::From;
-use futures::{ready, stream::Stream};
+use futures::{
+    future::{self, BoxFuture, FutureExt, Shared},
+    ready,
+    stream::Stream,
+};
 use std::{
+    collections::{HashMap, HashSet},
     future::Future,
+    mem,
+    net::IpAddr,
     pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
-use tarpc::context::current as rpc_context;
 use tokio::{
     stream::StreamExt,
     sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
+    time::{interval_at, Instant, Interval},
 };
-use nix::sys::signal;
-use nix::unistd::Pid;
+use tracing_futures::Instrument;
+
+/// How the training of a task ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrainingOutcome {
+    /// All the rounds were completed, or an operator ended the
+    /// training early
+    Complete,
+    /// The training was aborted because the aggregation failed too
+    /// many times in a row
+    Aborted,
+    /// The service stopped before the end of the training, because
+    /// its requests channel was closed
+    Interrupted,
+}
 
 struct AggregationFuture(Pin<Box<dyn Future<Output = Result<(), ()>> + Send>>);
 
@@ -41,15 +73,128 @@ impl Future for AggregationFuture {
 }
 
 impl AggregationFuture {
-    fn new(mut rpc_client: aggregator::rpc::Client) -> Self {
+    /// Aggregate the weights uploaded for the given round. If the
+    /// aggregation fails, no aggregator keeps the weights it
+    /// produced.
+    fn new(aggregators: &[AggregatorClient], rpc_settings: RpcCallSettings, round: u32) -> Self {
+        let timeout = Duration::from_secs(rpc_settings.aggregation_timeout);
+        let mut rpc_clients: Vec<aggregator::rpc::Client> = aggregators
+            .iter()
+            .map(|aggregator| aggregator.rpc_client.clone())
+            .collect();
+        // The retries of a request carry the same ID, so that the
+        // aggregators don't aggregate the same weights twice
+        let request_id = RequestId::new();
+        if rpc_clients.len() == 1 {
+            // UNWRAP_SAFE: there is exactly one client
+            let rpc_client = rpc_clients.pop().unwrap();
+            return Self(Box::pin(async move {
+                rpc::retry(&rpc_settings, "aggregate", || {
+                    let mut rpc_client = rpc_client.clone();
+                    async move {
+                        rpc_client
+                            .aggregate(rpc::context(timeout), request_id)
+                            .await
+                    }
+                })
+                .await
+                .map_err(|e| {
+                    error!(error=%e, "failed to perform aggregation");
+                })
+            }));
+        }
+
+        // The clients are spread over several aggregators: each
+        // aggregator aggregates the weights it received, and then
+        // every aggregator merges the partial aggregations, so that
+        // they all serve the same global weights.
         Self(Box::pin(async move {
-            rpc_client.aggregate(rpc_context()).await.map_err(|e| {
-                error!(error=%e, "failed to perform aggregation");
-            })
+            // A partial aggregation consumes the weights uploaded to
+            // the aggregator, so it is not retried
+            let partials = future::try_join_all(rpc_clients.iter().cloned().map(
+                |mut rpc_client| async move {
+                    rpc_client.partial_aggregate(rpc::context(timeout)).await
+                },
+            ))
+            .await
+            .map_err(|e| {
+                error!(error=%e, "failed to perform partial aggregation");
+            })?;
+            let results = future::join_all(rpc_clients.iter().cloned().map(|rpc_client| {
+                let partials = partials.clone();
+                async move {
+                    rpc::retry(&rpc_settings, "merge", || {
+                        let mut rpc_client = rpc_client.clone();
+                        let partials = partials.clone();
+                        async move {
+                            rpc_client
+                                .merge(rpc::context(timeout), request_id, partials)
+                                .await
+                        }
+                    })
+                    .await
+                }
+            }))
+            .await;
+            let error = match results.iter().find_map(|result| result.as_ref().err()) {
+                Some(e) => e,
+                None => return Ok(()),
+            };
+            error!(error=%error, "failed to merge the partial aggregations");
+            // The aggregators that merged already serve new global
+            // weights. They are rolled back right away, rather than
+            // when the round is aborted, so that the aggregators
+            // serve the same weights even if the training ends
+            // here. The others may still be merging, so they are
+            // reset as well.
+            if results.iter().any(Result::is_ok) {
+                warn!("rolling back the aggregators that merged the partial aggregations");
+                reset_all(rpc_clients, rpc_settings, round).await;
+            }
+            Err(())
         }))
     }
 }
 
+/// Reset the given aggregators for the given round, see
+/// [`aggregator::rpc::Client::reset`]. Resetting an aggregator twice is
+/// harmless: the global weights are only rolled back once.
+async fn reset_all(
+    rpc_clients: Vec<aggregator::rpc::Client>,
+    rpc_settings: RpcCallSettings,
+    round: u32,
+) {
+    let timeout = Duration::from_secs(rpc_settings.timeout);
+    let resets = rpc_clients.into_iter().map(|rpc_client| {
+        async move {
+            let result = rpc::retry(&rpc_settings, "reset", || {
+                let mut rpc_client = rpc_client.clone();
+                async move { rpc_client.reset(rpc::context(timeout), round).await }
+            })
+            .await;
+            if let Err(e) = result {
+                error!(error=%e, "failed to reset the aggregator");
+            }
+        }
+        .instrument(trace_span!("reset_rpc_request", round = round))
+    });
+    future::join_all(resets).await;
+}
+
+/// An aggregator the selected clients can upload their weights to
+#[derive(Clone)]
+pub struct AggregatorClient {
+    /// URL of the aggregator for clients to download/upload model
+    /// weights
+    pub url: String,
+
+    /// RPC client for the aggregator service. The RPC client
+    /// automatically tried to reconnect when the connection shuts
+    /// down, so after the initial connection, it is always
+    /// available.
+    pub rpc_client: aggregator::rpc::Client,
+}
+
 pub struct Service<S>
 where
     S: Selector,
@@ -62,6 +207,23 @@ where
     // done_training_expirations_rx: UnboundedReceiver<ClientId>,
     heartbeat_expirations_rx: UnboundedReceiver<ClientId>,
 
+    /// Push channels opened by the clients to be notified of the
+    /// changes of their heartbeat response
+    subscriptions: HashMap<ClientId, Subscription>,
+
+    /// Clients whose heartbeat response may have changed since it
+    /// was last pushed, or `None` if all of them may have changed
+    pending_pushes: Option<HashSet<ClientId>>,
+
+    /// Ticks at which the clients with an open push channel are
+    /// considered to have sent a heartbeat. It only runs while there
+    /// are open push channels.
+    subscriptions_keepalive: Option<Interval>,
+
+    /// Delay after which a client that doesn't send any heartbeat is
+    /// considered gone
+    heartbeat_timeout: Duration,
+
     /// Protocol state machine
     protocol: protocol::Protocol,
 
@@ -71,18 +233,53 @@ where
     /// Type that performs the selection
     selector: S,
 
-    /// URL of the aggregator for clients to download/upload model weights
-    aggregator_url: String,
+    /// Policy for evicting and banning misbehaving clients
+    eviction_settings: EvictionSettings,
 
-    /// RPC client for the aggregator service. The RPC client
-    /// automatically tried to reconnect when the connection shuts
-    /// down, so after the initial connection, it is always available.
-    rpc_client: aggregator::rpc::Client,
+    /// Addresses from which clients are not allowed to connect
+    ban_list: BanList,
+
+    /// Addresses the clients connected from, used to ban them
+    addresses: HashMap<ClientId, IpAddr>,
+
+    /// Capabilities negotiated with the clients on rendez-vous, see
+    /// [`CAPABILITIES`](crate::coordinator::models::CAPABILITIES)
+    capabilities: HashMap<ClientId, Vec<&'static str>>,
+
+    /// Capabilities a client must support to be accepted
+    required_capabilities: Vec<&'static str>,
+
+    /// Aggregators the selected clients are spread over
+    aggregators: Vec<AggregatorClient>,
+
+    /// Index of the aggregator the next selected client is assigned
+    /// to
+    next_aggregator: usize,
+
+    /// Deadlines and retries of the calls to the aggregators
+    rpc_settings: RpcCallSettings,
 
     /// Future that resolve when the aggregator finishes the
     /// aggregation.
     aggregation_future: Option<AggregationFuture>,
 
+    /// Last round the aggregators were told about, and whether it is
+    /// an evaluation round
+    announced_round: Option<(u32, bool)>,
+
+    /// Reset of the aggregators sent when the last round was
+    /// aborted. The calls to the aggregators for the next rounds are
+    /// only sent once it completed, so that the reset cannot undo
+    /// them.
+    pending_reset: Option<Shared<BoxFuture<'static, ()>>>,
+
+    /// Training statistics reported by the participants during the
+    /// current round
+    training_metrics: TrainingMetricsSum,
+
+    /// Training statistics of the completed rounds
+    training_history: Vec<RoundTrainingMetrics>,
+
     requests: ServiceRequests,
 
     /// IDs of the clients that the selector picked, but that the
@@ -104,23 +301,50 @@ where
     pub fn new(
         selector: S,
         fl_settings: FederatedLearningSettings,
-        aggregator_url: String,
-        rpc_client: aggregator::rpc::Client,
+        eviction_settings: EvictionSettings,
+        ban_list: BanList,
+        aggregators: Vec<AggregatorClient>,
+        rpc_settings: RpcCallSettings,
         requests: ServiceRequests,
         #[cfg(feature = "influx_metrics")] metrics_tx: Option<UnboundedSender<Measurement>>,
     ) -> Self {
+        assert!(
+            !aggregators.is_empty(),
+            "at least one aggregator is required"
+        );
         let (heartbeat_expirations_tx, heartbeat_expirations_rx) = unbounded_channel();
 
         let heartbeat_timeout = Duration::from_secs(fl_settings.heartbeat_timeout);
+        // The clients can't choose which rounds they take part in, so
+        // they must all support evaluation rounds if there are any
+        let required_capabilities = if fl_settings.evaluation.is_some() {
+            vec!["evaluation"]
+        } else {
+            Vec::new()
+        };
         Self {
             selector,
+            eviction_settings,
+            ban_list,
+            addresses: HashMap::new(),
+            capabilities: HashMap::new(),
+            required_capabilities,
             heartbeat_expirations_rx,
+            subscriptions: HashMap::new(),
+            pending_pushes: Some(HashSet::new()),
+            subscriptions_keepalive: None,
+            heartbeat_timeout,
             clients: Clients::new(heartbeat_expirations_tx, heartbeat_timeout),
             protocol: protocol::Protocol::new(fl_settings),
             pending_selection: Vec::new(),
-            rpc_client,
+            aggregators,
+            next_aggregator: 0,
+            rpc_settings,
             aggregation_future: None,
-            aggregator_url,
+            announced_round: None,
+            pending_reset: None,
+            training_metrics: TrainingMetricsSum::default(),
+            training_history: Vec::new(),
             requests,
             #[cfg(feature = "influx_metrics")]
             metrics_tx,
@@ -129,12 +353,91 @@ where
 
     /// Handle the pending state machine events.
     fn handle_protocol_events(&mut self) {
+        let was_training_complete = self.protocol.is_training_complete;
         while let Some(event) = self.protocol.next_event() {
             self.dispatch_event(event);
         }
+        if self.protocol.is_training_complete != was_training_complete {
+            self.pending_pushes = None;
+        }
+        self.maybe_announce_round();
+        self.push_heartbeat_responses();
         self.sanity_checks();
     }
 
+    /// Push the heartbeat responses that changed to the clients with
+    /// an open push channel. The channel is closed once the client is
+    /// told to finish or is rejected.
+    fn push_heartbeat_responses(&mut self) {
+        let ids: Vec<ClientId> = match self.pending_pushes.replace(HashSet::new()) {
+            Some(ids) => ids
+                .into_iter()
+                .filter(|id| self.subscriptions.contains_key(id))
+                .collect(),
+            None => self.subscriptions.keys().cloned().collect(),
+        };
+        for id in ids {
+            let response = self
+                .protocol
+                .heartbeat_response(self.clients.get_state(&id));
+            // UNWRAP_SAFE: only the IDs of subscriptions are kept
+            let subscription = self.subscriptions.get_mut(&id).unwrap();
+            subscription.push(response.clone());
+            if let HeartBeatResponse::Finish | HeartBeatResponse::Reject = response {
+                self.subscriptions.remove(&id);
+            }
+        }
+    }
+
+    /// Mark the heartbeat response of the given client as possibly
+    /// changed
+    fn mark_pending_push(&mut self, id: ClientId) {
+        if let Some(ids) = self.pending_pushes.as_mut() {
+            ids.insert(id);
+        }
+    }
+
+    /// Notify the aggregators when a new round starts
+    fn maybe_announce_round(&mut self) {
+        let round = self.protocol.current_round();
+        let evaluation = self.protocol.is_evaluating();
+        if self.protocol.is_training_complete || self.announced_round == Some((round, evaluation)) {
+            return;
+        }
+        self.announced_round = Some((round, evaluation));
+        let config = RoundConfig {
+            rounds: self.protocol.rounds(),
+            evaluation,
+        };
+        let rpc_settings = self.rpc_settings;
+        let timeout = Duration::from_secs(rpc_settings.timeout);
+        for aggregator in self.aggregators.iter() {
+            let rpc_client = aggregator.rpc_client.clone();
+            let config = config.clone();
+            let reset = self.wait_for_reset();
+            tokio::spawn(
+                async move {
+                    reset.await;
+                    // Announcing a round twice is harmless
+                    let result = rpc::retry(&rpc_settings, "start_round", || {
+                        let mut rpc_client = rpc_client.clone();
+                        let config = config.clone();
+                        async move {
+                            rpc_client
+                                .start_round(rpc::context(timeout), round, config)
+                                .await
+                        }
+                    })
+                    .await;
+                    if let Err(e) = result {
+                        error!(error=%e, "failed to notify the aggregator of the start of the round");
+                    }
+                }
+                .instrument(trace_span!("start_round_rpc_request", round = round)),
+            );
+        }
+    }
+
     /// Handle the incoming requests.
     fn poll_requests(&mut self, cx: &mut Context) -> Poll<()> {
         trace!("polling requests");
@@ -154,21 +457,58 @@ where
         match request {
             Request::RendezVous(req) => self.handle_rendez_vous_request(req),
             Request::HeartBeat(req) => self.handle_heartbeat_request(req),
+            Request::Subscribe(req) => self.handle_subscribe_request(req),
             Request::StartTraining(req) => self.handle_start_training_request(req),
             Request::EndTraining(req) => self.handle_end_training_request(req),
+            Request::Admin(req) => self.handle_admin_request(req),
+            Request::TrainingMetrics(req) => self.handle_training_metrics_request(req),
         }
     }
     /// Handle a rendez-vous request
     fn handle_rendez_vous_request(&mut self, req: RendezVousRequest) {
         debug!("handling rendez-vous request");
-        let RendezVousRequest { response_tx } = req;
+        let RendezVousRequest {
+            addr,
+            capabilities,
+            response_tx,
+        } = req;
+        if let Some(addr) = addr {
+            if self.ban_list.contains(&addr) {
+                info!("rejecting rendez-vous request from banned address {}", addr);
+                if response_tx.send(RendezVousResponse::Reject).is_err() {
+                    warn!("failed to send response back: channel closed");
+                }
+                return;
+            }
+        }
+        if let Some(capability) = self
+            .required_capabilities
+            .iter()
+            .find(|capability| !capabilities.contains(capability))
+        {
+            info!(
+                "rejecting rendez-vous request from client without the {} capability",
+                capability
+            );
+            if response_tx.send(RendezVousResponse::Reject).is_err() {
+                warn!("failed to send response back: channel closed");
+            }
+            return;
+        }
         let id = ClientId::new();
         // This should be "Unknown" since we just created a
         // new uuid.
         let status = self.clients.get_state(&id);
         let response = match self.protocol.rendez_vous(id, status) {
-            protocol::RendezVousResponse::Accept => RendezVousResponse::Accept(id),
+            protocol::RendezVousResponse::Accept => {
+                if let Some(addr) = addr {
+                    self.addresses.insert(id, addr);
+                }
+                self.capabilities.insert(id, capabilities);
+                RendezVousResponse::Accept(id)
+            }
             protocol::RendezVousResponse::Reject => RendezVousResponse::Reject,
+            protocol::RendezVousResponse::Retry(delay) => RendezVousResponse::Retry(delay),
         };
         if response_tx.send(response).is_err() {
             warn!("failed to send response back: channel closed");
@@ -186,6 +526,55 @@ where
         }
     }
 
+    /// Handle a request to open a push channel. Like a heartbeat, the
+    /// request resets the heartbeat of the client.
+    fn handle_subscribe_request(&mut self, req: SubscribeRequest) {
+        debug!("handling subscribe request");
+        let SubscribeRequest { id, response_tx } = req;
+        let response = self.protocol.heartbeat(id, self.clients.get_state(&id));
+        let (subscription, stream) = subscription::channel(response.clone());
+        // A client that is rejected or told to finish gets a single
+        // response. A previous channel of the client is closed.
+        match response {
+            HeartBeatResponse::Finish | HeartBeatResponse::Reject => {
+                self.subscriptions.remove(&id);
+            }
+            _ => {
+                self.subscriptions.insert(id, subscription);
+                // The heartbeats of the clients with a push channel
+                // must be refreshed before they expire
+                let period = self.heartbeat_timeout / 2;
+                self.subscriptions_keepalive
+                    .get_or_insert_with(|| interval_at(Instant::now() + period, period));
+            }
+        }
+        if response_tx.send(stream).is_err() {
+            warn!("failed to send response back: channel closed");
+        }
+    }
+
+    /// Count an open push channel as a heartbeat, and drop the
+    /// channels that the clients closed
+    fn poll_subscriptions_keepalive(&mut self, cx: &mut Context) {
+        while let Some(keepalive) = self.subscriptions_keepalive.as_mut() {
+            if keepalive.poll_tick(cx).is_pending() {
+                return;
+            }
+            self.subscriptions
+                .retain(|_, subscription| subscription.is_alive());
+            if self.subscriptions.is_empty() {
+                self.subscriptions_keepalive = None;
+                return;
+            }
+            let ids: Vec<ClientId> = self.subscriptions.keys().cloned().collect();
+            for id in ids {
+                let state = self.clients.get_state(&id);
+                self.protocol.heartbeat(id, state);
+            }
+            self.handle_protocol_events();
+        }
+    }
+
     /// Handle a start training request
     fn handle_start_training_request(&mut self, req: StartTrainingRequest) {
         debug!("handling start training request");
@@ -197,29 +586,50 @@ where
                 }
             }
             protocol::StartTrainingResponse::Accept => {
-                let mut rpc_client = self.rpc_client.clone();
-                let url = self.aggregator_url.clone();
+                // Spread the clients evenly over the aggregators
+                let AggregatorClient { url, rpc_client } =
+                    self.aggregators[self.next_aggregator].clone();
+                self.next_aggregator = (self.next_aggregator + 1) % self.aggregators.len();
 
+                // The clients that can't be told how to train are
+                // left to their own configuration. All the clients
+                // support evaluation rounds if there are any, and
+                // they need the configuration to know that they must
+                // evaluate the model.
+                let config =
+                    if self.protocol.is_evaluating() || self.supports(&id, "training_config") {
+                        Some(self.protocol.training_config())
+                    } else {
+                        None
+                    };
+                let rpc_settings = self.rpc_settings;
+                let reset = self.wait_for_reset();
                 tokio::spawn(async move {
+                    reset.await;
                     let token = Token::new();
                     let credentials = Credentials(id, token);
-                    // FIXME: upon RPC failure or if the aggregator
-                    // returns an error, we currently just drop the
-                    // response channel. For the sake of clarity,
-                    // maybe we should probably return a proper error
-                    // instead.
-                    match rpc_client.select(rpc_context(), credentials).await {
-                        Ok(()) => {
-                            if response_tx
-                                .send(StartTrainingResponse::Accept(url, token))
-                                .is_err()
-                            {
-                                warn!("failed to send response back: channel closed");
-                            };
+                    let request_id = RequestId::new();
+                    let timeout = Duration::from_secs(rpc_settings.timeout);
+                    let result = rpc::retry(&rpc_settings, "select", || {
+                        let mut rpc_client = rpc_client.clone();
+                        async move {
+                            rpc_client
+                                .select(rpc::context(timeout), request_id, credentials)
+                                .await
                         }
+                    })
+                    .await;
+                    // The client is still selected, so it can send a
+                    // new start training request later
+                    let response = match result {
+                        Ok(()) => StartTrainingResponse::Accept(url, token, config),
                         Err(e) => {
                             warn!(error=%e, "select request failed");
+                            StartTrainingResponse::Unavailable
                         }
+                    };
+                    if response_tx.send(response).is_err() {
+                        warn!("failed to send response back: channel closed");
                     }
                 });
             }
@@ -229,23 +639,79 @@ where
     /// Handle a start training request
     fn handle_end_training_request(&mut self, req: EndTrainingRequest) {
         debug!("handling end training request");
-        let EndTrainingRequest { id, success } = req;
+        let EndTrainingRequest {
+            id,
+            success,
+            metrics,
+        } = req;
         let state = self.clients.get_state(&id);
+        // Only the statistics of the participants that trained during
+        // the current round are counted
+        if let (true, protocol::ClientState::Selected, Some(metrics)) = (success, state, metrics) {
+            self.training_metrics.add(&metrics);
+        }
         self.protocol.end_training(id, success, state);
     }
 
+    /// Handle a request for the training statistics of the completed
+    /// rounds
+    fn handle_training_metrics_request(&mut self, req: TrainingMetricsRequest) {
+        debug!("handling training metrics request");
+        let _ = req.response_tx.send(self.training_history.clone());
+    }
+
+    /// Handle a command sent by an operator
+    fn handle_admin_request(&mut self, req: AdminRequest) {
+        debug!("handling admin request");
+        let AdminRequest {
+            command,
+            response_tx,
+        } = req;
+        let result = match command {
+            AdminCommand::Pause => self.protocol.pause(),
+            AdminCommand::Resume => self.protocol.resume(),
+            AdminCommand::AbortRound => self.protocol.abort_round(),
+            AdminCommand::Finish => self.protocol.finish_training(),
+            AdminCommand::ExtendTraining(rounds) => self.protocol.extend_training(rounds),
+            AdminCommand::Evict(id) => self.evict(id, false),
+            AdminCommand::Ban(id) => self.evict(id, true),
+        };
+        let response = match result {
+            Ok(()) => AdminResponse::Accept,
+            Err(_) => AdminResponse::Reject,
+        };
+        if response_tx.send(response).is_err() {
+            warn!("failed to send response back: channel closed");
+        }
+    }
+
+    /// Evict the given client, and ban the address it connected from
+    /// if `ban` is `true`.
+    fn evict(&mut self, id: ClientId, ban: bool) -> Result<(), protocol::InvalidState> {
+        if ban {
+            match self.addresses.get(&id) {
+                Some(addr) => {
+                    if let Err(e) = self.ban_list.ban(*addr) {
+                        error!(error=%e, "failed to persist the ban list");
+                    }
+                }
+                None => warn!("cannot ban {}: address unknown", id),
+            }
+        }
+        let state = self.clients.get_state(&id);
+        self.protocol.evict(id, state)
+    }
+
     /// If there is an aggregation request running, poll the
     /// corresponding future
     fn poll_aggregation(&mut self, cx: &mut Context) -> Poll<()> {
         if let Some(ref mut fut) = self.aggregation_future {
             trace!("polling aggregation future");
             match ready!(Pin::new(fut).poll(cx)) {
-                // FIXME: there are lots of things to think about
-                // when aggregation has failed. Currently the
-                // protocol just doesn't increment the round
-                // number. But we also need to make sure that the
-                // aggregators is reset and that the global weights
-                // are not updated.
+                // When aggregation fails, the protocol aborts the
+                // round: the aggregators are reset, which rolls back
+                // their global weights if needed, and new
+                // participants are selected.
                 Ok(()) => {
                     info!("aggregation finished successfully");
                     self.protocol.end_aggregation(true);
@@ -268,9 +734,6 @@ where
                     debug!("heartbeat expired: {}", id);
                     let state = self.clients.get_state(&id);
                     self.protocol.heartbeat_timeout(id, state);
-                    if self.protocol.is_training_complete && self.protocol.counters().waiting == 0 {
-                        signal::kill(Pid::this(), signal::Signal::SIGINT).unwrap();
-                    }
                     self.handle_protocol_events();
                 }
                 None => return Poll::Ready(()),
@@ -305,7 +768,7 @@ impl<S> Future for Service<S>
 where
     S: Selector + Unpin,
 {
-    type Output = ();
+    type Output = TrainingOutcome;
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         trace!("polling Service");
         let pin = self.get_mut();
@@ -313,18 +776,27 @@ where
         pin.apply_pending_selection();
 
         match pin.poll_requests(cx) {
-            Poll::Ready(()) => return Poll::Ready(()),
+            Poll::Ready(()) => return Poll::Ready(TrainingOutcome::Interrupted),
             Poll::Pending => {}
         }
 
         match pin.poll_heartbeat_expirations(cx) {
-            Poll::Ready(()) => return Poll::Ready(()),
+            Poll::Ready(()) => return Poll::Ready(TrainingOutcome::Interrupted),
             Poll::Pending => {}
         }
 
-        match pin.poll_aggregation(cx) {
-            Poll::Ready(()) => Poll::Ready(()),
-            Poll::Pending => Poll::Pending,
+        pin.poll_subscriptions_keepalive(cx);
+
+        if let Poll::Ready(()) = pin.poll_aggregation(cx) {
+            return Poll::Ready(TrainingOutcome::Interrupted);
+        }
+
+        match pin.training_outcome() {
+            Some(outcome) => {
+                info!("training ended ({:?}), stopping the service", outcome);
+                Poll::Ready(outcome)
+            }
+            None => Poll::Pending,
         }
     }
 }
@@ -333,6 +805,21 @@ impl<S> Service<S>
 where
     S: Selector,
 {
+    /// Return how the training ended, once it ended and all the
+    /// clients were told about it. The clients get a
+    /// [`HeartBeatResponse::Finish`] response to their heartbeats,
+    /// and are considered gone once their heartbeat expires.
+    fn training_outcome(&self) -> Option<TrainingOutcome> {
+        if !self.protocol.is_training_complete || self.protocol.counters().active() > 0 {
+            return None;
+        }
+        if self.protocol.is_training_aborted {
+            Some(TrainingOutcome::Aborted)
+        } else {
+            Some(TrainingOutcome::Complete)
+        }
+    }
+
     /// Handle a [`Event::Accept`] event
     fn accept_client(&mut self, id: ClientId) {
         let heartbeat_timer = self.clients.add(id);
@@ -345,11 +832,18 @@ where
         // an error. If it does, our state is invalid, so it is OK to
         // panic.
         self.clients.remove(&id).expect("failed to remove client");
+        self.addresses.remove(&id);
+        self.capabilities.remove(&id);
     }
 
     /// Handle a [`Event::ResetAll`] event
     fn reset_all_clients(&mut self) {
         self.clients.reset();
+        let clients = &self.clients;
+        self.addresses
+            .retain(|id, _| clients.get_state(id) != protocol::ClientState::Unknown);
+        self.capabilities
+            .retain(|id, _| clients.get_state(id) != protocol::ClientState::Unknown);
     }
 
     /// Handle a [`Event::SetState`] event
@@ -371,16 +865,24 @@ where
                 HeartBeatResetError::Expired => {}
                 // This should not happen
                 HeartBeatResetError::ClientNotFound => panic!("{}", e),
-                // FIXME: we should remove the node, but our state
-                // machine doesn't support that yet, so we just return
-                // for now
                 HeartBeatResetError::BackPressure => {
-                    error!("seems like {} is flooding us with heartbeats", id)
+                    error!("seems like {} is flooding us with heartbeats", id);
+                    if self.eviction_settings.evict_flooding_clients {
+                        let ban = self.eviction_settings.ban_flooding_clients;
+                        // UNWRAP_SAFE: the client is active, otherwise
+                        // we would have gotten `ClientNotFound`
+                        self.evict(id, ban).unwrap();
+                    }
                 }
             },
         }
     }
 
+    /// Whether the given client supports the given capability
+    fn supports(&self, id: &ClientId, capability: &str) -> bool {
+        matches!(self.capabilities.get(id), Some(capabilities) if capabilities.contains(&capability))
+    }
+
     /// Handle a [`Event::RunSelection`] event
     fn run_selection(&mut self, min_count: u32) {
         if self.pending_selection.len() >= min_count as usize {
@@ -404,7 +906,70 @@ where
 
     /// Handle a [`Event::RunAggregation`] event
     fn run_aggregation(&mut self) {
-        self.aggregation_future = Some(AggregationFuture::new(self.rpc_client.clone()))
+        self.aggregation_future = Some(AggregationFuture::new(
+            &self.aggregators,
+            self.rpc_settings,
+            self.protocol.current_round(),
+        ))
+    }
+
+    /// Handle a [`Event::EndRound`] event
+    fn end_round(&mut self, round: u32) {
+        let metrics = mem::take(&mut self.training_metrics);
+        // The participants are not required to report statistics
+        if metrics.is_empty() {
+            return;
+        }
+        let metrics = metrics.average(round);
+        info!(?metrics, "training statistics of round {}", round);
+        #[cfg(feature = "influx_metrics")]
+        self.write_training_metrics(&metrics);
+        self.training_history.push(metrics);
+    }
+
+    /// Handle a [`Event::AbortRound`] event
+    fn abort_round(&mut self, round: u32) {
+        // The clients have been reset, so there is nothing left to
+        // select from the previous selection.
+        self.pending_selection.clear();
+        // The round starts over, and so do the statistics
+        self.training_metrics = TrainingMetricsSum::default();
+
+        let rpc_clients = self
+            .aggregators
+            .iter()
+            .map(|aggregator| aggregator.rpc_client.clone())
+            .collect();
+        let rpc_settings = self.rpc_settings;
+        // The events of the protocol are handled before the next round
+        // starts, so the reset is sent before any call for the next
+        // round
+        let previous = self.wait_for_reset();
+        let reset = async move {
+            previous.await;
+            reset_all(rpc_clients, rpc_settings, round).await;
+        }
+        .boxed()
+        .shared();
+        tokio::spawn(reset.clone());
+        self.pending_reset = Some(reset);
+    }
+
+    /// Return a future that resolves once the last reset of the
+    /// aggregators completed
+    fn wait_for_reset(&self) -> BoxFuture<'static, ()> {
+        // Drop the resets that already completed, so that they don't
+        // pile up
+        let reset = self
+            .pending_reset
+            .clone()
+            .filter(|reset| reset.peek().is_none());
+        async move {
+            if let Some(reset) = reset {
+                reset.await;
+            }
+        }
+        .boxed()
     }
 
     #[cfg(feature = "influx_metrics")]
@@ -430,10 +995,32 @@ where
         });
     }
 
+    #[cfg(feature = "influx_metrics")]
+    fn write_training_metrics(&self, metrics: &RoundTrainingMetrics) {
+        self.metrics_tx.as_ref().map(|tx| {
+            let _ = tx.send(
+                TrainingMeasurement::new(
+                    metrics.round,
+                    metrics.participants,
+                    metrics.samples,
+                    metrics.loss,
+                    metrics.accuracy,
+                    metrics.duration,
+                )
+                .into(),
+            );
+        });
+    }
+
     /// Dispatch an [`Event`] to the appropriate handler
     fn dispatch_event(&mut self, event: protocol::Event) {
         use protocol::Event::*;
         info!("handling protocol event {:?}", event);
+        match event {
+            Accept(id) | Remove(id) | SetState(id, _) => self.mark_pending_push(id),
+            ResetAll | EndRound(_) | EndEvaluation(_) | AbortRound(_) => self.pending_pushes = None,
+            _ => (),
+        }
         match event {
             Accept(id) => self.accept_client(id),
             Remove(id) => self.remove_client(id),
@@ -442,7 +1029,9 @@ where
             ResetHeartBeat(id) => self.reset_heartbeat(id),
             RunAggregation => self.run_aggregation(),
             RunSelection(min_count) => self.run_selection(min_count),
-            EndRound(_) => (),
+            EndRound(round) => self.end_round(round),
+            EndEvaluation(_) => (),
+            AbortRound(round) => self.abort_round(round),
         }
 
         #[cfg(feature = "influx_metrics")]
@@ -474,12 +1063,18 @@ impl ServiceRequests {
         start_training: UnboundedReceiver<StartTrainingRequest>,
         end_training: UnboundedReceiver<EndTrainingRequest>,
         heartbeat: UnboundedReceiver<HeartBeatRequest>,
+        subscribe: UnboundedReceiver<SubscribeRequest>,
+        admin: UnboundedReceiver<AdminRequest>,
+        training_metrics: UnboundedReceiver<TrainingMetricsRequest>,
     ) -> Self {
         let stream = rendez_vous
             .map(Request::from)
             .merge(start_training.map(Request::from))
             .merge(end_training.map(Request::from))
-            .merge(heartbeat.map(Request::from));
+            .merge(heartbeat.map(Request::from))
+            .merge(subscribe.map(Request::from))
+            .merge(admin.map(Request::from))
+            .merge(training_metrics.map(Request::from));
         Self(Box::pin(stream))
     }
 }
@@ -488,12 +1083,18 @@ impl ServiceRequests {
 pub enum Request {
     RendezVous(RendezVousRequest),
     HeartBeat(HeartBeatRequest),
+    Subscribe(SubscribeRequest),
     StartTraining(StartTrainingRequest),
     EndTraining(EndTrainingRequest),
+    Admin(AdminRequest),
+    TrainingMetrics(TrainingMetricsRequest),
 }
 
 #[derive(From)]
 pub struct RendezVousRequest {
+    addr: Option<IpAddr>,
+    /// Capabilities negotiated with the client
+    capabilities: Vec<&'static str>,
     response_tx: oneshot::Sender<RendezVousResponse>,
 }
 
@@ -503,6 +1104,12 @@ pub struct HeartBeatRequest {
     response_tx: oneshot::Sender<HeartBeatResponse>,
 }
 
+#[derive(From)]
+pub struct SubscribeRequest {
+    id: ClientId,
+    response_tx: oneshot::Sender<HeartBeatStream>,
+}
+
 #[derive(From)]
 pub struct StartTrainingRequest {
     id: ClientId,
@@ -513,6 +1120,19 @@ pub struct StartTrainingRequest {
 pub struct EndTrainingRequest {
     id: ClientId,
     success: bool,
+    /// Training statistics reported by the client, if any
+    metrics: Option<TrainingMetrics>,
+}
+
+#[derive(From)]
+pub struct AdminRequest {
+    command: AdminCommand,
+    response_tx: oneshot::Sender<AdminResponse>,
+}
+
+#[derive(From)]
+pub struct TrainingMetricsRequest {
+    response_tx: oneshot::Sender<Vec<RoundTrainingMetrics>>,
 }
 
 #[derive(Clone)]
@@ -521,6 +1141,9 @@ pub struct ServiceHandle {
     start_training: UnboundedSender<StartTrainingRequest>,
     end_training: UnboundedSender<EndTrainingRequest>,
     heartbeat: UnboundedSender<HeartBeatRequest>,
+    subscribe: UnboundedSender<SubscribeRequest>,
+    admin: UnboundedSender<AdminRequest>,
+    training_metrics: UnboundedSender<TrainingMetricsRequest>,
 }
 
 impl ServiceHandle {
@@ -529,24 +1152,41 @@ impl ServiceHandle {
         let (start_training_tx, start_training_rx) = unbounded_channel::<StartTrainingRequest>();
         let (end_training_tx, end_training_rx) = unbounded_channel::<EndTrainingRequest>();
         let (heartbeat_tx, heartbeat_rx) = unbounded_channel::<HeartBeatRequest>();
+        let (subscribe_tx, subscribe_rx) = unbounded_channel::<SubscribeRequest>();
+        let (admin_tx, admin_rx) = unbounded_channel::<AdminRequest>();
+        let (training_metrics_tx, training_metrics_rx) =
+            unbounded_channel::<TrainingMetricsRequest>();
 
         let handle = Self {
             rendez_vous: rendez_vous_tx,
             start_training: start_training_tx,
             heartbeat: heartbeat_tx,
+            subscribe: subscribe_tx,
             end_training: end_training_tx,
+            admin: admin_tx,
+            training_metrics: training_metrics_tx,
         };
         let service_requests = ServiceRequests::new(
             rendez_vous_rx,
             start_training_rx,
             end_training_rx,
             heartbeat_rx,
+            subscribe_rx,
+            admin_rx,
+            training_metrics_rx,
         );
         (handle, service_requests)
     }
-    pub async fn rendez_vous(&self) -> Result<RendezVousResponse, RequestError> {
+    pub async fn rendez_vous(
+        &self,
+        addr: Option<IpAddr>,
+        capabilities: Vec<&'static str>,
+    ) -> Result<RendezVousResponse, RequestError> {
         let (tx, rx) = oneshot::channel();
-        Self::send_request(RendezVousRequest::from(tx), &self.rendez_vous);
+        Self::send_request(
+            RendezVousRequest::from((addr, capabilities, tx)),
+            &self.rendez_vous,
+        );
         rx.await.map_err(|_| {
             warn!("could not receive response: channel closed");
             RequestError
@@ -562,6 +1202,18 @@ impl ServiceHandle {
         })
     }
 
+    /// Open a push channel, on which the changes of the heartbeat
+    /// response of the client are delivered as soon as they happen.
+    /// The client is considered alive while the channel is open.
+    pub async fn subscribe(&self, id: ClientId) -> Result<HeartBeatStream, RequestError> {
+        let (tx, rx) = oneshot::channel();
+        Self::send_request(SubscribeRequest::from((id, tx)), &self.subscribe);
+        rx.await.map_err(|_| {
+            warn!("could not receive response: channel closed");
+            RequestError
+        })
+    }
+
     pub async fn start_training(
         &self,
         id: ClientId,
@@ -574,8 +1226,35 @@ impl ServiceHandle {
         })
     }
 
-    pub async fn end_training(&self, id: ClientId, success: bool) {
-        Self::send_request(EndTrainingRequest::from((id, success)), &self.end_training);
+    pub async fn end_training(
+        &self,
+        id: ClientId,
+        success: bool,
+        metrics: Option<TrainingMetrics>,
+    ) {
+        Self::send_request(
+            EndTrainingRequest::from((id, success, metrics)),
+            &self.end_training,
+        );
+    }
+
+    pub async fn admin(&self, command: AdminCommand) -> Result<AdminResponse, RequestError> {
+        let (tx, rx) = oneshot::channel();
+        Self::send_request(AdminRequest::from((command, tx)), &self.admin);
+        rx.await.map_err(|_| {
+            warn!("could not receive response: channel closed");
+            RequestError
+        })
+    }
+
+    /// Get the training statistics of the completed rounds
+    pub async fn training_metrics(&self) -> Result<Vec<RoundTrainingMetrics>, RequestError> {
+        let (tx, rx) = oneshot::channel();
+        Self::send_request(TrainingMetricsRequest::from(tx), &self.training_metrics);
+        rx.await.map_err(|_| {
+            warn!("could not receive response: channel closed");
+            RequestError
+        })
     }
 
     fn send_request<P>(payload: P, chan: &UnboundedSender<P>) {
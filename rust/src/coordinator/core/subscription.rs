@@ -0,0 +1,99 @@
+use crate::coordinator::models::HeartBeatResponse;
+use futures::stream::Stream;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+
+/// Create a push channel for a client that is currently given the
+/// `initial` response to its heartbeats
+pub fn channel(initial: HeartBeatResponse) -> (Subscription, HeartBeatStream) {
+    let (responses_tx, responses_rx) = unbounded_channel();
+    let (alive_tx, alive_rx) = oneshot::channel();
+    let mut subscription = Subscription {
+        responses: responses_tx,
+        alive: alive_tx,
+        last: None,
+    };
+    let stream = HeartBeatStream {
+        responses: responses_rx,
+        _alive: alive_rx,
+    };
+    subscription.push(initial);
+    (subscription, stream)
+}
+
+/// The service side of a push channel opened by a client, to be
+/// notified immediately when its heartbeat response changes instead
+/// of polling.
+pub struct Subscription {
+    responses: UnboundedSender<HeartBeatResponse>,
+    /// Closed once the client side of the channel is dropped
+    alive: oneshot::Sender<()>,
+    /// Last response pushed to the client
+    last: Option<HeartBeatResponse>,
+}
+
+impl Subscription {
+    /// Whether the client is still listening
+    pub fn is_alive(&self) -> bool {
+        !self.alive.is_closed()
+    }
+
+    /// Push the given response, unless the client already got it
+    pub fn push(&mut self, response: HeartBeatResponse) {
+        if self.last.as_ref() == Some(&response) {
+            return;
+        }
+        if self.responses.send(response.clone()).is_err() {
+            debug!("failed to push heartbeat response: channel closed");
+        }
+        self.last = Some(response);
+    }
+}
+
+/// The client side of a push channel: a stream of the changes of the
+/// heartbeat response of a client. The coordinator considers the
+/// client alive as long as the stream is not dropped.
+pub struct HeartBeatStream {
+    responses: UnboundedReceiver<HeartBeatResponse>,
+    _alive: oneshot::Receiver<()>,
+}
+
+impl Stream for HeartBeatStream {
+    type Item = HeartBeatResponse;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.responses.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{FutureExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_push() {
+        let (mut subscription, mut stream) = channel(HeartBeatResponse::StandBy);
+        subscription.push(HeartBeatResponse::StandBy);
+        subscription.push(HeartBeatResponse::Round(1));
+        drop(subscription);
+
+        assert_eq!(stream.next().await, Some(HeartBeatResponse::StandBy));
+        assert_eq!(stream.next().await, Some(HeartBeatResponse::Round(1)));
+        assert_eq!(stream.next().now_or_never(), Some(None));
+    }
+
+    #[test]
+    fn test_is_alive() {
+        let (subscription, stream) = channel(HeartBeatResponse::StandBy);
+        assert!(subscription.is_alive());
+        drop(stream);
+        assert!(!subscription.is_alive());
+    }
+}
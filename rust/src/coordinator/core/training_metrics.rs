@@ -0,0 +1,110 @@
+use crate::coordinator::models::{RoundTrainingMetrics, TrainingMetrics};
+
+/// Weighted sum of the values of a statistic
+#[derive(Debug, Default)]
+struct WeightedSum {
+    sum: f64,
+    weight: f64,
+}
+
+impl WeightedSum {
+    fn add(&mut self, value: Option<f64>, weight: f64) {
+        if let Some(value) = value {
+            self.sum += value * weight;
+            self.weight += weight;
+        }
+    }
+
+    fn average(&self) -> Option<f64> {
+        if self.weight > 0.0 {
+            Some(self.sum / self.weight)
+        } else {
+            None
+        }
+    }
+}
+
+/// Training statistics reported by the participants during the
+/// current round
+#[derive(Debug, Default)]
+pub struct TrainingMetricsSum {
+    participants: u32,
+    samples: u64,
+    loss: WeightedSum,
+    accuracy: WeightedSum,
+    duration: WeightedSum,
+}
+
+impl TrainingMetricsSum {
+    /// Add the statistics reported by a participant
+    pub fn add(&mut self, metrics: &TrainingMetrics) {
+        let samples = metrics.samples as f64;
+        self.participants += 1;
+        self.samples += metrics.samples;
+        self.loss.add(metrics.loss, samples);
+        self.accuracy.add(metrics.accuracy, samples);
+        self.duration.add(metrics.duration, 1.0);
+    }
+
+    /// Whether no participant reported statistics
+    pub fn is_empty(&self) -> bool {
+        self.participants == 0
+    }
+
+    /// Compute the statistics of the given round
+    pub fn average(&self, round: u32) -> RoundTrainingMetrics {
+        RoundTrainingMetrics {
+            round,
+            participants: self.participants,
+            samples: self.samples,
+            loss: self.loss.average(),
+            accuracy: self.accuracy.average(),
+            duration: self.duration.average(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_average() {
+        let mut sum = TrainingMetricsSum::default();
+        sum.add(&TrainingMetrics {
+            samples: 10,
+            loss: Some(1.0),
+            accuracy: None,
+            duration: Some(2.0),
+        });
+        sum.add(&TrainingMetrics {
+            samples: 30,
+            loss: Some(2.0),
+            accuracy: Some(0.5),
+            duration: Some(4.0),
+        });
+        sum.add(&TrainingMetrics {
+            samples: 0,
+            loss: Some(5.0),
+            accuracy: None,
+            duration: None,
+        });
+
+        let metrics = sum.average(2);
+        assert_eq!(metrics.round, 2);
+        assert_eq!(metrics.participants, 3);
+        assert_eq!(metrics.samples, 40);
+        assert!((metrics.loss.unwrap() - 1.75).abs() < 1e-9);
+        assert!((metrics.accuracy.unwrap() - 0.5).abs() < 1e-9);
+        assert!((metrics.duration.unwrap() - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_average_empty() {
+        let metrics = TrainingMetricsSum::default().average(0);
+        assert_eq!(metrics.participants, 0);
+        assert_eq!(metrics.loss, None);
+        assert_eq!(metrics.accuracy, None);
+        assert_eq!(metrics.duration, None);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// How long the responses to the requests carrying an idempotency key
+/// are kept
+pub const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(600);
+
+/// Maximum number of responses kept by a cache. Once it is reached,
+/// the oldest responses are dropped before they expire.
+pub const IDEMPOTENCY_CACHE_CAPACITY: usize = 100_000;
+
+/// Responses to the requests carrying an idempotency key. A request
+/// retried by a client, or replayed by a proxy, with the same key gets
+/// the response of the original request instead of being processed
+/// again.
+pub struct IdempotencyCache<T> {
+    entries: Arc<Mutex<Entries<T>>>,
+    ttl: Duration,
+    capacity: usize,
+    /// Whether a response can be replayed. The other responses are
+    /// not cached, so that the request is processed again when it is
+    /// retried.
+    cacheable: fn(&T) -> bool,
+}
+
+// Implemented by hand: deriving it would require `T: Clone`
+impl<T> Clone for IdempotencyCache<T> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            ttl: self.ttl,
+            capacity: self.capacity,
+            cacheable: self.cacheable,
+        }
+    }
+}
+
+type Slot<T> = Arc<AsyncMutex<Option<T>>>;
+
+struct Entries<T> {
+    slots: HashMap<String, Slot<T>>,
+    /// Keys of the slots, with the time they were created at. Since
+    /// all the slots live as long, the oldest one is always the next
+    /// one to expire.
+    keys: VecDeque<(Instant, String)>,
+}
+
+impl<T> Entries<T> {
+    /// Drop the expired slots
+    fn prune(&mut self, now: Instant, ttl: Duration) {
+        while let Some((created, _)) = self.keys.front() {
+            if now.duration_since(*created) < ttl {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    /// Drop the oldest slot, if any
+    fn pop_oldest(&mut self) {
+        if let Some((_, key)) = self.keys.pop_front() {
+            self.slots.remove(&key);
+        }
+    }
+}
+
+impl<T> IdempotencyCache<T>
+where
+    T: Clone,
+{
+    pub fn new(ttl: Duration, capacity: usize, cacheable: fn(&T) -> bool) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(Entries {
+                slots: HashMap::new(),
+                keys: VecDeque::new(),
+            })),
+            ttl,
+            capacity,
+            cacheable,
+        }
+    }
+
+    /// Return the response cached for `key`, or process the request
+    /// with `f`. Concurrent requests with the same key wait for the
+    /// first one to be processed.
+    pub async fn get_or_process<F, Fut, E>(&self, key: String, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let slot = {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            entries.prune(now, self.ttl);
+            match entries.slots.get(&key) {
+                Some(slot) => slot.clone(),
+                None => {
+                    if entries.keys.len() >= self.capacity {
+                        entries.pop_oldest();
+                    }
+                    let slot = Arc::new(AsyncMutex::new(None));
+                    entries.slots.insert(key.clone(), slot.clone());
+                    entries.keys.push_back((now, key));
+                    slot
+                }
+            }
+        };
+        let mut cached = slot.lock().await;
+        if let Some(ref response) = *cached {
+            debug!("replaying the response of an idempotent request");
+            return Ok(response.clone());
+        }
+        let response = f().await?;
+        if (self.cacheable)(&response) {
+            *cached = Some(response.clone());
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[tokio::test]
+    async fn test_get_or_process() {
+        let cache = IdempotencyCache::new(
+            IDEMPOTENCY_KEY_TTL,
+            IDEMPOTENCY_CACHE_CAPACITY,
+            |response: &u32| *response > 0,
+        );
+        let process = |response| move || future::ready(Ok::<_, ()>(response));
+
+        // Only the cacheable responses are replayed
+        assert_eq!(cache.get_or_process("a".into(), process(0)).await, Ok(0));
+        assert_eq!(cache.get_or_process("a".into(), process(1)).await, Ok(1));
+        assert_eq!(cache.get_or_process("a".into(), process(2)).await, Ok(1));
+        assert_eq!(cache.get_or_process("b".into(), process(3)).await, Ok(3));
+
+        // The errors are not cached either
+        let fail = || future::ready(Err(()));
+        assert_eq!(cache.get_or_process("c".into(), fail).await, Err(()));
+        assert_eq!(cache.get_or_process("c".into(), process(4)).await, Ok(4));
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let cache = IdempotencyCache::new(
+            Duration::from_millis(10),
+            IDEMPOTENCY_CACHE_CAPACITY,
+            |_: &u32| true,
+        );
+        let process = |response| move || future::ready(Ok::<_, ()>(response));
+
+        assert_eq!(cache.get_or_process("a".into(), process(1)).await, Ok(1));
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        assert_eq!(cache.get_or_process("a".into(), process(2)).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn test_capacity() {
+        let cache = IdempotencyCache::new(IDEMPOTENCY_KEY_TTL, 2, |_: &u32| true);
+        let process = |response| move || future::ready(Ok::<_, ()>(response));
+
+        assert_eq!(cache.get_or_process("a".into(), process(1)).await, Ok(1));
+        assert_eq!(cache.get_or_process("b".into(), process(2)).await, Ok(2));
+        assert_eq!(cache.get_or_process("c".into(), process(3)).await, Ok(3));
+
+        // The oldest response was dropped to make room for the last one
+        assert_eq!(cache.entries.lock().unwrap().slots.len(), 2);
+        assert_eq!(cache.get_or_process("b".into(), process(4)).await, Ok(2));
+        assert_eq!(cache.get_or_process("a".into(), process(5)).await, Ok(5));
+    }
+}
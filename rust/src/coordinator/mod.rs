@@ -1,5 +1,6 @@
 pub mod api;
 pub mod core;
+mod idempotency;
 pub mod models;
 pub mod rpc;
 pub mod settings;
@@ -1,7 +1,62 @@
 use crate::common::client::{ClientId, Token};
+use std::{collections::HashMap, time::Duration};
+
+/// Version of the client API of the coordinator. It is increased
+/// whenever the JSON models change in a way that breaks the existing
+/// clients. The API is served under the `/v<version>` prefix.
+pub const API_VERSION: u32 = 1;
+
+/// Optional features of the client API, which the clients can learn
+/// about on rendez-vous
+pub const CAPABILITIES: &[&str] = &[
+    // Push channel for the heartbeat responses
+    "heartbeat_events",
+    // Configuration of the local training in the start training
+    // responses
+    "training_config",
+    // Evaluation rounds
+    "evaluation",
+    // Training statistics attached to the weights uploads
+    "training_metrics",
+];
+
+/// Version and capabilities announced by a client on rendez-vous
+#[derive(Debug, Default, Deserialize)]
+pub struct ClientVersion {
+    /// Version of the API the client speaks. The clients that don't
+    /// announce a version are assumed to speak the current one.
+    pub version: Option<u32>,
+    /// Comma separated list of the capabilities the client supports
+    pub capabilities: Option<String>,
+}
+
+impl ClientVersion {
+    /// Whether the client speaks the version of the API served by the
+    /// coordinator
+    pub fn is_compatible(&self) -> bool {
+        self.version.map_or(true, |version| version == API_VERSION)
+    }
+
+    /// Capabilities supported by both the client and the coordinator.
+    /// The clients that don't announce their capabilities are told
+    /// about all of them.
+    pub fn negotiate(&self) -> Vec<&'static str> {
+        match self.capabilities {
+            Some(ref capabilities) => {
+                let capabilities: Vec<&str> = capabilities.split(',').map(str::trim).collect();
+                CAPABILITIES
+                    .iter()
+                    .filter(|capability| capabilities.contains(capability))
+                    .cloned()
+                    .collect()
+            }
+            None => CAPABILITIES.to_vec(),
+        }
+    }
+}
 
 /// Response to a heartbeat
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum HeartBeatResponse {
     /// The client should stand by in its current state
     StandBy,
@@ -18,15 +73,106 @@ pub enum HeartBeatResponse {
     Reject,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RendezVousResponse {
     Accept(ClientId),
     Reject,
+    /// The coordinator is full, and the client should retry after
+    /// the given delay
+    Retry(Duration),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum StartTrainingResponse {
-    Accept(String, Token),
+    /// The client can download the global model from the aggregator
+    /// at the given URL, and train it with the given configuration, if
+    /// the client supports it
+    Accept(String, Token, Option<TrainingConfig>),
+    Reject,
+    /// The aggregator could not be reached. The client can try again
+    /// later.
+    Unavailable,
+}
+
+/// Configuration of the local training for a round, or of the
+/// evaluation of the global model. The settings that are not set are
+/// left to the participants.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TrainingConfig {
+    pub round: u32,
+    /// Whether the participants should evaluate the global model
+    /// and upload metrics, instead of training it
+    pub evaluation: bool,
+    pub epochs: Option<u32>,
+    pub batch_size: Option<u32>,
+    pub learning_rate: Option<f64>,
+    pub hyperparameters: HashMap<String, serde_json::Value>,
+}
+
+/// Statistics of the local training of a participant, reported
+/// along with its local weights
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrainingMetrics {
+    /// Number of samples the participant trained on
+    pub samples: u64,
+    /// Loss of the local model at the end of the training
+    pub loss: Option<f64>,
+    /// Accuracy of the local model at the end of the training
+    pub accuracy: Option<f64>,
+    /// Duration of the local training, in seconds
+    pub duration: Option<f64>,
+}
+
+/// Training statistics of a round, aggregated over the participants
+/// that reported them
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoundTrainingMetrics {
+    pub round: u32,
+    /// Number of participants that reported statistics
+    pub participants: u32,
+    /// Total number of samples the participants trained on
+    pub samples: u64,
+    /// Average loss, weighted by the number of samples
+    pub loss: Option<f64>,
+    /// Average accuracy, weighted by the number of samples
+    pub accuracy: Option<f64>,
+    /// Average duration of the local training, in seconds
+    pub duration: Option<f64>,
+}
+
+/// Command sent by an operator to control the training
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AdminCommand {
+    /// Stop selecting new participants
+    Pause,
+
+    /// Resume the selection of participants
+    Resume,
+
+    /// Abort the current round and discard the weights uploaded so
+    /// far
+    AbortRound,
+
+    /// End the training before all the rounds are completed
+    Finish,
+
+    /// Train for the given number of additional rounds
+    ExtendTraining(u32),
+
+    /// Remove the given client, whatever its state
+    Evict(ClientId),
+
+    /// Remove the given client and ban the address it connected from
+    Ban(ClientId),
+}
+
+/// Response to an operator command
+#[derive(Debug, Eq, PartialEq)]
+pub enum AdminResponse {
+    /// The command has been applied
+    Accept,
+
+    /// The command cannot be applied in the current state
     Reject,
 }
 
@@ -34,27 +180,54 @@ pub mod json {
     use super::*;
 
     mod rendez_vous {
-        use super::RendezVousResponse;
-        use crate::common::client::ClientId;
+        use super::{ClientVersion, RendezVousResponse, API_VERSION};
+        use crate::common::{client::ClientId, rate_limit::retry_after_secs};
+        use std::collections::HashMap;
+
+        /// Body of the `POST` rendez-vous requests
+        #[derive(Debug, Deserialize)]
+        pub struct RendezVousRequestJson {
+            #[serde(flatten)]
+            pub client: ClientVersion,
+            /// Free-form information about the client, like the name
+            /// and version of its SDK. It is only logged.
+            #[serde(default)]
+            pub metadata: HashMap<String, String>,
+            /// Key identifying the request. A request retried with
+            /// the same key gets the same client ID.
+            #[serde(default)]
+            pub idempotency_key: Option<String>,
+        }
 
         #[derive(Serialize)]
         pub struct RendezVousResponseJson {
             id: Option<ClientId>,
             ok: bool,
+            /// Number of seconds after which the client can send a
+            /// new rendez-vous request
+            #[serde(skip_serializing_if = "Option::is_none")]
+            retry_after: Option<u64>,
+            /// Version of the API served by the coordinator
+            version: u32,
+            /// Capabilities supported by both the client and the
+            /// coordinator
+            capabilities: Vec<&'static str>,
         }
 
-        impl From<RendezVousResponse> for RendezVousResponseJson {
-            fn from(resp: RendezVousResponse) -> Self {
+        impl RendezVousResponseJson {
+            pub fn new(resp: RendezVousResponse, client: &ClientVersion) -> Self {
                 use RendezVousResponse::*;
-                match resp {
-                    Accept(id) => Self {
-                        ok: true,
-                        id: Some(id),
-                    },
-                    Reject => Self {
-                        ok: false,
-                        id: None,
-                    },
+                let (ok, id, retry_after) = match resp {
+                    Accept(id) => (true, Some(id), None),
+                    Reject => (false, None, None),
+                    Retry(delay) => (false, None, Some(retry_after_secs(delay))),
+                };
+                Self {
+                    id,
+                    ok,
+                    retry_after,
+                    version: API_VERSION,
+                    capabilities: client.negotiate(),
                 }
             }
         }
@@ -103,36 +276,120 @@ pub mod json {
     }
 
     mod start_training {
-        use super::StartTrainingResponse;
+        use super::{StartTrainingResponse, TrainingConfig};
         use crate::common::client::Token;
+        use std::collections::HashMap;
+
+        /// Body of the `POST` start training requests
+        #[derive(Debug, Default, Deserialize)]
+        pub struct StartTrainingRequestJson {
+            /// Key identifying the request. A request retried with
+            /// the same key gets the same token.
+            #[serde(default)]
+            pub idempotency_key: Option<String>,
+        }
+
+        #[derive(Serialize)]
+        pub struct TrainingConfigJson {
+            round: u32,
+            evaluation: bool,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            epochs: Option<u32>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            batch_size: Option<u32>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            learning_rate: Option<f64>,
+            hyperparameters: HashMap<String, serde_json::Value>,
+        }
+
+        impl From<TrainingConfig> for TrainingConfigJson {
+            fn from(config: TrainingConfig) -> Self {
+                Self {
+                    round: config.round,
+                    evaluation: config.evaluation,
+                    epochs: config.epochs,
+                    batch_size: config.batch_size,
+                    learning_rate: config.learning_rate,
+                    hyperparameters: config.hyperparameters,
+                }
+            }
+        }
 
         #[derive(Serialize)]
         pub struct StartTrainingResponseJson {
             url: Option<String>,
             token: Option<Token>,
             ok: bool,
+            /// Configuration of the local training
+            #[serde(skip_serializing_if = "Option::is_none")]
+            config: Option<TrainingConfigJson>,
         }
 
         impl From<StartTrainingResponse> for StartTrainingResponseJson {
             fn from(resp: StartTrainingResponse) -> Self {
                 use StartTrainingResponse::*;
                 match resp {
-                    Accept(url, token) => Self {
+                    Accept(url, token, config) => Self {
                         ok: true,
                         url: Some(url),
                         token: Some(token),
+                        config: config.map(Into::into),
                     },
-                    Reject => Self {
+                    Reject | Unavailable => Self {
                         ok: false,
                         url: None,
                         token: None,
+                        config: None,
                     },
                 }
             }
         }
     }
 
+    mod admin {
+        use super::AdminResponse;
+
+        #[derive(Serialize)]
+        pub struct AdminResponseJson {
+            ok: bool,
+        }
+
+        impl From<AdminResponse> for AdminResponseJson {
+            fn from(resp: AdminResponse) -> Self {
+                Self {
+                    ok: resp == AdminResponse::Accept,
+                }
+            }
+        }
+    }
+
+    pub use admin::*;
     pub use heartbeat::*;
     pub use rendez_vous::*;
     pub use start_training::*;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_version() {
+        let client = ClientVersion::default();
+        assert!(client.is_compatible());
+        assert_eq!(client.negotiate(), CAPABILITIES.to_vec());
+
+        let client = ClientVersion {
+            version: Some(API_VERSION),
+            capabilities: Some("training_metrics, unknown,evaluation".to_string()),
+        };
+        assert!(client.is_compatible());
+        assert_eq!(client.negotiate(), vec!["evaluation", "training_metrics"]);
+
+        let client = ClientVersion {
+            version: Some(API_VERSION + 1),
+            capabilities: None,
+        };
+        assert!(!client.is_compatible());
+    }
+}
@@ -1,20 +1,41 @@
-use crate::{common::client::ClientId, coordinator::core::ServiceHandle};
+#![cfg_attr(test, allow(unused_imports))]
+use crate::{
+    common::{
+        client::ClientId,
+        rpc::{ConnectionLimit, RpcCodec},
+        shutdown::{drain, Shutdown, UntilShutdown, DRAIN_TIMEOUT},
+        task::{TaskId, Tasks},
+    },
+    coordinator::{core::ServiceHandle, models::TrainingMetrics},
+};
+use futures::pin_mut;
 use std::{future::Future, io, iter, pin::Pin, time::Duration};
 use stubborn_io::{ReconnectOptions, StubbornTcpStream};
 use tarpc::{
     client::Config,
+    context::Context,
     rpc::server::{BaseChannel, Channel},
-    serde_transport::{tcp::listen, Transport},
+    serde_transport::{tcp, Transport},
+};
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    stream::StreamExt,
 };
-use tokio::{net::ToSocketAddrs, stream::StreamExt};
-use tokio_serde::formats::Json;
 use tracing_futures::Instrument;
 
 mod inner {
-    use crate::common::client::ClientId;
+    use crate::{
+        common::{client::ClientId, task::TaskId},
+        coordinator::models::TrainingMetrics,
+    };
     #[tarpc::service]
     pub trait Rpc {
-        async fn end_training(id: ClientId, success: bool);
+        async fn end_training(
+            task: TaskId,
+            id: ClientId,
+            success: bool,
+            metrics: Option<TrainingMetrics>,
+        );
     }
 }
 
@@ -22,8 +43,47 @@ pub use inner::Rpc;
 
 #[cfg(test)]
 pub use crate::tests::lib::rpc::coordinator::Client;
+
+/// A client for the coordinator RPC service. Each client sends its
+/// requests for a single task, see [`Client::for_task`].
+#[cfg(not(test))]
+#[derive(Clone)]
+pub struct Client(inner::RpcClient, TaskId);
+
 #[cfg(not(test))]
-pub use inner::RpcClient as Client;
+impl Client {
+    pub async fn connect<A: ToSocketAddrs + Unpin + Clone + Send + Sync + 'static>(
+        addr: A,
+        codec: RpcCodec,
+    ) -> io::Result<Self> {
+        let reconnect_opts = ReconnectOptions::new()
+            .with_exit_if_first_connect_fails(false)
+            .with_retries_generator(|| iter::repeat(Duration::from_secs(1)));
+        let tcp_stream = StubbornTcpStream::connect_with_options(addr, reconnect_opts).await?;
+        let transport = Transport::from((tcp_stream, codec.codec()));
+        Ok(Self(
+            inner::RpcClient::new(Config::default(), transport).spawn()?,
+            TaskId::default(),
+        ))
+    }
+
+    /// Return a client that shares the same connection, but sends
+    /// its requests for the given task.
+    pub fn for_task(&self, task: TaskId) -> Self {
+        Self(self.0.clone(), task)
+    }
+
+    pub fn end_training(
+        &mut self,
+        ctx: Context,
+        id: ClientId,
+        success: bool,
+        metrics: Option<TrainingMetrics>,
+    ) -> impl Future<Output = io::Result<()>> + '_ {
+        self.0
+            .end_training(ctx, self.1.clone(), id, success, metrics)
+    }
+}
 
 impl Rpc for Server {
     type EndTrainingFut = Pin<Box<dyn Future<Output = ()> + Send>>;
@@ -31,52 +91,221 @@ impl Rpc for Server {
     fn end_training(
         self,
         _: tarpc::context::Context,
+        task: TaskId,
         id: ClientId,
         success: bool,
+        metrics: Option<TrainingMetrics>,
     ) -> Self::EndTrainingFut {
         debug!("handling end training request");
-        let span = trace_span!("rpc_end_training_handler", client_id = %id, success = &success);
-        Box::pin(async move { self.0.end_training(id, success).await }.instrument(span))
+        let span = trace_span!(
+            "rpc_end_training_handler",
+            task = %task,
+            client_id = %id,
+            success = &success
+        );
+        let handle = self.0.get(&task).cloned();
+        Box::pin(
+            async move {
+                match handle {
+                    Some(handle) => handle.end_training(id, success, metrics).await,
+                    None => warn!("ignoring end training request for unknown task"),
+                }
+            }
+            .instrument(span),
+        )
     }
 }
 
 /// A server that serves a single client. A new `Server` is created
 /// for each new client.
 #[derive(Clone)]
-struct Server(ServiceHandle);
+struct Server(Tasks<ServiceHandle>);
 
-pub async fn client_connect<A: ToSocketAddrs + Unpin + Clone + Send + Sync + 'static>(
+/// Run an RPC server. Each aggregator opens its own connection, and
+/// the connections are processed concurrently, up to
+/// `max_connections` if it is set. The messages are serialized with
+/// the given `codec`. Once `shutdown` is triggered, the server stops
+/// accepting connections and returns when the open ones are closed.
+pub async fn serve<A: ToSocketAddrs + Send + Sync + 'static>(
     addr: A,
-) -> io::Result<Client> {
-    let reconnect_opts = ReconnectOptions::new()
-        .with_exit_if_first_connect_fails(false)
-        .with_retries_generator(|| iter::repeat(Duration::from_secs(1)));
-    let tcp_stream = StubbornTcpStream::connect_with_options(addr, reconnect_opts).await?;
-    let transport = Transport::from((tcp_stream, Json::default()));
-    Client::new(Config::default(), transport).spawn()
+    tasks: Tasks<ServiceHandle>,
+    max_connections: Option<usize>,
+    codec: RpcCodec,
+    shutdown: Shutdown,
+) -> ::std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    serve_listener(listener, tasks, max_connections, codec, shutdown).await
 }
 
-/// Run an RPC server that processes only one connection at a time.
-pub async fn serve<A: ToSocketAddrs + Send + Sync + 'static>(
-    addr: A,
-    service_handle: ServiceHandle,
+/// Run an RPC server on a listener that is already bound, see
+/// [`serve`]
+pub async fn serve_listener(
+    mut listener: TcpListener,
+    tasks: Tasks<ServiceHandle>,
+    max_connections: Option<usize>,
+    codec: RpcCodec,
+    shutdown: Shutdown,
 ) -> ::std::io::Result<()> {
-    let mut listener = listen(addr, Json::default).await?;
+    let mut incoming = listener
+        .incoming()
+        .map(|stream| stream.map(|stream| tcp::new(stream, codec.codec())));
+    let limit = ConnectionLimit::new(max_connections);
+    let connections_shutdown = shutdown.clone();
+    let shutdown = shutdown.wait();
+    pin_mut!(shutdown);
 
-    while let Some(accept_result) = listener.next().await {
+    loop {
+        let accept_result = tokio::select! {
+            accept_result = incoming.next() => accept_result,
+            _ = &mut shutdown => {
+                info!("shutting down the RPC server");
+                drain(&limit, DRAIN_TIMEOUT).await;
+                return Ok(());
+            }
+        };
         match accept_result {
-            Ok(transport) => {
+            Some(Ok(transport)) => {
+                let peer = transport
+                    .peer_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                let guard = match limit.try_acquire() {
+                    Some(guard) => guard,
+                    None => {
+                        warn!(
+                            "refusing RPC connection from {}: too many connections",
+                            peer
+                        );
+                        continue;
+                    }
+                };
+                // The connection is closed on shutdown once the
+                // requests in flight are answered
+                let transport = UntilShutdown::new(transport, connections_shutdown.clone());
                 let channel = BaseChannel::with_defaults(transport);
-                let server = Server(service_handle.clone());
+                let server = Server(tasks.clone());
                 let handler = channel.respond_with(server.serve());
-                handler
-                    .execute()
-                    // FIXME: add peer to span
-                    .instrument(trace_span!("rpc_handler"))
-                    .await;
+                tokio::spawn(
+                    async move {
+                        handler.execute().await;
+                        debug!("RPC connection closed");
+                        drop(guard);
+                    }
+                    .instrument(trace_span!("rpc_handler", peer = %peer)),
+                );
             }
-            Err(e) => error!("failed to accept RPC connection: {:?}", e),
+            Some(Err(e)) => error!("failed to accept RPC connection: {:?}", e),
+            None => return Ok(()),
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::shutdown::{self, ShutdownTrigger},
+        coordinator::core::ServiceRequests,
+    };
+    use std::{collections::HashMap, net::SocketAddr};
+    use tokio::{net::TcpStream, task::JoinHandle, time::timeout};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Start an RPC server for the default task, and return its
+    /// address. The server shuts down when the returned trigger is
+    /// dropped.
+    fn start_server(
+        max_connections: Option<usize>,
+    ) -> (
+        SocketAddr,
+        ServiceRequests,
+        ShutdownTrigger,
+        JoinHandle<io::Result<()>>,
+    ) {
+        // The listener is bound before the server starts, so that
+        // the clients can connect right away
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        let (handle, requests) = ServiceHandle::new();
+        let mut handles = HashMap::new();
+        handles.insert(TaskId::default(), handle);
+        let (trigger, shutdown) = shutdown::channel();
+        let server = tokio::spawn(serve_listener(
+            listener,
+            Tasks::new(handles),
+            max_connections,
+            RpcCodec::default(),
+            shutdown,
+        ));
+        (addr, requests, trigger, server)
+    }
+
+    fn connect_client(addr: SocketAddr) -> io::Result<inner::RpcClient> {
+        let stream = TcpStream::from_std(std::net::TcpStream::connect(addr)?)?;
+        let transport = tcp::new(stream, RpcCodec::default().codec());
+        inner::RpcClient::new(Config::default(), transport).spawn()
+    }
+
+    async fn end_training(client: &mut inner::RpcClient) -> Result<io::Result<()>, ()> {
+        let request = client.end_training(
+            tarpc::context::current(),
+            TaskId::default(),
+            ClientId::new(),
+            true,
+            None,
+        );
+        timeout(TIMEOUT, request).await.map_err(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_connections() {
+        let (addr, mut requests, _trigger, _server) = start_server(None);
+
+        // The first connection stays open while the second one is
+        // being processed
+        let mut first = connect_client(addr).unwrap();
+        let mut second = connect_client(addr).unwrap();
+        end_training(&mut first).await.unwrap().unwrap();
+        end_training(&mut second).await.unwrap().unwrap();
+        end_training(&mut first).await.unwrap().unwrap();
+
+        for _ in 0..3 {
+            timeout(TIMEOUT, requests.next()).await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let (addr, mut requests, _trigger, _server) = start_server(Some(1));
+
+        let mut first = connect_client(addr).unwrap();
+        end_training(&mut first).await.unwrap().unwrap();
+        timeout(TIMEOUT, requests.next()).await.unwrap().unwrap();
+
+        // The second connection is refused while the first one is
+        // open
+        let mut second = connect_client(addr).unwrap();
+        assert!(!matches!(end_training(&mut second).await, Ok(Ok(()))));
+        end_training(&mut first).await.unwrap().unwrap();
+    }
+
+    /// Test that an idle connection is closed on shutdown, instead of
+    /// holding the shutdown back until the drain timeout expires
+    #[tokio::test]
+    async fn test_shutdown_with_idle_connection() {
+        let (addr, mut requests, trigger, server) = start_server(None);
+
+        let mut client = connect_client(addr).unwrap();
+        end_training(&mut client).await.unwrap().unwrap();
+        timeout(TIMEOUT, requests.next()).await.unwrap().unwrap();
+
+        trigger.trigger();
+        timeout(DRAIN_TIMEOUT / 2, server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
 }
@@ -1,10 +1,25 @@
-use crate::common::settings::LoggingSettings;
+use crate::{
+    common::{
+        rpc::RpcCodec,
+        settings::{LoggingSettings, RateLimitSettings, RpcCallSettings},
+        task::TaskId,
+    },
+    coordinator::models::TrainingConfig,
+};
 use config::{Config, ConfigError};
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub logging: LoggingSettings,
     pub aggregator_url: String,
+    /// Aggregators to use in addition to the one at `aggregator_url`
+    /// and `rpc.aggregator_address`. The selected clients are spread
+    /// over all the aggregators, and each aggregator computes a
+    /// partial aggregation of the weights it received. The partial
+    /// aggregations are then merged by all the aggregators.
+    #[serde(default)]
+    pub additional_aggregators: Vec<AggregatorSettings>,
     pub api: ApiSettings,
     pub rpc: RpcSettings,
     // Note that we don't hide this behind a
@@ -12,7 +27,15 @@ pub struct Settings {
     // files to keep working independently of the features supported
     // by the binary.
     pub metric_store: Option<MetricStoreSettings>,
+    /// Settings of the default task
     pub federated_learning: FederatedLearningSettings,
+    /// Settings of the other tasks, by name. Each task has its own
+    /// participants and trains its own model. The aggregator must
+    /// run the same tasks.
+    #[serde(default)]
+    pub tasks: HashMap<TaskId, FederatedLearningSettings>,
+    #[serde(default)]
+    pub eviction: EvictionSettings,
 }
 
 #[derive(Debug, Deserialize)]
@@ -21,12 +44,241 @@ pub struct FederatedLearningSettings {
     pub participants_ratio: f64,
     pub min_clients: u32,
     pub heartbeat_timeout: u64,
-    // epoch: u32,
+    /// Maximum number of active clients. When it is reached, new
+    /// clients are handled according to the admission policy. If it
+    /// is not set, all the clients are accepted.
+    #[serde(default)]
+    pub max_clients: Option<u32>,
+    /// What to do with new clients when `max_clients` is reached
+    #[serde(default)]
+    pub admission_policy: AdmissionPolicy,
+    /// Whether the training happens in synchronous rounds or
+    /// asynchronously
+    #[serde(default)]
+    pub mode: Mode,
+    /// Number of times in a row an aggregation can fail before the
+    /// training is aborted. After a failure, the round starts over.
+    #[serde(default = "default_max_aggregation_failures")]
+    pub max_aggregation_failures: u32,
+    /// Configuration of the local training, sent to the participants
+    /// when they start training
+    #[serde(default)]
+    pub training: TrainingSettings,
+    /// Evaluation rounds, in which the participants compute metrics
+    /// of the global model on their local data instead of training
+    /// it. If they are not set, the global model is not evaluated.
+    #[serde(default)]
+    pub evaluation: Option<EvaluationSettings>,
+}
+
+/// When to evaluate the global model
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct EvaluationSettings {
+    /// Number of training rounds between two evaluation rounds. The
+    /// model produced by the last round is evaluated if the number
+    /// of rounds is a multiple of it.
+    pub every: u32,
+}
+
+fn default_max_aggregation_failures() -> u32 {
+    3
+}
+
+/// How the participants are scheduled
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Participants are selected for a round, and the coordinator
+    /// waits for all of them to finish training before aggregating
+    /// their weights and starting a new round.
+    Sync,
+    /// Clients train whenever they are available, up to
+    /// `concurrency` at the same time, and the aggregator merges the
+    /// updates every time `buffer_size` of them are received
+    /// (FedBuff). Each merge counts as a round. The aggregator must
+    /// accept stale updates, see its `async_aggregation` settings.
+    Async { concurrency: u32, buffer_size: u32 },
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Sync
+    }
+}
+
+/// Policy applied to the clients that send a rendez-vous request
+/// when the coordinator is full
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AdmissionPolicy {
+    /// Reject the clients
+    Reject,
+    /// Ask the clients to send a new rendez-vous request after the
+    /// given number of seconds
+    Queue { retry_after: u64 },
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        AdmissionPolicy::Reject
+    }
+}
+
+/// Configuration of the local training of the participants. Each
+/// setting is optional: the participants use their own defaults for
+/// the settings that are not set.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct TrainingSettings {
+    /// Number of local epochs
+    pub epochs: Option<u32>,
+    /// Size of the local batches
+    pub batch_size: Option<u32>,
+    /// Learning rate, which can vary with the rounds
+    pub learning_rate: Option<LearningRateSchedule>,
+    /// Additional hyperparameters, passed as is to the participants
+    #[serde(default)]
+    pub hyperparameters: HashMap<String, serde_json::Value>,
+    /// Settings overriding the ones above from a given round on. When
+    /// several stages apply to a round, the ones that start later
+    /// take precedence.
+    #[serde(default)]
+    pub stages: Vec<TrainingStage>,
+}
+
+/// Training settings that apply from a given round on
+#[derive(Debug, Deserialize, Clone)]
+pub struct TrainingStage {
+    /// First round of the stage
+    pub from_round: u32,
+    pub epochs: Option<u32>,
+    pub batch_size: Option<u32>,
+    pub learning_rate: Option<LearningRateSchedule>,
+    /// Hyperparameters to add or to override
+    #[serde(default)]
+    pub hyperparameters: HashMap<String, serde_json::Value>,
+}
+
+/// How the learning rate evolves over the rounds
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LearningRateSchedule {
+    /// The same learning rate for all the rounds
+    Constant { value: f64 },
+    /// The learning rate is multiplied by `factor` every `every`
+    /// rounds
+    Step {
+        initial: f64,
+        factor: f64,
+        every: u32,
+    },
+    /// The learning rate is multiplied by `decay` every round
+    Exponential { initial: f64, decay: f64 },
+}
+
+impl LearningRateSchedule {
+    /// Learning rate for the given round
+    pub fn learning_rate(&self, round: u32) -> f64 {
+        match *self {
+            LearningRateSchedule::Constant { value } => value,
+            LearningRateSchedule::Step {
+                initial,
+                factor,
+                every,
+            } => initial * factor.powi((round / every.max(1)) as i32),
+            LearningRateSchedule::Exponential { initial, decay } => {
+                initial * decay.powi(round as i32)
+            }
+        }
+    }
+}
+
+impl TrainingSettings {
+    /// Training configuration for the given round
+    pub fn round_config(&self, round: u32) -> TrainingConfig {
+        let mut epochs = self.epochs;
+        let mut batch_size = self.batch_size;
+        let mut learning_rate = self.learning_rate;
+        let mut hyperparameters = self.hyperparameters.clone();
+
+        let mut stages: Vec<&TrainingStage> = self
+            .stages
+            .iter()
+            .filter(|stage| stage.from_round <= round)
+            .collect();
+        // The sort is stable, so stages starting at the same round
+        // are applied in the order they are listed
+        stages.sort_by_key(|stage| stage.from_round);
+        for stage in stages {
+            epochs = stage.epochs.or(epochs);
+            batch_size = stage.batch_size.or(batch_size);
+            learning_rate = stage.learning_rate.or(learning_rate);
+            hyperparameters.extend(stage.hyperparameters.clone());
+        }
+
+        TrainingConfig {
+            round,
+            evaluation: false,
+            epochs,
+            batch_size,
+            learning_rate: learning_rate.map(|schedule| schedule.learning_rate(round)),
+            hyperparameters,
+        }
+    }
+}
+
+/// Settings for getting rid of misbehaving clients
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct EvictionSettings {
+    /// Whether to evict the clients that flood the coordinator with
+    /// heartbeats
+    #[serde(default)]
+    pub evict_flooding_clients: bool,
+    /// Whether to also ban the address of the clients evicted for
+    /// flooding the coordinator
+    #[serde(default)]
+    pub ban_flooding_clients: bool,
+    /// File in which the banned addresses are persisted. If it is not
+    /// set, the ban list is lost when the coordinator stops.
+    pub ban_list: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ApiSettings {
     pub bind_address: String,
+    /// Token that operators must send in the `Authorization` header
+    /// to use the admin endpoints. If it is not set, the admin
+    /// endpoints are disabled.
+    pub admin_token: Option<String>,
+    #[serde(default)]
+    pub rate_limit: ApiRateLimitSettings,
+    /// Whether to keep serving the `GET` variants of the rendez-vous
+    /// and start training endpoints, for the clients that predate the
+    /// `POST` ones. Enabled by default.
+    #[serde(default = "default_legacy_get_routes")]
+    pub legacy_get_routes: bool,
+}
+
+fn default_legacy_get_routes() -> bool {
+    true
+}
+
+/// Rate limits for the endpoints used by the participants
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct ApiRateLimitSettings {
+    #[serde(default)]
+    pub rendez_vous: RateLimitSettings,
+    #[serde(default)]
+    pub heartbeat: RateLimitSettings,
+    #[serde(default)]
+    pub start_training: RateLimitSettings,
+}
+
+impl ApiRateLimitSettings {
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.rendez_vous.validate("rendez_vous")?;
+        self.heartbeat.validate("heartbeat")?;
+        self.start_training.validate("start_training")
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -35,16 +287,124 @@ pub struct MetricStoreSettings {
     pub database_name: String,
 }
 
+/// Addresses of an additional aggregator
+#[derive(Debug, Deserialize)]
+pub struct AggregatorSettings {
+    /// URL of the aggregator API, sent to the clients
+    pub url: String,
+    /// Address of the aggregator RPC server
+    pub rpc_address: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RpcSettings {
     pub bind_address: String,
     pub aggregator_address: String,
+    /// Maximum number of RPC connections processed concurrently. New
+    /// connections are refused when the limit is reached. By default
+    /// the number of connections is not limited.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// Serialization format of the RPC messages, which must be the
+    /// same on the coordinator and the aggregator. JSON by default.
+    #[serde(default)]
+    pub codec: RpcCodec,
+    /// Deadlines and retries of the calls
+    #[serde(default)]
+    pub calls: RpcCallSettings,
 }
 
 impl Settings {
     pub fn new(path: &str) -> Result<Self, ConfigError> {
         let mut s = Config::new();
         s.merge(config::File::with_name(path))?;
-        s.try_into()
+        let settings: Self = s.try_into()?;
+        if settings.tasks.keys().any(TaskId::is_default) {
+            return Err(ConfigError::Message(format!(
+                "the `{}` task is configured by the `federated_learning` settings",
+                TaskId::default()
+            )));
+        }
+        settings.api.rate_limit.validate()?;
+        settings.federated_learning.validate()?;
+        for fl_settings in settings.tasks.values() {
+            fl_settings.validate()?;
+        }
+        Ok(settings)
+    }
+}
+
+impl FederatedLearningSettings {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.heartbeat_timeout == 0 {
+            return Err(ConfigError::Message(
+                "`heartbeat_timeout` must be at least 1".to_string(),
+            ));
+        }
+        match (self.evaluation, self.mode) {
+            (Some(EvaluationSettings { every: 0 }), _) => Err(ConfigError::Message(
+                "`evaluation.every` must be at least 1".to_string(),
+            )),
+            (Some(_), Mode::Async { .. }) => Err(ConfigError::Message(
+                "evaluation rounds are not supported in asynchronous mode".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_learning_rate_schedule() {
+        let constant = LearningRateSchedule::Constant { value: 0.1 };
+        assert_eq!(constant.learning_rate(0), 0.1);
+        assert_eq!(constant.learning_rate(10), 0.1);
+
+        let step = LearningRateSchedule::Step {
+            initial: 1.0,
+            factor: 0.5,
+            every: 2,
+        };
+        assert_eq!(step.learning_rate(0), 1.0);
+        assert_eq!(step.learning_rate(1), 1.0);
+        assert_eq!(step.learning_rate(2), 0.5);
+        assert_eq!(step.learning_rate(5), 0.25);
+
+        let exponential = LearningRateSchedule::Exponential {
+            initial: 1.0,
+            decay: 0.5,
+        };
+        assert_eq!(exponential.learning_rate(0), 1.0);
+        assert_eq!(exponential.learning_rate(3), 0.125);
+    }
+
+    #[test]
+    fn test_round_config() {
+        let stage = |from_round, epochs| TrainingStage {
+            from_round,
+            epochs: Some(epochs),
+            batch_size: None,
+            learning_rate: None,
+            hyperparameters: HashMap::new(),
+        };
+        let settings = TrainingSettings {
+            epochs: None,
+            batch_size: Some(16),
+            learning_rate: None,
+            hyperparameters: HashMap::new(),
+            // The stages don't have to be sorted
+            stages: vec![stage(5, 3), stage(2, 2)],
+        };
+
+        let config = settings.round_config(0);
+        assert_eq!(config.epochs, None);
+        assert_eq!(config.batch_size, Some(16));
+        assert_eq!(config.learning_rate, None);
+        assert_eq!(settings.round_config(3).epochs, Some(2));
+        assert_eq!(settings.round_config(5).epochs, Some(3));
+        assert_eq!(settings.round_config(5).batch_size, Some(16));
     }
 }
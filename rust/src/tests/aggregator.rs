@@ -1,17 +1,29 @@
 use crate::{
-    aggregator::service::Service,
-    common::client::{ClientId, Credentials, Token},
+    aggregator::{
+        evaluation::LocalMetrics,
+        service::{ModelVersion, RoundConfig, Service, ServiceError, UploadError},
+        settings::AsyncAggregationSettings,
+    },
+    common::{
+        client::{ClientId, Credentials, Token},
+        rpc::RequestId,
+        settings::RpcCallSettings,
+    },
+    coordinator::models::TrainingMetrics,
     tests::lib::{
-        aggregator::{ByteAggregator, ServiceHandle},
+        aggregator::{ByteAggregator, ServiceHandle, StalledAggregator},
         enable_logging,
         rpc::coordinator::{Client, MockClient},
     },
 };
 use bytes::Bytes;
 use futures::future;
-use tokio::task::JoinHandle;
+use std::{sync::Mutex, time::Duration};
+use tokio::{sync::oneshot, task::JoinHandle, time::timeout};
 
-fn start_service() -> (Client, ServiceHandle<ByteAggregator>, JoinHandle<()>) {
+fn start_service(
+    async_settings: Option<AsyncAggregationSettings>,
+) -> (Client, ServiceHandle<ByteAggregator>, JoinHandle<()>) {
     // Make it easy to debug this test by setting the `TEST_LOGS`
     // environment variable
     enable_logging();
@@ -21,14 +33,24 @@ fn start_service() -> (Client, ServiceHandle<ByteAggregator>, JoinHandle<()>) {
 
     let (service_handle, service_requests) = ServiceHandle::new();
 
-    let service = Service::new(aggregator, rpc_client.clone(), service_requests);
+    let service = Service::new(
+        aggregator,
+        rpc_client.clone(),
+        RpcCallSettings::default(),
+        service_requests,
+        async_settings,
+    );
     let join_handle = tokio::spawn(service);
     (rpc_client, service_handle, join_handle)
 }
 
 #[tokio::test]
 async fn test_aggregation() {
-    let (rpc_client, service_handle, _join_handle) = start_service();
+    let (rpc_client, service_handle, _join_handle) = start_service(None);
+    rpc_client
+        .mock()
+        .expect_end_training()
+        .returning(|_, _, _, _| future::ready(Ok(())));
 
     let client_1_credentials = Credentials(ClientId::new(), Token::new());
     let res = service_handle.select(client_1_credentials).await;
@@ -36,38 +58,483 @@ async fn test_aggregation() {
 
     let data = Bytes::from_static(b"1111");
     service_handle
-        .upload(client_1_credentials, data)
+        .upload(client_1_credentials, None, data)
+        .await
+        .unwrap();
+
+    let client_2_credentials = Credentials(ClientId::new(), Token::new());
+    let res = service_handle.select(client_2_credentials).await;
+    assert!(res.is_ok());
+
+    let data = Bytes::from_static(b"2222");
+    service_handle
+        .upload(client_2_credentials, None, data)
         .await
         .unwrap();
 
+    let res = service_handle.aggregate(RequestId::new()).await;
+    assert!(res.is_ok());
+
+    let res = service_handle.select(client_1_credentials).await;
+    assert!(res.is_ok());
+
+    let res = service_handle.download(client_1_credentials).await;
+
+    let expect = Bytes::from_static(b"11112222");
+    assert_eq!(expect[..], res.unwrap().weights[..]);
+}
+
+/// Test that the credentials of an upload can be checked before
+/// receiving the weights.
+#[tokio::test]
+async fn test_check_credentials() {
+    let (_rpc_client, service_handle, _join_handle) = start_service(None);
+
+    let credentials = Credentials(ClientId::new(), Token::new());
+    match service_handle.check_credentials(credentials).await {
+        Err(ServiceError::Request(UploadError::Unauthorized)) => {}
+        _ => panic!("expected the credentials to be rejected"),
+    }
+
+    service_handle.select(credentials).await.unwrap();
+    service_handle.check_credentials(credentials).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_stale_upload() {
+    let (rpc_client, service_handle, _join_handle) = start_service(None);
     rpc_client
         .mock()
         .expect_end_training()
-        .returning(|_, _, _| future::ready(Ok(())));
+        .returning(|_, _, _, _| future::ready(Ok(())));
+
+    let client_1_credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(client_1_credentials).await.unwrap();
+    let initial_version = service_handle
+        .download(client_1_credentials)
+        .await
+        .unwrap()
+        .version;
+    assert_eq!(initial_version, ModelVersion::new(0, &[]));
+
+    let data = Bytes::from_static(b"1111");
+    service_handle
+        .upload(client_1_credentials, Some(initial_version.clone()), data)
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
 
     let client_2_credentials = Credentials(ClientId::new(), Token::new());
-    let res = service_handle.select(client_2_credentials).await;
-    assert!(res.is_ok());
+    service_handle.select(client_2_credentials).await.unwrap();
+    let version = service_handle
+        .download(client_2_credentials)
+        .await
+        .unwrap()
+        .version;
+    assert_eq!(version, ModelVersion::new(1, b"1111"));
 
+    // Weights computed from the initial model are rejected
     let data = Bytes::from_static(b"2222");
+    let res = service_handle
+        .upload(client_2_credentials, Some(initial_version), data.clone())
+        .await;
+    match res {
+        Err(ServiceError::Request(UploadError::StaleModel)) => {}
+        _ => panic!("expected the upload to be rejected"),
+    }
+
     service_handle
-        .upload(client_2_credentials, data)
+        .upload(client_2_credentials, Some(version), data)
         .await
         .unwrap();
+}
 
+/// Test that the training statistics reported with the weights are
+/// forwarded to the coordinator.
+#[tokio::test]
+async fn test_upload_training_metrics() {
+    let (rpc_client, service_handle, _join_handle) = start_service(None);
+    let metrics = TrainingMetrics {
+        samples: 10,
+        loss: Some(0.1),
+        accuracy: Some(0.9),
+        duration: None,
+    };
+    let credentials = Credentials(ClientId::new(), Token::new());
+    let (tx, rx) = oneshot::channel();
+    let tx = Mutex::new(Some(tx));
     rpc_client
         .mock()
         .expect_end_training()
-        .returning(|_, _, _| future::ready(Ok(())));
+        .returning(move |_, id, success, metrics| {
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send((id, success, metrics));
+            }
+            future::ready(Ok(()))
+        });
 
-    let res = service_handle.aggregate().await;
-    assert!(res.is_ok());
+    service_handle.select(credentials).await.unwrap();
+    service_handle
+        .upload_with_metrics(credentials, metrics.clone(), Bytes::from_static(b"1111"))
+        .await
+        .unwrap();
+    let request = timeout(Duration::from_secs(1), rx).await.unwrap().unwrap();
+    assert_eq!(request, (*credentials.id(), true, Some(metrics)));
+}
 
-    let res = service_handle.select(client_1_credentials).await;
-    assert!(res.is_ok());
+/// Test that the retries of an aggregation request don't aggregate
+/// the weights again.
+#[tokio::test]
+async fn test_aggregation_retry() {
+    let (rpc_client, service_handle, _join_handle) = start_service(None);
+    rpc_client
+        .mock()
+        .expect_end_training()
+        .returning(|_, _, _, _| future::ready(Ok(())));
 
-    let res = service_handle.download(client_1_credentials).await;
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    service_handle
+        .upload(credentials, None, Bytes::from_static(b"1111"))
+        .await
+        .unwrap();
+    let request_id = RequestId::new();
+    service_handle.aggregate(request_id).await.unwrap();
 
-    let expect = Bytes::from_static(b"11112222");
-    assert_eq!(expect[..], res.unwrap()[..]);
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    service_handle
+        .upload(credentials, None, Bytes::from_static(b"2222"))
+        .await
+        .unwrap();
+
+    // The retry succeeds, but the global model doesn't change
+    service_handle.aggregate(request_id).await.unwrap();
+    let model = service_handle.download(credentials).await.unwrap();
+    assert_eq!(model.version, ModelVersion::new(1, b"1111"));
+
+    // A new request aggregates the weights uploaded since the
+    // last aggregation
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    let model = service_handle.download(credentials).await.unwrap();
+    assert_eq!(model.version, ModelVersion::new(2, b"11112222"));
+}
+
+/// Test that resetting the aggregator after an aggregation of the
+/// aborted round rolls back the global model, and that resetting it
+/// again has no effect.
+#[tokio::test]
+async fn test_reset_rollback() {
+    let (rpc_client, service_handle, _join_handle) = start_service(None);
+    rpc_client
+        .mock()
+        .expect_end_training()
+        .returning(|_, _, _, _| future::ready(Ok(())));
+
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    service_handle
+        .upload(credentials, None, Bytes::from_static(b"1111"))
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+
+    // The coordinator aborts round 0 although the aggregation
+    // succeeded on this aggregator
+    service_handle.reset(0).await.unwrap();
+    service_handle.reset(0).await.unwrap();
+
+    // The credentials of the aborted round are discarded
+    let res = service_handle.download(credentials).await;
+    assert!(res.is_err());
+
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    let model = service_handle.download(credentials).await.unwrap();
+    assert_eq!(model.version, ModelVersion::new(0, &[]));
+    assert!(model.weights.is_empty());
+
+    // The weights uploaded during the aborted round are not
+    // aggregated again
+    service_handle
+        .upload(credentials, None, Bytes::from_static(b"2222"))
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    let model = service_handle.download(credentials).await.unwrap();
+    assert_eq!(model.version, ModelVersion::new(1, b"2222"));
+}
+
+/// Test that resetting the aggregator while an aggregation is running
+/// cancels the aggregation, and that the global model doesn't change.
+#[tokio::test]
+async fn test_reset_while_aggregating() {
+    enable_logging();
+    let rpc_client: Client = MockClient::default().into();
+    let (service_handle, service_requests) = ServiceHandle::new();
+    let service = Service::new(
+        StalledAggregator,
+        rpc_client,
+        RpcCallSettings::default(),
+        service_requests,
+        None,
+    );
+    let _join_handle = tokio::spawn(service);
+
+    let aggregation = service_handle.aggregate(RequestId::new());
+    let reset = async {
+        // Give the service some time to start the aggregation
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        service_handle.reset(0).await
+    };
+    let (aggregation, reset) = timeout(Duration::from_secs(1), future::join(aggregation, reset))
+        .await
+        .unwrap();
+    reset.unwrap();
+    assert!(aggregation.is_err());
+
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    let model = service_handle.download(credentials).await.unwrap();
+    assert_eq!(model.version, ModelVersion::new(0, &[]));
+}
+
+/// Test that once the next round starts, the global model can no
+/// longer be rolled back.
+#[tokio::test]
+async fn test_start_round() {
+    let (rpc_client, service_handle, _join_handle) = start_service(None);
+    rpc_client
+        .mock()
+        .expect_end_training()
+        .returning(|_, _, _, _| future::ready(Ok(())));
+
+    let config = RoundConfig {
+        rounds: 2,
+        evaluation: false,
+    };
+    service_handle.start_round(0, config.clone()).await.unwrap();
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    service_handle
+        .upload(credentials, None, Bytes::from_static(b"1111"))
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+
+    service_handle.start_round(1, config).await.unwrap();
+    service_handle.reset(0).await.unwrap();
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    let model = service_handle.download(credentials).await.unwrap();
+    assert_eq!(model.version, ModelVersion::new(1, b"1111"));
+}
+
+/// Test that during an evaluation round, the participants upload
+/// metrics instead of weights, and the aggregation averages them
+/// without changing the global model.
+#[tokio::test]
+async fn test_evaluation() {
+    let (rpc_client, service_handle, _join_handle) = start_service(None);
+    rpc_client
+        .mock()
+        .expect_end_training()
+        .returning(|_, _, _, _| future::ready(Ok(())));
+    let metrics = |samples, loss| LocalMetrics {
+        samples,
+        metrics: vec![("loss".to_string(), loss)].into_iter().collect(),
+    };
+
+    // Metrics are rejected during training rounds
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    match service_handle
+        .upload_metrics(credentials, metrics(1, 1.0))
+        .await
+    {
+        Err(ServiceError::Request(UploadError::NotEvaluationRound)) => (),
+        res => panic!("expected NotEvaluationRound error, got {:?}", res),
+    }
+    service_handle
+        .upload(credentials, None, Bytes::from_static(b"1111"))
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+
+    let config = RoundConfig {
+        rounds: 2,
+        evaluation: true,
+    };
+    service_handle.start_round(1, config).await.unwrap();
+
+    // Weights are rejected during evaluation rounds
+    let credentials_1 = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials_1).await.unwrap();
+    match service_handle
+        .upload(credentials_1, None, Bytes::from_static(b"2222"))
+        .await
+    {
+        Err(ServiceError::Request(UploadError::EvaluationRound)) => (),
+        res => panic!("expected EvaluationRound error, got {:?}", res),
+    }
+    service_handle
+        .upload_metrics(credentials_1, metrics(10, 1.0))
+        .await
+        .unwrap();
+    // The metrics of a participant are only counted once
+    assert!(service_handle
+        .upload_metrics(credentials_1, metrics(10, 1.0))
+        .await
+        .is_err());
+
+    let credentials_2 = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials_2).await.unwrap();
+    service_handle
+        .upload_metrics(credentials_2, metrics(30, 2.0))
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+
+    let evaluations = service_handle.evaluations().await.unwrap();
+    assert_eq!(evaluations.len(), 1);
+    assert_eq!(evaluations[0].round, 1);
+    assert_eq!(evaluations[0].participants, 2);
+    assert_eq!(evaluations[0].samples, 40);
+    assert!((evaluations[0].metrics["loss"] - 1.75).abs() < 1e-9);
+
+    // The global model did not change
+    let credentials = Credentials(ClientId::new(), Token::new());
+    service_handle.select(credentials).await.unwrap();
+    let model = service_handle.download(credentials).await.unwrap();
+    assert_eq!(model.version, ModelVersion::new(1, b"1111"));
+}
+
+/// Test that the partial evaluations of several aggregators are
+/// merged.
+#[tokio::test]
+async fn test_evaluation_merge() {
+    let (rpc_client_1, service_handle_1, _join_handle_1) = start_service(None);
+    let (rpc_client_2, service_handle_2, _join_handle_2) = start_service(None);
+    let config = RoundConfig {
+        rounds: 1,
+        evaluation: true,
+    };
+    let mut partials = vec![];
+    for (samples, rpc_client, service_handle) in vec![
+        (10, &rpc_client_1, &service_handle_1),
+        (30, &rpc_client_2, &service_handle_2),
+    ] {
+        rpc_client
+            .mock()
+            .expect_end_training()
+            .returning(|_, _, _, _| future::ready(Ok(())));
+        service_handle.start_round(1, config.clone()).await.unwrap();
+        let credentials = Credentials(ClientId::new(), Token::new());
+        service_handle.select(credentials).await.unwrap();
+        let metrics = LocalMetrics {
+            samples,
+            metrics: vec![("loss".to_string(), samples as f64)]
+                .into_iter()
+                .collect(),
+        };
+        service_handle
+            .upload_metrics(credentials, metrics)
+            .await
+            .unwrap();
+        partials.push(service_handle.partial_aggregate().await.unwrap());
+    }
+
+    let request_id = RequestId::new();
+    for service_handle in vec![&service_handle_1, &service_handle_2] {
+        service_handle
+            .merge(request_id, partials.clone())
+            .await
+            .unwrap();
+        let evaluations = service_handle.evaluations().await.unwrap();
+        assert_eq!(evaluations.len(), 1);
+        assert_eq!(evaluations[0].participants, 2);
+        assert_eq!(evaluations[0].samples, 40);
+        assert!((evaluations[0].metrics["loss"] - 25.0).abs() < 1e-9);
+    }
+}
+
+/// Test that the service terminates when the coordinator notifies
+/// the end of the training.
+#[tokio::test]
+async fn test_finish_training() {
+    let (_rpc_client, service_handle, join_handle) = start_service(None);
+    service_handle.finish_training().await.unwrap();
+    timeout(Duration::from_secs(5), join_handle)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+/// Test that in asynchronous mode, updates computed from recent
+/// versions of the model are accepted, while updates that are too
+/// stale or not versioned are rejected.
+#[tokio::test]
+async fn test_async_stale_upload() {
+    let (rpc_client, service_handle, _join_handle) =
+        start_service(Some(AsyncAggregationSettings { max_staleness: 1 }));
+    rpc_client
+        .mock()
+        .expect_end_training()
+        .returning(|_, _, _, _| future::ready(Ok(())));
+
+    // Participants that download the model before the aggregations
+    let credentials: Vec<Credentials> = (0..3)
+        .map(|_| Credentials(ClientId::new(), Token::new()))
+        .collect();
+    for credentials in credentials.iter() {
+        service_handle.select(*credentials).await.unwrap();
+    }
+    let initial_version = ModelVersion::new(0, &[]);
+
+    service_handle
+        .upload(
+            credentials[0],
+            Some(initial_version.clone()),
+            Bytes::from_static(b"1111"),
+        )
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+
+    // The credentials are still valid after the aggregation, and an
+    // update computed from the previous version is accepted
+    service_handle
+        .upload(
+            credentials[1],
+            Some(initial_version.clone()),
+            Bytes::from_static(b"2222"),
+        )
+        .await
+        .unwrap();
+    service_handle.aggregate(RequestId::new()).await.unwrap();
+
+    // ... but not from two versions ago
+    let res = service_handle
+        .upload(
+            credentials[2],
+            Some(initial_version),
+            Bytes::from_static(b"3333"),
+        )
+        .await;
+    match res {
+        Err(ServiceError::Request(UploadError::StaleModel)) => {}
+        _ => panic!("expected the upload to be rejected"),
+    }
+
+    let res = service_handle
+        .upload(credentials[2], None, Bytes::from_static(b"3333"))
+        .await;
+    match res {
+        Err(ServiceError::Request(UploadError::MissingModelVersion)) => {}
+        _ => panic!("expected the upload to be rejected"),
+    }
 }
@@ -1,5 +1,17 @@
 use crate::{
-    coordinator::{core::Service, models::HeartBeatResponse, settings::FederatedLearningSettings},
+    aggregator::rpc::ServerError,
+    common::settings::RpcCallSettings,
+    coordinator::{
+        core::{AggregatorClient, BanList, Service, TrainingOutcome},
+        models::{
+            HeartBeatResponse, RendezVousResponse, RoundTrainingMetrics, StartTrainingResponse,
+            TrainingConfig, TrainingMetrics,
+        },
+        settings::{
+            AdmissionPolicy, EvaluationSettings, EvictionSettings, FederatedLearningSettings,
+            LearningRateSchedule, Mode, TrainingSettings, TrainingStage,
+        },
+    },
     tests::lib::{
         coordinator::{MaxSelector, ServiceHandle},
         enable_logging,
@@ -7,29 +19,63 @@ use crate::{
         sleep_ms,
     },
 };
-use futures::future;
-use tokio::task::JoinHandle;
+use futures::{future, StreamExt};
+use serde_json::json;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{task::JoinHandle, time::timeout};
 
 const AGGREGATOR_URL: &str = "http://localhost:8082";
 
-fn start_service(settings: FederatedLearningSettings) -> (Client, ServiceHandle, JoinHandle<()>) {
+fn start_service(
+    settings: FederatedLearningSettings,
+) -> (Client, ServiceHandle, JoinHandle<TrainingOutcome>) {
+    let (mut rpc_clients, service_handle, join_handle) =
+        start_service_with_aggregators(settings, &[AGGREGATOR_URL]);
+    (rpc_clients.pop().unwrap(), service_handle, join_handle)
+}
+
+/// Start a service that spreads the clients over aggregators with
+/// the given URLs, and return the RPC clients of these aggregators.
+fn start_service_with_aggregators(
+    settings: FederatedLearningSettings,
+    urls: &[&str],
+) -> (Vec<Client>, ServiceHandle, JoinHandle<TrainingOutcome>) {
     // Make it easy to debug this test by setting the `TEST_LOGS`
     // environment variable
     enable_logging();
 
-    let rpc_client: Client = MockClient::default().into();
+    let rpc_clients: Vec<Client> = urls.iter().map(|_| MockClient::default().into()).collect();
+    let aggregators = urls
+        .iter()
+        .zip(rpc_clients.iter())
+        .map(|(url, rpc_client)| AggregatorClient {
+            url: url.to_string(),
+            rpc_client: rpc_client.clone(),
+        })
+        .collect();
+    for rpc_client in rpc_clients.iter() {
+        rpc_client
+            .mock()
+            .expect_start_round()
+            .returning(|_, _, _| future::ready(Ok(())));
+    }
 
     let (service_handle, service_requests) = ServiceHandle::new();
 
     let service = Service::new(
         MaxSelector,
         settings,
-        AGGREGATOR_URL.to_string(),
-        rpc_client.clone(),
+        EvictionSettings::default(),
+        BanList::default(),
+        aggregators,
+        RpcCallSettings::default(),
         service_requests,
     );
     let join_handle = tokio::spawn(service);
-    (rpc_client, service_handle, join_handle)
+    (rpc_clients, service_handle, join_handle)
 }
 
 /// Test a full cycle with a single round and a single participant.
@@ -39,9 +85,15 @@ async fn full_cycle_1_round_1_participant() {
         rounds: 1,
         participants_ratio: 1.0,
         min_clients: 1,
-        heartbeat_timeout: 10,
+        heartbeat_timeout: 1,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: None,
     };
-    let (rpc_client, service_handle, _join_handle) = start_service(settings);
+    let (rpc_client, service_handle, join_handle) = start_service(settings);
 
     let id = service_handle.rendez_vous_accepted().await;
     let round = service_handle.heartbeat_selected(id).await;
@@ -50,9 +102,9 @@ async fn full_cycle_1_round_1_participant() {
     rpc_client
         .mock()
         .expect_select()
-        .returning(|_, _| future::ready(Ok(())));
+        .returning(|_, _, _| future::ready(Ok(())));
 
-    let (url, _token) = service_handle.start_training_accepted(id).await;
+    let (url, _token, _) = service_handle.start_training_accepted(id).await;
     assert_eq!(&url, AGGREGATOR_URL);
 
     // pretend the client trained and sent its weights to the
@@ -63,7 +115,7 @@ async fn full_cycle_1_round_1_participant() {
     rpc_client
         .mock()
         .expect_aggregate()
-        .returning(|_| future::ready(Ok(())));
+        .returning(|_, _| future::ready(Ok(())));
 
     service_handle.end_training(id, true).await;
     loop {
@@ -73,6 +125,508 @@ async fn full_cycle_1_round_1_participant() {
             _ => panic!("expected StandBy or Finish"),
         }
     }
+
+    // The service stops once the client is gone
+    let outcome = timeout(Duration::from_secs(5), join_handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(outcome, TrainingOutcome::Complete);
+}
+
+/// Test that the changes of the heartbeat response are pushed to the
+/// clients that opened a push channel, and that the channel keeps the
+/// client alive.
+#[tokio::test]
+async fn heartbeat_push_channel() {
+    let settings = FederatedLearningSettings {
+        rounds: 1,
+        participants_ratio: 1.0,
+        min_clients: 2,
+        heartbeat_timeout: 1,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: None,
+    };
+    let (_rpc_client, service_handle, _join_handle) = start_service(settings);
+
+    let id_1 = service_handle.rendez_vous_accepted().await;
+    let mut events = service_handle.subscribe(id_1).await;
+    assert_eq!(events.next().await, Some(HeartBeatResponse::StandBy));
+
+    // The channel counts as a heartbeat
+    sleep_ms(1500).await;
+
+    // The round starts once the second client is there
+    let id_2 = service_handle.rendez_vous_accepted().await;
+    let event = timeout(Duration::from_secs(1), events.next())
+        .await
+        .unwrap();
+    assert_eq!(event, Some(HeartBeatResponse::Round(0)));
+    assert_eq!(service_handle.heartbeat_selected(id_2).await, 0);
+
+    // Once the channel is closed, the client must send heartbeats
+    // again
+    drop(events);
+    sleep_ms(1500).await;
+    assert_eq!(
+        service_handle.heartbeat(id_1).await,
+        HeartBeatResponse::Reject
+    );
+}
+
+/// Test that the aggregator is notified of the start of each round.
+#[tokio::test]
+async fn announce_rounds() {
+    let settings = FederatedLearningSettings {
+        rounds: 2,
+        participants_ratio: 1.0,
+        min_clients: 1,
+        heartbeat_timeout: 10,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: None,
+    };
+    let (rpc_client, service_handle, _join_handle) = start_service(settings);
+    // Replace the default expectation, before the service handles
+    // its first request
+    rpc_client.mock().checkpoint();
+    let rounds = Arc::new(Mutex::new(vec![]));
+    let rounds_clone = rounds.clone();
+    rpc_client
+        .mock()
+        .expect_start_round()
+        .returning(move |_, round, config| {
+            rounds_clone.lock().unwrap().push((round, config.rounds));
+            future::ready(Ok(()))
+        });
+    rpc_client
+        .mock()
+        .expect_select()
+        .returning(|_, _, _| future::ready(Ok(())));
+    rpc_client
+        .mock()
+        .expect_aggregate()
+        .returning(|_, _| future::ready(Ok(())));
+
+    let id = service_handle.rendez_vous_accepted().await;
+    for expected_round in 0..2 {
+        loop {
+            match service_handle.heartbeat(id).await {
+                HeartBeatResponse::StandBy => sleep_ms(10).await,
+                HeartBeatResponse::Round(round) => {
+                    assert_eq!(round, expected_round);
+                    break;
+                }
+                resp => panic!("expected StandBy or Round, got {:?}", resp),
+            }
+        }
+        service_handle.start_training_accepted(id).await;
+        service_handle.end_training(id, true).await;
+    }
+    loop {
+        match service_handle.heartbeat(id).await {
+            HeartBeatResponse::StandBy | HeartBeatResponse::Round(_) => sleep_ms(10).await,
+            HeartBeatResponse::Finish => break,
+            resp => panic!("expected StandBy, Round or Finish, got {:?}", resp),
+        }
+    }
+
+    // The end of the training is not announced as a new round
+    sleep_ms(100).await;
+    assert_eq!(*rounds.lock().unwrap(), vec![(0, 2), (1, 2)]);
+}
+
+/// Test that the model produced by the last round is evaluated
+/// before the training completes.
+#[tokio::test]
+async fn evaluation_rounds() {
+    let settings = FederatedLearningSettings {
+        rounds: 1,
+        participants_ratio: 1.0,
+        min_clients: 1,
+        heartbeat_timeout: 10,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: Some(EvaluationSettings { every: 1 }),
+    };
+    let (rpc_client, service_handle, _join_handle) = start_service(settings);
+    rpc_client.mock().checkpoint();
+    let rounds = Arc::new(Mutex::new(vec![]));
+    let rounds_clone = rounds.clone();
+    rpc_client
+        .mock()
+        .expect_start_round()
+        .returning(move |_, round, config| {
+            rounds_clone
+                .lock()
+                .unwrap()
+                .push((round, config.evaluation));
+            future::ready(Ok(()))
+        });
+    rpc_client
+        .mock()
+        .expect_select()
+        .returning(|_, _, _| future::ready(Ok(())));
+    rpc_client
+        .mock()
+        .expect_aggregate()
+        .returning(|_, _| future::ready(Ok(())));
+
+    let id = service_handle.rendez_vous_accepted().await;
+    for &(expected_round, evaluation) in [(0, false), (1, true)].iter() {
+        loop {
+            match service_handle.heartbeat(id).await {
+                HeartBeatResponse::StandBy => sleep_ms(10).await,
+                HeartBeatResponse::Round(round) => {
+                    assert_eq!(round, expected_round);
+                    break;
+                }
+                resp => panic!("expected StandBy or Round, got {:?}", resp),
+            }
+        }
+        let (_url, _token, config) = service_handle.start_training_accepted(id).await;
+        assert_eq!(config.unwrap().evaluation, evaluation);
+        service_handle.end_training(id, true).await;
+    }
+    loop {
+        match service_handle.heartbeat(id).await {
+            HeartBeatResponse::StandBy | HeartBeatResponse::Round(_) => sleep_ms(10).await,
+            HeartBeatResponse::Finish => break,
+            resp => panic!("expected StandBy, Round or Finish, got {:?}", resp),
+        }
+    }
+
+    sleep_ms(100).await;
+    assert_eq!(*rounds.lock().unwrap(), vec![(0, false), (1, true)]);
+}
+
+/// Test that the clients that don't support evaluation rounds are
+/// rejected when there are some, and that the clients that don't
+/// support the training configuration only get it for evaluation
+/// rounds.
+#[tokio::test]
+async fn capabilities() {
+    let settings = FederatedLearningSettings {
+        rounds: 1,
+        participants_ratio: 1.0,
+        min_clients: 1,
+        heartbeat_timeout: 10,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: Some(EvaluationSettings { every: 1 }),
+    };
+    let (rpc_client, service_handle, _join_handle) = start_service(settings);
+    rpc_client
+        .mock()
+        .expect_select()
+        .returning(|_, _, _| future::ready(Ok(())));
+    rpc_client
+        .mock()
+        .expect_aggregate()
+        .returning(|_, _| future::ready(Ok(())));
+
+    match service_handle.rendez_vous(&["training_config"]).await {
+        RendezVousResponse::Reject => {}
+        resp => panic!("expected Reject, got {:?}", resp),
+    }
+
+    let id = match service_handle.rendez_vous(&["evaluation"]).await {
+        RendezVousResponse::Accept(id) => id,
+        resp => panic!("expected Accept, got {:?}", resp),
+    };
+    for &evaluation in [false, true].iter() {
+        loop {
+            match service_handle.heartbeat(id).await {
+                HeartBeatResponse::StandBy => sleep_ms(10).await,
+                HeartBeatResponse::Round(_) => break,
+                resp => panic!("expected StandBy or Round, got {:?}", resp),
+            }
+        }
+        let (_url, _token, config) = service_handle.start_training_accepted(id).await;
+        if evaluation {
+            assert!(config.unwrap().evaluation);
+        } else {
+            assert_eq!(config, None);
+        }
+        service_handle.end_training(id, true).await;
+    }
+}
+
+/// Test that the training statistics reported by the participants
+/// are aggregated at the end of each round.
+#[tokio::test]
+async fn training_metrics() {
+    let settings = FederatedLearningSettings {
+        rounds: 2,
+        participants_ratio: 1.0,
+        min_clients: 1,
+        heartbeat_timeout: 10,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: None,
+    };
+    let (rpc_client, service_handle, _join_handle) = start_service(settings);
+    rpc_client
+        .mock()
+        .expect_select()
+        .returning(|_, _, _| future::ready(Ok(())));
+    rpc_client
+        .mock()
+        .expect_aggregate()
+        .returning(|_, _| future::ready(Ok(())));
+
+    let id = service_handle.rendez_vous_accepted().await;
+    assert_eq!(service_handle.heartbeat_selected(id).await, 0);
+    service_handle.start_training_accepted(id).await;
+    let metrics = TrainingMetrics {
+        samples: 100,
+        loss: Some(0.5),
+        accuracy: None,
+        duration: Some(12.0),
+    };
+    service_handle
+        .end_training_with_metrics(id, metrics.clone())
+        .await;
+    loop {
+        match service_handle.heartbeat(id).await {
+            HeartBeatResponse::StandBy => sleep_ms(10).await,
+            HeartBeatResponse::Round(1) => break,
+            resp => panic!("expected StandBy or Round(1), got {:?}", resp),
+        }
+    }
+
+    let expected = RoundTrainingMetrics {
+        round: 0,
+        participants: 1,
+        samples: 100,
+        loss: Some(0.5),
+        accuracy: None,
+        duration: Some(12.0),
+    };
+    assert_eq!(service_handle.training_metrics().await, vec![expected]);
+}
+
+/// Test that the start training responses carry the training
+/// configuration of the current round.
+#[tokio::test]
+async fn training_config() {
+    let training = TrainingSettings {
+        epochs: Some(2),
+        batch_size: Some(32),
+        learning_rate: Some(LearningRateSchedule::Exponential {
+            initial: 0.1,
+            decay: 0.5,
+        }),
+        hyperparameters: vec![("momentum".to_string(), json!(0.9))]
+            .into_iter()
+            .collect(),
+        stages: vec![TrainingStage {
+            from_round: 1,
+            epochs: Some(5),
+            batch_size: None,
+            learning_rate: None,
+            hyperparameters: vec![("dropout".to_string(), json!(0.2))]
+                .into_iter()
+                .collect(),
+        }],
+    };
+    let settings = FederatedLearningSettings {
+        rounds: 2,
+        participants_ratio: 1.0,
+        min_clients: 1,
+        heartbeat_timeout: 10,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training,
+        evaluation: None,
+    };
+    let (rpc_client, service_handle, _join_handle) = start_service(settings);
+    rpc_client
+        .mock()
+        .expect_select()
+        .returning(|_, _, _| future::ready(Ok(())));
+    rpc_client
+        .mock()
+        .expect_aggregate()
+        .returning(|_, _| future::ready(Ok(())));
+
+    let id = service_handle.rendez_vous_accepted().await;
+    let mut configs = vec![];
+    for _ in 0..2 {
+        loop {
+            match service_handle.heartbeat(id).await {
+                HeartBeatResponse::StandBy => sleep_ms(10).await,
+                HeartBeatResponse::Round(_) => break,
+                resp => panic!("expected StandBy or Round, got {:?}", resp),
+            }
+        }
+        let (_url, _token, config) = service_handle.start_training_accepted(id).await;
+        configs.push(config.unwrap());
+        service_handle.end_training(id, true).await;
+    }
+
+    assert_eq!(
+        configs[0],
+        TrainingConfig {
+            round: 0,
+            evaluation: false,
+            epochs: Some(2),
+            batch_size: Some(32),
+            learning_rate: Some(0.1),
+            hyperparameters: vec![("momentum".to_string(), json!(0.9))]
+                .into_iter()
+                .collect(),
+        }
+    );
+    assert_eq!(
+        configs[1],
+        TrainingConfig {
+            round: 1,
+            evaluation: false,
+            epochs: Some(5),
+            batch_size: Some(32),
+            learning_rate: Some(0.05),
+            hyperparameters: vec![
+                ("momentum".to_string(), json!(0.9)),
+                ("dropout".to_string(), json!(0.2)),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    );
+}
+
+/// Test that a client gets a proper error when the aggregator fails
+/// to select it, and can try again.
+#[tokio::test]
+async fn start_training_aggregator_unavailable() {
+    let settings = FederatedLearningSettings {
+        rounds: 1,
+        participants_ratio: 1.0,
+        min_clients: 1,
+        heartbeat_timeout: 10,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: None,
+    };
+    let (rpc_client, service_handle, _join_handle) = start_service(settings);
+
+    let id = service_handle.rendez_vous_accepted().await;
+    service_handle.heartbeat_selected(id).await;
+
+    rpc_client
+        .mock()
+        .expect_select()
+        .times(1)
+        .returning(|_, _, _| future::ready(Err(ServerError::Internal("select".to_string()))));
+    match service_handle.start_training(id).await {
+        StartTrainingResponse::Unavailable => {}
+        response => panic!("expected Unavailable, got {:?}", response),
+    }
+
+    // The client is still selected
+    rpc_client
+        .mock()
+        .expect_select()
+        .returning(|_, _, _| future::ready(Ok(())));
+    let (url, _token, _) = service_handle.start_training_accepted(id).await;
+    assert_eq!(&url, AGGREGATOR_URL);
+}
+
+/// Test that a failed aggregation aborts the round: the aggregator
+/// is reset and the participants are selected again, until the
+/// training is aborted after too many failures.
+#[tokio::test]
+async fn aggregation_failure() {
+    let settings = FederatedLearningSettings {
+        rounds: 1,
+        participants_ratio: 1.0,
+        min_clients: 1,
+        heartbeat_timeout: 1,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 1,
+        training: TrainingSettings::default(),
+        evaluation: None,
+    };
+    let (rpc_client, service_handle, join_handle) = start_service(settings);
+    // Calls to the aggregator, in the order they were received
+    let calls = Arc::new(Mutex::new(vec![]));
+    let calls_clone = calls.clone();
+    rpc_client.mock().expect_select().returning(move |_, _, _| {
+        calls_clone.lock().unwrap().push("select");
+        future::ready(Ok(()))
+    });
+    rpc_client
+        .mock()
+        .expect_aggregate()
+        .times(2)
+        .returning(|_, _| future::ready(Err(ServerError::Internal("aggregate".to_string()))));
+    let resets = Arc::new(Mutex::new(vec![]));
+    let resets_clone = resets.clone();
+    let calls_clone = calls.clone();
+    rpc_client.mock().expect_reset().returning(move |_, round| {
+        resets_clone.lock().unwrap().push(round);
+        calls_clone.lock().unwrap().push("reset");
+        future::ready(Ok(()))
+    });
+
+    let id = service_handle.rendez_vous_accepted().await;
+    let round = service_handle.heartbeat_selected(id).await;
+    assert_eq!(round, 0);
+    service_handle.start_training_accepted(id).await;
+    service_handle.end_training(id, true).await;
+
+    // The round is aborted and the participant is selected again
+    while resets.lock().unwrap().is_empty() {
+        sleep_ms(10).await;
+    }
+    assert_eq!(*resets.lock().unwrap(), vec![0]);
+    let round = service_handle.heartbeat_selected(id).await;
+    assert_eq!(round, 0);
+
+    // The second failure aborts the training. The participant is
+    // only selected by the aggregator once the reset went through.
+    service_handle.start_training_accepted(id).await;
+    assert_eq!(
+        calls.lock().unwrap()[..3],
+        ["select", "reset", "select"][..]
+    );
+    service_handle.end_training(id, true).await;
+    loop {
+        match service_handle.heartbeat(id).await {
+            HeartBeatResponse::StandBy | HeartBeatResponse::Round(_) => sleep_ms(10).await,
+            HeartBeatResponse::Finish => break,
+            resp => panic!("expected StandBy, Round or Finish, got {:?}", resp),
+        }
+    }
+    let outcome = timeout(Duration::from_secs(5), join_handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(outcome, TrainingOutcome::Aborted);
 }
 
 #[tokio::test]
@@ -82,6 +636,12 @@ async fn dropout_1_participant_during_training() {
         participants_ratio: 1.0,
         min_clients: 2,
         heartbeat_timeout: 1,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: None,
     };
     let (rpc_client, service_handle, _join_handle) = start_service(settings);
 
@@ -103,14 +663,14 @@ async fn dropout_1_participant_during_training() {
     rpc_client
         .mock()
         .expect_select()
-        .returning(|_, _| future::ready(Ok(())));
+        .returning(|_, _, _| future::ready(Ok(())));
     rpc_client
         .mock()
         .expect_select()
-        .returning(|_, _| future::ready(Ok(())));
-    let (url, _token) = service_handle.start_training_accepted(id_1).await;
+        .returning(|_, _, _| future::ready(Ok(())));
+    let (url, _token, _) = service_handle.start_training_accepted(id_1).await;
     assert_eq!(&url, AGGREGATOR_URL);
-    let (url, _token) = service_handle.start_training_accepted(id_2).await;
+    let (url, _token, _) = service_handle.start_training_accepted(id_2).await;
     assert_eq!(&url, AGGREGATOR_URL);
 
     // Here we reset only the heartbeat of the first client and wait until the
@@ -133,8 +693,8 @@ async fn dropout_1_participant_during_training() {
     rpc_client
         .mock()
         .expect_select()
-        .returning(|_, _| future::ready(Ok(())));
-    let (url, _token) = service_handle.start_training_accepted(id_3).await;
+        .returning(|_, _, _| future::ready(Ok(())));
+    let (url, _token, _) = service_handle.start_training_accepted(id_3).await;
     assert_eq!(&url, AGGREGATOR_URL);
 
     // Let's simulate that the second client tries to reconnect to the coordinator.
@@ -152,7 +712,7 @@ async fn dropout_1_participant_during_training() {
     rpc_client
         .mock()
         .expect_aggregate()
-        .returning(|_| future::ready(Ok(())));
+        .returning(|_, _| future::ready(Ok(())));
 
     // After the third client finished training, the coordinator should return the heartbeat
     // response `Finish`.
@@ -164,3 +724,62 @@ async fn dropout_1_participant_during_training() {
         }
     }
 }
+
+/// Test a full cycle with two participants spread over two
+/// aggregators: each aggregator computes a partial aggregation, and
+/// both merge the two partial aggregations.
+#[tokio::test]
+async fn full_cycle_2_aggregators() {
+    let settings = FederatedLearningSettings {
+        rounds: 1,
+        participants_ratio: 1.0,
+        min_clients: 2,
+        heartbeat_timeout: 10,
+        max_clients: None,
+        admission_policy: AdmissionPolicy::Reject,
+        mode: Mode::Sync,
+        max_aggregation_failures: 3,
+        training: TrainingSettings::default(),
+        evaluation: None,
+    };
+    let urls = ["http://localhost:8082", "http://localhost:8083"];
+    let (rpc_clients, service_handle, _join_handle) =
+        start_service_with_aggregators(settings, &urls);
+
+    let id_1 = service_handle.rendez_vous_accepted().await;
+    let id_2 = service_handle.rendez_vous_accepted().await;
+    service_handle.heartbeat_selected(id_1).await;
+    service_handle.heartbeat_selected(id_2).await;
+
+    // Each client is assigned to its own aggregator
+    for (i, rpc_client) in rpc_clients.iter().enumerate() {
+        let mut mock = rpc_client.mock();
+        mock.expect_select()
+            .times(1)
+            .returning(|_, _, _| future::ready(Ok(())));
+        mock.expect_partial_aggregate()
+            .times(1)
+            .returning(move |_| future::ready(Ok(vec![i as u8])));
+        mock.expect_merge()
+            .withf(|_, _, partials| partials == &vec![vec![0], vec![1]])
+            .times(1)
+            .returning(|_, _, _| future::ready(Ok(())));
+    }
+    let (url_1, _token, _) = service_handle.start_training_accepted(id_1).await;
+    let (url_2, _token, _) = service_handle.start_training_accepted(id_2).await;
+    assert_eq!(&url_1, urls[0]);
+    assert_eq!(&url_2, urls[1]);
+
+    service_handle.end_training(id_1, true).await;
+    service_handle.end_training(id_2, true).await;
+    loop {
+        match service_handle.heartbeat(id_1).await {
+            HeartBeatResponse::StandBy => sleep_ms(10).await,
+            HeartBeatResponse::Finish => break,
+            _ => panic!("expected StandBy or Finish"),
+        }
+    }
+    for rpc_client in rpc_clients.iter() {
+        rpc_client.mock().checkpoint();
+    }
+}
@@ -1,9 +1,13 @@
 use crate::{
-    aggregator::service::{
-        Aggregator, DownloadError, ServiceError, ServiceHandle as InnerServiceHandle,
-        ServiceRequests, UploadError,
+    aggregator::{
+        evaluation::{Evaluation, LocalMetrics},
+        service::{
+            Aggregator, DownloadError, Model, ModelVersion, RoundConfig, ServiceError,
+            ServiceHandle as InnerServiceHandle, ServiceRequests, UploadError,
+        },
     },
-    common::client::Credentials,
+    common::{client::Credentials, rpc::RequestId},
+    coordinator::models::TrainingMetrics,
 };
 use bytes::Bytes;
 use futures::future;
@@ -31,8 +35,9 @@ impl Aggregator for ByteAggregator {
 
     type AddWeightsFut = future::Ready<Result<(), Self::Error>>;
     type AggregateFut = future::Ready<Result<Bytes, Self::Error>>;
+    type ResetFut = future::Ready<Result<(), Self::Error>>;
 
-    fn add_weights(&mut self, weights: Bytes) -> Self::AddWeightsFut {
+    fn add_weights(&mut self, weights: Bytes, _staleness: Option<u32>) -> Self::AddWeightsFut {
         self.weights.extend(weights.into_iter());
         future::ready(Ok(()))
     }
@@ -42,6 +47,53 @@ impl Aggregator for ByteAggregator {
         let global_weights = Bytes::copy_from_slice(&self.weights[..]);
         future::ready(Ok(global_weights))
     }
+
+    fn partial_aggregate(&mut self) -> Self::AggregateFut {
+        let partial = self.aggregate();
+        self.weights.clear();
+        partial
+    }
+
+    fn merge(&mut self, partials: Vec<Bytes>) -> Self::AggregateFut {
+        self.weights = partials.into_iter().flatten().collect();
+        self.aggregate()
+    }
+
+    fn reset(&mut self, _global_weights: Bytes) -> Self::ResetFut {
+        self.weights.clear();
+        future::ready(Ok(()))
+    }
+}
+
+/// An aggregator whose aggregations never complete
+pub struct StalledAggregator;
+
+impl Aggregator for StalledAggregator {
+    type Error = ByteAggregatorError;
+
+    type AddWeightsFut = future::Ready<Result<(), Self::Error>>;
+    type AggregateFut = future::Pending<Result<Bytes, Self::Error>>;
+    type ResetFut = future::Ready<Result<(), Self::Error>>;
+
+    fn add_weights(&mut self, _weights: Bytes, _staleness: Option<u32>) -> Self::AddWeightsFut {
+        future::ready(Ok(()))
+    }
+
+    fn aggregate(&mut self) -> Self::AggregateFut {
+        future::pending()
+    }
+
+    fn partial_aggregate(&mut self) -> Self::AggregateFut {
+        future::pending()
+    }
+
+    fn merge(&mut self, _partials: Vec<Bytes>) -> Self::AggregateFut {
+        future::pending()
+    }
+
+    fn reset(&mut self, _global_weights: Bytes) -> Self::ResetFut {
+        future::ready(Ok(()))
+    }
 }
 
 impl<A> ServiceHandle<A>
@@ -56,23 +108,81 @@ where
     pub async fn download(
         &self,
         credentials: Credentials,
-    ) -> Result<Bytes, ServiceError<DownloadError>> {
+    ) -> Result<Model, ServiceError<DownloadError>> {
         self.0.download(credentials).await
     }
 
+    pub async fn check_credentials(
+        &self,
+        credentials: Credentials,
+    ) -> Result<Model, ServiceError<UploadError>> {
+        self.0.check_credentials(credentials).await
+    }
+
     pub async fn upload(
         &self,
         credentials: Credentials,
+        version: Option<ModelVersion>,
+        data: Bytes,
+    ) -> Result<(), ServiceError<UploadError>> {
+        self.0.upload(credentials, version, None, data).await
+    }
+
+    /// Upload local weights along with training statistics
+    pub async fn upload_with_metrics(
+        &self,
+        credentials: Credentials,
+        metrics: TrainingMetrics,
         data: Bytes,
     ) -> Result<(), ServiceError<UploadError>> {
-        self.0.upload(credentials, data).await
+        self.0.upload(credentials, None, Some(metrics), data).await
     }
 
-    pub async fn aggregate(&self) -> Result<(), ServiceError<A::Error>> {
-        self.0.aggregate().await
+    pub async fn upload_metrics(
+        &self,
+        credentials: Credentials,
+        metrics: LocalMetrics,
+    ) -> Result<(), ServiceError<UploadError>> {
+        self.0.upload_metrics(credentials, metrics).await
+    }
+
+    pub async fn aggregate(&self, request_id: RequestId) -> Result<(), ServiceError<A::Error>> {
+        self.0.aggregate(request_id).await
+    }
+
+    pub async fn partial_aggregate(&self) -> Result<Bytes, ServiceError<A::Error>> {
+        self.0.partial_aggregate().await
+    }
+
+    pub async fn merge(
+        &self,
+        request_id: RequestId,
+        partials: Vec<Bytes>,
+    ) -> Result<(), ServiceError<A::Error>> {
+        self.0.merge(request_id, partials).await
     }
 
     pub async fn select(&self, credentials: Credentials) -> Result<(), ServiceError<A::Error>> {
         self.0.select(credentials).await
     }
+
+    pub async fn reset(&self, round: u32) -> Result<(), ServiceError<A::Error>> {
+        self.0.reset(round).await
+    }
+
+    pub async fn start_round(
+        &self,
+        round: u32,
+        config: RoundConfig,
+    ) -> Result<(), ServiceError<A::Error>> {
+        self.0.start_round(round, config).await
+    }
+
+    pub async fn finish_training(&self) -> Result<(), ServiceError<A::Error>> {
+        self.0.finish_training().await
+    }
+
+    pub async fn evaluations(&self) -> Result<Vec<Evaluation>, ServiceError<A::Error>> {
+        self.0.evaluations().await
+    }
 }
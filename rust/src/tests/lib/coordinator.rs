@@ -1,8 +1,11 @@
 use crate::{
     common::client::{ClientId, Token},
     coordinator::{
-        core::{Selector, ServiceHandle as InnerServiceHandle, ServiceRequests},
-        models::{HeartBeatResponse, RendezVousResponse, StartTrainingResponse},
+        core::{HeartBeatStream, Selector, ServiceHandle as InnerServiceHandle, ServiceRequests},
+        models::{
+            HeartBeatResponse, RendezVousResponse, RoundTrainingMetrics, StartTrainingResponse,
+            TrainingConfig, TrainingMetrics, CAPABILITIES,
+        },
     },
 };
 
@@ -42,12 +45,23 @@ impl ServiceHandle {
     /// This method panics if the service fails to answer the request
     /// of if the rendez-vous request is rejected.
     pub async fn rendez_vous_accepted(&self) -> ClientId {
-        match self.0.rendez_vous().await.unwrap() {
+        match self.rendez_vous(CAPABILITIES).await {
             RendezVousResponse::Accept(id) => id,
-            RendezVousResponse::Reject => panic!("rendez-vous rejected"),
+            RendezVousResponse::Reject | RendezVousResponse::Retry(_) => {
+                panic!("rendez-vous rejected")
+            }
         }
     }
 
+    /// Send a rendez-vous request for a client that supports the
+    /// given capabilities
+    pub async fn rendez_vous(&self, capabilities: &[&'static str]) -> RendezVousResponse {
+        self.0
+            .rendez_vous(None, capabilities.to_vec())
+            .await
+            .unwrap()
+    }
+
     /// Send a heartbeat, assuming the response will be a
     /// [`HeartBeatResponse::Round`].
     ///
@@ -75,21 +89,45 @@ impl ServiceHandle {
         self.0.heartbeat(id).await.unwrap()
     }
 
+    /// Open a push channel
+    pub async fn subscribe(&self, id: ClientId) -> HeartBeatStream {
+        self.0.subscribe(id).await.unwrap()
+    }
+
     /// Send a start training request, assuming it will be accepted.
     ///
     /// # Panic
     ///
     /// This method panics if the service fails to answer the request
     /// or if it rejects it.
-    pub async fn start_training_accepted(&self, id: ClientId) -> (String, Token) {
+    pub async fn start_training_accepted(
+        &self,
+        id: ClientId,
+    ) -> (String, Token, Option<TrainingConfig>) {
         match self.0.start_training(id).await.unwrap() {
-            StartTrainingResponse::Accept(url, token) => (url, token),
-            StartTrainingResponse::Reject => panic!("start_training rejected"),
+            StartTrainingResponse::Accept(url, token, config) => (url, token, config),
+            response => panic!("start_training failed: {:?}", response),
         }
     }
 
+    /// Send a start training request
+    pub async fn start_training(&self, id: ClientId) -> StartTrainingResponse {
+        self.0.start_training(id).await.unwrap()
+    }
+
     /// Send an training request
     pub async fn end_training(&self, id: ClientId, success: bool) {
-        self.0.end_training(id, success).await
+        self.0.end_training(id, success, None).await
+    }
+
+    /// Send a successful end training request that carries the
+    /// given training statistics
+    pub async fn end_training_with_metrics(&self, id: ClientId, metrics: TrainingMetrics) {
+        self.0.end_training(id, true, Some(metrics)).await
+    }
+
+    /// Get the training statistics of the completed rounds
+    pub async fn training_metrics(&self) -> Vec<RoundTrainingMetrics> {
+        self.0.training_metrics().await.unwrap()
     }
 }
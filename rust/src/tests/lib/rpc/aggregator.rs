@@ -1,4 +1,7 @@
-use crate::{aggregator::rpc::ServerError, common::client::Credentials};
+use crate::{
+    aggregator::{rpc::ServerError, service::RoundConfig},
+    common::{client::Credentials, rpc::RequestId},
+};
 use futures::future;
 use mockall::mock;
 use std::{
@@ -17,9 +20,19 @@ mock! {
     pub Client {
         fn new<T: Transport<(), ()> + 'static>(config: Config, transport: T) -> MockNewClient;
 
-        fn select(&mut self, ctx: Context, credentials: Credentials) -> future::Ready<Result<(), ServerError<String>>>;
+        fn select(&mut self, ctx: Context, request_id: RequestId, credentials: Credentials) -> future::Ready<Result<(), ServerError<String>>>;
+
+        fn aggregate(&mut self, ctx: Context, request_id: RequestId) -> future::Ready<Result<(), ServerError<String>>>;
+
+        fn partial_aggregate(&mut self, ctx: Context) -> future::Ready<Result<Vec<u8>, ServerError<String>>>;
+
+        fn merge(&mut self, ctx: Context, request_id: RequestId, partials: Vec<Vec<u8>>) -> future::Ready<Result<(), ServerError<String>>>;
+
+        fn reset(&mut self, ctx: Context, round: u32) -> future::Ready<Result<(), ServerError<String>>>;
 
-        fn aggregate(&mut self, ctx: Context) -> future::Ready<Result<(), ServerError<String>>>;
+        fn start_round(&mut self, ctx: Context, round: u32, config: RoundConfig) -> future::Ready<Result<(), ServerError<String>>>;
+
+        fn finish_training(&mut self, ctx: Context) -> future::Ready<Result<(), ServerError<String>>>;
     }
 }
 
@@ -52,14 +65,64 @@ impl Client {
     pub fn select(
         &mut self,
         ctx: Context,
+        request_id: RequestId,
         credentials: Credentials,
     ) -> future::Ready<Result<(), ServerError<String>>> {
-        self.mock().select(ctx, credentials)
+        self.mock().select(ctx, request_id, credentials)
     }
 
     /// Get the inner `MockClient`'s `aggregate` method.
-    pub fn aggregate(&mut self, ctx: Context) -> future::Ready<Result<(), ServerError<String>>> {
-        self.mock().aggregate(ctx)
+    pub fn aggregate(
+        &mut self,
+        ctx: Context,
+        request_id: RequestId,
+    ) -> future::Ready<Result<(), ServerError<String>>> {
+        self.mock().aggregate(ctx, request_id)
+    }
+
+    /// Get the inner `MockClient`'s `partial_aggregate` method.
+    pub fn partial_aggregate(
+        &mut self,
+        ctx: Context,
+    ) -> future::Ready<Result<Vec<u8>, ServerError<String>>> {
+        self.mock().partial_aggregate(ctx)
+    }
+
+    /// Get the inner `MockClient`'s `merge` method.
+    pub fn merge(
+        &mut self,
+        ctx: Context,
+        request_id: RequestId,
+        partials: Vec<Vec<u8>>,
+    ) -> future::Ready<Result<(), ServerError<String>>> {
+        self.mock().merge(ctx, request_id, partials)
+    }
+
+    /// Get the inner `MockClient`'s `reset` method.
+    pub fn reset(
+        &mut self,
+        ctx: Context,
+        round: u32,
+    ) -> future::Ready<Result<(), ServerError<String>>> {
+        self.mock().reset(ctx, round)
+    }
+
+    /// Get the inner `MockClient`'s `start_round` method.
+    pub fn start_round(
+        &mut self,
+        ctx: Context,
+        round: u32,
+        config: RoundConfig,
+    ) -> future::Ready<Result<(), ServerError<String>>> {
+        self.mock().start_round(ctx, round, config)
+    }
+
+    /// Get the inner `MockClient`'s `finish_training` method.
+    pub fn finish_training(
+        &mut self,
+        ctx: Context,
+    ) -> future::Ready<Result<(), ServerError<String>>> {
+        self.mock().finish_training(ctx)
     }
 
     /// Get the inner `MockClient`.
@@ -1,4 +1,4 @@
-use crate::common::client::ClientId;
+use crate::{common::client::ClientId, coordinator::models::TrainingMetrics};
 use futures::future;
 use mockall::mock;
 use std::{
@@ -17,7 +17,7 @@ mock! {
     pub Client {
         fn new<T: Transport<(), ()> + 'static>(config: Config, transport: T) -> MockNewClient;
 
-        fn end_training(&mut self, ctx: Context, id: ClientId, success: bool) -> future::Ready<io::Result<()>>;
+        fn end_training(&mut self, ctx: Context, id: ClientId, success: bool, metrics: Option<TrainingMetrics>) -> future::Ready<io::Result<()>>;
     }
 }
 
@@ -34,8 +34,9 @@ impl Client {
         ctx: Context,
         id: ClientId,
         success: bool,
+        metrics: Option<TrainingMetrics>,
     ) -> future::Ready<io::Result<()>> {
-        self.mock().end_training(ctx, id, success)
+        self.mock().end_training(ctx, id, success, metrics)
     }
 
     pub fn mock(&self) -> MutexGuard<MockClient> {